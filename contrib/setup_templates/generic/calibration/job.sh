@@ -0,0 +1,44 @@
+#!/bin/bash
+
+set -e -u -o pipefail
+
+# A small set of synthetic benchmarks that only rely on tools available
+# in basically every distribution image.
+# The results are left in the job config file system, where Forrest
+# picks them up after the machine has powered off.
+
+RESULTS="${HOME}/config/results.json"
+SCRATCH="${HOME}/calibration.bin"
+
+seconds() {
+    local start end
+
+    start="$(date +%s.%N)"
+    "$@" > /dev/null 2>&1
+    end="$(date +%s.%N)"
+
+    awk "BEGIN { print ${end} - ${start} }"
+}
+
+# CPU: hash 2GiB of zeros on every available core in parallel.
+cpu_single="$(seconds sh -c 'head -c 2G /dev/zero | sha256sum')"
+cpu_all="$(seconds sh -c "seq $(nproc) | xargs -P $(nproc) -I{} sh -c 'head -c 2G /dev/zero | sha256sum'")"
+
+# Memory bandwidth: copy 16GiB through a large buffer.
+memory="$(seconds dd if=/dev/zero of=/dev/null bs=64M count=256)"
+
+# Disk: write and read back 2GiB bypassing the page cache.
+disk_write="$(seconds dd if=/dev/zero of="${SCRATCH}" bs=4M count=512 oflag=direct conv=fsync)"
+disk_read="$(seconds dd if="${SCRATCH}" of=/dev/null bs=4M iflag=direct)"
+rm -f "${SCRATCH}"
+
+cat > "${RESULTS}" << EOT
+{
+  "nproc": $(nproc),
+  "cpu_single_seconds": ${cpu_single},
+  "cpu_all_seconds": ${cpu_all},
+  "memory_seconds": ${memory},
+  "disk_write_seconds": ${disk_write},
+  "disk_read_seconds": ${disk_read}
+}
+EOT
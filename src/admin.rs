@@ -0,0 +1,1058 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, Permissions};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use nix::ifaddrs::getifaddrs;
+use octocrab::models::RunId;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::task::AbortHandle;
+use tokio::time::timeout;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::api_health::DegradedInfo;
+use crate::config::{parse_duration, AdminListen, AdminRole, AdminTls, Config, Overrides};
+use crate::error::Category;
+use crate::forge::BuildbotForge;
+use crate::jobs::{JobInfo, Manager as JobManager};
+use crate::machines::{
+    self, BackendReadiness, MachineInfo, Manager as MachineManager, OwnerAndRepo, Triplet,
+    UsageReport,
+};
+use crate::metrics::{DelayStats, EventCounts, Health, Histogram, Metrics};
+use crate::probe::{ProbeResult, Prober};
+use crate::subsystems::{Subsystem, Subsystems};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_SIZE_LIMIT: u64 = 64 * 1024;
+
+// Network interfaces may come and go (e.g. when a VPN re-connects)
+// and may change their addresses.
+// Re-resolve the addresses to listen on in this interval.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The body of a `POST /buildbot/workers/<worker>` request
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WorkerRequest {
+    machine: String,
+    password: String,
+}
+
+/// Where an admin request came from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    /// The admin socket, which only the user and group Forrest runs as can access
+    Socket,
+    /// A network listener on a loopback address
+    Loopback,
+    /// A network listener on any other address
+    Network,
+    /// A network listener that only accepts clients with a valid certificate,
+    /// which are granted the given role
+    Certificate(AdminRole),
+}
+
+#[derive(Serialize)]
+struct Status {
+    webhooks: &'static str,
+    polling_interval: u64,
+    machines: Vec<MachineInfo>,
+    jobs: Vec<JobInfo>,
+    repositories: Vec<ProbeResult>,
+    backends: Vec<BackendReadiness>,
+    degraded: Option<DegradedInfo>,
+}
+
+#[derive(Serialize)]
+struct BudgetStatus {
+    monthly: f64,
+    used: f64,
+    exceeded: bool,
+}
+
+#[derive(Serialize)]
+struct Accounting {
+    budgets: HashMap<String, BudgetStatus>,
+    months: UsageReport,
+}
+
+#[derive(Serialize)]
+struct MetricsReport {
+    delays: BTreeMap<&'static str, DelayStats>,
+    events: BTreeMap<String, EventCounts>,
+    histograms: BTreeMap<&'static str, Histogram>,
+    errors: BTreeMap<&'static str, BTreeMap<Category, u64>>,
+    resources: BTreeMap<String, BTreeMap<&'static str, DelayStats>>,
+    aliases: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    threshold: Option<f64>,
+    /// The machine types with a score below the threshold
+    unhealthy: Vec<String>,
+    machines: BTreeMap<String, Health>,
+}
+
+struct Response {
+    code: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct AdminHandler {
+    config: Config,
+    machine_manager: MachineManager,
+    job_manager: JobManager,
+    prober: Prober,
+    metrics: Metrics,
+    buildbot: Option<Arc<BuildbotForge>>,
+    subsystems: Subsystems,
+}
+
+impl Response {
+    fn json(value: &impl Serialize) -> Self {
+        Self {
+            code: 200,
+            reason: "OK",
+            content_type: "application/json",
+            body: serde_json::to_vec_pretty(value).unwrap(),
+        }
+    }
+
+    fn error(code: u16, reason: &'static str) -> Self {
+        Self {
+            code,
+            reason,
+            content_type: "text/plain",
+            body: reason.as_bytes().to_vec(),
+        }
+    }
+
+    fn bad_request(message: String) -> Self {
+        Self {
+            code: 400,
+            reason: "Bad Request",
+            content_type: "text/plain",
+            body: message.into_bytes(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res = format!(
+            "HTTP/1.1 {} {}\r\nServer: Forrest\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.code,
+            self.reason,
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+
+        res.extend_from_slice(&self.body);
+
+        res
+    }
+}
+
+/// The role needed to make a `method` request for `path`
+fn required_role(method: &str, path: &str) -> AdminRole {
+    // Match on the same path `route()` does, without the query.
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+    match method {
+        "GET" => AdminRole::ReadOnly,
+        // These create and remove credentials to register runners with.
+        _ if path.starts_with("/buildbot/") => AdminRole::Admin,
+        // These stop Forrest from picking up jobs or starting machines at all.
+        _ if path.starts_with("/subsystems/") => AdminRole::Admin,
+        // These change how much of the host Forrest may use at all.
+        _ if path == "/overrides" || path.starts_with("/overrides/host") => AdminRole::Admin,
+        _ => AdminRole::Operator,
+    }
+}
+
+/// Get the value of the query parameter `name`
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Parse the value of the optional query parameter `name`
+fn parse_param<T: FromStr>(query: &str, name: &str) -> Result<Option<T>, Response> {
+    match query_param(query, name).map(str::parse) {
+        Some(Ok(value)) => Ok(Some(value)),
+        Some(Err(_)) => Err(Response::bad_request(format!("Invalid {name}"))),
+        None => Ok(None),
+    }
+}
+
+/// Read the PEM encoded certificates in the file at `path`
+fn read_certs(path: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = std::io::BufReader::new(File::open(path)?);
+
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+/// Set up the TLS server side of an admin listener
+///
+/// If the `tls` config has a `client_ca`, clients have to present a
+/// certificate signed by it.
+fn tls_acceptor(tls: &AdminTls) -> std::io::Result<TlsAcceptor> {
+    let certs = read_certs(&tls.certificate)?;
+
+    let key = {
+        let mut reader = std::io::BufReader::new(File::open(&tls.key)?);
+
+        rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("No private key found in {}", tls.key.display()),
+            )
+        })?
+    };
+
+    let builder = ServerConfig::builder();
+
+    let builder = match &tls.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+
+            for cert in read_certs(client_ca)? {
+                roots.add(cert).map_err(std::io::Error::other)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(std::io::Error::other)?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(std::io::Error::other)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Resolve the socket addresses to listen on for a `listen` config entry
+///
+/// If an interface name is given all addresses of that interface are used.
+fn resolve(listen: &AdminListen) -> Vec<SocketAddr> {
+    let port = listen.port;
+
+    let mut addrs = Vec::new();
+
+    if let Some(address) = listen.address {
+        addrs.push(SocketAddr::new(address, port));
+    }
+
+    if let Some(interface) = &listen.interface {
+        let ifaddrs = match getifaddrs() {
+            Ok(ifaddrs) => ifaddrs,
+            Err(e) => {
+                error!("Failed to get network interface addresses: {e}");
+                return addrs;
+            }
+        };
+
+        for ifaddr in ifaddrs.filter(|ifa| &ifa.interface_name == interface) {
+            let address = match ifaddr.address {
+                Some(address) => address,
+                None => continue,
+            };
+
+            if let Some(sin) = address.as_sockaddr_in() {
+                addrs.push(SocketAddr::new(IpAddr::V4(sin.ip()), port));
+            }
+
+            if let Some(sin6) = address.as_sockaddr_in6() {
+                // Link local IPv6 addresses are only unique in combination
+                // with the interface, hence the scope id.
+                let addr = SocketAddrV6::new(sin6.ip(), port, 0, sin6.scope_id());
+                addrs.push(SocketAddr::V6(addr));
+            }
+        }
+
+        if addrs.is_empty() {
+            warn!("Network interface {interface} has no addresses (yet)");
+        }
+    }
+
+    addrs
+}
+
+impl AdminHandler {
+    pub fn new(
+        config: Config,
+        machine_manager: MachineManager,
+        job_manager: JobManager,
+        prober: Prober,
+        metrics: Metrics,
+        buildbot: Option<Arc<BuildbotForge>>,
+        subsystems: Subsystems,
+    ) -> Self {
+        Self {
+            config,
+            machine_manager,
+            job_manager,
+            prober,
+            metrics,
+            buildbot,
+            subsystems,
+        }
+    }
+
+    fn status(&self) -> Status {
+        let cfg = self.config.get();
+
+        let webhooks = match (cfg.github.poll_only(), &cfg.github.webhook_relay) {
+            (true, _) => "disabled",
+            (false, Some(_)) => "relay",
+            (false, None) => "listener",
+        };
+
+        Status {
+            webhooks,
+            polling_interval: cfg.github.polling_interval().as_secs(),
+            machines: self.machine_manager.machine_info(),
+            jobs: self.job_manager.job_info(),
+            repositories: self.prober.results(),
+            backends: self.machine_manager.backend_readiness(),
+            degraded: self.machine_manager.degraded(),
+        }
+    }
+
+    fn metrics(&self) -> MetricsReport {
+        MetricsReport {
+            delays: self.metrics.delays(),
+            events: self.metrics.events(),
+            histograms: self.metrics.histograms(),
+            errors: self.metrics.errors(),
+            resources: self.metrics.resources(),
+            aliases: self.metrics.aliases(),
+        }
+    }
+
+    fn health(&self) -> HealthReport {
+        let threshold = self.config.get().admin.health_threshold;
+        let machines = self.metrics.health();
+
+        let unhealthy = machines
+            .iter()
+            .filter(|(_, health)| threshold.is_some_and(|t| health.score < t))
+            .map(|(machine, _)| machine.clone())
+            .collect();
+
+        HealthReport {
+            threshold,
+            unhealthy,
+            machines,
+        }
+    }
+
+    fn accounting(&self) -> Accounting {
+        let cfg = self.config.get();
+
+        let budgets = cfg
+            .budgets
+            .iter()
+            .map(|(owner, budget)| {
+                let used = self.machine_manager.usage(owner).cost;
+
+                let status = BudgetStatus {
+                    monthly: budget.monthly,
+                    used,
+                    exceeded: used >= budget.monthly,
+                };
+
+                (owner.clone(), status)
+            })
+            .collect();
+
+        Accounting {
+            budgets,
+            months: self.machine_manager.usage_report(),
+        }
+    }
+
+    /// Pin or un-pin machines of a triplet
+    ///
+    /// Takes the `count` and `duration` from the query parameters,
+    /// like `/pins/owner/repo/machine?count=2&duration=8h`.
+    fn pin(&self, triplet: &str, query: &str, remove: bool) -> Response {
+        let triplet = match triplet.parse() {
+            Ok(triplet) => triplet,
+            Err(e) => return Response::bad_request(e),
+        };
+
+        let (count, duration) = match remove {
+            true => (0, Duration::ZERO),
+            false => {
+                let count = match query_param(query, "count").map(str::parse) {
+                    Some(Ok(count)) => count,
+                    _ => return Response::bad_request("Missing or invalid count".to_owned()),
+                };
+
+                let duration = match query_param(query, "duration").map(parse_duration) {
+                    Some(Ok(duration)) => duration,
+                    Some(Err(e)) => return Response::bad_request(e),
+                    None => return Response::bad_request("Missing duration".to_owned()),
+                };
+
+                (count, duration)
+            }
+        };
+
+        match self.machine_manager.pin(triplet, count, duration) {
+            Ok(()) => Response::json(&self.machine_manager.pins()),
+            Err(e) => Response::bad_request(e),
+        }
+    }
+
+    /// Override the registration limit of a repository or remove the override
+    ///
+    /// Takes the `limit` and `duration` from the query parameters,
+    /// like `/registration-limits/owner/repo?limit=50&duration=2h`.
+    /// A `limit` of `none` lifts the limit for the duration.
+    fn registration_limit(&self, oar: &str, query: &str, remove: bool) -> Response {
+        let oar = match oar.parse() {
+            Ok(oar) => oar,
+            Err(e) => return Response::bad_request(e),
+        };
+
+        let (limit, duration) = match remove {
+            true => (None, Duration::ZERO),
+            false => {
+                let limit = match query_param(query, "limit") {
+                    Some("none") => None,
+                    Some(limit) => match limit.parse() {
+                        Ok(limit) => Some(limit),
+                        Err(_) => return Response::bad_request("Invalid limit".to_owned()),
+                    },
+                    None => return Response::bad_request("Missing limit".to_owned()),
+                };
+
+                let duration = match query_param(query, "duration").map(parse_duration) {
+                    Some(Ok(duration)) => duration,
+                    Some(Err(e)) => return Response::bad_request(e),
+                    None => return Response::bad_request("Missing duration".to_owned()),
+                };
+
+                (limit, duration)
+            }
+        };
+
+        match self
+            .machine_manager
+            .override_registration_limit(oar, limit, duration)
+        {
+            Ok(()) => Response::json(&self.machine_manager.registration_limits()),
+            Err(e) => Response::bad_request(e),
+        }
+    }
+
+    /// Change the overrides of `target` or remove them
+    ///
+    /// `target` is `host`, `machines/owner/repo/machine` or `repositories/owner/repo`.
+    /// Takes the values to override from the query parameters,
+    /// like `/overrides/machines/owner/repo/machine?standby=2`.
+    /// Values that are not given keep their current override, if any.
+    fn edit_overrides(
+        &self,
+        target: &str,
+        query: &str,
+        remove: bool,
+    ) -> Result<Overrides, Response> {
+        let cfg = self.config.get();
+        let mut overrides = self.config.overrides();
+
+        if target == "host" {
+            let host = &mut overrides.host;
+
+            match remove {
+                true => *host = Default::default(),
+                false => {
+                    host.ram = parse_param(query, "ram")?.or(host.ram);
+                    host.cpus = parse_param(query, "cpus")?.or(host.cpus);
+                }
+            }
+        } else if let Some(triplet) = target.strip_prefix("machines/") {
+            let triplet: Triplet = triplet.parse().map_err(Response::bad_request)?;
+
+            let known = cfg
+                .repositories
+                .get(triplet.owner())
+                .and_then(|repos| repos.get(triplet.repository()))
+                .is_some_and(|repo| repo.machines.contains_key(triplet.machine_name()));
+
+            if !known {
+                return Err(Response::error(404, "Not Found"));
+            }
+
+            match remove {
+                true => {
+                    overrides.machines.remove(&triplet.to_string());
+                }
+                false => {
+                    let standby = parse_param(query, "standby")?;
+                    let machine = overrides.machines.entry(triplet.to_string()).or_default();
+
+                    machine.standby = standby.or(machine.standby);
+                }
+            }
+        } else if let Some(oar) = target.strip_prefix("repositories/") {
+            let oar: OwnerAndRepo = oar.parse().map_err(Response::bad_request)?;
+
+            let known = cfg
+                .repositories
+                .get(oar.owner())
+                .is_some_and(|repos| repos.contains_key(oar.repository()));
+
+            if !known {
+                return Err(Response::error(404, "Not Found"));
+            }
+
+            match remove {
+                true => {
+                    overrides.repositories.remove(&oar.to_string());
+                }
+                false => {
+                    let registrations_per_hour = parse_param(query, "registrations_per_hour")?;
+                    let max_concurrent_machines = parse_param(query, "max_concurrent_machines")?;
+                    let repo = overrides.repositories.entry(oar.to_string()).or_default();
+
+                    repo.registrations_per_hour =
+                        registrations_per_hour.or(repo.registrations_per_hour);
+                    repo.max_concurrent_machines =
+                        max_concurrent_machines.or(repo.max_concurrent_machines);
+                }
+            }
+        } else {
+            return Err(Response::error(404, "Not Found"));
+        }
+
+        Ok(overrides)
+    }
+
+    /// Persist and apply `overrides`
+    ///
+    /// `caller` identifies who made the request, for the log.
+    fn set_overrides(&self, overrides: Overrides, caller: &str) -> Response {
+        if let Err(e) = self.config.set_overrides(&overrides) {
+            error!("Failed to store the overrides requested by {caller}: {e}");
+            return Response::error(500, "Internal Server Error");
+        }
+
+        info!("Settings overridden by {caller} via the admin API");
+
+        // E.g. start machines for a raised standby count right away.
+        self.machine_manager.apply_config();
+
+        Response::json(&overrides)
+    }
+
+    /// Request or release a Buildbot latent worker
+    ///
+    /// Takes the `machine` type and worker `password` from a JSON body,
+    /// so that the password does not end up in e.g. access logs.
+    fn buildbot_worker(&self, worker: &str, body: &[u8], release: bool) -> Response {
+        let buildbot = match &self.buildbot {
+            Some(buildbot) => buildbot,
+            None => return Response::error(404, "Not Found"),
+        };
+
+        if release {
+            return match buildbot.release(worker) {
+                true => Response::json(&buildbot.workers()),
+                false => Response::error(404, "Not Found"),
+            };
+        }
+
+        let request: WorkerRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return Response::bad_request(format!("Invalid worker request: {e}")),
+        };
+
+        let triplet = match request.machine.parse() {
+            Ok(triplet) => triplet,
+            Err(e) => return Response::bad_request(e),
+        };
+
+        match buildbot.request(worker, triplet, &request.password) {
+            Ok(()) => Response::json(&buildbot.workers()),
+            Err(e) => Response::bad_request(e),
+        }
+    }
+
+    /// Boost a workflow run or remove its boost
+    ///
+    /// `caller` identifies who made the request, for the log.
+    fn boost(&self, run_id: &str, caller: &str, remove: bool) -> Response {
+        let run_id = match run_id.parse() {
+            Ok(run_id) => RunId(run_id),
+            Err(_) => return Response::bad_request("Invalid run id".to_owned()),
+        };
+
+        let found = match remove {
+            true => self.job_manager.unboost_run(run_id, caller),
+            false => self.job_manager.boost_run(run_id, caller),
+        };
+
+        match found {
+            true => Response::json(&self.job_manager.boosts()),
+            false => Response::error(404, "Not Found"),
+        }
+    }
+
+    /// Start or stop a subsystem
+    ///
+    /// `caller` identifies who made the request, for the log.
+    fn set_subsystem(&self, subsystem: &str, caller: &str, running: bool) -> Response {
+        let subsystem: Subsystem = match subsystem.parse() {
+            Ok(subsystem) => subsystem,
+            Err(e) => return Response::bad_request(e),
+        };
+
+        if self.subsystems.set_running(subsystem, running) {
+            match running {
+                true => {
+                    info!("The {subsystem} subsystem was started by {caller} via the admin API")
+                }
+                false => {
+                    info!("The {subsystem} subsystem was stopped by {caller} via the admin API")
+                }
+            }
+        }
+
+        Response::json(&self.subsystems.states())
+    }
+
+    fn route(&self, method: &str, path: &str, body: &[u8], caller: &str) -> Response {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+        if let Some(run_id) = path
+            .strip_prefix("/runs/")
+            .and_then(|p| p.strip_suffix("/boost"))
+        {
+            return match method {
+                "POST" => self.boost(run_id, caller, false),
+                "DELETE" => self.boost(run_id, caller, true),
+                _ => Response::error(405, "Method Not Allowed"),
+            };
+        }
+
+        if let Some((subsystem, action)) = path
+            .strip_prefix("/subsystems/")
+            .and_then(|p| p.split_once('/'))
+        {
+            return match (method, action) {
+                ("POST", "start") => self.set_subsystem(subsystem, caller, true),
+                ("POST", "stop") => self.set_subsystem(subsystem, caller, false),
+                (_, "start" | "stop") => Response::error(405, "Method Not Allowed"),
+                _ => Response::error(404, "Not Found"),
+            };
+        }
+
+        if let Some(triplet) = path.strip_prefix("/pins/") {
+            return match method {
+                "POST" => self.pin(triplet, query, false),
+                "DELETE" => self.pin(triplet, query, true),
+                _ => Response::error(405, "Method Not Allowed"),
+            };
+        }
+
+        if let Some(oar) = path.strip_prefix("/registration-limits/") {
+            return match method {
+                "POST" => self.registration_limit(oar, query, false),
+                "DELETE" => self.registration_limit(oar, query, true),
+                _ => Response::error(405, "Method Not Allowed"),
+            };
+        }
+
+        if let Some(target) = path.strip_prefix("/overrides/") {
+            let remove = match method {
+                "POST" => false,
+                "DELETE" => true,
+                _ => return Response::error(405, "Method Not Allowed"),
+            };
+
+            return match self.edit_overrides(target, query, remove) {
+                Ok(overrides) => self.set_overrides(overrides, caller),
+                Err(response) => response,
+            };
+        }
+
+        if let Some(worker) = path.strip_prefix("/buildbot/workers/") {
+            return match method {
+                "POST" => self.buildbot_worker(worker, body, false),
+                "DELETE" => self.buildbot_worker(worker, body, true),
+                _ => Response::error(405, "Method Not Allowed"),
+            };
+        }
+
+        if let Some(triplet) = path.strip_prefix("/demand/") {
+            let demand = self
+                .machine_manager
+                .demand_info()
+                .into_iter()
+                .find(|d| d.triplet == triplet);
+
+            return match (method, demand) {
+                ("GET", Some(demand)) => Response::json(&demand),
+                ("GET", None) => Response::error(404, "Not Found"),
+                _ => Response::error(405, "Method Not Allowed"),
+            };
+        }
+
+        if let Some(runner_name) = path
+            .strip_prefix("/machines/")
+            .and_then(|p| p.strip_suffix("/drain"))
+        {
+            return match method {
+                "POST" => match self
+                    .machine_manager
+                    .drain_machine(runner_name, "drained via the admin API")
+                {
+                    true => Response::json(&self.machine_manager.machine_history(runner_name)),
+                    false => Response::error(404, "Not Found"),
+                },
+                _ => Response::error(405, "Method Not Allowed"),
+            };
+        }
+
+        if let Some(runner_name) = path
+            .strip_prefix("/machines/")
+            .and_then(|p| p.strip_suffix("/history"))
+        {
+            return match (method, self.machine_manager.machine_history(runner_name)) {
+                ("GET", Some(history)) => Response::json(&history),
+                ("GET", None) => Response::error(404, "Not Found"),
+                _ => Response::error(405, "Method Not Allowed"),
+            };
+        }
+
+        if let Some(runner_name) = path
+            .strip_prefix("/machines/")
+            .and_then(|p| p.strip_suffix("/sbom"))
+        {
+            return match (method, machines::job_sbom(&self.config.get(), runner_name)) {
+                ("GET", Some(sbom)) => Response {
+                    code: 200,
+                    reason: "OK",
+                    content_type: "application/spdx+json",
+                    body: sbom.into_bytes(),
+                },
+                ("GET", None) => Response::error(404, "Not Found"),
+                _ => Response::error(405, "Method Not Allowed"),
+            };
+        }
+
+        match (method, path) {
+            ("GET", "/pins") => Response::json(&self.machine_manager.pins()),
+            ("GET", "/boosts") => Response::json(&self.job_manager.boosts()),
+            ("GET", "/registration-limits") => {
+                Response::json(&self.machine_manager.registration_limits())
+            }
+            ("GET", "/status") => Response::json(&self.status()),
+            ("GET", "/accounting") => Response::json(&self.accounting()),
+            ("GET", "/recommendations") => {
+                Response::json(&machines::recommendations(&self.config.get()))
+            }
+            ("GET", "/demand") => Response::json(&self.machine_manager.demand_info()),
+            ("GET", "/metrics") => Response::json(&self.metrics()),
+            ("GET", "/health") => Response::json(&self.health()),
+            ("GET", "/subsystems") => Response::json(&self.subsystems.states()),
+            ("GET", "/config-diff") => Response::json(&self.config.last_diff().as_deref()),
+            ("GET", "/label-scopes") => Response::json(&self.config.get().label_scopes()),
+            ("GET", "/overrides") => Response::json(&self.config.overrides()),
+            ("DELETE", "/overrides") => self.set_overrides(Overrides::default(), caller),
+            ("GET", "/buildbot/workers") => match &self.buildbot {
+                Some(buildbot) => Response::json(&buildbot.workers()),
+                None => Response::error(404, "Not Found"),
+            },
+            ("GET", _) => Response::error(404, "Not Found"),
+            _ => Response::error(405, "Method Not Allowed"),
+        }
+    }
+
+    /// Check if a request may be made
+    ///
+    /// Requests via the admin socket are trusted, as the socket is only
+    /// accessible to the user and group Forrest runs as.
+    /// Requests via the network need a token or client certificate with a
+    /// sufficient role.
+    /// If no tokens are configured at all, requests via a loopback address
+    /// may only read, as any local user can make them.
+    /// Returns who made the request, e.g. for audit log messages.
+    fn authorize(
+        &self,
+        origin: Origin,
+        authorization: Option<&str>,
+        method: &str,
+        path: &str,
+    ) -> Result<String, Response> {
+        let cfg = self.config.get();
+
+        if origin == Origin::Socket {
+            return Ok("the admin socket".to_owned());
+        }
+
+        let token = authorization.and_then(|a| cfg.admin.token(a));
+
+        let (caller, role) = match (token, origin) {
+            (Some((name, role)), _) => (format!("token {name}"), role),
+            (None, Origin::Certificate(role)) => ("a client certificate".to_owned(), role),
+            (None, Origin::Loopback) if cfg.admin.tokens.is_empty() => (
+                "an unauthenticated local request".to_owned(),
+                AdminRole::ReadOnly,
+            ),
+            (None, _) => return Err(Response::error(401, "Unauthorized")),
+        };
+
+        let required = required_role(method, path);
+
+        if role < required {
+            warn!("Refusing admin request {method} {path} from {caller} ({role:?} < {required:?})");
+            return Err(Response::error(403, "Forbidden"));
+        }
+
+        Ok(caller)
+    }
+
+    async fn handle<S>(&self, sock: S, origin: Origin) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (read, mut write) = tokio::io::split(sock);
+        let mut read = BufReader::new(read.take(REQUEST_SIZE_LIMIT));
+
+        // Like the webhook handler this is a very minimal HTTP server,
+        // that only understands as much of the protocol as it needs to.
+        let mut line = String::new();
+        read.read_line(&mut line).await?;
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_owned();
+        let path = parts.next().unwrap_or_default().to_owned();
+
+        // Skip the headers, except for the ones we need for authorization
+        // and to read the body.
+        let mut authorization = None;
+        let mut content_length = 0;
+
+        loop {
+            line.clear();
+            read.read_line(&mut line).await?;
+
+            if line.trim().is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                let name = name.trim();
+
+                if name.eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_owned());
+                }
+
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        debug!("Got admin request {method} {path}");
+
+        let response = match self.authorize(origin, authorization.as_deref(), &method, &path) {
+            Ok(caller) => {
+                // The body can not be larger than the request size limit anyways.
+                let mut body = vec![0; content_length.min(REQUEST_SIZE_LIMIT as usize)];
+                read.read_exact(&mut body).await?;
+
+                self.route(&method, &path, &body, &caller)
+            }
+            Err(response) => response,
+        };
+
+        write.write_all(&response.to_bytes()).await
+    }
+
+    fn handle_in_background<S>(&self, sock: S, origin: Origin)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let handler = self.clone();
+
+        tokio::task::spawn(async move {
+            let res = timeout(REQUEST_TIMEOUT, handler.handle(sock, origin)).await;
+
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("Admin request handler failed due to: {err}"),
+                Err(_) => warn!("Admin request handler took too long to run"),
+            }
+        });
+    }
+
+    async fn serve_tcp(self, listener: TcpListener, origin: Origin, tls: Option<TlsAcceptor>) {
+        loop {
+            let sock = match listener.accept().await {
+                Ok((sock, _)) => sock,
+                Err(e) => {
+                    error!("Failed to accept admin connection: {e}");
+                    continue;
+                }
+            };
+
+            let acceptor = match &tls {
+                Some(acceptor) => acceptor.clone(),
+                None => {
+                    self.handle_in_background(sock, origin);
+                    continue;
+                }
+            };
+
+            // Do the handshake in the background as well,
+            // so that a slow client does not hold up the others.
+            let handler = self.clone();
+
+            tokio::task::spawn(async move {
+                match timeout(REQUEST_TIMEOUT, acceptor.accept(sock)).await {
+                    Ok(Ok(stream)) => handler.handle_in_background(stream, origin),
+                    Ok(Err(e)) => warn!("TLS handshake of admin connection failed: {e}"),
+                    Err(_) => warn!("TLS handshake of admin connection took too long"),
+                }
+            });
+        }
+    }
+
+    /// Keep the set of TCP listeners in sync with the config and interface addresses
+    ///
+    /// Addresses other than loopback addresses are only listened on if
+    /// requests to them are authenticated via `admin.tokens` or
+    /// client certificates, so that the API is never exposed to the network
+    /// without authentication.
+    async fn manage_tcp_listeners(&self) {
+        let mut bound: HashMap<SocketAddr, (Option<AdminTls>, AbortHandle)> = HashMap::new();
+        let mut refused: HashSet<SocketAddr> = HashSet::new();
+
+        loop {
+            let cfg = self.config.get();
+
+            let mut wanted = HashMap::new();
+            let mut unauthenticated = HashSet::new();
+
+            for listen in &cfg.admin.listen {
+                let authenticated = cfg.admin.authenticates(listen);
+
+                for addr in resolve(listen) {
+                    if addr.ip().is_loopback() || authenticated {
+                        wanted.insert(addr, listen.tls.clone());
+                    } else {
+                        unauthenticated.insert(addr);
+                    }
+                }
+            }
+
+            for addr in unauthenticated.difference(&refused) {
+                error!("Refusing to listen for admin requests on {addr} without authentication via admin.tokens or client certificates");
+            }
+
+            refused = unauthenticated;
+
+            // Listeners whose TLS settings changed are bound anew.
+            bound.retain(|addr, (tls, abort)| {
+                let keep = wanted.get(addr) == Some(tls);
+
+                if !keep {
+                    info!("Stop listening for admin requests on {addr}");
+                    abort.abort();
+                }
+
+                keep
+            });
+
+            for (addr, tls) in wanted {
+                if bound.contains_key(&addr) {
+                    continue;
+                }
+
+                let origin = match &tls {
+                    Some(AdminTls {
+                        client_ca: Some(_),
+                        client_role,
+                        ..
+                    }) => Origin::Certificate(*client_role),
+                    _ if addr.ip().is_loopback() => Origin::Loopback,
+                    _ => Origin::Network,
+                };
+
+                let acceptor = match tls.as_ref().map(tls_acceptor).transpose() {
+                    Ok(acceptor) => acceptor,
+                    Err(e) => {
+                        error!("Failed to set up TLS for admin requests on {addr}: {e}");
+                        continue;
+                    }
+                };
+
+                match TcpListener::bind(addr).await {
+                    Ok(listener) => {
+                        info!("Listening for admin requests on {addr}");
+
+                        let task = tokio::spawn(self.clone().serve_tcp(listener, origin, acceptor));
+                        bound.insert(addr, (tls, task.abort_handle()));
+                    }
+                    Err(e) => error!("Failed to listen for admin requests on {addr}: {e}"),
+                }
+            }
+
+            tokio::time::sleep(RESOLVE_INTERVAL).await;
+        }
+    }
+
+    /// Serve admin requests on the admin socket and configured network listeners
+    pub async fn run(&self) -> std::io::Result<()> {
+        let listener = {
+            let path = self.config.get().host.base_dir.join("admin.sock");
+
+            let _ = std::fs::remove_file(&path);
+
+            let listener = UnixListener::bind(&path)?;
+
+            // Only allow the user and group forrest runs as to connect.
+            std::fs::set_permissions(path, Permissions::from_mode(0o770))?;
+
+            listener
+        };
+
+        tokio::select! {
+            _ = self.manage_tcp_listeners() => Ok(()),
+            res = async {
+                loop {
+                    let (sock, _) = listener.accept().await?;
+                    self.handle_in_background(sock, Origin::Socket);
+                }
+            } => res,
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::Category;
+
+// A single failed request is not an incident, no matter how long ago the
+// last successful one was.
+const MIN_FAILURES: u64 = 3;
+
+/// A series of failed GitHub API requests without a successful one in between
+struct Failing {
+    since: Instant,
+    since_time: DateTime<Utc>,
+    failures: u64,
+    last_error: String,
+    degraded: bool,
+}
+
+/// The state of degraded mode, as reported via the admin API
+#[derive(Serialize)]
+pub struct DegradedInfo {
+    pub since: DateTime<Utc>,
+    pub failures: u64,
+    pub last_error: String,
+}
+
+/// Keeps track of whether the GitHub API is currently usable
+///
+/// Once requests have failed with server errors or timeouts for longer than
+/// `github.degraded_after` we enter degraded mode.
+/// In degraded mode machines are not stopped based on (missing) feedback
+/// from the API, as this could kill perfectly healthy runners.
+/// The first successful request ends degraded mode.
+pub struct ApiHealth {
+    config: Config,
+    failing: Mutex<Option<Failing>>,
+    recovered: Mutex<Option<Instant>>,
+}
+
+impl ApiHealth {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            failing: Mutex::new(None),
+            recovered: Mutex::new(None),
+        }
+    }
+
+    /// Record a successful request to the API
+    pub fn success(&self) {
+        let failing = self.failing.lock().unwrap().take();
+
+        if let Some(failing) = failing.filter(|f| f.degraded) {
+            *self.recovered.lock().unwrap() = Some(Instant::now());
+
+            info!(
+                "The GitHub API recovered after {} failed requests in {}s. Leaving degraded mode",
+                failing.failures,
+                failing.since.elapsed().as_secs()
+            );
+        }
+    }
+
+    /// Record a failed request to the API
+    ///
+    /// Only transient errors, like server errors and timeouts, count towards
+    /// degraded mode, as e.g. authentication problems are not an incident.
+    pub fn failure(&self, category: Category, err: &dyn std::fmt::Display) {
+        if category != Category::Transient {
+            return;
+        }
+
+        let degraded_after = self.config.get().github.degraded_after();
+
+        let mut failing = self.failing.lock().unwrap();
+
+        let failing = failing.get_or_insert_with(|| Failing {
+            since: Instant::now(),
+            since_time: Utc::now(),
+            failures: 0,
+            last_error: String::new(),
+            degraded: false,
+        });
+
+        failing.failures += 1;
+        failing.last_error = err.to_string();
+
+        let sustained =
+            failing.failures >= MIN_FAILURES && failing.since.elapsed() >= degraded_after;
+
+        if sustained && !failing.degraded {
+            failing.degraded = true;
+
+            error!(
+                "The GitHub API failed {} times in {}s ({err}). Entering degraded mode: Machines are not stopped based on API feedback until it recovers",
+                failing.failures,
+                failing.since.elapsed().as_secs()
+            );
+        }
+    }
+
+    /// Record the outcome of a request made via octocrab
+    pub fn record<T>(&self, res: &octocrab::Result<T>) {
+        match res {
+            Ok(_) => self.success(),
+            Err(e) => self.failure(Category::of_github(e), e),
+        }
+    }
+
+    /// Is the API failing for long enough to be considered down?
+    pub fn is_degraded(&self) -> bool {
+        self.failing
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|f| f.degraded)
+    }
+
+    /// How long the API has been usable since degraded mode last ended
+    ///
+    /// Returns `None` while in degraded mode and `Duration::MAX` if we were
+    /// never in degraded mode.
+    /// Timeouts that are based on API feedback only count this time,
+    /// so that machines are not killed right after the API recovered.
+    pub fn healthy_for(&self) -> Option<Duration> {
+        if self.is_degraded() {
+            return None;
+        }
+
+        let healthy_for = self
+            .recovered
+            .lock()
+            .unwrap()
+            .map(|recovered| recovered.elapsed())
+            .unwrap_or(Duration::MAX);
+
+        Some(healthy_for)
+    }
+
+    /// Get the state of degraded mode, or `None` if the API works
+    pub fn degraded(&self) -> Option<DegradedInfo> {
+        self.failing
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|f| f.degraded)
+            .map(|f| DegradedInfo {
+                since: f.since_time,
+                failures: f.failures,
+                last_error: f.last_error.clone(),
+            })
+    }
+}
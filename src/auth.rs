@@ -3,31 +3,105 @@ use std::sync::{Arc, Mutex};
 
 use octocrab::models::InstallationId;
 use octocrab::Octocrab;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+use crate::api_health::ApiHealth;
 use crate::config::Config;
+use crate::error::Category;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("No GitHub App is configured in the github section")]
+    NotConfigured,
+    #[error("Failed to read the JWT key file {path}: {source}")]
+    KeyFile {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Invalid JWT key: {0}")]
+    Key(#[from] jsonwebtoken::errors::Error),
+    #[error("Failed to set up the GitHub client: {0}")]
+    Client(Box<octocrab::Error>),
+}
+
+impl From<octocrab::Error> for Error {
+    fn from(e: octocrab::Error) -> Self {
+        Self::Client(Box::new(e))
+    }
+}
+
+impl Error {
+    pub fn category(&self) -> Category {
+        match self {
+            Self::NotConfigured | Self::KeyFile { .. } | Self::Key(_) => Category::Config,
+            Self::Client(e) => Category::of_github(e),
+        }
+    }
+}
+
+/// A semaphore limiting the concurrent API requests of an installation
+struct Limiter {
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+}
 
 pub struct Auth {
+    config: Config,
     app: Arc<Octocrab>,
     users: Mutex<HashMap<String, (InstallationId, Arc<Octocrab>)>>,
+    limiters: Mutex<HashMap<String, Limiter>>,
+    health: ApiHealth,
 }
 
 impl Auth {
-    pub fn new(config: &Config) -> anyhow::Result<Arc<Self>> {
+    pub fn new(config: &Config) -> Result<Arc<Self>, Error> {
         let cfg = config.get();
 
+        if cfg.github.jwt_key_file.is_empty() {
+            return Err(Error::NotConfigured);
+        }
+
         let app_id = octocrab::models::AppId(cfg.github.app_id);
         let token = {
-            let pem = std::fs::read(&cfg.github.jwt_key_file)?;
+            let path = &cfg.github.jwt_key_file;
+            let pem = std::fs::read(path).map_err(|source| Error::KeyFile {
+                path: path.clone(),
+                source,
+            })?;
+
             jsonwebtoken::EncodingKey::from_rsa_pem(&pem)?
         };
 
-        let app = Arc::new(octocrab::Octocrab::builder().app(app_id, token).build()?);
+        let app = octocrab::Octocrab::builder().app(app_id, token).build()?;
+
+        Ok(Self::with_app(config, app))
+    }
+
+    /// Get an `Auth` without GitHub App credentials
+    ///
+    /// This is used with the fake forge, which does not talk to GitHub.
+    /// No installations are ever known, so `user()` always returns `None`.
+    pub fn anonymous(config: &Config) -> Result<Arc<Self>, Error> {
+        let app = octocrab::Octocrab::builder().build()?;
+
+        Ok(Self::with_app(config, app))
+    }
 
+    fn with_app(config: &Config, app: Octocrab) -> Arc<Self> {
+        let app = Arc::new(app);
         let users = Mutex::new(HashMap::new());
+        let limiters = Mutex::new(HashMap::new());
+        let health = ApiHealth::new(config.clone());
 
-        let auth = Self { app, users };
+        let auth = Self {
+            config: config.clone(),
+            app,
+            users,
+            limiters,
+            health,
+        };
 
-        Ok(Arc::new(auth))
+        Arc::new(auth)
     }
 
     /// Get an Octocrab instance authenticated as our GitHub application
@@ -64,4 +138,42 @@ impl Auth {
             .get(user)
             .map(|(_, user)| user.clone())
     }
+
+    /// Wait until another API request on behalf of `user` may be made
+    ///
+    /// The number of concurrent requests per installation is limited by
+    /// `github.api_concurrency`, so that e.g. many machines registering at
+    /// once do not trip the secondary rate limits of the GitHub API.
+    /// The request should be made while holding on to the returned permit.
+    pub async fn api_permit(&self, user: &str) -> OwnedSemaphorePermit {
+        let limit = self.config.get().github.api_concurrency.limit(user);
+
+        let semaphore = {
+            let mut limiters = self.limiters.lock().unwrap();
+
+            let limiter = limiters.entry(user.to_owned()).or_insert_with(|| Limiter {
+                limit,
+                semaphore: Arc::new(Semaphore::new(limit)),
+            });
+
+            // The limit was changed in the config file.
+            // Requests holding a permit of the old semaphore finish as usual.
+            if limiter.limit != limit {
+                *limiter = Limiter {
+                    limit,
+                    semaphore: Arc::new(Semaphore::new(limit)),
+                };
+            }
+
+            limiter.semaphore.clone()
+        };
+
+        // We never close the semaphore, so acquiring can not fail.
+        semaphore.acquire_owned().await.unwrap()
+    }
+
+    /// Get the tracker of failed API requests, which decides on degraded mode
+    pub fn health(&self) -> &ApiHealth {
+        &self.health
+    }
 }
@@ -0,0 +1,114 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+
+use crate::machines::{OwnerAndRepo, Triplet};
+
+const DEFAULT_CONFIG_PATH: &str = "config.yaml";
+
+/// How commands print their results
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    /// Human readable tables and lines of text
+    Table,
+    /// JSON, for use in scripts
+    Json,
+}
+
+/// Run GitHub self-hosted runners in qemu VMs
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The config file to run the daemon with
+    #[arg(default_value = DEFAULT_CONFIG_PATH)]
+    pub config: String,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Boot each configured machine type once with a benchmark job and record the results
+    Calibrate {
+        #[arg(default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+
+        #[arg(long, value_enum, default_value_t = Output::Table)]
+        output: Output,
+    },
+    /// Print suggestions to resize machine types based on their past resource usage
+    Recommend {
+        #[arg(default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+
+        #[arg(long, value_enum, default_value_t = Output::Table)]
+        output: Output,
+    },
+    /// Replay the recorded jobs against a config and print the expected queue times and utilization
+    Simulate {
+        /// The job history to replay, by default the one in the `host.base_dir`
+        #[arg(long)]
+        history: Option<String>,
+
+        /// The candidate config to evaluate
+        #[arg(long, default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+
+        #[arg(long, value_enum, default_value_t = Output::Table)]
+        output: Output,
+    },
+    /// Print how a machine type would be started (command line, seed files, network) without starting it
+    DryRun {
+        /// The machine type, as <user>/<repo>/<machine type>
+        machine: Triplet,
+
+        #[arg(default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+
+        #[arg(long, value_enum, default_value_t = Output::Table)]
+        output: Output,
+    },
+    /// Print the machines, queue, budgets and errors of the running daemon
+    Status {
+        #[arg(default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+
+        #[arg(long, value_enum, default_value_t = Output::Table)]
+        output: Output,
+    },
+    /// Write the persistent state (accounting, calibration records, ...) to a snapshot file
+    ExportState {
+        snapshot: String,
+
+        #[arg(default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+    },
+    /// Restore the persistent state from a snapshot file, e.g. on a new host
+    ImportState {
+        snapshot: String,
+
+        #[arg(default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+    },
+    /// Dispatch the smoke test workflow of a repository and check that Forrest runs it
+    SmokeTest {
+        /// The repository, as <user>/<repo>
+        repository: OwnerAndRepo,
+
+        #[arg(default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+
+        #[arg(long, value_enum, default_value_t = Output::Table)]
+        output: Output,
+    },
+    /// Start machine processes and do other privileged work for an instance with `host.helper` configured
+    Helper {
+        #[arg(default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+    },
+    /// Print a completion script for the given shell
+    Completions { shell: Shell },
+    /// Connect stdin and stdout to a machine metadata socket (used by qemu)
+    #[command(hide = true)]
+    MetadataProxy { socket: String },
+}
@@ -4,31 +4,92 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
-use log::{error, info};
+use log::{error, info, warn};
+use rand::{seq::SliceRandom, thread_rng};
+use serde::de::Error as _;
 use serde::Deserialize;
 
+mod admin;
+mod budget;
+mod diff;
 mod duration_human;
+mod forge;
 mod github;
 mod host;
+mod job_webhook;
+mod label_scope;
 mod machine;
+mod overrides;
+mod owner;
+mod quarantine;
+mod reservation;
+mod retention;
 mod size_in_bytes;
+mod smoke_test;
+mod tenancy;
 
-pub use github::GitHubConfig;
-pub use host::HostConfig;
-pub use machine::{MachineConfig, Repository, SeedBasePolicy};
+pub use admin::{AdminConfig, AdminListen, AdminRole, AdminTls};
+pub use budget::{Budget, BudgetPolicy};
+pub use diff::ConfigDiff;
+pub use duration_human::parse as parse_duration;
+pub use forge::{
+    AzureDevOpsConfig, BitbucketConfig, BuildbotConfig, FakeForgeConfig, ForgeKind, JenkinsConfig,
+};
+pub use github::{GitHubConfig, InactiveRepositories};
+pub use host::{HostConfig, HostDevice};
+pub use job_webhook::JobWebhook;
+pub use label_scope::{LabelScope, LabelScopes};
+pub use machine::{
+    Accel, Arch, Backend, ContainerSignature, ExposedDirectory, MachineConfig, Repository,
+    SeedBasePolicy, SetupTemplate, SharedFs,
+};
+pub use overrides::Overrides;
+pub use owner::Owner;
+pub use quarantine::QuarantineRules;
+pub use reservation::Reservation;
+pub use retention::{RetentionConfig, RetentionPolicy};
+pub use size_in_bytes::SizeInBytes;
+pub use tenancy::{TenancyConfig, Tenant};
+
+use crate::machines::Triplet;
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ConfigFile {
+    #[serde(default)]
+    pub admin: AdminConfig,
+    pub azure_devops: Option<AzureDevOpsConfig>,
+    pub bitbucket: Option<BitbucketConfig>,
+    #[serde(default)]
+    pub budgets: HashMap<String, Budget>,
+    pub buildbot: Option<BuildbotConfig>,
+    pub fake_forge: Option<FakeForgeConfig>,
+    #[serde(default)]
+    pub forge: ForgeKind,
+    #[serde(default)]
     pub github: GitHubConfig,
     pub host: HostConfig,
+    pub jenkins: Option<JenkinsConfig>,
+    #[serde(default)]
+    pub job_webhooks: Vec<JobWebhook>,
+    #[serde(default)]
+    pub owners: HashMap<String, Owner>,
     pub repositories: HashMap<String, HashMap<String, Repository>>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub tenancy: TenancyConfig,
+
+    /// How the machine types of `owners.<user>.machines` were resolved
+    #[serde(skip)]
+    resolutions: label_scope::Resolutions,
 }
 
 struct Inner {
     path: PathBuf,
     config_file: Arc<ConfigFile>,
     last_modified: SystemTime,
+    last_diff: Option<Arc<ConfigDiff>>,
 }
 
 #[derive(Clone)]
@@ -37,6 +98,97 @@ pub struct Config {
 }
 
 impl ConfigFile {
+    /// Look up the configuration of the machine type described by `triplet`
+    pub fn machine_config(&self, triplet: &Triplet) -> Option<&MachineConfig> {
+        let repo = self
+            .repositories
+            .get(triplet.owner())?
+            .get(triplet.repository())?;
+
+        let machine_name = triplet.machine_name();
+
+        repo.machines.get(machine_name).or_else(|| {
+            repo.aliases
+                .get(machine_name)
+                .and_then(|target| repo.machines.get(target))
+        })
+    }
+
+    /// Look up the machine type `triplet` is an alias of
+    ///
+    /// Returns `None` if `triplet` is not an alias, e.g. because it is
+    /// a machine type itself, which takes precedence.
+    pub fn alias_of(&self, triplet: &Triplet) -> Option<Triplet> {
+        let repo = self
+            .repositories
+            .get(triplet.owner())?
+            .get(triplet.repository())?;
+
+        if repo.machines.contains_key(triplet.machine_name()) {
+            return None;
+        }
+
+        let target = repo.aliases.get(triplet.machine_name())?;
+
+        repo.machines
+            .contains_key(target)
+            .then(|| Triplet::new(triplet.owner(), triplet.repository(), target))
+    }
+
+    /// Pick one of the machine types `triplet` was expanded into via `variants`
+    ///
+    /// Returns `None` if `triplet` has no variants.
+    pub fn variant_of(&self, triplet: &Triplet) -> Option<Triplet> {
+        let variants: Vec<Triplet> = self
+            .machine_config(triplet)?
+            .variants
+            .iter()
+            .map(|variant| Triplet::new(triplet.owner(), triplet.repository(), variant))
+            .filter(|variant| self.machine_config(variant).is_some())
+            .collect();
+
+        variants.choose(&mut thread_rng()).cloned()
+    }
+
+    /// Look up the machine type to run quarantined jobs of `triplet` on
+    ///
+    /// Returns `None` if there is no such machine type configured.
+    pub fn quarantine_of(&self, triplet: &Triplet) -> Option<Triplet> {
+        let machine_name = self.machine_config(triplet)?.quarantine.as_ref()?;
+        let quarantine = Triplet::new(triplet.owner(), triplet.repository(), machine_name);
+
+        self.machine_config(&quarantine).map(|_| quarantine)
+    }
+
+    /// The forge machines of the type `machine_config` register with
+    pub fn forge_of(&self, machine_config: &MachineConfig) -> ForgeKind {
+        machine_config.forge.unwrap_or(self.forge)
+    }
+
+    /// Get all configured machine types, sorted by their triplet
+    pub fn triplets(&self) -> Vec<Triplet> {
+        let mut triplets: Vec<Triplet> = self
+            .repositories
+            .iter()
+            .flat_map(|(owner, repos)| {
+                repos.iter().flat_map(move |(repository, repo)| {
+                    repo.machines
+                        .keys()
+                        .map(move |machine_name| Triplet::new(owner, repository, machine_name))
+                })
+            })
+            .collect();
+
+        triplets.sort_unstable_by_key(|t| t.to_string());
+
+        triplets
+    }
+
+    /// Report which machine types are defined by owners and repositories
+    pub fn label_scopes(&self) -> LabelScopes {
+        LabelScopes::new(self)
+    }
+
     fn from_file(fd: &mut File) -> serde_yml::Result<Arc<Self>> {
         // First we read the config file as generic serde_yml Value.
         let mut cfg: serde_yml::Value = serde_yml::from_reader(fd)?;
@@ -66,13 +218,262 @@ impl ConfigFile {
             });
         }
 
+        // Machines can extend named templates from `machine_templates`.
+        // This happens before expanding variants, so that variants
+        // inherit from the template as well.
+        apply_templates(&mut cfg)?;
+
+        // Machines can declare variants, which are expanded into one
+        // machine per variant.
+        expand_variants(&mut cfg);
+
+        // Owners with `label_scope: organization` pass their machines on
+        // to their repositories, which may override them.
+        let resolutions = label_scope::apply_organization_machines(&mut cfg)?;
+
         // And then we convert to our config format.
-        let cfg = serde_yml::from_value(cfg)?;
+        let mut cfg: Self = serde_yml::from_value(cfg)?;
+        cfg.resolutions = resolutions;
+
+        for (name, owner) in &cfg.owners {
+            if owner.label_scope == LabelScope::Repository && !owner.machines.is_empty() {
+                warn!(
+                    "The machines of owner {name} are ignored, because it does not use label_scope: organization"
+                );
+            }
+        }
+
+        // Host resources configured as `auto` are detected on every (re-)read.
+        cfg.host.detect_resources();
+
+        // Settings changed via the admin API go over those from the file.
+        let overrides = Overrides::load(&cfg.host.base_dir);
+
+        if !overrides.is_empty() {
+            info!("Applying the overrides made via the admin API");
+            overrides.apply(&mut cfg);
+        }
 
         Ok(Arc::new(cfg))
     }
 }
 
+/// Merge `overrides` into `base`
+///
+/// Mappings are merged key by key, so that e.g. a single
+/// `setup_template.parameters` entry can be overridden.
+/// Everything else is replaced as a whole.
+fn merge(base: &mut serde_yml::Value, overrides: serde_yml::Value) {
+    match (base.as_mapping_mut(), overrides) {
+        (Some(base), serde_yml::Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (_, overrides) => *base = overrides,
+    }
+}
+
+/// Get the machine template `name`, with the templates it extends merged in
+///
+/// `chain` holds the templates that are currently being resolved,
+/// to detect templates that (indirectly) extend themselves.
+fn resolve_template(
+    templates: &serde_yml::Mapping,
+    name: &serde_yml::Value,
+    chain: &mut Vec<String>,
+) -> serde_yml::Result<serde_yml::Value> {
+    let name = name.as_str().ok_or_else(|| {
+        serde_yml::Error::custom("extends must be the name of a machine template")
+    })?;
+
+    if chain.iter().any(|n| n == name) {
+        return Err(serde_yml::Error::custom(format!(
+            "Machine template {name} extends itself via {}",
+            chain.join(" -> ")
+        )));
+    }
+
+    let mut template = templates
+        .get(name)
+        .cloned()
+        .ok_or_else(|| serde_yml::Error::custom(format!("Unknown machine template {name}")))?;
+
+    let parent = template.as_mapping_mut().and_then(|t| t.remove("extends"));
+
+    if let Some(parent) = parent {
+        chain.push(name.to_owned());
+
+        let mut base = resolve_template(templates, &parent, chain)?;
+        merge(&mut base, template);
+        template = base;
+
+        chain.pop();
+    }
+
+    Ok(template)
+}
+
+/// Get the `machines` of all repositories and owners in the config file
+fn machine_sets(cfg: &mut serde_yml::Value) -> impl Iterator<Item = &mut serde_yml::Mapping> {
+    cfg.as_mapping_mut()
+        .into_iter()
+        .flat_map(|cfg| cfg.iter_mut())
+        .flat_map(|(key, value)| {
+            let sets: Box<dyn Iterator<Item = &mut serde_yml::Value>> = match key.as_str() {
+                Some("repositories") => Box::new(
+                    value
+                        .as_mapping_mut()
+                        .into_iter()
+                        .flat_map(|owners| owners.values_mut())
+                        .filter_map(|repos| repos.as_mapping_mut())
+                        .flat_map(|repos| repos.values_mut()),
+                ),
+                Some("owners") => Box::new(
+                    value
+                        .as_mapping_mut()
+                        .into_iter()
+                        .flat_map(|owners| owners.values_mut()),
+                ),
+                _ => Box::new(std::iter::empty()),
+            };
+
+            sets
+        })
+        .filter_map(|set| set.get_mut("machines"))
+        .filter_map(|machines| machines.as_mapping_mut())
+}
+
+/// Apply the machine templates machines `extends`
+///
+/// A machine like this:
+///
+/// ```yaml
+/// machine_templates:
+///   base-linux:
+///     cpus: 4
+///     ram: 8G
+///     setup_template:
+///       path: /etc/forrest/templates/generic
+///
+/// repositories:
+///   owner:
+///     repo:
+///       machines:
+///         build:
+///           extends: base-linux
+///           ram: 16G
+/// ```
+///
+/// gets all settings of `base-linux`, except for the `ram` it overrides.
+fn apply_templates(cfg: &mut serde_yml::Value) -> serde_yml::Result<()> {
+    let templates = cfg
+        .as_mapping_mut()
+        .and_then(|c| c.remove("machine_templates"))
+        .and_then(|t| t.as_mapping().cloned())
+        .unwrap_or_default();
+
+    let machines = machine_sets(cfg).flat_map(|machines| machines.values_mut());
+
+    for machine in machines {
+        let extends = machine.as_mapping_mut().and_then(|m| m.remove("extends"));
+
+        if let Some(extends) = extends {
+            let mut base = resolve_template(&templates, &extends, &mut Vec::new())?;
+            merge(&mut base, std::mem::take(machine));
+            *machine = base;
+        }
+    }
+
+    Ok(())
+}
+
+/// Expand machines with `variants` into one machine per variant
+///
+/// A machine like this:
+///
+/// ```yaml
+/// test:
+///   setup_template:
+///     path: /etc/forrest/templates/lab
+///   variants:
+///     k6-1:
+///       KERNEL: "6.1"
+///     k6-6:
+///       KERNEL: "6.6"
+/// ```
+///
+/// is turned into the machines `test-k6-1` and `test-k6-6`,
+/// each with the `KERNEL` parameter added to the `setup_template.parameters`.
+fn expand_variants(cfg: &mut serde_yml::Value) {
+    for machines in machine_sets(cfg) {
+        let mut expanded = serde_yml::Mapping::new();
+
+        for (name, mut machine) in std::mem::take(machines) {
+            let variants = machine
+                .as_mapping_mut()
+                .and_then(|m| m.remove("variants"))
+                .and_then(|v| v.as_mapping().cloned());
+
+            let (name, variants) = match (name.as_str(), variants) {
+                (Some(name), Some(variants)) => (name.to_owned(), variants),
+                (_, _) => {
+                    expanded.insert(name, machine);
+                    continue;
+                }
+            };
+
+            let mut variant_names = Vec::new();
+
+            for (variant, parameters) in variants {
+                let variant_name = match variant.as_str() {
+                    Some(vn) => format!("{name}-{vn}"),
+                    None => {
+                        error!("Ignoring variant of machine {name} with non-string name");
+                        continue;
+                    }
+                };
+
+                let mut variant_machine = machine.clone();
+
+                let variant_parameters = variant_machine
+                    .get_mut("setup_template")
+                    .and_then(|st| st.as_mapping_mut())
+                    .map(|st| {
+                        st.entry("parameters".into())
+                            .or_insert_with(|| serde_yml::Mapping::new().into())
+                    })
+                    .and_then(|p| p.as_mapping_mut());
+
+                if let (Some(vp), Some(parameters)) = (variant_parameters, parameters.as_mapping())
+                {
+                    vp.extend(parameters.clone());
+                }
+
+                variant_names.push(serde_yml::Value::from(variant_name.clone()));
+                expanded.insert(variant_name.into(), variant_machine);
+            }
+
+            // Keep the base machine type, so that jobs using its label are
+            // run on any of the variants.
+            // Standby machines are already kept for each of the variants.
+            if let Some(machine) = machine.as_mapping_mut() {
+                machine.remove("standby");
+                machine.insert("variants".into(), variant_names.into());
+            }
+
+            expanded.insert(name.into(), machine);
+        }
+
+        *machines = expanded;
+    }
+}
+
 impl Inner {
     fn should_refresh(&self) -> Option<(File, SystemTime)> {
         let fd = match File::open(&self.path) {
@@ -98,9 +499,14 @@ impl Inner {
         if let Some((mut fd, last_modified)) = self.should_refresh() {
             match ConfigFile::from_file(&mut fd) {
                 Ok(cf) => {
+                    info!("Re-read config file {}", self.path.display());
+
+                    let diff = ConfigDiff::new(&self.config_file, &cf);
+                    diff.log();
+
                     self.config_file = cf;
                     self.last_modified = last_modified;
-                    info!("Re-read config file {}", self.path.display());
+                    self.last_diff = Some(Arc::new(diff));
                 }
                 Err(e) => {
                     error!("Failed to re-read config: {e}. Re-using previous version.");
@@ -123,6 +529,7 @@ impl Config {
             path: path.as_ref().into(),
             config_file,
             last_modified,
+            last_diff: None,
         };
 
         let inner = Arc::new(Mutex::new(inner));
@@ -139,4 +546,37 @@ impl Config {
     pub fn get(&self) -> Arc<ConfigFile> {
         self.inner.lock().unwrap().get()
     }
+
+    /// Get the settings currently overridden via the admin API
+    pub fn overrides(&self) -> Overrides {
+        Overrides::load(&self.get().host.base_dir)
+    }
+
+    /// Replace the settings overridden via the admin API and apply them
+    ///
+    /// The overrides are persisted, so they survive restarts,
+    /// and the config is re-read to apply them right away.
+    pub fn set_overrides(&self, overrides: &Overrides) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        overrides.store(&inner.config_file.host.base_dir)?;
+
+        // Make sure the config is re-read, even if the file did not change.
+        inner.last_modified = SystemTime::UNIX_EPOCH;
+        inner.get();
+
+        Ok(())
+    }
+
+    /// Get the changes made by the most recent config reload
+    ///
+    /// Returns `None` if the config was not reloaded since startup.
+    pub fn last_diff(&self) -> Option<Arc<ConfigDiff>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // Pick up changes that were not noticed yet.
+        inner.get();
+
+        inner.last_diff.clone()
+    }
 }
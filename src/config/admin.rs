@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminListen {
+    pub interface: Option<String>,
+    pub address: Option<IpAddr>,
+    pub port: u16,
+    pub tls: Option<AdminTls>,
+}
+
+/// Serve the admin API via TLS, optionally requiring client certificates
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct AdminTls {
+    /// The PEM encoded certificate chain to present to clients
+    pub certificate: PathBuf,
+    /// The PEM encoded private key of the certificate
+    pub key: PathBuf,
+    /// Only accept clients with a certificate signed by one of these PEM encoded CAs
+    pub client_ca: Option<PathBuf>,
+    /// What clients with a valid certificate may do without sending a token
+    #[serde(default = "AdminTls::default_client_role")]
+    pub client_role: AdminRole,
+}
+
+impl AdminTls {
+    fn default_client_role() -> AdminRole {
+        AdminRole::ReadOnly
+    }
+}
+
+/// What the holder of an admin API token may do
+///
+/// Each role may do everything the roles before it may.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// Read the state, e.g. for dashboards
+    ReadOnly,
+    /// Change the machine demand, e.g. via pins and registration limits
+    Operator,
+    /// Everything else, e.g. managing buildbot worker credentials
+    Admin,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminToken {
+    pub token: String,
+    pub role: AdminRole,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub listen: Vec<AdminListen>,
+
+    /// Tokens required for requests via the network listeners, by name
+    #[serde(default)]
+    pub tokens: HashMap<String, AdminToken>,
+
+    /// Machine types with a health score below this are reported as unhealthy
+    pub health_threshold: Option<f64>,
+}
+
+/// Compare two secrets in a time that only depends on their lengths
+///
+/// This keeps an attacker from guessing a token byte by byte by measuring
+/// how long it takes to reject it.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl AdminConfig {
+    /// Find the name and role of the token sent as `authorization` header
+    pub fn token(&self, authorization: &str) -> Option<(&str, AdminRole)> {
+        let token = authorization.strip_prefix("Bearer ")?.trim();
+
+        // Compare against every token, so that the time it takes does not
+        // tell which one matched either.
+        let mut found = None;
+
+        for (name, t) in &self.tokens {
+            if constant_time_eq(t.token.as_bytes(), token.as_bytes()) {
+                found = Some((name.as_str(), t.role));
+            }
+        }
+
+        found
+    }
+
+    /// Are requests via `listen` authenticated, either by tokens or client certificates?
+    pub fn authenticates(&self, listen: &AdminListen) -> bool {
+        let client_certs = listen
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.client_ca.is_some());
+
+        !self.tokens.is_empty() || client_certs
+    }
+}
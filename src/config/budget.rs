@@ -0,0 +1,18 @@
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetPolicy {
+    #[default]
+    Queue,
+    Reject,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    pub monthly: f64,
+
+    #[serde(default)]
+    pub when_exceeded: BudgetPolicy,
+}
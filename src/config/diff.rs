@@ -0,0 +1,183 @@
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::Serialize;
+
+use super::{ConfigFile, MachineConfig};
+
+#[derive(Serialize)]
+pub struct MachineChange {
+    pub machine: String,
+    pub changes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct BudgetChange {
+    pub owner: String,
+    pub old: Option<f64>,
+    pub new: Option<f64>,
+}
+
+/// A summary of what changed between two versions of the config file
+#[derive(Serialize)]
+pub struct ConfigDiff {
+    pub applied: DateTime<Utc>,
+    pub repositories_added: Vec<String>,
+    pub repositories_removed: Vec<String>,
+    pub machines_added: Vec<String>,
+    pub machines_removed: Vec<String>,
+    pub machines_changed: Vec<MachineChange>,
+    pub budgets_changed: Vec<BudgetChange>,
+}
+
+/// The machine settings that are worth reporting when they change
+fn summary(machine: &MachineConfig) -> Vec<(&'static str, String)> {
+    let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_owned());
+
+    vec![
+        ("ram", machine.ram.to_string()),
+        ("cpus", machine.cpus.to_string()),
+        ("disk", machine.disk.to_string()),
+        ("cost", machine.cost.to_string()),
+        (
+            "base_machine",
+            opt(machine.base_machine.as_ref().map(|b| b.to_string())),
+        ),
+        (
+            "base_image",
+            opt(machine.base_image.as_ref().map(|b| b.display().to_string())),
+        ),
+        (
+            "setup_template",
+            machine.setup_template.path.display().to_string(),
+        ),
+        (
+            "scratch",
+            opt(machine
+                .scratch
+                .as_ref()
+                .map(|s| format!("{} in {}", s.size, s.pool))),
+        ),
+    ]
+}
+
+fn repositories(cfg: &ConfigFile) -> BTreeSet<String> {
+    cfg.repositories
+        .iter()
+        .flat_map(|(owner, repos)| repos.keys().map(move |repo| format!("{owner}/{repo}")))
+        .collect()
+}
+
+fn machines(cfg: &ConfigFile) -> BTreeSet<String> {
+    cfg.triplets().iter().map(|t| t.to_string()).collect()
+}
+
+impl ConfigDiff {
+    pub(super) fn new(old: &ConfigFile, new: &ConfigFile) -> Self {
+        let old_repos = repositories(old);
+        let new_repos = repositories(new);
+
+        let old_machines = machines(old);
+        let new_machines = machines(new);
+
+        let machines_changed = new
+            .triplets()
+            .iter()
+            .filter_map(|triplet| {
+                let old_summary = summary(old.machine_config(triplet)?);
+                let new_summary = summary(new.machine_config(triplet)?);
+
+                let changes: Vec<String> = old_summary
+                    .into_iter()
+                    .zip(new_summary)
+                    .filter(|((_, o), (_, n))| o != n)
+                    .map(|((field, o), (_, n))| format!("{field}: {o} → {n}"))
+                    .collect();
+
+                (!changes.is_empty()).then(|| MachineChange {
+                    machine: triplet.to_string(),
+                    changes,
+                })
+            })
+            .collect();
+
+        let owners: BTreeSet<&String> = old.budgets.keys().chain(new.budgets.keys()).collect();
+
+        let budgets_changed = owners
+            .into_iter()
+            .filter_map(|owner| {
+                let o = old.budgets.get(owner).map(|b| b.monthly);
+                let n = new.budgets.get(owner).map(|b| b.monthly);
+
+                (o != n).then(|| BudgetChange {
+                    owner: owner.clone(),
+                    old: o,
+                    new: n,
+                })
+            })
+            .collect();
+
+        Self {
+            applied: Utc::now(),
+            repositories_added: new_repos.difference(&old_repos).cloned().collect(),
+            repositories_removed: old_repos.difference(&new_repos).cloned().collect(),
+            machines_added: new_machines.difference(&old_machines).cloned().collect(),
+            machines_removed: old_machines.difference(&new_machines).cloned().collect(),
+            machines_changed,
+            budgets_changed,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.repositories_added.is_empty()
+            && self.repositories_removed.is_empty()
+            && self.machines_added.is_empty()
+            && self.machines_removed.is_empty()
+            && self.machines_changed.is_empty()
+            && self.budgets_changed.is_empty()
+    }
+
+    /// Log a human readable summary of the changes
+    pub(super) fn log(&self) {
+        if self.is_empty() {
+            info!("Config changes: none that affect repositories, machines or budgets");
+            return;
+        }
+
+        for repo in self.repositories_added.iter() {
+            info!("Config changes: added repository {repo}");
+        }
+
+        for repo in self.repositories_removed.iter() {
+            info!("Config changes: removed repository {repo}");
+        }
+
+        for machine in self.machines_added.iter() {
+            info!("Config changes: added machine {machine}");
+        }
+
+        for machine in self.machines_removed.iter() {
+            info!("Config changes: removed machine {machine}");
+        }
+
+        for mc in self.machines_changed.iter() {
+            info!(
+                "Config changes: changed machine {}: {}",
+                mc.machine,
+                mc.changes.join(", ")
+            );
+        }
+
+        for bc in self.budgets_changed.iter() {
+            let fmt = |b: Option<f64>| b.map(|b| b.to_string()).unwrap_or("none".to_owned());
+
+            info!(
+                "Config changes: changed budget of {}: {} → {}",
+                bc.owner,
+                fmt(bc.old),
+                fmt(bc.new)
+            );
+        }
+    }
+}
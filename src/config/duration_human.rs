@@ -1,26 +1,53 @@
 use std::time::Duration;
 
-use serde::{Deserialize, Deserializer};
+use serde::{de::Error, Deserialize, Deserializer};
 
-pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let mut duration_str: String = Deserialize::deserialize(deserializer)?;
+/// Parse a duration like `30s`, `15m`, `2h` or `7d`
+pub fn parse(duration_str: &str) -> Result<Duration, String> {
+    // Split off the last character, which may not be ASCII in invalid input.
+    let split = duration_str
+        .char_indices()
+        .last()
+        .map(|(index, _)| index)
+        .unwrap_or(0);
 
-    let unit = duration_str.pop();
+    let (value, unit) = duration_str.split_at(split);
 
     let multiplier = match unit {
-        Some('s') => 1,
-        Some('m') => 60,
-        Some('h') => 60 * 60,
-        Some('d') => 24 * 60 * 60,
-        _ => panic!("Failed to parse duration string '{duration_str}': unknown unit"),
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "Failed to parse duration string '{duration_str}': unknown unit"
+            ))
+        }
     };
 
-    let value: u64 = duration_str
-        .parse()
-        .expect("Failed to parse duration string '{duration_str}': can not parse as u64");
+    let value: u64 = value.parse().map_err(|_| {
+        format!("Failed to parse duration string '{duration_str}': can not parse as u64")
+    })?;
+
+    let secs = value.checked_mul(multiplier).ok_or_else(|| {
+        format!("Failed to parse duration string '{duration_str}': duration is too long")
+    })?;
 
-    Ok(Duration::from_secs(value * multiplier))
+    Ok(Duration::from_secs(secs))
+}
+
+pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration_str: String = Deserialize::deserialize(deserializer)?;
+
+    parse(&duration_str).map_err(D::Error::custom)
+}
+
+pub(super) fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize(deserializer).map(Some)
 }
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// The code hosting platform Forrest gets its jobs from
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ForgeKind {
+    /// The GitHub API, as configured in the `github` section
+    #[default]
+    Github,
+    /// Synthetic jobs from a local file, for local development
+    Fake,
+    /// Bitbucket Pipelines runners, as configured in the `bitbucket` section
+    Bitbucket,
+    /// Azure Pipelines agents, as configured in the `azure_devops` section
+    AzureDevops,
+    /// Jenkins inbound agents, as configured in the `jenkins` section
+    Jenkins,
+    /// Buildbot latent workers, as configured in the `buildbot` section
+    Buildbot,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FakeForgeConfig {
+    /// A YAML file listing the queued jobs
+    pub jobs: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BitbucketConfig {
+    /// A file containing a workspace or repository access token
+    pub token_file: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AzureDevOpsConfig {
+    /// E.g. `https://dev.azure.com/<organization>`
+    pub organization_url: String,
+    /// The agent pool the agents join
+    pub pool: String,
+    /// A file containing a personal access token
+    pub token_file: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JenkinsConfig {
+    /// E.g. `https://jenkins.example.com/`
+    pub url: String,
+    /// The user to access the Jenkins API as
+    pub user: String,
+    /// A file containing an API token of `user`
+    pub token_file: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildbotConfig {
+    /// The `host:port` workers connect to, e.g. `buildbot.example.com:9989`
+    pub master: String,
+    /// The base URL of the web interface, e.g. `https://buildbot.example.com/`
+    pub url: String,
+}
@@ -1,20 +1,120 @@
+use std::collections::HashMap;
 use std::time::Duration;
 
 use serde::Deserialize;
 
 use super::duration_human;
 
-fn default_timeout() -> Duration {
-    Duration::from_secs(15 * 60)
+// Webhooks are our primary source of information,
+// polling is only a fallback that does not have to happen that often.
+const POLLING_INTERVAL_WEBHOOK: Duration = Duration::from_secs(15 * 60);
+
+// Without webhooks polling is our only source of information,
+// which means it has to happen a lot more often.
+// Polling once a minute with a handful of repositories should still be
+// well within the GitHub API rate limits for an App installation.
+const POLLING_INTERVAL_POLL_ONLY: Duration = Duration::from_secs(60);
+
+// Long enough to ride out the occasional failed request,
+// short enough to not kill machines over a GitHub incident.
+const DEGRADED_AFTER: Duration = Duration::from_secs(5 * 60);
+
+// GitHub recommends not to make concurrent API requests at all,
+// but registering a handful of runners in parallel is fine in practice
+// and keeps bursts of new jobs from being served one by one.
+fn default_api_concurrency() -> usize {
+    4
+}
+
+/// What to do with configured repositories that were archived or disabled
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InactiveRepositories {
+    /// Keep polling them like any other repository
+    #[default]
+    Keep,
+    /// Stop polling them and drop the machines the config asks for
+    Drop,
 }
 
+/// Limits on the number of concurrent API requests per App installation
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
+pub struct ApiConcurrency {
+    #[serde(default = "default_api_concurrency")]
+    pub default: usize,
+    #[serde(default)]
+    pub owners: HashMap<String, usize>,
+}
+
+impl Default for ApiConcurrency {
+    fn default() -> Self {
+        Self {
+            default: default_api_concurrency(),
+            owners: HashMap::new(),
+        }
+    }
+}
+
+impl ApiConcurrency {
+    /// The maximum number of concurrent API requests on behalf of `owner`
+    pub fn limit(&self, owner: &str) -> usize {
+        self.owners
+            .get(owner)
+            .copied()
+            .unwrap_or(self.default)
+            .max(1)
+    }
+}
+
+// The section may be left out when using the fake forge,
+// in which case it is filled with placeholders that are never used.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct GitHubConfig {
     pub app_id: u64,
     pub jwt_key_file: String,
-    pub webhook_secret: String,
-    #[serde(default = "default_timeout")]
-    #[serde(deserialize_with = "duration_human::deserialize")]
-    pub polling_interval: Duration,
+    pub webhook_secret: Option<String>,
+    pub webhook_relay: Option<String>,
+    pub webhook_url: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    polling_interval: Option<Duration>,
+    #[serde(default)]
+    pub api_concurrency: ApiConcurrency,
+    #[serde(default)]
+    pub inactive_repositories: InactiveRepositories,
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    degraded_after: Option<Duration>,
+}
+
+impl GitHubConfig {
+    /// Are webhooks disabled, making polling the only source of information?
+    pub fn poll_only(&self) -> bool {
+        self.webhook_secret.is_none()
+    }
+
+    /// The URL GitHub should deliver webhooks to, if it should be set up by us
+    ///
+    /// Defaults to the relay channel URL if a relay is configured.
+    pub fn webhook_url(&self) -> Option<&str> {
+        self.webhook_url
+            .as_deref()
+            .or(self.webhook_relay.as_deref())
+    }
+
+    /// The configured polling interval or a default based on the webhook configuration
+    pub fn polling_interval(&self) -> Duration {
+        match (self.polling_interval, self.poll_only()) {
+            (Some(interval), _) => interval,
+            (None, false) => POLLING_INTERVAL_WEBHOOK,
+            (None, true) => POLLING_INTERVAL_POLL_ONLY,
+        }
+    }
+
+    /// How long API requests have to fail before entering degraded mode
+    pub fn degraded_after(&self) -> Duration {
+        self.degraded_after.unwrap_or(DEGRADED_AFTER)
+    }
 }
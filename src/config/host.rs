@@ -1,12 +1,252 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use log::{error, info};
+use nix::sys::statvfs::statvfs;
 use serde::Deserialize;
 
+use super::duration_human;
 use super::size_in_bytes::SizeInBytes;
 
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScratchPool {
+    pub path: PathBuf,
+    pub size: SizeInBytes,
+}
+
+/// A USB or PCI device of the host that can be passed through to machines
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "snake_case")]
+pub enum HostDevice {
+    /// A USB device identified by its bus and port path, e.g. `1-2.3`
+    Usb(String),
+    /// A PCI device identified by its address, e.g. `0000:01:00.0`
+    Pci(String),
+}
+
+/// When to pause idle machines to take load off the host
+///
+/// The pressure is the share of time (in percent) tasks were stalled
+/// waiting for CPU or memory, as reported by the kernel.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadShedding {
+    pub pause_above: f64,
+    pub resume_below: f64,
+}
+
+/// How the unprivileged instance and the `forrest helper` work together
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Helper {
+    /// The unprivileged user Forrest runs as
+    ///
+    /// Only this user may make requests to the helper,
+    /// and the helper never starts machine processes as root.
+    pub user: String,
+}
+
+/// Where and how often to write the heartbeat file for external monitoring
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Heartbeat {
+    pub path: PathBuf,
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    interval: Option<Duration>,
+}
+
+impl Heartbeat {
+    /// How often the heartbeat file is written
+    pub fn interval(&self) -> Duration {
+        self.interval.unwrap_or(HEARTBEAT_INTERVAL)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Auto {
+    Auto,
+}
+
+/// A host resource that is either given in the config or detected (`auto`)
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+enum Setting<T> {
+    Auto(Auto),
+    Fixed(T),
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct HostConfig {
     pub base_dir: PathBuf,
+
+    #[serde(rename = "ram")]
+    ram_setting: Setting<SizeInBytes>,
+
+    /// RAM to keep free for the host itself if `ram` is detected
+    pub ram_reserve: Option<SizeInBytes>,
+
+    /// Hand out this many times the `ram` to machines
+    pub overcommit_ratio: Option<f64>,
+
+    #[serde(rename = "cpus")]
+    cpus_setting: Option<Setting<u32>>,
+
+    #[serde(rename = "disk")]
+    disk_setting: Option<Setting<SizeInBytes>>,
+
+    /// Disk space to keep for everything but running machines if `disk` is detected
+    pub disk_reserve: Option<SizeInBytes>,
+
+    /// The RAM to hand out, as configured or detected by `detect_resources()`
+    #[serde(skip)]
     pub ram: SizeInBytes,
+
+    /// The number of CPU cores to hand out, not limited if `None`
+    #[serde(skip)]
+    pub cpus: Option<u32>,
+
+    /// The space the disk images of running machines may take up, not limited if `None`
+    #[serde(skip)]
+    pub disk: Option<SizeInBytes>,
+
+    #[serde(default)]
+    pub scratch: HashMap<String, ScratchPool>,
+
+    /// Where to place qcow2 overlays instead of copying the source image for each machine
+    pub overlay_dir: Option<PathBuf>,
+
+    /// Where to keep the console logs of the machines, named after their runner
+    pub console_log_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    pub devices: HashMap<String, HostDevice>,
+
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub rolling_restart: Option<Duration>,
+
+    /// How often to check for updates of the container images of machine types
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub container_image_updates: Option<Duration>,
+
+    /// Refuse container images without a verified `container_signature`
+    #[serde(default)]
+    pub require_signed_images: bool,
+
+    pub load_shedding: Option<LoadShedding>,
+
+    #[serde(default)]
+    pub keep_machines_on_restart: bool,
+
+    /// Leave privileged operations to a `forrest helper` running as root
+    pub helper: Option<Helper>,
+
+    /// How long running jobs may take to complete when Forrest is stopped
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub drain_timeout: Option<Duration>,
+
+    /// Periodically write our state to a file, so that it can be monitored
+    pub heartbeat: Option<Heartbeat>,
+}
+
+/// Get the total RAM of the host from `/proc/meminfo`
+fn detect_ram() -> std::io::Result<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|rest| rest.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| std::io::Error::other("No MemTotal in /proc/meminfo"))
+}
+
+/// Get the size of the filesystem of `path`
+///
+/// This is the total and not the free space, as the free space shrinks
+/// with the images of the machines that are running while it is detected,
+/// which are accounted for separately.
+fn detect_disk(path: &Path) -> std::io::Result<u64> {
+    let stat = statvfs(path)?;
+
+    Ok(stat.blocks() * stat.fragment_size())
+}
+
+impl HostConfig {
+    /// The RAM (in bytes) that may be handed out to machines
+    ///
+    /// This is the `ram` scaled by the `overcommit_ratio`, if any.
+    pub fn ram_schedulable(&self) -> u64 {
+        match self.overcommit_ratio {
+            Some(ratio) => (self.ram.bytes() as f64 * ratio) as u64,
+            None => self.ram.bytes(),
+        }
+    }
+
+    /// Fill in the resources that are configured as `auto` from the host
+    ///
+    /// This is done whenever the config file is (re-)read.
+    /// Resources that can not be detected are treated as zero (for `ram`)
+    /// or not limited (for `cpus` and `disk`), and the problem is logged.
+    pub(super) fn detect_resources(&mut self) {
+        self.ram = match self.ram_setting {
+            Setting::Fixed(ram) => ram,
+            Setting::Auto(_) => {
+                let total = detect_ram().unwrap_or_else(|e| {
+                    error!("Failed to detect the RAM of the host: {e}");
+                    0
+                });
+
+                let reserve = self.ram_reserve.map(|r| r.bytes()).unwrap_or(0);
+                let ram = SizeInBytes::from_bytes(total.saturating_sub(reserve));
+
+                info!("Detected {ram} of RAM to hand out to machines");
+
+                ram
+            }
+        };
+
+        self.cpus = match self.cpus_setting {
+            None => None,
+            Some(Setting::Fixed(cpus)) => Some(cpus),
+            Some(Setting::Auto(_)) => match std::thread::available_parallelism() {
+                Ok(cpus) => {
+                    info!("Detected {cpus} CPU cores to hand out to machines");
+                    Some(cpus.get() as u32)
+                }
+                Err(e) => {
+                    error!("Failed to detect the CPU cores of the host: {e}");
+                    None
+                }
+            },
+        };
+
+        self.disk = match self.disk_setting {
+            None => None,
+            Some(Setting::Fixed(disk)) => Some(disk),
+            Some(Setting::Auto(_)) => match detect_disk(&self.base_dir) {
+                Ok(total) => {
+                    let reserve = self.disk_reserve.map(|r| r.bytes()).unwrap_or(0);
+                    let disk = SizeInBytes::from_bytes(total.saturating_sub(reserve));
+                    info!("Detected {disk} of disk space to hand out to machines");
+                    Some(disk)
+                }
+                Err(e) => {
+                    error!("Failed to detect the disk space of the host: {e}");
+                    None
+                }
+            },
+        };
+    }
 }
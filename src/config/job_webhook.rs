@@ -0,0 +1,10 @@
+use serde::Deserialize;
+
+/// A downstream system that gets a summary of every completed job
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobWebhook {
+    pub url: String,
+    /// Sign the summaries using HMAC-SHA256 with this secret
+    pub secret: Option<String>,
+}
@@ -0,0 +1,196 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use super::ConfigFile;
+use crate::machines::Triplet;
+
+/// Where the machine types an owner's repositories can use are defined
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelScope {
+    /// Only in `repositories.<user>.<repository>.machines`
+    #[default]
+    Repository,
+    /// In `owners.<user>.machines`, with overrides per repository
+    Organization,
+}
+
+/// Where the definition of a machine type came from
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MachineSource {
+    /// Defined by the repository alone
+    Repository,
+    /// Defined by the owner
+    Organization,
+    /// Defined by the owner, with some settings overridden by the repository
+    Override,
+}
+
+/// How a machine type was resolved, as recorded while reading the config file
+#[derive(Clone)]
+pub(super) struct Resolution {
+    source: MachineSource,
+    overridden: Vec<String>,
+}
+
+/// The resolution of all machine types defined via `owners.<user>.machines`
+pub(super) type Resolutions = HashMap<Triplet, Resolution>;
+
+#[derive(Serialize)]
+pub struct MachineResolution {
+    pub machine: String,
+    pub scope: LabelScope,
+    pub source: MachineSource,
+    pub overridden: Vec<String>,
+}
+
+/// The precedence report of the `GET /label-scopes` admin endpoint
+#[derive(Serialize)]
+pub struct LabelScopes {
+    pub owners: BTreeMap<String, LabelScope>,
+    pub machines: Vec<MachineResolution>,
+}
+
+fn scope_of(owner: &serde_yml::Value) -> serde_yml::Result<LabelScope> {
+    owner
+        .get("label_scope")
+        .cloned()
+        .map(serde_yml::from_value)
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+/// Make the `owners.<user>.machines` available to the owner's repositories
+///
+/// This only happens for owners with `label_scope: organization`.
+/// Every repository of such an owner gets all of the owner's machine types.
+/// A machine type of the same name defined by the repository itself is
+/// merged over the one of the owner, so that it can override single settings.
+/// The rest of Forrest then only has to look at the repository machines.
+pub(super) fn apply_organization_machines(
+    cfg: &mut serde_yml::Value,
+) -> serde_yml::Result<Resolutions> {
+    let mut resolutions = Resolutions::new();
+
+    let owners = match cfg.get("owners").and_then(|o| o.as_mapping()) {
+        Some(owners) => owners.clone(),
+        None => return Ok(resolutions),
+    };
+
+    for (owner_name, owner) in owners {
+        if scope_of(&owner)? != LabelScope::Organization {
+            continue;
+        }
+
+        let (owner_name, org_machines) = match (
+            owner_name.as_str(),
+            owner.get("machines").and_then(|m| m.as_mapping()),
+        ) {
+            (Some(owner_name), Some(org_machines)) => (owner_name, org_machines),
+            (_, _) => continue,
+        };
+
+        let repos = cfg
+            .get_mut("repositories")
+            .and_then(|r| r.get_mut(owner_name))
+            .and_then(|r| r.as_mapping_mut())
+            .into_iter()
+            .flat_map(|repos| repos.iter_mut());
+
+        for (repo_name, repo) in repos {
+            let (repo_name, repo) = match (repo_name.as_str(), repo.as_mapping_mut()) {
+                (Some(repo_name), Some(repo)) => (repo_name, repo),
+                (_, _) => continue,
+            };
+
+            let machines = match repo
+                .entry("machines".into())
+                .or_insert_with(|| serde_yml::Mapping::new().into())
+                .as_mapping_mut()
+            {
+                Some(machines) => machines,
+                None => continue,
+            };
+
+            for (machine_name, org_machine) in org_machines {
+                let machine_name = match machine_name.as_str() {
+                    Some(machine_name) => machine_name,
+                    None => continue,
+                };
+
+                let mut machine = org_machine.clone();
+
+                let resolution = match machines.remove(machine_name) {
+                    Some(repo_machine) => {
+                        let overridden = repo_machine
+                            .as_mapping()
+                            .into_iter()
+                            .flat_map(|m| m.keys())
+                            .filter_map(|k| k.as_str())
+                            .map(str::to_owned)
+                            .collect();
+
+                        super::merge(&mut machine, repo_machine);
+
+                        Resolution {
+                            source: MachineSource::Override,
+                            overridden,
+                        }
+                    }
+                    None => Resolution {
+                        source: MachineSource::Organization,
+                        overridden: Vec::new(),
+                    },
+                };
+
+                machines.insert(machine_name.into(), machine);
+
+                let triplet = Triplet::new(owner_name, repo_name, machine_name);
+                resolutions.insert(triplet, resolution);
+            }
+        }
+    }
+
+    Ok(resolutions)
+}
+
+impl LabelScopes {
+    pub(super) fn new(cfg: &ConfigFile) -> Self {
+        let scope = |owner: &str| {
+            cfg.owners
+                .get(owner)
+                .map(|o| o.label_scope)
+                .unwrap_or_default()
+        };
+
+        let owners = cfg
+            .repositories
+            .keys()
+            .map(|owner| (owner.clone(), scope(owner)))
+            .collect();
+
+        let machines = cfg
+            .triplets()
+            .into_iter()
+            .map(|triplet| {
+                let resolution = cfg.resolutions.get(&triplet).cloned();
+
+                let (source, overridden) = match resolution {
+                    Some(Resolution { source, overridden }) => (source, overridden),
+                    None => (MachineSource::Repository, Vec::new()),
+                };
+
+                MachineResolution {
+                    machine: triplet.to_string(),
+                    scope: scope(triplet.owner()),
+                    source,
+                    overridden,
+                }
+            })
+            .collect();
+
+        Self { owners, machines }
+    }
+}
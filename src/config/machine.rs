@@ -1,9 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
+use std::time::Duration;
 
-use serde::Deserialize;
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
 
+use super::duration_human;
+use super::forge::ForgeKind;
+use super::quarantine::QuarantineRules;
+use super::reservation::Reservation;
 use super::size_in_bytes::SizeInBytes;
+use super::smoke_test::SmokeTest;
 use crate::machines::Triplet;
 
 #[derive(Deserialize)]
@@ -12,16 +18,114 @@ pub struct SetupTemplate {
 
     #[serde(default)]
     pub parameters: HashMap<String, String>,
+
+    /// Files to write into the machine via cloud-init, keyed by their path in the machine
+    #[serde(default)]
+    pub files: BTreeMap<String, PathBuf>,
 }
 
-#[derive(Deserialize, Clone, Copy)]
+#[derive(Deserialize, Clone, Copy, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum SeedBasePolicy {
+    #[default]
     IfNewer,
     Always,
     Never,
 }
 
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Qemu,
+    Nspawn,
+    Kata,
+    Kubernetes,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Qemu => "qemu",
+            Self::Nspawn => "nspawn",
+            Self::Kata => "kata",
+            Self::Kubernetes => "kubernetes",
+        })
+    }
+}
+
+/// How a shared directory is passed through to a qemu machine
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedFs {
+    #[default]
+    #[serde(rename = "9p")]
+    NineP,
+    Virtiofs,
+}
+
+/// The CPU architecture of a machine
+///
+/// Machines of another architecture than the host are emulated by qemu.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Arch {
+    #[default]
+    X86_64,
+    Aarch64,
+    Riscv64,
+}
+
+impl Arch {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64",
+            Self::Aarch64 => "aarch64",
+            Self::Riscv64 => "riscv64",
+        }
+    }
+
+    /// Is this the architecture of the host we run on?
+    pub fn is_native(&self) -> bool {
+        self.as_str() == std::env::consts::ARCH
+    }
+}
+
+impl std::fmt::Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How qemu runs the machine's CPUs
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Accel {
+    /// KVM if the machine is native and the host provides it, TCG otherwise
+    #[default]
+    Auto,
+    /// Hardware virtualization, refuse to run the machine without it
+    Kvm,
+    /// Emulation, which is a lot slower
+    Tcg,
+}
+
+impl Accel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Kvm => "kvm",
+            Self::Tcg => "tcg",
+        }
+    }
+}
+
+impl std::fmt::Display for Accel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ExposedDirectory {
@@ -29,14 +133,72 @@ pub struct ExposedDirectory {
     pub tag: String,
     #[serde(default)]
     pub writable: bool,
+    #[serde(default)]
+    pub fs: SharedFs,
+    /// Evict the least recently used files once the directory grows beyond this
+    pub size_limit: Option<SizeInBytes>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScratchDisk {
+    pub pool: String,
+    pub size: SizeInBytes,
 }
 
-impl Default for SeedBasePolicy {
-    fn default() -> Self {
-        Self::IfNewer
+/// How to verify the signature of a container image using cosign
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ContainerSignature {
+    /// Signed with the private key belonging to this public key file
+    Key(PathBuf),
+    /// Signed keyless via Sigstore by this identity, as vouched for by this OIDC issuer
+    Keyless { identity: String, issuer: String },
+}
+
+impl std::fmt::Display for ContainerSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Key(key) => write!(f, "key {}", key.display()),
+            Self::Keyless { identity, issuer } => write!(f, "identity {identity} ({issuer})"),
+        }
     }
 }
 
+/// Where to run machines using the `kubernetes` backend
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct KubernetesConfig {
+    /// The default kubeconfig of the user Forrest runs as is used if not set
+    pub kubeconfig: Option<PathBuf>,
+    /// The default namespace of the kubeconfig is used if not set
+    pub namespace: Option<String>,
+}
+
+fn default_cost() -> f64 {
+    1.0
+}
+
+fn default_after_failures() -> u32 {
+    2
+}
+
+/// An alternative machine type to use if a machine type fails to spawn
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fallback {
+    pub machine: String,
+    #[serde(default = "default_after_failures")]
+    pub after_failures: u32,
+}
+
+/// How far to shrink the RAM of idle machines via a virtio-balloon device
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Balloon {
+    pub idle_ram: SizeInBytes,
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct MachineConfig {
@@ -47,17 +209,177 @@ pub struct MachineConfig {
     #[serde(default)]
     pub use_base: SeedBasePolicy,
 
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub trusted: bool,
+    pub container_image: Option<String>,
+    pub container_signature: Option<ContainerSignature>,
+    #[serde(default)]
+    pub kubernetes: KubernetesConfig,
+
+    #[serde(default = "default_cost")]
+    pub cost: f64,
+
+    /// Start machines of this type before those of lower priority
+    pub priority: Option<i32>,
+
+    /// Hold back RAM for up to this long for jobs that workflow runs in
+    /// progress are expected to queue for this machine type
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub anticipate_for: Option<Duration>,
+
     pub cpus: u32,
     pub disk: SizeInBytes,
     pub ram: SizeInBytes,
 
+    /// The CPU architecture of the machine, emulated if it is not the host's
+    #[serde(default)]
+    pub arch: Arch,
+
+    /// Whether the machine has to, may or must not use KVM
+    #[serde(default)]
+    pub accel: Accel,
+
+    /// Use this CPU model instead of the default one of the `arch`
+    pub cpu_model: Option<String>,
+
+    /// CPU features to enable (`+feature`) or disable (`-feature`) on top of the `cpu_model`
+    #[serde(default)]
+    pub cpu_features: Vec<String>,
+
+    /// Let the machine run virtual machines of its own
+    #[serde(default)]
+    pub nested: bool,
+
+    /// Boot from this firmware instead of the default one of the `arch`
+    pub firmware: Option<PathBuf>,
+
+    /// Pin the virtual CPUs to dedicated host cores
+    #[serde(default)]
+    pub cpu_pin: bool,
+
+    /// Pin the machine to cores and RAM of this NUMA node of the host
+    pub numa_node: Option<u32>,
+
+    /// Reclaim RAM from machines that wait for a job
+    pub balloon: Option<Balloon>,
+
+    pub scratch: Option<ScratchDisk>,
+
     #[serde(default)]
     pub shared: Vec<ExposedDirectory>,
+
+    #[serde(default)]
+    pub devices: Vec<String>,
+
+    pub fallback: Option<Fallback>,
+
+    /// Keep this many machines booted and waiting for jobs, even without demand
+    #[serde(default)]
+    pub standby: u64,
+
+    /// Kill machines that did not register as runner within this time
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub start_timeout: Option<Duration>,
+
+    /// Give up on the queued jobs after this many machines failed to come up in a row
+    pub start_retries: Option<u32>,
+
+    /// Stop machines that waited longer than this for a job
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub idle_timeout: Option<Duration>,
+
+    /// Stop machines that have been running a job for longer than this
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub max_job_duration: Option<Duration>,
+
+    /// The machine type to run quarantined jobs of this machine type on
+    pub quarantine: Option<String>,
+
+    /// Connect the machine to this network bridge instead of the one of its tenant
+    pub bridge: Option<String>,
+
+    /// Register with this forge instead of the instance wide `forge`
+    pub forge: Option<ForgeKind>,
+
+    /// The machine types this one was expanded into via `variants`
+    #[serde(default)]
+    pub variants: Vec<String>,
+}
+
+/// The webhook event types Forrest knows how to handle
+const KNOWN_EVENTS: &[&str] = &["workflow_job"];
+
+fn default_events() -> Vec<String> {
+    vec!["workflow_job".to_owned()]
+}
+
+fn deserialize_events<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let events = Vec::<String>::deserialize(deserializer)?;
+
+    if events.is_empty() {
+        return Err(D::Error::custom("events must list at least one event type"));
+    }
+
+    if let Some(unknown) = events.iter().find(|e| !KNOWN_EVENTS.contains(&e.as_str())) {
+        return Err(D::Error::custom(format!(
+            "Unknown event type {unknown}, expected one of: {}",
+            KNOWN_EVENTS.join(", ")
+        )));
+    }
+
+    Ok(events)
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Repository {
     pub persistence_token: Option<String>,
+    #[serde(default)]
     pub machines: HashMap<String, MachineConfig>,
+
+    /// The webhook event types to process for this repository
+    #[serde(default = "default_events")]
+    #[serde(deserialize_with = "deserialize_events")]
+    pub events: Vec<String>,
+
+    /// Run jobs matching these rules on the `quarantine` machine types
+    pub quarantine: Option<QuarantineRules>,
+
+    /// Start at most this many machines for this repository per hour
+    pub registrations_per_hour: Option<u32>,
+
+    /// Run at most this many machines for this repository at once
+    pub max_concurrent_machines: Option<u32>,
+
+    /// The priority of the machine types that do not set their own
+    pub priority: Option<i32>,
+
+    /// Keep room for the jobs of protected branches
+    pub reserved: Option<Reservation>,
+
+    /// Old machine names that are still accepted, mapped to the machine type
+    /// that replaced them
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+
+    /// Tell jobs that waited longer than this for a machine why they did
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub wait_notice_after: Option<Duration>,
+
+    /// The workflow to dispatch via `forrest smoke-test`
+    pub smoke_test: Option<SmokeTest>,
+
+    /// Start machines for jobs that still wait for a deployment approval
+    #[serde(default)]
+    pub boot_before_approval: bool,
 }
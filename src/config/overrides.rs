@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use super::size_in_bytes::SizeInBytes;
+use super::ConfigFile;
+
+// Kept in the `host.base_dir`, as the config file itself may not be writable.
+const OVERRIDES_FILE: &str = "overrides.json";
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HostOverrides {
+    pub ram: Option<SizeInBytes>,
+    pub cpus: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MachineOverrides {
+    pub standby: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryOverrides {
+    pub registrations_per_hour: Option<u32>,
+    pub max_concurrent_machines: Option<u32>,
+}
+
+/// Settings changed at runtime via the admin API
+///
+/// These take precedence over the config file until they are removed again,
+/// e.g. once the config file was changed accordingly.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Overrides {
+    #[serde(default)]
+    pub host: HostOverrides,
+    /// By machine type, e.g. `owner/repo/machine`
+    #[serde(default)]
+    pub machines: BTreeMap<String, MachineOverrides>,
+    /// By repository, e.g. `owner/repo`
+    #[serde(default)]
+    pub repositories: BTreeMap<String, RepositoryOverrides>,
+}
+
+impl Overrides {
+    /// Read the overrides persisted in `base_dir`
+    ///
+    /// Returns no overrides if there are none or they can not be read.
+    pub(super) fn load(base_dir: &Path) -> Self {
+        let path = base_dir.join(OVERRIDES_FILE);
+
+        match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                error!("Failed to parse {}, ignoring it: {e}", path.display());
+                Self::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                error!("Failed to read {}, ignoring it: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub(super) fn store(&self, base_dir: &Path) -> std::io::Result<()> {
+        let path = base_dir.join(OVERRIDES_FILE);
+
+        // Write to a temporary file first and move it into place,
+        // so we never leave a half written file behind.
+        let tmp_path = path.with_extension("json.tmp");
+
+        let content = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, &path)
+    }
+
+    /// Apply the overrides to `cfg`
+    ///
+    /// Overrides for machine types and repositories that are not in the
+    /// config (anymore) are skipped.
+    pub(super) fn apply(&self, cfg: &mut ConfigFile) {
+        if let Some(ram) = self.host.ram {
+            cfg.host.ram = ram;
+        }
+
+        if let Some(cpus) = self.host.cpus {
+            cfg.host.cpus = Some(cpus);
+        }
+
+        for (name, overrides) in &self.repositories {
+            let repo = name
+                .split_once('/')
+                .and_then(|(owner, repo)| cfg.repositories.get_mut(owner)?.get_mut(repo));
+
+            let Some(repo) = repo else {
+                warn!("Skipping overrides for unknown repository {name}");
+                continue;
+            };
+
+            if let Some(limit) = overrides.registrations_per_hour {
+                repo.registrations_per_hour = Some(limit);
+            }
+
+            if let Some(limit) = overrides.max_concurrent_machines {
+                repo.max_concurrent_machines = Some(limit);
+            }
+        }
+
+        for (name, overrides) in &self.machines {
+            let machine = match name.split('/').collect::<Vec<_>>().as_slice() {
+                [owner, repo, machine] => cfg
+                    .repositories
+                    .get_mut(*owner)
+                    .and_then(|repos| repos.get_mut(*repo))
+                    .and_then(|repo| repo.machines.get_mut(*machine)),
+                _ => None,
+            };
+
+            let Some(machine) = machine else {
+                warn!("Skipping overrides for unknown machine type {name}");
+                continue;
+            };
+
+            if let Some(standby) = overrides.standby {
+                machine.standby = standby;
+            }
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.host.ram.is_none()
+            && self.host.cpus.is_none()
+            && self.machines.is_empty()
+            && self.repositories.is_empty()
+    }
+}
@@ -0,0 +1,21 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::{LabelScope, MachineConfig};
+
+/// Settings that apply to all repositories of an owner
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Owner {
+    /// Run at most this many machines for the repositories of the owner at once
+    pub max_concurrent_machines: Option<u32>,
+
+    /// Whether the repositories of the owner can use the owner's `machines`
+    #[serde(default)]
+    pub label_scope: LabelScope,
+
+    /// Machine types available to all repositories of the owner
+    #[serde(default)]
+    pub machines: HashMap<String, MachineConfig>,
+}
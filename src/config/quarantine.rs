@@ -0,0 +1,23 @@
+use serde::Deserialize;
+
+/// Which jobs of a repository to run on the `quarantine` variant of their machine type
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct QuarantineRules {
+    /// Jobs of pull requests from forks
+    #[serde(default)]
+    pub fork_pull_requests: bool,
+    /// Jobs of pull requests opened by users that have not contributed before
+    #[serde(default)]
+    pub first_time_contributors: bool,
+    /// Jobs of pull requests that change workflows or local actions
+    #[serde(default)]
+    pub workflow_changes: bool,
+}
+
+impl QuarantineRules {
+    /// Do any of the rules need the pull request a run belongs to?
+    pub fn need_pull_request(&self) -> bool {
+        self.first_time_contributors || self.workflow_changes
+    }
+}
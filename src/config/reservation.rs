@@ -0,0 +1,25 @@
+use serde::Deserialize;
+
+/// Capacity of the host kept free for the jobs of protected branches
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reservation {
+    /// The branches whose jobs may use the reserved slots
+    ///
+    /// A trailing `*` matches any suffix, e.g. `release/*`.
+    pub branches: Vec<String>,
+    /// The number of machines of the repository to keep room for
+    pub slots: u32,
+}
+
+impl Reservation {
+    /// May jobs of `branch` use the reserved slots?
+    pub fn protects(&self, branch: &str) -> bool {
+        self.branches
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => branch.starts_with(prefix),
+                None => branch == pattern,
+            })
+    }
+}
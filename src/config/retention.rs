@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::duration_human;
+use super::size_in_bytes::SizeInBytes;
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub max_age: Option<Duration>,
+    pub max_size: Option<SizeInBytes>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub run_dirs: RetentionPolicy,
+    #[serde(default)]
+    pub broken_images: RetentionPolicy,
+    #[serde(default)]
+    pub calibration: RetentionPolicy,
+    #[serde(default)]
+    pub console_logs: RetentionPolicy,
+    #[serde(default)]
+    pub job_history: RetentionPolicy,
+}
@@ -1,33 +1,61 @@
-use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct SizeInBytes(u64);
 
-impl<'de> Deserialize<'de> for SizeInBytes {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let mut size_str: String = Deserialize::deserialize(deserializer)?;
+impl std::str::FromStr for SizeInBytes {
+    type Err = String;
+
+    fn from_str(size_str: &str) -> Result<Self, Self::Err> {
+        let mut size = size_str.to_owned();
 
-        let multiplier = match size_str.pop() {
+        let multiplier = match size.pop() {
             Some('B') => 1,
             Some('K') => 1024,
             Some('M') => 1024 * 1024,
             Some('G') => 1024 * 1024 * 1024,
             Some('T') => 1024 * 1024 * 1024 * 1024,
-            _ => panic!("Failed to parse size string '{size_str}': unknown unit"),
+            _ => {
+                return Err(format!(
+                    "Failed to parse size string '{size_str}': unknown unit"
+                ))
+            }
         };
 
-        let size: u64 = size_str
-            .parse()
-            .expect("Failed to parse size string '{size_str}': can not parse as u64");
+        let size: u64 = size.parse().map_err(|_| {
+            format!("Failed to parse size string '{size_str}': can not parse as u64")
+        })?;
 
         Ok(SizeInBytes(size * multiplier))
     }
 }
 
+impl<'de> Deserialize<'de> for SizeInBytes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let size_str: String = Deserialize::deserialize(deserializer)?;
+
+        size_str.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for SizeInBytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 impl SizeInBytes {
+    pub fn from_bytes(bytes: u64) -> Self {
+        Self(bytes)
+    }
+
     pub fn bytes(&self) -> u64 {
         self.0
     }
@@ -40,3 +68,24 @@ impl SizeInBytes {
         self.kilobyes() / 1024
     }
 }
+
+impl std::fmt::Display for SizeInBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Use the largest unit the size is a whole multiple of,
+        // like it would usually be written in the config file.
+        let units = [
+            ('T', 1 << 40),
+            ('G', 1 << 30),
+            ('M', 1 << 20),
+            ('K', 1 << 10),
+        ];
+
+        for (suffix, multiplier) in units {
+            if self.0 != 0 && self.0 % multiplier == 0 {
+                return write!(f, "{}{suffix}", self.0 / multiplier);
+            }
+        }
+
+        write!(f, "{}B", self.0)
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::duration_human;
+
+fn default_ref() -> String {
+    "main".to_owned()
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+/// The workflow `forrest smoke-test` dispatches to check a repository end to end
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmokeTest {
+    /// The file name (e.g. `smoke-test.yaml`) or id of the workflow
+    pub workflow: String,
+    /// The branch or tag to run the workflow on
+    #[serde(default = "default_ref", rename = "ref")]
+    pub git_ref: String,
+    /// Give up if the workflow run did not complete after this long
+    #[serde(default = "default_timeout")]
+    #[serde(deserialize_with = "duration_human::deserialize")]
+    pub timeout: Duration,
+}
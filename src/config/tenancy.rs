@@ -0,0 +1,23 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tenant {
+    pub user: Option<String>,
+    pub bridge: Option<String>,
+    /// Another bridge machine types of this owner may use, e.g. for quarantine
+    pub quarantine_bridge: Option<String>,
+    pub cache_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TenancyConfig {
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub owners: HashMap<String, Tenant>,
+}
@@ -0,0 +1,61 @@
+use serde::Serialize;
+
+/// A machine-readable category of an error
+///
+/// The category decides how an operation is retried and is used to count
+/// errors in the metrics.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// Authentication failed or we lack permissions.
+    /// Retrying will not help until the App settings are fixed.
+    Auth,
+    /// We are about to exceed an API rate limit.
+    /// Retrying makes sense once the limit is reset.
+    RateLimit,
+    /// The config (or data derived from it) is invalid.
+    Config,
+    /// The host can not run a machine, e.g. due to missing tooling.
+    Backend,
+    /// Network problems, server errors and the like.
+    /// Retrying soon will likely succeed.
+    Transient,
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::RateLimit => "rate_limit",
+            Self::Config => "config",
+            Self::Backend => "backend",
+            Self::Transient => "transient",
+        }
+    }
+
+    /// Categorize an error returned by the GitHub API
+    pub fn of_github(err: &octocrab::Error) -> Self {
+        match err {
+            octocrab::Error::GitHub { source, .. } => {
+                let status = source.status_code.as_u16();
+                let rate_limited = source.message.to_lowercase().contains("rate limit");
+
+                match status {
+                    429 => Self::RateLimit,
+                    403 if rate_limited => Self::RateLimit,
+                    401 | 403 | 404 => Self::Auth,
+                    422 => Self::Config,
+                    _ => Self::Transient,
+                }
+            }
+            octocrab::Error::JWT { .. } => Self::Auth,
+            _ => Self::Transient,
+        }
+    }
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
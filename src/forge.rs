@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::config::ForgeKind;
+use crate::machines::Triplet;
+
+mod azure_devops;
+mod bitbucket;
+mod buildbot;
+mod error;
+mod fake;
+mod github;
+mod jenkins;
+mod rest;
+
+pub use azure_devops::AzureDevOpsForge;
+pub use bitbucket::BitbucketForge;
+pub use buildbot::BuildbotForge;
+pub use error::{Error, Result};
+pub use fake::FakeForge;
+pub use github::GitHubForge;
+pub use jenkins::JenkinsForge;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A just-in-time runner registered with a forge
+pub struct Registration {
+    /// The forge specific id of the runner, e.g. to de-register it
+    pub runner_id: String,
+    /// The runner configuration handed to the machine as `<JITCONFIG>`
+    pub encoded_jit_config: String,
+}
+
+/// The state of a runner as reported by a forge
+pub struct RunnerStatus {
+    pub online: bool,
+    pub busy: bool,
+}
+
+/// The code hosting platform our machines register as runners with
+pub trait Forge: Send + Sync {
+    /// Register a just-in-time runner `runner_name` with `labels`
+    /// for the repository of `triplet`
+    fn register_runner<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_name: &'a str,
+        labels: Vec<String>,
+    ) -> BoxFuture<'a, Result<Registration>>;
+
+    /// Remove a runner registered via `register_runner()`
+    fn deregister_runner<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Get the state of a runner registered via `register_runner()`
+    ///
+    /// Returns `Ok(None)` if the forge reports runner states some other way,
+    /// like GitHub does via job events and the runner list.
+    fn runner_status<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RunnerStatus>>>;
+}
+
+/// The forges that are set up, by kind
+///
+/// Usually all machines register with the instance wide `forge`,
+/// but machine types can use another one, e.g. to share a host between
+/// GitHub and Jenkins.
+#[derive(Clone, Default)]
+pub struct Forges {
+    forges: HashMap<ForgeKind, Arc<dyn Forge>>,
+}
+
+impl Forges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, kind: ForgeKind, forge: Arc<dyn Forge>) {
+        self.forges.insert(kind, forge);
+    }
+
+    pub fn get(&self, kind: ForgeKind) -> Option<Arc<dyn Forge>> {
+        self.forges.get(&kind).cloned()
+    }
+}
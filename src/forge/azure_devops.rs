@@ -0,0 +1,242 @@
+use std::sync::Mutex;
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use super::rest::{encode_component, Client};
+use super::{BoxFuture, Error, Forge, Registration, Result, RunnerStatus};
+use crate::config::{AzureDevOpsConfig, Config};
+use crate::machines::Triplet;
+
+const FORGE: &str = "Azure DevOps";
+const API_VERSION: &str = "7.1";
+
+/// The list responses of the Azure DevOps API
+#[derive(Deserialize)]
+struct List<T> {
+    value: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct Pool {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Agent {
+    id: u64,
+    status: String,
+    assigned_request: Option<serde_json::Value>,
+}
+
+/// What the agent in the machine needs to configure itself
+///
+/// This is handed to the machine as base64 encoded JSON.
+#[derive(Serialize)]
+struct AgentConfig<'a> {
+    url: &'a str,
+    pool: &'a str,
+    agent: &'a str,
+    token: String,
+}
+
+/// Run Azure Pipelines agents in our machines
+///
+/// Azure DevOps has no just-in-time registration like GitHub does.
+/// Instead the agent registers itself in the configured pool, using the
+/// access token handed to the machine, and runs a single job (`--once`).
+/// The agent is named after the machine, which is how we find it again.
+///
+/// As the token ends up in the machines, where the jobs can read it,
+/// only tokens limited to managing agent pools are handed out.
+pub struct AzureDevOpsForge {
+    config: Config,
+    /// The last token that was found to be limited to managing agent pools
+    checked_token: Mutex<Option<String>>,
+}
+
+impl AzureDevOpsForge {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            checked_token: Mutex::new(None),
+        }
+    }
+
+    /// Make sure the `token` can not be used for more than managing agent pools
+    ///
+    /// Azure DevOps does not tell the scopes of a token,
+    /// so this tries to list the projects of the organization instead,
+    /// which the "Agent Pools (read & manage)" scope does not allow.
+    async fn check_token_scope(&self, organization_url: &str, token: &str) -> Result<()> {
+        if self.checked_token.lock().unwrap().as_deref() == Some(token) {
+            return Ok(());
+        }
+
+        let client = self.client(token)?;
+
+        let projects: Result<serde_json::Value> = client
+            .get(&format!(
+                "{}/_apis/projects?$top=1&api-version={API_VERSION}",
+                organization_url.trim_end_matches('/')
+            ))
+            .await;
+
+        match projects {
+            Ok(_) => {
+                return Err(Error::Config(
+                    "The azure_devops.token_file has more scopes than \"Agent Pools (read & manage)\", refusing to hand it to machines".to_owned(),
+                ))
+            }
+            // Requests without the required scope are either rejected or
+            // redirected to a sign-in page, which is not valid JSON.
+            Err(Error::Status { status, .. }) if matches!(status.as_u16(), 401 | 403) => {}
+            Err(Error::Response { .. }) => {}
+            Err(e) => return Err(e),
+        }
+
+        *self.checked_token.lock().unwrap() = Some(token.to_owned());
+
+        Ok(())
+    }
+
+    fn with_config<T>(&self, f: impl FnOnce(&AzureDevOpsConfig) -> T) -> Result<T> {
+        match &self.config.get().azure_devops {
+            Some(azure_devops) => Ok(f(azure_devops)),
+            None => Err(Error::Config(
+                "No azure_devops section configured".to_owned(),
+            )),
+        }
+    }
+
+    fn client(&self, token: &str) -> Result<Client> {
+        // Personal access tokens are sent as password with an empty user name.
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!(":{token}"));
+
+        Client::new(FORGE, format!("Basic {credentials}"))
+    }
+
+    /// Look up the agent named `agent_name` in the configured pool
+    ///
+    /// Returns the client, the url of the pool and the agent, if it has
+    /// registered yet.
+    async fn find_agent(&self, agent_name: &str) -> Result<(Client, String, Option<Agent>)> {
+        let (organization_url, pool_name, token_file) = self.with_config(|cfg| {
+            (
+                cfg.organization_url.trim_end_matches('/').to_owned(),
+                cfg.pool.clone(),
+                cfg.token_file.clone(),
+            )
+        })?;
+
+        let client = self.client(&Client::read_token(FORGE, &token_file)?)?;
+
+        let pools: List<Pool> = client
+            .get(&format!(
+                "{organization_url}/_apis/distributedtask/pools?poolName={}&api-version={API_VERSION}",
+                encode_component(&pool_name)
+            ))
+            .await?;
+
+        let pool = pools
+            .value
+            .first()
+            .ok_or_else(|| Error::Config(format!("Unknown agent pool {pool_name}")))?;
+
+        let pool_url = format!("{organization_url}/_apis/distributedtask/pools/{}", pool.id);
+
+        let agents: List<Agent> = client
+            .get(&format!(
+                "{pool_url}/agents?agentName={}&includeAssignedRequest=true&api-version={API_VERSION}",
+                encode_component(agent_name)
+            ))
+            .await?;
+
+        Ok((client, pool_url, agents.value.into_iter().next()))
+    }
+}
+
+impl Forge for AzureDevOpsForge {
+    fn register_runner<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        runner_name: &'a str,
+        _labels: Vec<String>,
+    ) -> BoxFuture<'a, Result<Registration>> {
+        Box::pin(async move {
+            let (organization_url, pool, token_file) = self.with_config(|cfg| {
+                (
+                    cfg.organization_url.clone(),
+                    cfg.pool.clone(),
+                    cfg.token_file.clone(),
+                )
+            })?;
+
+            let token = Client::read_token(FORGE, &token_file)?;
+
+            self.check_token_scope(&organization_url, &token).await?;
+
+            let agent_config = AgentConfig {
+                url: &organization_url,
+                pool: &pool,
+                agent: runner_name,
+                token,
+            };
+
+            let agent_config = serde_json::to_vec(&agent_config).unwrap();
+            let encoded_jit_config = base64::engine::general_purpose::STANDARD.encode(agent_config);
+
+            Ok(Registration {
+                runner_id: runner_name.to_owned(),
+                encoded_jit_config,
+            })
+        })
+    }
+
+    fn deregister_runner<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let (client, pool_url, agent) = self.find_agent(runner_id).await?;
+
+            match agent {
+                Some(agent) => {
+                    client
+                        .delete(&format!(
+                            "{pool_url}/agents/{}?api-version={API_VERSION}",
+                            agent.id
+                        ))
+                        .await
+                }
+                // The agent never got around to registering itself.
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn runner_status<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RunnerStatus>>> {
+        Box::pin(async move {
+            let (_, _, agent) = self.find_agent(runner_id).await?;
+
+            let status = match agent {
+                Some(agent) => RunnerStatus {
+                    online: agent.status == "online",
+                    busy: agent.assigned_request.is_some(),
+                },
+                None => RunnerStatus {
+                    online: false,
+                    busy: false,
+                },
+            };
+
+            Ok(Some(status))
+        })
+    }
+}
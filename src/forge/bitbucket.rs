@@ -0,0 +1,190 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::rest::{encode_component, Client};
+use super::{BoxFuture, Error, Forge, Registration, Result, RunnerStatus};
+use crate::config::Config;
+use crate::machines::Triplet;
+
+const FORGE: &str = "Bitbucket";
+const API_URL: &str = "https://api.bitbucket.org/2.0";
+
+#[derive(Deserialize)]
+struct Uuid {
+    uuid: String,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    uuid: String,
+    workspace: Uuid,
+}
+
+#[derive(Deserialize)]
+struct OAuthClient {
+    id: String,
+    secret: String,
+}
+
+#[derive(Deserialize)]
+struct RunnerState {
+    status: String,
+    /// The pipeline step the runner is executing, if any
+    step: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct Runner {
+    state: Option<RunnerState>,
+}
+
+/// The credentials of a runner are only returned once, when it is created
+#[derive(Deserialize)]
+struct CreatedRunner {
+    uuid: String,
+    oauth_client: OAuthClient,
+}
+
+/// What the runner in the machine needs to connect to Bitbucket
+///
+/// This is handed to the machine as base64 encoded JSON.
+#[derive(Serialize)]
+struct RunnerConfig {
+    account_uuid: String,
+    repository_uuid: String,
+    runner_uuid: String,
+    oauth_client_id: String,
+    oauth_client_secret: String,
+}
+
+/// Register machines as Bitbucket Pipelines repository runners
+///
+/// The `<user>` and `<repository>` of a machine are the Bitbucket workspace
+/// and repository slug.
+pub struct BitbucketForge {
+    config: Config,
+}
+
+impl BitbucketForge {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    fn client(&self) -> Result<Client> {
+        let token = match &self.config.get().bitbucket {
+            Some(bitbucket) => Client::read_token(FORGE, &bitbucket.token_file)?,
+            None => return Err(Error::Config("No bitbucket section configured".to_owned())),
+        };
+
+        Client::new(FORGE, format!("Bearer {token}"))
+    }
+
+    fn runners_url(triplet: &Triplet) -> String {
+        format!(
+            "{API_URL}/repositories/{}/{}/pipelines-config/runners",
+            encode_component(triplet.owner()),
+            encode_component(triplet.repository()),
+        )
+    }
+
+    fn runner_url(triplet: &Triplet, runner_id: &str) -> String {
+        format!(
+            "{}/{}",
+            Self::runners_url(triplet),
+            encode_component(runner_id)
+        )
+    }
+}
+
+impl Forge for BitbucketForge {
+    fn register_runner<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_name: &'a str,
+        labels: Vec<String>,
+    ) -> BoxFuture<'a, Result<Registration>> {
+        Box::pin(async move {
+            let client = self.client()?;
+
+            let repository: Repository = client
+                .get(&format!(
+                    "{API_URL}/repositories/{}/{}",
+                    encode_component(triplet.owner()),
+                    encode_component(triplet.repository()),
+                ))
+                .await?;
+
+            // Bitbucket spells the label differently and needs to know
+            // which operating system the runner uses.
+            let labels: Vec<String> = ["self.hosted", "linux"]
+                .into_iter()
+                .map(str::to_owned)
+                .chain(labels.into_iter().filter(|label| label != "self-hosted"))
+                .collect();
+
+            let runner: CreatedRunner = client
+                .post(
+                    &Self::runners_url(triplet),
+                    &json!({
+                        "name": runner_name,
+                        "labels": labels,
+                    }),
+                )
+                .await?;
+
+            let runner_config = RunnerConfig {
+                account_uuid: repository.workspace.uuid,
+                repository_uuid: repository.uuid,
+                runner_uuid: runner.uuid.clone(),
+                oauth_client_id: runner.oauth_client.id,
+                oauth_client_secret: runner.oauth_client.secret,
+            };
+
+            let runner_config = serde_json::to_vec(&runner_config).unwrap();
+
+            Ok(Registration {
+                runner_id: runner.uuid,
+                encoded_jit_config: base64::engine::general_purpose::STANDARD.encode(runner_config),
+            })
+        })
+    }
+
+    fn deregister_runner<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.client()?
+                .delete(&Self::runner_url(triplet, runner_id))
+                .await
+        })
+    }
+
+    fn runner_status<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RunnerStatus>>> {
+        Box::pin(async move {
+            let runner: Runner = self
+                .client()?
+                .get(&Self::runner_url(triplet, runner_id))
+                .await?;
+
+            let status = match runner.state {
+                Some(state) => RunnerStatus {
+                    online: state.status == "ONLINE",
+                    busy: state.step.is_some(),
+                },
+                None => RunnerStatus {
+                    online: false,
+                    busy: false,
+                },
+            };
+
+            Ok(Some(status))
+        })
+    }
+}
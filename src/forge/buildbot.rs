@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use octocrab::models::workflows::Status;
+use octocrab::models::{JobId, RunId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use super::rest::{encode_component, Client};
+use super::{BoxFuture, Error, Forge, Registration, Result, RunnerStatus};
+use crate::config::{Config, ForgeKind};
+use crate::jobs::Manager as JobManager;
+use crate::machines::Triplet;
+
+const FORGE: &str = "Buildbot";
+
+// How often the master is asked whether the requested workers have connected.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct Workers {
+    workers: Vec<Worker>,
+}
+
+#[derive(Deserialize)]
+struct Worker {
+    connected_to: Vec<serde_json::Value>,
+}
+
+/// What the worker in the machine needs to connect to the master
+///
+/// This is handed to the machine as base64 encoded JSON.
+#[derive(Serialize)]
+struct WorkerConfig<'a> {
+    master: &'a str,
+    name: &'a str,
+    password: &'a str,
+}
+
+/// A latent worker the Buildbot master asked us to substantiate
+struct Request {
+    id: u64,
+    triplet: Triplet,
+    password: String,
+    requested_at: DateTime<Utc>,
+    /// The machine the worker runs in, once one was registered for it
+    runner_name: Option<String>,
+    connected: bool,
+    released: bool,
+}
+
+/// The state of a requested worker, as reported via the admin API
+#[derive(Serialize)]
+pub struct WorkerInfo {
+    pub worker: String,
+    pub machine: String,
+    pub runner_name: Option<String>,
+    pub connected: bool,
+    pub requested_at: DateTime<Utc>,
+}
+
+/// Provide latent workers to Buildbot masters
+///
+/// The master requests and releases workers via the admin API,
+/// from the `start_instance()` and `stop_instance()` methods of a latent
+/// worker.
+/// A requested worker is treated like a queued job for its machine type
+/// and is in progress once it has connected to the master.
+/// Once it is released the machine is no longer busy and is stopped.
+pub struct BuildbotForge {
+    config: Config,
+    next_id: AtomicU64,
+    requests: Mutex<HashMap<String, Request>>,
+    changed: Notify,
+}
+
+impl BuildbotForge {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            next_id: AtomicU64::new(1),
+            requests: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Request a machine of type `triplet` to run the worker `worker`
+    ///
+    /// Requesting a worker that is already requested does nothing.
+    /// The worker always runs in a machine of the type it was first requested as.
+    pub fn request(
+        &self,
+        worker: &str,
+        triplet: Triplet,
+        password: &str,
+    ) -> std::result::Result<(), String> {
+        let cfg = self.config.get();
+
+        let machine_config = cfg
+            .machine_config(&triplet)
+            .ok_or_else(|| format!("Unknown machine type {triplet}"))?;
+
+        if cfg.forge_of(machine_config) != ForgeKind::Buildbot {
+            return Err(format!(
+                "Machine type {triplet} does not use the buildbot forge"
+            ));
+        }
+
+        let mut requests = self.requests.lock().unwrap();
+
+        // The worker may be requested again before its release was processed.
+        if let Some(request) = requests.get_mut(worker) {
+            request.released = false;
+            return Ok(());
+        }
+
+        info!("Buildbot requested worker {worker} on {triplet}");
+
+        let request = Request {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            triplet,
+            password: password.to_owned(),
+            requested_at: Utc::now(),
+            runner_name: None,
+            connected: false,
+            released: false,
+        };
+
+        requests.insert(worker.to_owned(), request);
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    /// Release the worker `worker`, so that its machine is stopped
+    ///
+    /// Returns `false` if the worker was not requested.
+    pub fn release(&self, worker: &str) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+
+        match requests.get_mut(worker) {
+            Some(request) => {
+                info!("Buildbot released worker {worker}");
+
+                request.released = true;
+                self.changed.notify_one();
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn workers(&self) -> Vec<WorkerInfo> {
+        let requests = self.requests.lock().unwrap();
+
+        let mut workers: Vec<WorkerInfo> = requests
+            .iter()
+            .filter(|(_, request)| !request.released)
+            .map(|(worker, request)| WorkerInfo {
+                worker: worker.clone(),
+                machine: request.triplet.to_string(),
+                runner_name: request.runner_name.clone(),
+                connected: request.connected,
+                requested_at: request.requested_at,
+            })
+            .collect();
+
+        workers.sort_unstable_by_key(|w| w.requested_at);
+
+        workers
+    }
+
+    /// Ask the master whether `worker` is connected to it
+    async fn is_connected(&self, worker: &str) -> Result<bool> {
+        let url = {
+            let cfg = self.config.get();
+
+            let buildbot = cfg
+                .buildbot
+                .as_ref()
+                .ok_or_else(|| Error::Config("No buildbot section configured".to_owned()))?;
+
+            format!(
+                "{}/api/v2/workers/{}",
+                buildbot.url.trim_end_matches('/'),
+                encode_component(worker)
+            )
+        };
+
+        let workers: Workers = Client::anonymous(FORGE)?.get(&url).await?;
+
+        Ok(workers.workers.iter().any(|w| !w.connected_to.is_empty()))
+    }
+
+    /// Report the state of the requested workers to the job manager
+    async fn sync(&self, job_manager: &JobManager) {
+        let assigned: Vec<String> = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, request)| !request.released && request.runner_name.is_some())
+            .map(|(worker, _)| worker.clone())
+            .collect();
+
+        let mut connected = HashMap::new();
+
+        for worker in assigned {
+            match self.is_connected(&worker).await {
+                Ok(is_connected) => {
+                    connected.insert(worker, is_connected);
+                }
+                Err(e) => warn!("Failed to get the state of Buildbot worker {worker}: {e}"),
+            }
+        }
+
+        let mut requests = self.requests.lock().unwrap();
+
+        requests.retain(|worker, request| {
+            if let Some(is_connected) = connected.get(worker) {
+                request.connected = *is_connected;
+            }
+
+            let status = match (request.released, request.connected) {
+                (true, _) => Status::Completed,
+                (false, true) => Status::InProgress,
+                (false, false) => Status::Queued,
+            };
+
+            job_manager.status_feedback(
+                &request.triplet,
+                JobId(request.id),
+                RunId(request.id),
+                None,
+                request.requested_at,
+                status,
+                None,
+                request.runner_name.as_deref(),
+            );
+
+            !request.released
+        });
+    }
+
+    /// Keep the job manager up to date about requested and released workers
+    pub async fn run(&self, job_manager: JobManager) -> std::io::Result<()> {
+        loop {
+            self.sync(&job_manager).await;
+
+            let _ = tokio::time::timeout(POLL_INTERVAL, self.changed.notified()).await;
+        }
+    }
+}
+
+impl Forge for BuildbotForge {
+    fn register_runner<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_name: &'a str,
+        _labels: Vec<String>,
+    ) -> BoxFuture<'a, Result<Registration>> {
+        Box::pin(async move {
+            let master = self
+                .config
+                .get()
+                .buildbot
+                .as_ref()
+                .map(|buildbot| buildbot.master.clone())
+                .ok_or_else(|| Error::Config("No buildbot section configured".to_owned()))?;
+
+            let mut requests = self.requests.lock().unwrap();
+
+            // Hand out the longest waiting worker of this machine type.
+            let (worker, request) = requests
+                .iter_mut()
+                .filter(|(_, r)| &r.triplet == triplet && !r.released && r.runner_name.is_none())
+                .min_by_key(|(_, r)| r.id)
+                .ok_or_else(|| {
+                    Error::Config(format!("No Buildbot worker requested for {triplet}"))
+                })?;
+
+            request.runner_name = Some(runner_name.to_owned());
+
+            info!("Running Buildbot worker {worker} in {runner_name}");
+
+            let worker_config = WorkerConfig {
+                master: &master,
+                name: worker,
+                password: &request.password,
+            };
+
+            let worker_config = serde_json::to_vec(&worker_config).unwrap();
+
+            Ok(Registration {
+                runner_id: worker.clone(),
+                encoded_jit_config: base64::engine::general_purpose::STANDARD.encode(worker_config),
+            })
+        })
+    }
+
+    fn deregister_runner<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // The worker is still requested, but its machine is gone.
+            // Have another machine started for it.
+            if let Some(request) = self.requests.lock().unwrap().get_mut(runner_id) {
+                request.runner_name = None;
+                request.connected = false;
+                self.changed.notify_one();
+            }
+
+            Ok(())
+        })
+    }
+
+    fn runner_status<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        _runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RunnerStatus>>> {
+        // Connected workers are reported as jobs in progress instead.
+        Box::pin(async { Ok(None) })
+    }
+}
@@ -0,0 +1,50 @@
+use crate::error::Category;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("GitHub API request failed: {0}")]
+    GitHub(Box<octocrab::Error>),
+    #[error("{forge} API request failed: {source}")]
+    Request {
+        forge: &'static str,
+        source: Box<octocrab::Error>,
+    },
+    #[error("{forge} API responded with status {status}: {message}")]
+    Status {
+        forge: &'static str,
+        status: http::StatusCode,
+        message: String,
+    },
+    #[error("Unexpected {forge} API response: {source}")]
+    Response {
+        forge: &'static str,
+        source: serde_json::Error,
+    },
+    #[error("{0}")]
+    Config(String),
+}
+
+impl From<octocrab::Error> for Error {
+    fn from(e: octocrab::Error) -> Self {
+        // octocrab errors are large, box them to keep our `Result`s small.
+        Self::GitHub(Box::new(e))
+    }
+}
+
+impl Error {
+    pub fn category(&self) -> Category {
+        match self {
+            Self::GitHub(e) => Category::of_github(e),
+            Self::Status { status, .. } => match status.as_u16() {
+                401 | 403 => Category::Auth,
+                429 => Category::RateLimit,
+                400..=499 => Category::Config,
+                _ => Category::Transient,
+            },
+            Self::Config(_) => Category::Config,
+            Self::Request { .. } | Self::Response { .. } => Category::Transient,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
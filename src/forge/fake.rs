@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use octocrab::models::workflows::Status;
+use octocrab::models::{JobId, RunId};
+use serde::Deserialize;
+
+use super::{BoxFuture, Forge, Registration, Result, RunnerStatus};
+use crate::config::Config;
+use crate::jobs::Manager as JobManager;
+use crate::machines::{OwnerAndRepo, Triplet};
+
+// How often the jobs file is re-read.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A job as listed in the `fake_forge.jobs` file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FakeJob {
+    id: u64,
+    /// The repository in `owner/repository` form
+    repository: String,
+    /// The machine type to run the job on
+    machine: String,
+}
+
+impl FakeJob {
+    fn triplet(&self) -> Option<Triplet> {
+        let (owner, repository) = self.repository.split_once('/')?;
+
+        Some(OwnerAndRepo::new(owner, repository).into_triplet(&self.machine))
+    }
+}
+
+/// A stand-in for GitHub for local development
+///
+/// The fake forge serves the jobs listed in a local YAML file as queued jobs
+/// and hands out made-up runner registrations.
+/// This allows trying out machine setups end-to-end without setting up a
+/// GitHub App first.
+/// The action runner in the machines can not connect to anything, of course,
+/// so registered runners are simply reported as online and idle.
+pub struct FakeForge {
+    config: Config,
+    next_runner_id: AtomicU64,
+    runners: Mutex<HashSet<String>>,
+}
+
+impl FakeForge {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            next_runner_id: AtomicU64::new(1),
+            runners: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn read_jobs(path: &Path) -> std::io::Result<Vec<FakeJob>> {
+        let content = std::fs::read(path)?;
+
+        serde_yml::from_slice(&content).map_err(std::io::Error::other)
+    }
+
+    /// Serve the jobs from the jobs file
+    ///
+    /// Jobs are reported as queued while they are listed in the file and as
+    /// completed once they are removed from it.
+    pub async fn run(&self, job_manager: JobManager) -> std::io::Result<()> {
+        let mut served: HashMap<u64, (Triplet, DateTime<Utc>)> = HashMap::new();
+
+        loop {
+            let path = self
+                .config
+                .get()
+                .fake_forge
+                .as_ref()
+                .map(|ff| ff.jobs.clone());
+
+            let jobs = match path {
+                Some(path) => Self::read_jobs(&path).unwrap_or_else(|e| {
+                    error!("Failed to read fake jobs from {}: {e}", path.display());
+                    Vec::new()
+                }),
+                None => {
+                    warn!("No fake_forge.jobs file configured. Serving no jobs");
+                    Vec::new()
+                }
+            };
+
+            let mut current = HashMap::new();
+
+            for job in jobs {
+                let triplet = match job.triplet() {
+                    Some(triplet) => triplet,
+                    None => {
+                        error!(
+                            "Fake job {} has an invalid repository {}",
+                            job.id, job.repository
+                        );
+                        continue;
+                    }
+                };
+
+                let queued_at = match served.get(&job.id) {
+                    Some((_, queued_at)) => *queued_at,
+                    None => {
+                        info!("Serving fake job {} for {triplet}", job.id);
+                        Utc::now()
+                    }
+                };
+
+                job_manager.status_feedback(
+                    &triplet,
+                    JobId(job.id),
+                    RunId(job.id),
+                    None,
+                    queued_at,
+                    Status::Queued,
+                    None,
+                    None,
+                );
+
+                current.insert(job.id, (triplet, queued_at));
+            }
+
+            for (id, (triplet, queued_at)) in served {
+                if !current.contains_key(&id) {
+                    info!("Fake job {id} for {triplet} was removed");
+
+                    job_manager.status_feedback(
+                        &triplet,
+                        JobId(id),
+                        RunId(id),
+                        None,
+                        queued_at,
+                        Status::Completed,
+                        None,
+                        None,
+                    );
+                }
+            }
+
+            served = current;
+
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+        }
+    }
+}
+
+impl Forge for FakeForge {
+    fn register_runner<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        _runner_name: &'a str,
+        _labels: Vec<String>,
+    ) -> BoxFuture<'a, Result<Registration>> {
+        Box::pin(async move {
+            let runner_id = self
+                .next_runner_id
+                .fetch_add(1, Ordering::Relaxed)
+                .to_string();
+
+            self.runners.lock().unwrap().insert(runner_id.clone());
+
+            Ok(Registration {
+                runner_id,
+                encoded_jit_config: "fake".to_owned(),
+            })
+        })
+    }
+
+    fn deregister_runner<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.runners.lock().unwrap().remove(runner_id);
+
+            Ok(())
+        })
+    }
+
+    fn runner_status<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RunnerStatus>>> {
+        Box::pin(async move {
+            // There is no runner in the machines that could report back,
+            // so pretend they came online right away.
+            let status = RunnerStatus {
+                online: self.runners.lock().unwrap().contains(runner_id),
+                busy: false,
+            };
+
+            Ok(Some(status))
+        })
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use octocrab::models::{RunnerGroupId, RunnerId};
+
+use super::{BoxFuture, Error, Forge, Registration, Result, RunnerStatus};
+use crate::auth::Auth;
+use crate::machines::Triplet;
+
+/// Register runners with GitHub, using the App installation of the owner
+pub struct GitHubForge {
+    auth: Arc<Auth>,
+}
+
+impl GitHubForge {
+    pub fn new(auth: Arc<Auth>) -> Self {
+        Self { auth }
+    }
+}
+
+impl Forge for GitHubForge {
+    fn register_runner<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_name: &'a str,
+        labels: Vec<String>,
+    ) -> BoxFuture<'a, Result<Registration>> {
+        Box::pin(async move {
+            let octocrab = self.auth.user(triplet.owner()).unwrap();
+            let _permit = self.auth.api_permit(triplet.owner()).await;
+
+            let jit_config = octocrab
+                .actions()
+                .create_repo_jit_runner_config(
+                    triplet.owner(),
+                    triplet.repository(),
+                    runner_name,
+                    RunnerGroupId(1),
+                    labels,
+                )
+                .send()
+                .await;
+
+            self.auth.health().record(&jit_config);
+            let jit_config = jit_config?;
+
+            Ok(Registration {
+                runner_id: jit_config.runner.id.to_string(),
+                encoded_jit_config: jit_config.encoded_jit_config,
+            })
+        })
+    }
+
+    fn deregister_runner<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let runner_id = runner_id
+                .parse()
+                .map(RunnerId)
+                .map_err(|_| Error::Config(format!("Invalid GitHub runner id {runner_id}")))?;
+
+            let octocrab = self.auth.user(triplet.owner()).unwrap();
+            let _permit = self.auth.api_permit(triplet.owner()).await;
+
+            octocrab
+                .actions()
+                .delete_repo_runner(triplet.owner(), triplet.repository(), runner_id)
+                .await?;
+
+            Ok(())
+        })
+    }
+
+    fn runner_status<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        _runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RunnerStatus>>> {
+        // The machines::Manager sweeps the runner list of each repository
+        // and job events tell us about busy runners.
+        Box::pin(async { Ok(None) })
+    }
+}
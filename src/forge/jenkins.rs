@@ -0,0 +1,507 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use log::{error, info, warn};
+use octocrab::models::workflows::Status;
+use octocrab::models::{JobId, RunId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::rest::{encode_component, Client};
+use super::{BoxFuture, Error, Forge, Registration, Result, RunnerStatus};
+use crate::config::{Config, ForgeKind};
+use crate::jobs::Manager as JobManager;
+use crate::machines::Triplet;
+
+const FORGE: &str = "Jenkins";
+
+// How often the build queue is polled for jobs waiting for our agents.
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+// The directory Jenkins uses on the agent.
+// The setup template has to make sure it exists and is writable.
+const AGENT_DIR: &str = "/var/lib/jenkins-agent";
+
+// Jenkins queue item ids count up from one and would collide with the
+// job and run ids of other forges in the job manager.
+// GitHub ids are nowhere near this range.
+const ID_NAMESPACE: u64 = 1 << 62;
+
+#[derive(Deserialize)]
+struct Queue {
+    items: Vec<QueueItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueueItem {
+    id: u64,
+    buildable: bool,
+    in_queue_since: i64,
+    assigned_label: Option<Label>,
+    task: Option<Task>,
+}
+
+/// The job a queue item belongs to
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Task {
+    assigned_label: Option<Label>,
+}
+
+#[derive(Deserialize)]
+struct Label {
+    name: String,
+}
+
+/// A queue item that may have left the queue
+#[derive(Deserialize)]
+struct LeftItem {
+    #[serde(default)]
+    cancelled: bool,
+    executable: Option<Executable>,
+}
+
+#[derive(Deserialize)]
+struct Executable {
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Build {
+    building: bool,
+    built_on: Option<String>,
+    /// E.g. `SUCCESS` or `FAILURE`, once the build has completed
+    result: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Computer {
+    offline: bool,
+    idle: bool,
+}
+
+/// What the agent in the machine needs to connect to Jenkins
+///
+/// This is handed to the machine as base64 encoded JSON.
+#[derive(Serialize)]
+struct AgentConfig<'a> {
+    url: &'a str,
+    agent: &'a str,
+    secret: String,
+}
+
+/// A queue item we have reported to the job manager
+struct Tracked {
+    triplet: Triplet,
+    queued_at: DateTime<Utc>,
+    build_url: Option<String>,
+}
+
+/// Register machines as ephemeral Jenkins inbound agents
+///
+/// Machine types using this forge are labeled with their machine name.
+/// Builds waiting in the Jenkins queue for one of these labels are
+/// treated like queued jobs on GitHub and create demand for machines.
+/// Once the build on an agent is done the agent is removed from Jenkins,
+/// which disconnects it, so the machine can power itself off.
+pub struct JenkinsForge {
+    config: Config,
+}
+
+impl JenkinsForge {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Get an API client and the base URL of Jenkins
+    fn client(&self) -> Result<(Client, String)> {
+        let cfg = self.config.get();
+
+        let jenkins = cfg
+            .jenkins
+            .as_ref()
+            .ok_or_else(|| Error::Config("No jenkins section configured".to_owned()))?;
+
+        let token = Client::read_token(FORGE, &jenkins.token_file)?;
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{token}", jenkins.user));
+
+        let client = Client::new(FORGE, format!("Basic {credentials}"))?;
+        let url = jenkins.url.trim_end_matches('/').to_owned();
+
+        Ok((client, url))
+    }
+
+    fn computer_url(url: &str, runner_name: &str) -> String {
+        format!("{url}/computer/{}", encode_component(runner_name))
+    }
+
+    /// Get the machine types using this forge by their label
+    fn labels(&self) -> HashMap<String, Triplet> {
+        let cfg = self.config.get();
+        let mut labels = HashMap::new();
+
+        for triplet in cfg.triplets() {
+            let is_jenkins = cfg
+                .machine_config(&triplet)
+                .map(|mc| cfg.forge_of(mc) == ForgeKind::Jenkins)
+                .unwrap_or(false);
+
+            if !is_jenkins {
+                continue;
+            }
+
+            let label = triplet.machine_name().to_owned();
+
+            if let Some(other) = labels.insert(label, triplet.clone()) {
+                warn!("Machine types {other} and {triplet} use the same Jenkins label. Using {triplet}");
+            }
+        }
+
+        labels
+    }
+
+    /// Get the label a queue item is waiting for
+    ///
+    /// Pipeline steps carry the label of their `node` block themselves,
+    /// freestyle builds only via the job they belong to.
+    fn waiting_for(item: &QueueItem) -> Option<&str> {
+        item.assigned_label
+            .as_ref()
+            .or_else(|| item.task.as_ref()?.assigned_label.as_ref())
+            .map(|label| label.name.as_str())
+    }
+
+    /// Get the ids to report the queue item `id` to the job manager with
+    fn ids(id: u64) -> (JobId, RunId) {
+        (JobId(ID_NAMESPACE | id), RunId(ID_NAMESPACE | id))
+    }
+
+    /// Follow a tracked item that left the queue to its build
+    ///
+    /// Returns `true` once the item does not need to be tracked anymore.
+    async fn follow(
+        &self,
+        client: &Client,
+        url: &str,
+        id: u64,
+        item: &mut Tracked,
+        job_manager: &JobManager,
+    ) -> Result<bool> {
+        if item.build_url.is_none() {
+            let left: Result<LeftItem> =
+                client.get(&format!("{url}/queue/item/{id}/api/json")).await;
+
+            match left {
+                Ok(LeftItem {
+                    executable: Some(executable),
+                    ..
+                }) => item.build_url = Some(executable.url),
+                // Jenkins forgets about items some time after they left the queue.
+                Ok(LeftItem {
+                    cancelled: true, ..
+                })
+                | Err(Error::Status {
+                    status: StatusCode::NOT_FOUND,
+                    ..
+                }) => {
+                    let (job_id, run_id) = Self::ids(id);
+
+                    job_manager.status_feedback(
+                        &item.triplet,
+                        job_id,
+                        run_id,
+                        None,
+                        item.queued_at,
+                        Status::Completed,
+                        None,
+                        None,
+                    );
+
+                    return Ok(true);
+                }
+                // Left the queue, but the build did not start yet.
+                Ok(_) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let build_url = item.build_url.as_deref().unwrap_or_default();
+
+        let build: Build = client
+            .get(&format!(
+                "{}/api/json?tree=building,builtOn,result",
+                build_url.trim_end_matches('/')
+            ))
+            .await?;
+
+        // Builds on the built-in node have an empty `builtOn`.
+        let runner_name = build.built_on.filter(|name| !name.is_empty());
+
+        let status = match build.building {
+            true => Status::InProgress,
+            false => Status::Completed,
+        };
+
+        let conclusion = build.result.map(|result| result.to_lowercase());
+
+        let (job_id, run_id) = Self::ids(id);
+
+        job_manager.status_feedback(
+            &item.triplet,
+            job_id,
+            run_id,
+            None,
+            item.queued_at,
+            status,
+            conclusion.as_deref(),
+            runner_name.as_deref(),
+        );
+
+        if build.building {
+            return Ok(false);
+        }
+
+        // Our agents are only good for a single build.
+        // Removing the agent disconnects it, so the machine can power off.
+        if let Some(runner_name) = runner_name.filter(|name| name.starts_with("forrest-")) {
+            let res = client
+                .post_action(&format!(
+                    "{}/doDelete",
+                    Self::computer_url(url, &runner_name)
+                ))
+                .await;
+
+            match res {
+                Ok(()) => info!("Removed Jenkins agent {runner_name} after its build"),
+                Err(e) => warn!("Failed to remove Jenkins agent {runner_name}: {e}"),
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn poll_once(
+        &self,
+        job_manager: &JobManager,
+        tracked: &mut HashMap<u64, Tracked>,
+    ) -> Result<()> {
+        let (client, url) = self.client()?;
+        let labels = self.labels();
+
+        let queue: Queue = client
+            .get(&format!(
+                "{url}/queue/api/json?tree={}",
+                encode_component(
+                    "items[id,buildable,inQueueSince,assignedLabel[name],task[assignedLabel[name]]]"
+                )
+            ))
+            .await?;
+
+        let mut queued = HashSet::new();
+
+        for item in queue.items {
+            if !item.buildable {
+                continue;
+            }
+
+            let triplet = match Self::waiting_for(&item).and_then(|label| labels.get(label)) {
+                Some(triplet) => triplet.clone(),
+                None => continue,
+            };
+
+            let queued_at =
+                DateTime::from_timestamp_millis(item.in_queue_since).unwrap_or_else(Utc::now);
+
+            let (job_id, run_id) = Self::ids(item.id);
+
+            job_manager.status_feedback(
+                &triplet,
+                job_id,
+                run_id,
+                None,
+                queued_at,
+                Status::Queued,
+                None,
+                None,
+            );
+
+            tracked.entry(item.id).or_insert(Tracked {
+                triplet,
+                queued_at,
+                build_url: None,
+            });
+
+            queued.insert(item.id);
+        }
+
+        let left: Vec<u64> = tracked
+            .keys()
+            .filter(|id| !queued.contains(id))
+            .copied()
+            .collect();
+
+        for id in left {
+            let item = tracked.get_mut(&id).unwrap();
+
+            match self.follow(&client, &url, id, item, job_manager).await {
+                Ok(true) => {
+                    tracked.remove(&id);
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Failed to get the state of Jenkins queue item {id}: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Periodically poll the Jenkins build queue for builds our agents can run
+    pub async fn run(&self, job_manager: JobManager) -> std::io::Result<()> {
+        let mut tracked = HashMap::new();
+
+        loop {
+            if let Err(e) = self.poll_once(&job_manager, &mut tracked).await {
+                error!("Failed to poll the Jenkins queue ({}): {e}", e.category());
+            }
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Forge for JenkinsForge {
+    fn register_runner<'a>(
+        &'a self,
+        triplet: &'a Triplet,
+        runner_name: &'a str,
+        _labels: Vec<String>,
+    ) -> BoxFuture<'a, Result<Registration>> {
+        Box::pin(async move {
+            let (client, url) = self.client()?;
+
+            let node = json!({
+                "name": runner_name,
+                "nodeDescription": format!("Forrest machine for {triplet}"),
+                "numExecutors": "1",
+                "remoteFS": AGENT_DIR,
+                "labelString": triplet.machine_name(),
+                "mode": "EXCLUSIVE",
+                "launcher": {
+                    "stapler-class": "hudson.slaves.JNLPLauncher",
+                    "$class": "hudson.slaves.JNLPLauncher",
+                    "webSocket": true,
+                },
+                "retentionStrategy": {
+                    "stapler-class": "hudson.slaves.RetentionStrategy$Always",
+                    "$class": "hudson.slaves.RetentionStrategy$Always",
+                },
+                "nodeProperties": {
+                    "stapler-class-bag": "true",
+                },
+                "type": "hudson.slaves.DumbSlave",
+            });
+
+            client
+                .post_action(&format!(
+                    "{url}/computer/doCreateItem?name={}&type=hudson.slaves.DumbSlave&json={}",
+                    encode_component(runner_name),
+                    encode_component(&node.to_string()),
+                ))
+                .await?;
+
+            // The secret is the first argument in the JNLP file of the agent.
+            let jnlp = client
+                .get_text(&format!(
+                    "{}/jenkins-agent.jnlp",
+                    Self::computer_url(&url, runner_name)
+                ))
+                .await?;
+
+            let secret = jnlp
+                .split("<argument>")
+                .nth(1)
+                .and_then(|rest| rest.split("</argument>").next())
+                .ok_or_else(|| Error::Status {
+                    forge: FORGE,
+                    status: StatusCode::OK,
+                    message: format!("No agent secret for {runner_name} in the JNLP file"),
+                })?;
+
+            let agent_config = AgentConfig {
+                url: &url,
+                agent: runner_name,
+                secret: secret.to_owned(),
+            };
+
+            let agent_config = serde_json::to_vec(&agent_config).unwrap();
+
+            Ok(Registration {
+                runner_id: runner_name.to_owned(),
+                encoded_jit_config: base64::engine::general_purpose::STANDARD.encode(agent_config),
+            })
+        })
+    }
+
+    fn deregister_runner<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let (client, url) = self.client()?;
+
+            let res = client
+                .post_action(&format!("{}/doDelete", Self::computer_url(&url, runner_id)))
+                .await;
+
+            match res {
+                // The agent was already removed after its build.
+                Err(Error::Status {
+                    status: StatusCode::NOT_FOUND,
+                    ..
+                }) => Ok(()),
+                res => res,
+            }
+        })
+    }
+
+    fn runner_status<'a>(
+        &'a self,
+        _triplet: &'a Triplet,
+        runner_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RunnerStatus>>> {
+        Box::pin(async move {
+            let (client, url) = self.client()?;
+
+            let computer: Result<Computer> = client
+                .get(&format!(
+                    "{}/api/json?tree=offline,idle",
+                    Self::computer_url(&url, runner_id)
+                ))
+                .await;
+
+            let status = match computer {
+                Ok(computer) => RunnerStatus {
+                    online: !computer.offline,
+                    busy: !computer.idle,
+                },
+                Err(Error::Status {
+                    status: StatusCode::NOT_FOUND,
+                    ..
+                }) => RunnerStatus {
+                    online: false,
+                    busy: false,
+                },
+                Err(e) => return Err(e),
+            };
+
+            Ok(Some(status))
+        })
+    }
+}
@@ -0,0 +1,199 @@
+use http::header::AUTHORIZATION;
+use http::StatusCode;
+use http_body_util::BodyExt;
+use octocrab::Octocrab;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{Error, Result};
+
+/// A minimal JSON API client for forges other than GitHub
+///
+/// This re-uses the HTTP client of Octocrab with absolute URLs,
+/// like the webhook relay client does.
+pub(super) struct Client {
+    forge: &'static str,
+    client: Octocrab,
+}
+
+impl Client {
+    /// Get a client that sends `authorization` as `Authorization` header
+    pub(super) fn new(forge: &'static str, authorization: String) -> Result<Self> {
+        let client = Octocrab::builder()
+            .add_header(AUTHORIZATION, authorization)
+            .build()
+            .map_err(|source| Error::Request {
+                forge,
+                source: Box::new(source),
+            })?;
+
+        Ok(Self { forge, client })
+    }
+
+    /// Get a client for APIs that do not require authentication
+    pub(super) fn anonymous(forge: &'static str) -> Result<Self> {
+        let client = Octocrab::builder()
+            .build()
+            .map_err(|source| Error::Request {
+                forge,
+                source: Box::new(source),
+            })?;
+
+        Ok(Self { forge, client })
+    }
+
+    /// Read the (whitespace trimmed) access token from `path`
+    pub(super) fn read_token(forge: &'static str, path: &str) -> Result<String> {
+        std::fs::read_to_string(path)
+            .map(|token| token.trim().to_owned())
+            .map_err(|e| Error::Config(format!("Failed to read the {forge} token {path}: {e}")))
+    }
+
+    fn request_error(&self, source: octocrab::Error) -> Error {
+        Error::Request {
+            forge: self.forge,
+            source: Box::new(source),
+        }
+    }
+
+    /// Turn unsuccessful responses into errors
+    fn check(&self, status: StatusCode, body: &[u8]) -> Result<()> {
+        match status.is_success() {
+            true => Ok(()),
+            false => Err(Error::Status {
+                forge: self.forge,
+                status,
+                message: String::from_utf8_lossy(body).into_owned(),
+            }),
+        }
+    }
+
+    fn decode<R: DeserializeOwned>(&self, status: StatusCode, body: &[u8]) -> Result<R> {
+        self.check(status, body)?;
+
+        serde_json::from_slice(body).map_err(|source| Error::Response {
+            forge: self.forge,
+            source,
+        })
+    }
+
+    pub(super) async fn get<R: DeserializeOwned>(&self, url: &str) -> Result<R> {
+        let response = self
+            .client
+            ._get(url)
+            .await
+            .map_err(|e| self.request_error(e))?;
+
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| self.request_error(e))?
+            .to_bytes();
+
+        self.decode(status, &body)
+    }
+
+    pub(super) async fn post<B: Serialize, R: DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &B,
+    ) -> Result<R> {
+        let response = self
+            .client
+            ._post(url, Some(body))
+            .await
+            .map_err(|e| self.request_error(e))?;
+
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| self.request_error(e))?
+            .to_bytes();
+
+        self.decode(status, &body)
+    }
+
+    /// Get a non-JSON document
+    pub(super) async fn get_text(&self, url: &str) -> Result<String> {
+        let response = self
+            .client
+            ._get(url)
+            .await
+            .map_err(|e| self.request_error(e))?;
+
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| self.request_error(e))?
+            .to_bytes();
+
+        self.check(status, &body)?;
+
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// Send a POST request without a body, e.g. to trigger an action
+    ///
+    /// Redirects are not followed, but count as success,
+    /// as they are the usual response to form submissions.
+    pub(super) async fn post_action(&self, url: &str) -> Result<()> {
+        let response = self
+            .client
+            ._post(url, None::<&()>)
+            .await
+            .map_err(|e| self.request_error(e))?;
+
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| self.request_error(e))?
+            .to_bytes();
+
+        match status.is_redirection() {
+            true => Ok(()),
+            false => self.check(status, &body),
+        }
+    }
+
+    pub(super) async fn delete(&self, url: &str) -> Result<()> {
+        let response = self
+            .client
+            ._delete(url, None::<&()>)
+            .await
+            .map_err(|e| self.request_error(e))?;
+
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| self.request_error(e))?
+            .to_bytes();
+
+        self.check(status, &body)
+    }
+}
+
+/// Percent-encode `component` for use as a path segment or query value
+pub(super) fn encode_component(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+
+    for byte in component.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::jobs::Manager as JobManager;
+use crate::machines::Manager as MachineManager;
+
+// How often to check whether a heartbeat was configured in the meantime,
+// if there is neither a heartbeat file nor a systemd watchdog to serve.
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The content of the heartbeat file
+#[derive(Serialize)]
+struct Beat {
+    timestamp: DateTime<Utc>,
+    pid: u32,
+    machines: usize,
+    machines_by_status: BTreeMap<String, usize>,
+    jobs: usize,
+    degraded: bool,
+}
+
+/// Proves to external monitoring that we are still alive
+///
+/// A heartbeat file with a timestamp and some counts is written every
+/// `host.heartbeat.interval`, so that checks on the host can verify our
+/// liveness without going through the (possibly firewalled) admin API.
+/// If systemd was configured with a `WatchdogSec=` it is pinged as well.
+/// Both happen from the async runtime, so they stop if it gets stuck.
+pub struct Heartbeat {
+    config: Config,
+    machine_manager: MachineManager,
+    job_manager: JobManager,
+    watchdog: Option<Duration>,
+}
+
+impl Beat {
+    /// Write the heartbeat to `path`
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        // Write to a temporary file first and move it into place,
+        // so that a check never reads a half written file.
+        let tmp_path = path.with_extension("tmp");
+
+        let content = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, path)
+    }
+}
+
+impl Heartbeat {
+    pub fn new(config: Config, machine_manager: MachineManager, job_manager: JobManager) -> Self {
+        let mut usec = 0;
+
+        let watchdog =
+            sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec));
+
+        if let Some(watchdog) = watchdog {
+            info!(
+                "The systemd watchdog is enabled. Pinging it every {}ms",
+                (watchdog / 2).as_millis()
+            );
+        }
+
+        Self {
+            config,
+            machine_manager,
+            job_manager,
+            watchdog,
+        }
+    }
+
+    fn beat(&self) -> Beat {
+        let machines = self.machine_manager.machine_info();
+
+        let mut machines_by_status = BTreeMap::new();
+
+        for machine in &machines {
+            *machines_by_status
+                .entry(machine.status.clone())
+                .or_default() += 1;
+        }
+
+        Beat {
+            timestamp: Utc::now(),
+            pid: std::process::id(),
+            machines: machines.len(),
+            machines_by_status,
+            jobs: self.job_manager.job_info().len(),
+            degraded: self.machine_manager.degraded().is_some(),
+        }
+    }
+
+    pub async fn run(&self) -> std::io::Result<()> {
+        loop {
+            let mut interval = IDLE_INTERVAL;
+
+            if let Some(heartbeat) = &self.config.get().host.heartbeat {
+                if let Err(e) = self.beat().write(&heartbeat.path) {
+                    error!(
+                        "Failed to write heartbeat file {}: {e}",
+                        heartbeat.path.display()
+                    );
+                }
+
+                interval = heartbeat.interval();
+            }
+
+            if let Some(watchdog) = self.watchdog {
+                if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+                    warn!("Failed to ping the systemd watchdog: {e}");
+                }
+
+                // systemd recommends pinging at half the configured interval.
+                interval = interval.min(watchdog / 2);
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
@@ -1,5 +1,18 @@
+mod app_hook;
+mod approval;
+mod attribution;
+mod checkpoint;
+mod error;
 mod poll;
+mod quarantine;
+mod relay;
+mod reservation;
+mod wait_notice;
 mod webhook;
 
+pub use app_hook::configure as configure_app_hook;
+pub use checkpoint::Checkpoint;
+pub use error::{Error, Result};
 pub use poll::Poller;
+pub use relay::Relay;
 pub use webhook::WebhookHandler;
@@ -0,0 +1,68 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::Result;
+use crate::auth::Auth;
+use crate::config::ConfigFile;
+
+/// The webhook event types we need the App to be subscribed to
+const REQUIRED_EVENTS: &[&str] = &["workflow_job"];
+
+/// The webhook configuration of a GitHub App as used by `/app/hook/config`
+#[derive(Serialize, Deserialize)]
+struct HookConfig {
+    url: Option<String>,
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    insecure_ssl: Option<String>,
+}
+
+/// Make sure the App delivers its webhooks to where we expect them
+///
+/// This sets the webhook URL and secret of the App from the config
+/// (if a `github.webhook_url` or relay is configured), so that setting up
+/// a new instance does not require clicking through the App settings.
+/// The subscribed events can not be changed via the API,
+/// so we only warn if the required ones are missing.
+pub async fn configure(cfg: &ConfigFile, auth: &Auth) -> Result<()> {
+    let (url, secret) = match (cfg.github.webhook_url(), &cfg.github.webhook_secret) {
+        (Some(url), Some(secret)) => (url, secret),
+        (Some(_), None) => {
+            warn!("Not setting up the App webhook: no webhook secret configured");
+            return Ok(());
+        }
+        (None, _) => return Ok(()),
+    };
+
+    let app = auth.app();
+
+    let current: HookConfig = app.get("/app/hook/config", None::<&()>).await?;
+
+    let wanted = HookConfig {
+        url: Some(url.to_owned()),
+        content_type: Some("json".to_owned()),
+        secret: Some(secret.clone()),
+        insecure_ssl: Some("0".to_owned()),
+    };
+
+    if current.url != wanted.url {
+        info!("Updating the App webhook URL to {url}");
+    }
+
+    // GitHub never returns the secret, so we can not tell if it changed
+    // and always update it along with the rest.
+    let _: HookConfig = app.patch("/app/hook/config", Some(&wanted)).await?;
+
+    let events = app.current().app().await?.events;
+
+    for event in REQUIRED_EVENTS {
+        if !events.iter().any(|e| e == event) {
+            warn!(
+                "The GitHub App is not subscribed to \"{event}\" events. Enable them in the App settings"
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use octocrab::models::workflows::Job;
+use serde_json::Value;
+
+use crate::config::ConfigFile;
+use crate::machines::OwnerAndRepo;
+
+/// Parse a workflow job of `oar`, as reported by webhooks and the API
+///
+/// Jobs that wait for a deployment to be approved, e.g. because they use an
+/// environment with required reviewers, have the status `waiting`, which
+/// octocrab does not know about.
+/// They are tracked like pending jobs, which do not get a machine,
+/// unless the repository wants machines booted before the approval.
+/// Once the deployment is approved the job is reported as queued.
+pub(super) fn parse_job(
+    cfg: &ConfigFile,
+    oar: &OwnerAndRepo,
+    mut workflow_job: Value,
+) -> serde_json::Result<Job> {
+    if workflow_job.get("status").and_then(Value::as_str) == Some("waiting") {
+        let boot_before_approval = cfg
+            .repositories
+            .get(oar.owner())
+            .and_then(|repos| repos.get(oar.repository()))
+            .is_some_and(|repo| repo.boot_before_approval);
+
+        let status = match boot_before_approval {
+            true => "queued",
+            false => "pending",
+        };
+
+        workflow_job["status"] = Value::from(status);
+    }
+
+    serde_json::from_value(workflow_job)
+}
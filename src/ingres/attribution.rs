@@ -0,0 +1,59 @@
+use log::{debug, warn};
+use serde_json::Value;
+
+use crate::machines::OwnerAndRepo;
+
+/// Get the repository a workflow run belongs to from its API URL
+///
+/// E.g. `https://api.github.com/repos/<owner>/<repository>/actions/runs/<id>`.
+fn run_repository(run_url: &str) -> Option<OwnerAndRepo> {
+    let (_, path) = run_url.split_once("/repos/")?;
+    let mut segments = path.split('/');
+
+    let owner = segments.next().filter(|s| !s.is_empty())?;
+    let repository = segments.next().filter(|s| !s.is_empty())?;
+
+    (segments.next() == Some("actions")).then(|| OwnerAndRepo::new(owner, repository))
+}
+
+/// The repository to attribute the `workflow_job` of an event for `oar` to
+///
+/// Jobs of reusable workflows (called via `workflow_call`) run in the
+/// workflow run of the calling repository, even if the workflow is hosted in
+/// another repository.
+/// This is the repository their runner has to be registered with and their
+/// demand, budgets and limits count towards.
+/// The `run_url` of the job is authoritative on that, the `oar` the event
+/// was delivered for is only used if the job does not have one.
+/// Callers of another owner are not trusted, as API requests on behalf of
+/// the job use the installation of the owner of `oar`.
+pub(super) fn calling_repository(oar: OwnerAndRepo, workflow_job: &Value) -> OwnerAndRepo {
+    let run_url = match workflow_job.get("run_url").and_then(Value::as_str) {
+        Some(run_url) => run_url,
+        None => return oar,
+    };
+
+    let caller = match run_repository(run_url) {
+        Some(caller) => caller,
+        None => {
+            warn!("Could not get the repository of {oar} job from its run URL {run_url}");
+            return oar;
+        }
+    };
+
+    if caller.owner() != oar.owner() {
+        warn!("Not attributing job of {oar} to {caller}, which belongs to another owner");
+        return oar;
+    }
+
+    if caller != oar {
+        let workflow = workflow_job
+            .get("workflow_name")
+            .and_then(Value::as_str)
+            .unwrap_or("-");
+
+        debug!("Attributing job of {oar} to {caller}, which called it via workflow {workflow}");
+    }
+
+    caller
+}
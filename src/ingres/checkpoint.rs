@@ -0,0 +1,101 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::error;
+
+/// Do not write the checkpoint file for every single webhook event.
+/// Losing a few seconds of progress only makes the catch-up poll after
+/// a restart marginally larger.
+const PERSIST_INTERVAL: TimeDelta = TimeDelta::seconds(30);
+
+struct Inner {
+    path: PathBuf,
+    last_event: Option<DateTime<Utc>>,
+    last_persisted: Option<DateTime<Utc>>,
+}
+
+/// The point in time up to which we know we have seen all job updates
+///
+/// This is advanced by processed webhook events and successful polls
+/// and persisted to disk, so that after a restart (or a longer outage)
+/// only the gap since then has to be polled for.
+#[derive(Clone)]
+pub struct Checkpoint {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Checkpoint {
+    pub fn new(base_dir: &Path) -> Self {
+        let path = base_dir.join("last_event.json");
+
+        let last_event = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                error!("Failed to parse {}, ignoring it: {e}", path.display());
+                None
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                error!("Failed to read {}, ignoring it: {e}", path.display());
+                None
+            }
+        };
+
+        let inner = Inner {
+            path,
+            last_event,
+            last_persisted: last_event,
+        };
+
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// The time of the last event processed, possibly by a previous instance
+    pub fn last_event(&self) -> Option<DateTime<Utc>> {
+        self.inner.lock().unwrap().last_event
+    }
+
+    /// Note that all job updates up to `at` were processed
+    pub fn advance(&self, at: DateTime<Utc>) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.last_event.map(|le| le >= at).unwrap_or(false) {
+            return;
+        }
+
+        inner.last_event = Some(at);
+
+        let persist_due = inner
+            .last_persisted
+            .map(|lp| at - lp >= PERSIST_INTERVAL)
+            .unwrap_or(true);
+
+        if persist_due {
+            inner.persist();
+        }
+    }
+}
+
+impl Inner {
+    fn persist(&mut self) {
+        // Write to a temporary file first and move it into place,
+        // so we never leave a half written file behind.
+        let tmp_path = self.path.with_extension("json.tmp");
+
+        let res = serde_json::to_vec(&self.last_event)
+            .map_err(std::io::Error::other)
+            .and_then(|content| std::fs::write(&tmp_path, content))
+            .and_then(|()| std::fs::rename(&tmp_path, &self.path));
+
+        match res {
+            Ok(()) => self.last_persisted = self.last_event,
+            Err(e) => error!(
+                "Failed to persist event checkpoint to {}: {e}",
+                self.path.display()
+            ),
+        }
+    }
+}
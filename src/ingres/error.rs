@@ -0,0 +1,22 @@
+use crate::error::Category;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("GitHub API request failed: {0}")]
+    GitHub(#[from] octocrab::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Webhook relay error: {0}")]
+    Relay(String),
+}
+
+impl Error {
+    pub fn category(&self) -> Category {
+        match self {
+            Self::GitHub(e) => Category::of_github(e),
+            Self::Io(_) | Self::Relay(_) => Category::Transient,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -1,56 +1,208 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use chrono::{TimeDelta, Utc};
-use log::{debug, error, info};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, error, info, warn};
+use octocrab::models::workflows::{Run, Status};
 use octocrab::models::RunId;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
 
+use super::{approval, attribution, quarantine, reservation, wait_notice, Checkpoint, Result};
 use crate::auth::Auth;
-use crate::config::{Config, Repository};
+use crate::config::{Config, InactiveRepositories, Repository};
+use crate::error::Category;
 use crate::jobs::Manager as JobManager;
 use crate::machines::OwnerAndRepo;
+use crate::metrics::Metrics;
 
 /// The cut-off point when fetching the initial run list.
 /// Once a run is encountered that is older than this the search will stop.
 const MAX_NEW_RUN_AGE: TimeDelta = TimeDelta::days(7);
 
+/// How far before the last processed event the catch-up poll after a
+/// restart starts looking for runs.
+/// This covers events that were in flight when we went down as well as
+/// some clock skew between us and GitHub.
+const CATCH_UP_MARGIN: TimeDelta = TimeDelta::minutes(10);
+
+/// The states of workflow runs that may have jobs waiting for a runner
+const ACTIVE_RUN_STATUSES: &[&str] = &["queued", "in_progress"];
+
+/// The fraction of the API rate limit to keep in reserve.
+/// Polling is postponed until the rate limit is reset once less than this
+/// fraction of requests is remaining.
+const RATE_LIMIT_RESERVE: f64 = 0.2;
+
+/// Retry a poll that failed due to e.g. a network problem after this time,
+/// instead of waiting for the whole polling interval.
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Check if archived or disabled repositories were restored after this time.
+/// Active repositories are checked as often, as they may be archived at any time.
+const REPOSITORY_STATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize)]
+struct RepositoryInfo {
+    archived: bool,
+    disabled: bool,
+}
+
+/// A page of the workflow runs of a repository
+#[derive(Deserialize)]
+struct RunsPage {
+    workflow_runs: Vec<Run>,
+}
+
+/// A page of the jobs of a workflow run
+///
+/// The jobs are parsed one by one, see `approval::parse_job`.
+#[derive(Deserialize)]
+struct JobsPage {
+    jobs: Vec<serde_json::Value>,
+}
+
+/// Whether a repository was archived or disabled, as of when it was last checked
+struct RepositoryState {
+    inactive: Option<&'static str>,
+    checked: Instant,
+}
+
 pub struct Poller {
     auth: Arc<Auth>,
     config: Config,
     job_manager: JobManager,
+    checkpoint: Checkpoint,
+    metrics: Metrics,
     most_recent_run_id: Arc<Mutex<HashMap<OwnerAndRepo, RunId>>>,
+    repository_states: Arc<Mutex<HashMap<OwnerAndRepo, RepositoryState>>>,
 }
 
 impl Poller {
-    pub fn new(config: Config, auth: Arc<Auth>, job_manager: JobManager) -> Self {
+    pub fn new(
+        config: Config,
+        auth: Arc<Auth>,
+        job_manager: JobManager,
+        checkpoint: Checkpoint,
+        metrics: Metrics,
+    ) -> Self {
         let most_recent_run_id = Arc::new(Mutex::new(HashMap::new()));
+        let repository_states = Arc::new(Mutex::new(HashMap::new()));
 
         Self {
             auth,
             config,
             job_manager,
+            checkpoint,
+            metrics,
             most_recent_run_id,
+            repository_states,
         }
     }
 
+    /// Is the repository `oar` archived or disabled?
+    ///
+    /// The state is only fetched from the API every `REPOSITORY_STATE_INTERVAL`.
+    /// Repositories whose state can not be fetched are treated as active,
+    /// so that their jobs are still picked up.
+    async fn is_inactive(&self, oar: &OwnerAndRepo) -> bool {
+        let cached = self
+            .repository_states
+            .lock()
+            .unwrap()
+            .get(oar)
+            .filter(|state| state.checked.elapsed() < REPOSITORY_STATE_INTERVAL)
+            .map(|state| state.inactive.is_some());
+
+        if let Some(inactive) = cached {
+            return inactive;
+        }
+
+        let info: octocrab::Result<RepositoryInfo> = {
+            let octocrab = self.auth.user(oar.owner()).unwrap();
+            let _permit = self.auth.api_permit(oar.owner()).await;
+
+            let route = format!("/repos/{}/{}", oar.owner(), oar.repository());
+
+            octocrab.get(route, None::<&()>).await
+        };
+
+        let inactive = match info {
+            Ok(info) if info.archived => Some("archived"),
+            Ok(info) if info.disabled => Some("disabled"),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Failed to check if {oar} is archived or disabled: {e}");
+                None
+            }
+        };
+
+        let state = RepositoryState {
+            inactive,
+            checked: Instant::now(),
+        };
+
+        let was_inactive = self
+            .repository_states
+            .lock()
+            .unwrap()
+            .insert(oar.clone(), state)
+            .is_some_and(|previous| previous.inactive.is_some());
+
+        match (inactive, was_inactive) {
+            (Some(reason), false) => warn!(
+                "Repository {oar} is {reason}. Not polling it and not starting machines for it until it is restored. Consider removing it from the config"
+            ),
+            (None, true) => info!("Repository {oar} is active again"),
+            _ => {}
+        }
+
+        self.job_manager.set_inactive(oar, inactive.is_some());
+
+        inactive.is_some()
+    }
+
+    /// Get a page of the workflow runs of `oar` matching the `query`
+    async fn runs_page(
+        &self,
+        oar: &OwnerAndRepo,
+        query: (&str, &str),
+        page: u32,
+    ) -> octocrab::Result<Vec<Run>> {
+        let octocrab = self.auth.user(oar.owner()).unwrap();
+
+        let route = format!("/repos/{}/{}/actions/runs", oar.owner(), oar.repository());
+
+        let page = page.to_string();
+        let params = [query, ("page", page.as_str())];
+
+        let _permit = self.auth.api_permit(oar.owner()).await;
+        let runs: RunsPage = octocrab.get(&route, Some(&params)).await?;
+
+        Ok(runs.workflow_runs)
+    }
+
     async fn get_new_workflow_runs(
         &self,
         oar: &OwnerAndRepo,
+        since: DateTime<Utc>,
         runs: &mut HashSet<RunId>,
     ) -> octocrab::Result<()> {
-        let octocrab = self.auth.user(oar.owner()).unwrap();
-        let workflows = octocrab.workflows(oar.owner(), oar.repository());
+        // Let GitHub skip the runs that are too old to be of interest,
+        // instead of paging through them ourselves.
+        let created = format!(">={}", since.format("%Y-%m-%dT%H:%M:%SZ"));
 
         let mut prev_run_id = None;
 
-        for page in 1u32.. {
-            let workflow_runs = workflows.list_all_runs().page(page).send().await?;
+        'created: for page in 1u32.. {
+            let workflow_runs = self.runs_page(oar, ("created", &created), page).await?;
 
             if page == 0 {
                 // The first run on the first page is the newest one.
                 // Save its id for later run so we know where to stop looking
                 // for new runs.
-                if let Some(newest_run) = workflow_runs.items.first() {
+                if let Some(newest_run) = workflow_runs.first() {
                     prev_run_id = self
                         .most_recent_run_id
                         .lock()
@@ -59,50 +211,102 @@ impl Poller {
                 }
             }
 
-            if workflow_runs.items.is_empty() {
+            if workflow_runs.is_empty() {
                 // We have reached an empty page. Time to stop.
                 break;
             }
 
-            for run in workflow_runs.items {
+            for run in workflow_runs {
                 if prev_run_id.map(|p| p == run.id).unwrap_or(false) {
                     // We have seen this run_id in a previous round of polling.
                     // This means we can stop here.
-                    return Ok(());
+                    break 'created;
                 }
 
-                let age = Utc::now() - run.created_at;
-
-                if age > MAX_NEW_RUN_AGE {
-                    // Runs older than a few days are likely not relevant to us anymore.
-                    return Ok(());
+                if run.created_at < since {
+                    // Runs older than that are not relevant to us anymore.
+                    break 'created;
                 }
 
                 runs.insert(run.id);
             }
         }
 
+        // Runs created before `since` may have been updated since,
+        // e.g. because some of their jobs were re-run.
+        // GitHub can not filter runs by their update time, but these runs are
+        // active again, which it can filter by.
+        for status in ACTIVE_RUN_STATUSES {
+            for page in 1u32.. {
+                let workflow_runs = self.runs_page(oar, ("status", status), page).await?;
+
+                if workflow_runs.is_empty() {
+                    break;
+                }
+
+                runs.extend(
+                    workflow_runs
+                        .into_iter()
+                        .filter(|run| run.updated_at >= since)
+                        .map(|run| run.id),
+                );
+            }
+        }
+
         Ok(())
     }
 
     async fn poll_run(&self, oar: &OwnerAndRepo, run_id: RunId) -> octocrab::Result<()> {
         let octocrab = self.auth.user(oar.owner()).unwrap();
-        let workflows = octocrab.workflows(oar.owner(), oar.repository());
+
+        let route = format!(
+            "/repos/{}/{}/actions/runs/{run_id}/jobs",
+            oar.owner(),
+            oar.repository()
+        );
 
         for page in 1u32.. {
-            let jobs = workflows.list_jobs(run_id).page(page).send().await?;
+            let jobs: JobsPage = {
+                let _permit = self.auth.api_permit(oar.owner()).await;
 
-            if jobs.items.is_empty() {
+                octocrab.get(&route, Some(&[("page", page)])).await?
+            };
+
+            if jobs.jobs.is_empty() {
                 // We have reached an empty page. Time to stop.
                 break;
             }
 
-            for job in jobs.items {
+            for job in jobs.jobs {
+                // Like for webhook events, so that a job is tracked under the
+                // same triplet no matter how we learned about it.
+                let oar = &attribution::calling_repository(oar.clone(), &job);
+
+                let conclusion = job
+                    .get("conclusion")
+                    .and_then(serde_json::Value::as_str)
+                    .map(str::to_owned);
+
+                let job = match approval::parse_job(&self.config.get(), oar, job) {
+                    Ok(job) => job,
+                    Err(err) => {
+                        error!("Could not parse job of run {run_id} of {oar}: {err}");
+                        continue;
+                    }
+                };
+
                 let triplet = match oar.clone().into_triplet_via_labels(&job.labels) {
                     Some(triplet) => triplet,
                     None => continue,
                 };
 
+                if matches!(job.status, Status::Queued | Status::Pending) {
+                    let cfg = self.config.get();
+
+                    quarantine::check_run(&cfg, &self.auth, &self.job_manager, oar, run_id).await;
+                    reservation::check_run(&cfg, &self.auth, &self.job_manager, oar, run_id).await;
+                }
+
                 // Update the job state in the job manager or create the job there
                 // in the first place.
                 // The job manager will then forward the demand for machines to the
@@ -111,9 +315,16 @@ impl Poller {
                     &triplet,
                     job.id,
                     run_id,
-                    job.status,
+                    None,
+                    job.created_at,
+                    job.status.clone(),
+                    conclusion.as_deref(),
                     job.runner_name.as_deref(),
                 );
+
+                let cfg = self.config.get();
+
+                wait_notice::notice(&cfg, &self.auth, &self.job_manager, oar, &triplet, &job).await;
             }
         }
 
@@ -123,10 +334,11 @@ impl Poller {
     async fn poll_repository(
         &self,
         oar: &OwnerAndRepo,
+        since: DateTime<Utc>,
         mut run_ids: HashSet<RunId>,
     ) -> octocrab::Result<()> {
         // Add new runs that we do not know yet to the list of runs to poll.
-        self.get_new_workflow_runs(oar, &mut run_ids).await?;
+        self.get_new_workflow_runs(oar, since, &mut run_ids).await?;
 
         for run_id in run_ids {
             self.poll_run(oar, run_id).await?;
@@ -139,20 +351,39 @@ impl Poller {
         &self,
         user: &str,
         repos: &HashMap<String, Repository>,
+        since: DateTime<Utc>,
         runs_of_interest: &mut HashMap<OwnerAndRepo, HashSet<RunId>>,
-    ) {
+    ) -> bool {
+        let mut complete = true;
+
         for repo_name in repos.keys() {
             let oar = OwnerAndRepo::new(user, repo_name);
             let run_ids = runs_of_interest.remove(&oar).unwrap_or_default();
 
+            let inactive = match self.config.get().github.inactive_repositories {
+                InactiveRepositories::Keep => {
+                    self.job_manager.set_inactive(&oar, false);
+                    false
+                }
+                InactiveRepositories::Drop => self.is_inactive(&oar).await,
+            };
+
+            if inactive {
+                debug!("Not polling {oar}, it is archived or disabled");
+                continue;
+            }
+
             debug!("Polling for repository {oar}");
 
-            let res = self.poll_repository(&oar, run_ids).await;
+            let res = self.poll_repository(&oar, since, run_ids).await;
 
             if let Err(e) = res {
                 error!("Failed to poll {oar} for queued jobs: {e}");
+                complete = false;
             }
         }
+
+        complete
     }
 
     /// Poll the list of runs and jobs for each registered repository
@@ -160,8 +391,37 @@ impl Poller {
     /// How far back to go in the run history is decided by `MAX_NEW_RUN_AGE`,
     /// the most recent run id already known for the repository and the list
     /// of runs the `create::jobs::Manager` is interested in.
-    pub async fn poll_once(&self) -> octocrab::Result<()> {
+    pub async fn poll_once(&self) -> Result<()> {
+        self.poll_since(Utc::now() - MAX_NEW_RUN_AGE).await
+    }
+
+    /// Poll only for runs created or updated since the last event we processed
+    ///
+    /// This is used at startup to pick up what happened while we were down,
+    /// without going through the whole `MAX_NEW_RUN_AGE` of run history.
+    /// Falls back to a regular poll if there is no (recent) checkpoint.
+    pub async fn catch_up(&self) -> Result<()> {
+        let oldest = Utc::now() - MAX_NEW_RUN_AGE;
+
+        let since = self
+            .checkpoint
+            .last_event()
+            .map(|le| le - CATCH_UP_MARGIN)
+            .filter(|since| *since > oldest);
+
+        match since {
+            Some(since) => {
+                info!("Catching up on runs updated since {since}");
+                self.poll_since(since).await
+            }
+            None => self.poll_once().await,
+        }
+    }
+
+    async fn poll_since(&self, since: DateTime<Utc>) -> Result<()> {
         let cfg = self.config.get();
+        let started = Utc::now();
+        let mut complete = true;
 
         // These are runs for which we have jobs in "interesting" states,
         // like "pending", "queued" or "in_progress".
@@ -198,7 +458,9 @@ impl Poller {
                     // Poll all repositories of registered for this user.
                     // The list of repositories always comes from the config file
                     // and not the API.
-                    self.poll_user(user, repos, &mut runs_of_interest).await;
+                    complete &= self
+                        .poll_user(user, repos, since, &mut runs_of_interest)
+                        .await;
                 } else {
                     // If the runner application is listed as public then basically
                     // anyone can install it.
@@ -208,21 +470,109 @@ impl Poller {
             }
         }
 
+        // We have seen the state of all runs as of when we started polling,
+        // unless some repositories could not be polled.
+        if complete {
+            self.checkpoint.advance(started);
+        }
+
         Ok(())
     }
 
+    /// Check if we are about to exhaust the API rate limit for any of our users
+    ///
+    /// Returns the time to wait until the rate limit is reset if less than
+    /// `RATE_LIMIT_RESERVE` of the requests are remaining.
+    /// This leaves some headroom for e.g. runner registrations, which are more
+    /// important than polling for updates.
+    async fn rate_limit_delay(&self) -> Option<Duration> {
+        let cfg = self.config.get();
+        let mut delay = None;
+
+        for user in cfg.repositories.keys() {
+            let octocrab = match self.auth.user(user) {
+                Some(oc) => oc,
+                None => continue,
+            };
+
+            let rate = match octocrab.ratelimit().get().await {
+                Ok(rl) => rl.resources.core,
+                Err(e) => {
+                    error!("Failed to get API rate limit for {user}: {e}");
+                    continue;
+                }
+            };
+
+            debug!(
+                "API rate limit for {user}: {} of {} requests remaining",
+                rate.remaining, rate.limit
+            );
+
+            if (rate.remaining as f64) < (rate.limit as f64) * RATE_LIMIT_RESERVE {
+                let reset = UNIX_EPOCH + Duration::from_secs(rate.reset);
+                let until_reset = reset.duration_since(SystemTime::now()).unwrap_or_default();
+
+                warn!(
+                    "Only {} of {} API requests remaining for {user}. Delaying next poll by {}s",
+                    rate.remaining,
+                    rate.limit,
+                    until_reset.as_secs()
+                );
+
+                delay = delay.max(Some(until_reset));
+            }
+        }
+
+        delay
+    }
+
     /// Periodically poll the runs and jobs for each registered repository.
     ///
-    /// The polling period is determined by the config file.
+    /// The polling period is determined by the config file,
+    /// but may be extended if we are about to exceed the API rate limit
+    /// or shortened to retry after transient errors.
+    /// The first poll happens one period in, since we have just caught up
+    /// at startup.
     pub async fn poll(&self) -> std::io::Result<()> {
+        let mut last_error = None;
+
         loop {
-            debug!("Poll for pending jobs");
+            let mut delay = {
+                // Add some jitter to the polling interval,
+                // so we do not poll in lockstep with other periodic tasks.
+                let interval = self.config.get().github.polling_interval();
+                let jitter = thread_rng().gen_range(0.9..1.1);
+
+                interval.mul_f64(jitter)
+            };
+
+            if last_error == Some(Category::Transient) {
+                delay = delay.min(TRANSIENT_RETRY_DELAY);
+            }
 
-            if let Err(e) = self.poll_once().await {
-                error!("Failed to poll for installations: {e}");
+            if let Some(rate_limit_delay) = self.rate_limit_delay().await {
+                delay = delay.max(rate_limit_delay);
             }
 
-            tokio::time::sleep(self.config.get().github.polling_interval).await;
+            tokio::time::sleep(delay).await;
+
+            debug!("Poll for pending jobs");
+
+            last_error = match self.poll_once().await {
+                Ok(()) => {
+                    self.auth.health().success();
+                    None
+                }
+                Err(e) => {
+                    let category = e.category();
+
+                    error!("Failed to poll for installations ({category}): {e}");
+                    self.metrics.count_error("poll", category);
+                    self.auth.health().failure(category, &e);
+
+                    Some(category)
+                }
+            };
         }
     }
 }
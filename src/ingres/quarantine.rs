@@ -0,0 +1,224 @@
+use log::error;
+use octocrab::models::RunId;
+use serde::Deserialize;
+
+use crate::auth::Auth;
+use crate::config::{ConfigFile, QuarantineRules};
+use crate::jobs::Manager as JobManager;
+use crate::machines::OwnerAndRepo;
+
+// Changes to files below these paths change what runs in the machine
+// outside of the regular build, e.g. by adding steps to a workflow.
+const WORKFLOW_PATHS: &[&str] = &[".github/workflows/", ".github/actions/"];
+
+// Users with these associations have not contributed to the repository before.
+const FIRST_TIME_ASSOCIATIONS: &[&str] = &["FIRST_TIMER", "FIRST_TIME_CONTRIBUTOR", "NONE"];
+
+// GitHub lists at most 3000 files of a pull request.
+const FILES_PER_PAGE: u32 = 100;
+const MAX_FILE_PAGES: u32 = 30;
+
+#[derive(Deserialize)]
+struct Owner {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    full_name: String,
+    owner: Owner,
+}
+
+#[derive(Deserialize)]
+struct PullRequestRef {
+    number: u64,
+}
+
+#[derive(Deserialize)]
+struct Run {
+    event: String,
+    head_branch: Option<String>,
+    head_repository: Option<Repository>,
+    repository: Repository,
+    pull_requests: Vec<PullRequestRef>,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    number: u64,
+    author_association: String,
+}
+
+#[derive(Deserialize)]
+struct PullRequestFile {
+    filename: String,
+}
+
+/// Find the open pull request the workflow run was triggered for
+///
+/// GitHub only lists the pull requests of runs for branches in the same
+/// repository, the ones from forks have to be looked up via their head.
+async fn pull_request(
+    octocrab: &octocrab::Octocrab,
+    oar: &OwnerAndRepo,
+    run: &Run,
+) -> octocrab::Result<Option<PullRequest>> {
+    let owner = oar.owner();
+    let repository = oar.repository();
+
+    if let Some(pr) = run.pull_requests.first() {
+        let route = format!("/repos/{owner}/{repository}/pulls/{}", pr.number);
+
+        return octocrab.get(route, None::<&()>).await.map(Some);
+    }
+
+    let (head_repository, head_branch) = match (&run.head_repository, &run.head_branch) {
+        (Some(head_repository), Some(head_branch)) => (head_repository, head_branch),
+        _ => return Ok(None),
+    };
+
+    let route = format!("/repos/{owner}/{repository}/pulls");
+    let head = format!("{}:{head_branch}", head_repository.owner.login);
+    let parameters = [("state", "open"), ("head", head.as_str())];
+
+    let prs: Vec<PullRequest> = octocrab.get(route, Some(&parameters)).await?;
+
+    Ok(prs.into_iter().next())
+}
+
+/// Does the pull request change any workflows or local actions?
+async fn changes_workflows(
+    octocrab: &octocrab::Octocrab,
+    oar: &OwnerAndRepo,
+    number: u64,
+) -> octocrab::Result<bool> {
+    let route = format!(
+        "/repos/{}/{}/pulls/{number}/files",
+        oar.owner(),
+        oar.repository()
+    );
+
+    for page in 1..=MAX_FILE_PAGES {
+        let parameters = [("per_page", FILES_PER_PAGE), ("page", page)];
+
+        let files: Vec<PullRequestFile> = octocrab.get(&route, Some(&parameters)).await?;
+
+        let changes_workflows = files
+            .iter()
+            .any(|file| WORKFLOW_PATHS.iter().any(|p| file.filename.starts_with(p)));
+
+        if changes_workflows {
+            return Ok(true);
+        }
+
+        if files.len() < FILES_PER_PAGE as usize {
+            break;
+        }
+    }
+
+    Ok(false)
+}
+
+/// Check the workflow run `run_id` against the quarantine `rules`
+///
+/// Returns why the jobs of the run should be quarantined, if they should.
+async fn classify(
+    auth: &Auth,
+    rules: &QuarantineRules,
+    oar: &OwnerAndRepo,
+    run_id: RunId,
+) -> octocrab::Result<Option<String>> {
+    let octocrab = auth.user(oar.owner()).unwrap();
+    let _permit = auth.api_permit(oar.owner()).await;
+
+    let route = format!(
+        "/repos/{}/{}/actions/runs/{run_id}",
+        oar.owner(),
+        oar.repository()
+    );
+
+    let run: Run = octocrab.get(route, None::<&()>).await?;
+
+    // Only pull requests bring in code from users without write access.
+    if run.event != "pull_request" && run.event != "pull_request_target" {
+        return Ok(None);
+    }
+
+    let from_fork = run
+        .head_repository
+        .as_ref()
+        .is_some_and(|head| head.full_name != run.repository.full_name);
+
+    if rules.fork_pull_requests && from_fork {
+        return Ok(Some("pull request from a fork".to_owned()));
+    }
+
+    if !rules.need_pull_request() {
+        return Ok(None);
+    }
+
+    let pr = match pull_request(&octocrab, oar, &run).await? {
+        Some(pr) => pr,
+        None => {
+            return Ok(Some(
+                "could not find the pull request of the run".to_owned(),
+            ))
+        }
+    };
+
+    if rules.first_time_contributors
+        && FIRST_TIME_ASSOCIATIONS.contains(&pr.author_association.as_str())
+    {
+        return Ok(Some(format!(
+            "pull request #{} by a first-time contributor",
+            pr.number
+        )));
+    }
+
+    if rules.workflow_changes && changes_workflows(&octocrab, oar, pr.number).await? {
+        return Ok(Some(format!(
+            "pull request #{} changes workflows",
+            pr.number
+        )));
+    }
+
+    Ok(None)
+}
+
+/// Make sure the job manager knows if the jobs of `run_id` are quarantined
+///
+/// This has to be called before the jobs of the run are reported to the
+/// job manager, or while they are held back via `JobManager::checking_run()`.
+/// Runs that can not be checked, e.g. due to API errors, are quarantined.
+pub(super) async fn check_run(
+    cfg: &ConfigFile,
+    auth: &Auth,
+    job_manager: &JobManager,
+    oar: &OwnerAndRepo,
+    run_id: RunId,
+) {
+    let rules = cfg
+        .repositories
+        .get(oar.owner())
+        .and_then(|repos| repos.get(oar.repository()))
+        .and_then(|repo| repo.quarantine);
+
+    let rules = match rules {
+        Some(rules) => rules,
+        None => return,
+    };
+
+    if !job_manager.needs_quarantine_verdict(run_id) {
+        return;
+    }
+
+    let reason = match classify(auth, &rules, oar, run_id).await {
+        Ok(reason) => reason,
+        Err(err) => {
+            error!("Failed to check run {run_id} of {oar} against the quarantine rules: {err}");
+            Some("failed to check the quarantine rules".to_owned())
+        }
+    };
+
+    job_manager.quarantine_verdict(run_id, reason);
+}
@@ -0,0 +1,235 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http::header::{HeaderMap, HeaderValue, ACCEPT};
+use http_body_util::BodyExt;
+use log::{debug, error, info, warn};
+use octocrab::Octocrab;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use super::webhook::{verify_and_parse, workflow_job_handler};
+use super::{Checkpoint, Error, Result};
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::jobs::Manager as JobManager;
+use crate::metrics::Metrics;
+
+// Wait a bit before re-connecting to the relay after the connection was lost,
+// so we do not hammer it while it is down.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// Limit the amount of data we buffer while waiting for the end of an event.
+// This matches the request size limit of the webhook listener.
+const EVENT_SIZE_LIMIT: usize = 4 * 1024 * 1024;
+
+/// A webhook event as forwarded by a smee.io compatible relay
+///
+/// The relay forwards the request headers (in lower case) as fields of
+/// a JSON object, next to the request body.
+/// The signature can only be checked against the body exactly as GitHub
+/// sent it, so we need the base64 encoded raw body (`bodyB`, as sent
+/// e.g. by gosmee) and not the parsed `body`.
+#[derive(Deserialize)]
+struct RelayedEvent<'a> {
+    #[serde(rename = "x-github-event")]
+    event_type: Option<String>,
+    #[serde(rename = "x-hub-signature-256")]
+    signature: Option<String>,
+    #[serde(rename = "bodyB")]
+    raw_body: Option<String>,
+    #[serde(borrow)]
+    body: Option<&'a RawValue>,
+}
+
+pub struct Relay {
+    config: Config,
+    auth: Arc<Auth>,
+    job_manager: JobManager,
+    metrics: Metrics,
+    checkpoint: Checkpoint,
+}
+
+impl Relay {
+    pub fn new(
+        config: Config,
+        auth: Arc<Auth>,
+        job_manager: JobManager,
+        metrics: Metrics,
+        checkpoint: Checkpoint,
+    ) -> Self {
+        Self {
+            config,
+            auth,
+            job_manager,
+            metrics,
+            checkpoint,
+        }
+    }
+
+    /// Handle a single server sent event from the relay
+    async fn handle_event(&self, data: &str) {
+        let event: RelayedEvent = match serde_json::from_str(data) {
+            Ok(ev) => ev,
+            Err(e) => {
+                warn!("Got malformed event from webhook relay: {e}");
+                return;
+            }
+        };
+
+        let (event_type, signature, raw_body) =
+            match (event.event_type, event.signature, event.raw_body) {
+                (Some(et), Some(sig), Some(raw_body)) => (et, sig, raw_body),
+                (Some(_), Some(_), None) if event.body.is_some() => {
+                    warn!("Got webhook without raw body from relay, which can not be verified");
+                    return;
+                }
+                // The relay also sends e.g. "ready" and "ping" events
+                // that do not contain a webhook.
+                _ => return,
+            };
+
+        let signature = match signature.strip_prefix("sha256=").map(hex::decode) {
+            Some(Ok(sig)) => sig,
+            _ => {
+                warn!("Got webhook with malformed signature from relay");
+                return;
+            }
+        };
+
+        let cfg = self.config.get();
+
+        let secret = cfg
+            .github
+            .webhook_secret
+            .as_deref()
+            .unwrap_or_default()
+            .as_bytes();
+
+        let content = match STANDARD.decode(raw_body) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("Got webhook with malformed raw body from relay: {e}");
+                return;
+            }
+        };
+
+        match verify_and_parse(
+            secret,
+            &event_type,
+            &signature,
+            &content,
+            &cfg,
+            &self.metrics,
+        ) {
+            Ok(None) => {}
+            Ok(Some(event)) => {
+                let job_manager = self.job_manager.clone();
+
+                workflow_job_handler(
+                    event,
+                    &cfg,
+                    &self.auth,
+                    job_manager,
+                    &self.metrics,
+                    &self.checkpoint,
+                )
+                .await
+            }
+            Err(e) => error!("Got malformed webhook from relay: {e}"),
+        }
+    }
+
+    /// Connect to the relay and handle events until the connection is closed
+    async fn listen(&self, url: &str) -> Result<()> {
+        // An unauthenticated client, so we do not leak our credentials
+        // to the relay.
+        let client = Octocrab::builder().build()?;
+
+        let headers = {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static("text/event-stream"));
+            headers
+        };
+
+        let response = client._get_with_headers(url, Some(headers)).await?;
+
+        if !response.status().is_success() {
+            return Err(Error::Relay(format!(
+                "Relay responded with status {}",
+                response.status()
+            )));
+        }
+
+        info!("Connected to webhook relay {url}");
+
+        let mut body = response.into_body();
+        let mut buf: Vec<u8> = Vec::new();
+        let mut data = String::new();
+
+        while let Some(frame) = body.frame().await {
+            if let Ok(chunk) = frame?.into_data() {
+                buf.extend_from_slice(&chunk);
+            }
+
+            // Server sent events are line based.
+            // Process all complete lines we have received so far.
+            while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buf.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\r', '\n']);
+
+                if line.is_empty() {
+                    // An empty line marks the end of an event.
+                    if !data.is_empty() {
+                        self.handle_event(&data).await;
+                        data.clear();
+                    }
+                } else if let Some(d) = line.strip_prefix("data:") {
+                    if !data.is_empty() {
+                        data.push('\n');
+                    }
+
+                    data.push_str(d.trim_start());
+                }
+            }
+
+            if buf.len() + data.len() > EVENT_SIZE_LIMIT {
+                return Err(Error::Relay(
+                    "Event from relay exceeds the size limit".to_owned(),
+                ));
+            }
+        }
+
+        debug!("Webhook relay closed the connection");
+
+        Ok(())
+    }
+
+    /// Receive webhook events via a smee.io compatible relay
+    ///
+    /// This allows receiving webhooks in (almost) realtime on hosts
+    /// that can not be reached from the outside.
+    pub async fn run(&self) -> std::io::Result<()> {
+        loop {
+            let url = self.config.get().github.webhook_relay.clone();
+
+            match url {
+                Some(url) => {
+                    if let Err(e) = self.listen(&url).await {
+                        error!(
+                            "Lost connection to webhook relay {url} ({}): {e}",
+                            e.category()
+                        );
+                        self.metrics.count_error("relay", e.category());
+                    }
+                }
+                None => debug!("No webhook relay configured"),
+            }
+
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use log::warn;
+use octocrab::models::RunId;
+use serde::Deserialize;
+
+use crate::auth::Auth;
+use crate::config::{ConfigFile, Reservation};
+use crate::jobs::Manager as JobManager;
+use crate::machines::OwnerAndRepo;
+
+#[derive(Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+#[derive(Deserialize)]
+struct Run {
+    event: String,
+    head_branch: Option<String>,
+    head_repository: Option<Repository>,
+    repository: Repository,
+}
+
+/// Was the workflow run `run_id` triggered for one of the protected branches?
+///
+/// Pull requests are never protected, even if their head branch has a
+/// protected name, because it may just be the name of a branch in a fork.
+async fn is_protected(
+    auth: &Auth,
+    reservation: &Reservation,
+    oar: &OwnerAndRepo,
+    run_id: RunId,
+) -> octocrab::Result<bool> {
+    let octocrab = auth.user(oar.owner()).unwrap();
+    let _permit = auth.api_permit(oar.owner()).await;
+
+    let route = format!(
+        "/repos/{}/{}/actions/runs/{run_id}",
+        oar.owner(),
+        oar.repository()
+    );
+
+    let run: Run = octocrab.get(route, None::<&()>).await?;
+
+    if run.event == "pull_request" || run.event == "pull_request_target" {
+        return Ok(false);
+    }
+
+    let same_repository = run
+        .head_repository
+        .is_some_and(|head| head.full_name == run.repository.full_name);
+
+    let protected = run
+        .head_branch
+        .is_some_and(|branch| reservation.protects(&branch));
+
+    Ok(same_repository && protected)
+}
+
+/// Make sure the job manager knows if the jobs of `run_id` may use reserved slots
+///
+/// This has to be called before the jobs of the run are reported to the
+/// job manager, or while they are held back via `JobManager::checking_run()`.
+/// Runs that can not be checked, e.g. due to API errors, do not get to use
+/// the reserved slots.
+pub(super) async fn check_run(
+    cfg: &ConfigFile,
+    auth: &Auth,
+    job_manager: &JobManager,
+    oar: &OwnerAndRepo,
+    run_id: RunId,
+) {
+    let reservation = cfg
+        .repositories
+        .get(oar.owner())
+        .and_then(|repos| repos.get(oar.repository()))
+        .and_then(|repo| repo.reserved.as_ref());
+
+    let reservation = match reservation {
+        Some(reservation) => reservation,
+        None => return,
+    };
+
+    if !job_manager.needs_reservation_verdict(run_id) {
+        return;
+    }
+
+    let protected = match is_protected(auth, reservation, oar, run_id).await {
+        Ok(protected) => protected,
+        Err(err) => {
+            warn!("Failed to check if run {run_id} of {oar} is for a protected branch: {err}");
+            false
+        }
+    };
+
+    job_manager.reservation_verdict(run_id, protected);
+}
@@ -0,0 +1,114 @@
+use log::{info, warn};
+use octocrab::models::workflows::{Job, Status};
+use serde_json::json;
+
+use crate::auth::Auth;
+use crate::config::ConfigFile;
+use crate::jobs::Manager as JobManager;
+use crate::machines::{OwnerAndRepo, Triplet};
+
+/// The text of the check run, with a title fit for the list of checks
+fn describe(job: &Job, triplet: &Triplet, postponed_by: Option<&str>) -> (String, String) {
+    let minutes = (job.started_at - job.created_at).num_minutes();
+    let title = format!("Waited {minutes} minutes for a machine");
+
+    let runner = match &job.runner_name {
+        Some(runner_name) => format!("`{runner_name}`"),
+        None => "an unknown runner".to_owned(),
+    };
+
+    let mut summary = format!(
+        "The job `{}` was queued for {minutes} minutes before it was picked up by {runner}, a `{}` machine.\n\n",
+        job.name,
+        triplet.machine_name()
+    );
+
+    match postponed_by {
+        Some(resource) => summary.push_str(&format!(
+            "The start of the machine was held back, last due to a lack of {resource} on the host.",
+        )),
+        None => summary.push_str(
+            "The start of the machine was not held back by host resources. \
+             The time was spent e.g. booting the machine, waiting for other jobs \
+             to free up a machine, or due to budget or registration limits.",
+        ),
+    }
+
+    (title, summary)
+}
+
+/// Tell the workflow run of `job` why it waited for a machine, if it waited long
+///
+/// The notice is a neutral check run on the commit the job ran for,
+/// which shows up next to the job in the GitHub UI.
+/// This has to be called after the job was reported to the job manager.
+pub(super) async fn notice(
+    cfg: &ConfigFile,
+    auth: &Auth,
+    job_manager: &JobManager,
+    oar: &OwnerAndRepo,
+    triplet: &Triplet,
+    job: &Job,
+) {
+    let after = cfg
+        .repositories
+        .get(oar.owner())
+        .and_then(|repos| repos.get(oar.repository()))
+        .and_then(|repo| repo.wait_notice_after);
+
+    let after = match after {
+        Some(after) => after,
+        None => return,
+    };
+
+    if job.status != Status::InProgress {
+        return;
+    }
+
+    let waited = (job.started_at - job.created_at)
+        .to_std()
+        .unwrap_or_default();
+
+    if waited < after || !job_manager.needs_wait_notice(triplet, job.id) {
+        return;
+    }
+
+    let postponed_by = job
+        .runner_name
+        .as_deref()
+        .and_then(|runner_name| job_manager.postponed_by(triplet, runner_name));
+
+    info!(
+        "Job {} of {oar} waited {}s for a machine, limited by {}",
+        job.id,
+        waited.as_secs(),
+        postponed_by.as_deref().unwrap_or("nothing")
+    );
+
+    let (title, summary) = describe(job, triplet, postponed_by.as_deref());
+
+    let body = json!({
+        "name": format!("{} (queue wait)", job.name),
+        "head_sha": job.head_sha,
+        "status": "completed",
+        "conclusion": "neutral",
+        "output": {
+            "title": title,
+            "summary": summary,
+        },
+    });
+
+    let octocrab = auth.user(oar.owner()).unwrap();
+    let _permit = auth.api_permit(oar.owner()).await;
+
+    let route = format!("/repos/{}/{}/check-runs", oar.owner(), oar.repository());
+
+    let res: octocrab::Result<serde_json::Value> = octocrab.post(route, Some(&body)).await;
+
+    if let Err(err) = res {
+        warn!(
+            "Failed to tell job {} of {oar} about its wait: {err}",
+            job.id
+        );
+    }
+}
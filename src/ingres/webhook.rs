@@ -3,24 +3,31 @@ use std::os::unix::fs::PermissionsExt;
 use std::sync::Arc;
 use std::time::Duration;
 
+use chrono::Utc;
 use hmac::{Hmac, Mac};
 use log::{error, info, trace, warn};
 use octocrab::models::webhook_events::EventInstallation;
 use octocrab::models::webhook_events::{WebhookEvent, WebhookEventPayload};
-use octocrab::models::workflows::Job;
+use octocrab::models::workflows::Status;
 use sha2::Sha256;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::unix::ReadHalf;
 use tokio::net::{UnixListener, UnixStream};
 use tokio::time::timeout;
 
+use super::{approval, attribution, quarantine, reservation, wait_notice, Checkpoint};
 use crate::auth::Auth;
 use crate::config::{Config, ConfigFile};
 use crate::jobs::Manager as JobManager;
 use crate::machines::OwnerAndRepo;
+use crate::metrics::Metrics;
 
 const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
 const WEBHOOK_SIZE_LIMIT: u64 = 4 * 1024 * 1024;
+
+/// The webhook event types we know how to handle.
+/// Events of other types are ignored, even if a repository lists them.
+const HANDLED_EVENTS: &[&str] = &["workflow_job"];
 const ERROR_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r
 Server: Forrest\r
 Content-Length: 35\r
@@ -37,11 +44,19 @@ pub struct WebhookHandler {
     config: Config,
     auth: Arc<Auth>,
     job_manager: JobManager,
+    metrics: Metrics,
+    checkpoint: Checkpoint,
     listener: UnixListener,
 }
 
 impl WebhookHandler {
-    pub fn new(config: Config, auth: Arc<Auth>, job_manager: JobManager) -> std::io::Result<Self> {
+    pub fn new(
+        config: Config,
+        auth: Arc<Auth>,
+        job_manager: JobManager,
+        metrics: Metrics,
+        checkpoint: Checkpoint,
+    ) -> std::io::Result<Self> {
         let listener = {
             let cfg = config.get();
 
@@ -61,16 +76,20 @@ impl WebhookHandler {
             config,
             auth,
             job_manager,
+            metrics,
+            checkpoint,
             listener,
         })
     }
 
-    pub async fn run(&mut self) -> std::io::Result<()> {
+    pub async fn run(&self) -> std::io::Result<()> {
         loop {
             let (sock, _) = self.listener.accept().await?;
             let config = self.config.get();
             let auth = self.auth.clone();
             let job_manager = self.job_manager.clone();
+            let metrics = self.metrics.clone();
+            let checkpoint = self.checkpoint.clone();
 
             tokio::task::spawn(async move {
                 let timeout_error = Err(std::io::Error::new(
@@ -80,7 +99,7 @@ impl WebhookHandler {
 
                 let res = timeout(
                     WEBHOOK_TIMEOUT,
-                    webook_handler(sock, &config, &auth, job_manager),
+                    webook_handler(sock, &config, &auth, job_manager, &metrics, &checkpoint),
                 )
                 .await
                 .or(timeout_error);
@@ -95,20 +114,30 @@ impl WebhookHandler {
 
 async fn webook_handler(
     mut sock: UnixStream,
-    config: &ConfigFile,
-    auth: &Auth,
+    config: &Arc<ConfigFile>,
+    auth: &Arc<Auth>,
     job_manager: JobManager,
+    metrics: &Metrics,
+    checkpoint: &Checkpoint,
 ) -> std::io::Result<()> {
     let (read, mut write) = sock.split();
 
-    let secret = config.github.webhook_secret.as_bytes();
+    // The webhook secret may have been removed from the config file after
+    // the listener was set up.
+    let secret = config
+        .github
+        .webhook_secret
+        .as_deref()
+        .unwrap_or_default()
+        .as_bytes();
 
-    let response = match read_req(secret, read).await {
-        Ok(res) => {
-            workflow_job_handler(res, config, auth, job_manager).await;
+    let response = match read_req(secret, read, config, metrics).await {
+        Ok(Some(res)) => {
+            workflow_job_handler(res, config, auth, job_manager, metrics, checkpoint).await;
 
             OK_RESPONSE
         }
+        Ok(None) => OK_RESPONSE,
         Err(e) => {
             error!("Got malformed webhook request: {e}");
 
@@ -119,7 +148,19 @@ async fn webook_handler(
     write.write_all(response).await
 }
 
-async fn read_req<'a>(secret: &[u8], read: ReadHalf<'a>) -> std::io::Result<WebhookEvent> {
+async fn read_req<'a>(
+    secret: &[u8],
+    read: ReadHalf<'a>,
+    config: &ConfigFile,
+    metrics: &Metrics,
+) -> std::io::Result<Option<WebhookEvent>> {
+    if secret.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "No webhook secret configured",
+        ));
+    }
+
     // Limit the maximum request size and buffer the stream so we can read
     // individual bytes like when searching for a '\n'.
     let mut read = BufReader::new(read.take(WEBHOOK_SIZE_LIMIT));
@@ -196,46 +237,79 @@ async fn read_req<'a>(secret: &[u8], read: ReadHalf<'a>) -> std::io::Result<Webh
         Err(std::io::Error::other("Content-Length is too large"))?;
     }
 
-    let content = {
-        let mut content = vec![0; content_length];
-        read.read_exact(&mut content).await?;
+    let mut content = vec![0; content_length];
+    read.read_exact(&mut content).await?;
 
-        let mut hmac: Hmac<Sha256> = Hmac::new_from_slice(secret).unwrap();
-        hmac.update(&content);
-        let content_valid = hmac.verify_slice(&signature);
+    verify_and_parse(secret, &event_type, &signature, &content, config, metrics)
+}
 
-        if content_valid.is_err() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "HMAC signature does not match",
-            ));
-        }
+/// Is any configured repository interested in events of `event_type`?
+fn is_subscribed(config: &ConfigFile, event_type: &str) -> bool {
+    HANDLED_EVENTS.contains(&event_type)
+        && config
+            .repositories
+            .values()
+            .flat_map(|repos| repos.values())
+            .any(|repo| repo.events.iter().any(|e| e == event_type))
+}
 
-        content
-    };
+/// Check the HMAC signature of a webhook event and parse it
+///
+/// Events no repository is subscribed to are counted and dropped before
+/// parsing them, returning `None`.
+/// This is shared between the webhook listener and the webhook relay client.
+pub(super) fn verify_and_parse(
+    secret: &[u8],
+    event_type: &str,
+    signature: &[u8],
+    content: &[u8],
+    config: &ConfigFile,
+    metrics: &Metrics,
+) -> std::io::Result<Option<WebhookEvent>> {
+    let mut hmac: Hmac<Sha256> = Hmac::new_from_slice(secret).unwrap();
+    hmac.update(content);
+    let content_valid = hmac.verify_slice(signature);
+
+    if content_valid.is_err() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "HMAC signature does not match",
+        ));
+    }
 
     trace!("Got webhook event of type {event_type}");
 
-    WebhookEvent::try_from_header_and_body(&event_type, &content).map_err(|_| {
-        std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Failed to parse request body",
-        )
-    })
+    if !is_subscribed(config, event_type) {
+        metrics.count_event(event_type, false);
+        return Ok(None);
+    }
+
+    WebhookEvent::try_from_header_and_body(event_type, content)
+        .map(Some)
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Failed to parse request body",
+            )
+        })
 }
 
-async fn workflow_job_handler(
+pub(super) async fn workflow_job_handler(
     event: WebhookEvent,
-    config: &ConfigFile,
-    auth: &Auth,
+    config: &Arc<ConfigFile>,
+    auth: &Arc<Auth>,
     job_manager: JobManager,
+    metrics: &Metrics,
+    checkpoint: &Checkpoint,
 ) {
+    let received = Utc::now();
+
     let job = match event.specific {
         WebhookEventPayload::WorkflowJob(job) => job,
         _ => return,
     };
 
-    let oar = {
+    let event_oar = {
         let repository = match event.repository {
             Some(repo) => repo,
             None => {
@@ -255,14 +329,27 @@ async fn workflow_job_handler(
         OwnerAndRepo::new(owner, repository.name)
     };
 
-    let exists = config
+    // Jobs of reusable workflows count towards the repository that called them.
+    let oar = attribution::calling_repository(event_oar.clone(), &job.workflow_job);
+
+    let repo_config = config
         .repositories
         .get(oar.owner())
-        .and_then(|repos| repos.get(oar.repository()))
-        .is_some();
+        .and_then(|repos| repos.get(oar.repository()));
+
+    let subscribed = match repo_config {
+        Some(repo) => repo.events.iter().any(|e| e == "workflow_job"),
+        None => {
+            info!("Refusing to service webhook from unlisted user/repo {oar}");
+            metrics.count_event("workflow_job", false);
+            return;
+        }
+    };
 
-    if !exists {
-        info!("Refusing to service webhook from unlisted user/repo {oar}");
+    metrics.count_event("workflow_job", subscribed);
+
+    if !subscribed {
+        trace!("Ignoring workflow_job event for {oar}");
         return;
     }
 
@@ -275,7 +362,20 @@ async fn workflow_job_handler(
         }
     };
 
-    let workflow_job: Job = match serde_json::from_value(job.workflow_job) {
+    // Not part of the octocrab job model, but included in the webhook payload.
+    let run_attempt = job
+        .workflow_job
+        .get("run_attempt")
+        .and_then(serde_json::Value::as_u64)
+        .and_then(|run_attempt| u32::try_from(run_attempt).ok());
+
+    let conclusion = job
+        .workflow_job
+        .get("conclusion")
+        .and_then(serde_json::Value::as_str)
+        .map(str::to_owned);
+
+    let workflow_job = match approval::parse_job(config, &oar, job.workflow_job) {
         Ok(workflow_job) => workflow_job,
         Err(err) => {
             error!("Could not parse workflow job received from webhook: {err}");
@@ -288,20 +388,61 @@ async fn workflow_job_handler(
         workflow_job.labels.join(",")
     );
 
+    // The payload does not contain the time the event was sent,
+    // but the time of the job status change that caused it.
+    let changed = match workflow_job.status {
+        Status::Queued | Status::Pending => Some(workflow_job.created_at),
+        Status::InProgress => Some(workflow_job.started_at),
+        _ => workflow_job.completed_at,
+    };
+
+    if let Some(changed) = changed {
+        metrics.record_delay("webhook_delivery", received - changed);
+    }
+
     // Associate the user with their installation id so we can make API
     // requests on their behalf later.
     auth.update_user(oar.owner(), installation_id);
 
-    let triplet = match oar.into_triplet_via_labels(&workflow_job.labels) {
+    let triplet = match oar.clone().into_triplet_via_labels(&workflow_job.labels) {
         Some(triplet) => triplet,
         None => return,
     };
 
+    // Checking the run takes further API requests, which should neither
+    // delay recording the job nor count towards the webhook timeout.
+    // The job manager holds back the jobs of the run until the check is done,
+    // so that they are not started on a regular machine in the meantime.
+    if matches!(workflow_job.status, Status::Queued | Status::Pending) {
+        let config = config.clone();
+        let auth = auth.clone();
+        let job_manager = job_manager.clone();
+        let oar = oar.clone();
+        let run_id = workflow_job.run_id;
+
+        job_manager.checking_run(run_id);
+
+        tokio::task::spawn(async move {
+            quarantine::check_run(&config, &auth, &job_manager, &oar, run_id).await;
+            reservation::check_run(&config, &auth, &job_manager, &oar, run_id).await;
+            job_manager.checked_run(run_id);
+        });
+    }
+
     job_manager.status_feedback(
         &triplet,
         workflow_job.id,
         workflow_job.run_id,
-        workflow_job.status,
+        run_attempt,
+        workflow_job.created_at,
+        workflow_job.status.clone(),
+        conclusion.as_deref(),
         workflow_job.runner_name.as_deref(),
     );
+
+    wait_notice::notice(config, auth, &job_manager, &oar, &triplet, &workflow_job).await;
+
+    // Webhooks are delivered in (roughly) chronological order,
+    // so everything up to this event should have been seen by now.
+    checkpoint.advance(received);
 }
@@ -1,4 +1,8 @@
+mod anticipation;
+mod history;
 mod job;
 mod manager;
+mod webhooks;
 
-pub use manager::Manager;
+pub use history::path as history_path;
+pub use manager::{JobInfo, Manager};
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use octocrab::models::RunId;
+
+use crate::machines::Triplet;
+
+// Only anticipate jobs after seeing this many runs with a job of the machine
+// type that usually precedes them.
+const MIN_RUNS: u32 = 5;
+
+// Only anticipate jobs that followed in at least this share of those runs.
+const MIN_SHARE: f64 = 0.5;
+
+/// A workflow run we have seen jobs of
+struct Run {
+    /// When we first saw a job of the run
+    seen: DateTime<Utc>,
+    /// The machine types of jobs that have started, and when the first one did
+    started: HashMap<Triplet, DateTime<Utc>>,
+    /// The machine types of all jobs that were queued
+    queued: HashSet<Triplet>,
+}
+
+/// Learns which machine types the later jobs of a workflow run will need
+///
+/// Workflows often run in stages, e.g. a quick build on a small machine
+/// followed by a test job on a large one.
+/// For each machine type we count in how many runs a job of another machine
+/// type was queued after one of its jobs had started.
+/// If that happens in most runs, a run with a started job of the first type
+/// is likely to ask for a machine of the second type soon.
+///
+/// What was learned is not persisted and starts over after a restart.
+#[derive(Default)]
+pub(super) struct Anticipation {
+    runs: HashMap<RunId, Run>,
+    /// In how many runs a job of a machine type started
+    starts: HashMap<Triplet, u32>,
+    /// In how many runs a job of the second machine type was queued after
+    /// a job of the first one had started
+    followers: HashMap<(Triplet, Triplet), u32>,
+}
+
+impl Anticipation {
+    fn run(&mut self, run_id: RunId) -> &mut Run {
+        self.runs.entry(run_id).or_insert_with(|| Run {
+            seen: Utc::now(),
+            started: HashMap::new(),
+            queued: HashSet::new(),
+        })
+    }
+
+    /// Note that a job of `triplet` was queued in `run_id`
+    pub(super) fn queued(&mut self, run_id: RunId, triplet: &Triplet) {
+        let run = self.run(run_id);
+
+        if !run.queued.insert(triplet.clone()) {
+            return;
+        }
+
+        let preceding: Vec<Triplet> = run
+            .started
+            .keys()
+            .filter(|preceding| *preceding != triplet)
+            .cloned()
+            .collect();
+
+        for preceding in preceding {
+            *self
+                .followers
+                .entry((preceding, triplet.clone()))
+                .or_default() += 1;
+        }
+    }
+
+    /// Note that a job of `triplet` started in `run_id`
+    pub(super) fn started(&mut self, run_id: RunId, triplet: &Triplet) {
+        self.queued(run_id, triplet);
+
+        let run = self.run(run_id);
+
+        if run.started.contains_key(triplet) {
+            return;
+        }
+
+        run.started.insert(triplet.clone(), Utc::now());
+
+        *self.starts.entry(triplet.clone()).or_default() += 1;
+    }
+
+    /// Forget about runs we first saw longer ago than `retention`
+    pub(super) fn prune(&mut self, retention: TimeDelta) {
+        let now = Utc::now();
+
+        self.runs.retain(|_, run| now - run.seen < retention);
+    }
+
+    /// The machine types the runs in progress are likely to ask for soon
+    ///
+    /// Returns, per machine type, the time since when each run has been
+    /// expected to ask for it.
+    pub(super) fn expected(&self) -> HashMap<Triplet, Vec<DateTime<Utc>>> {
+        let mut expected: HashMap<Triplet, Vec<DateTime<Utc>>> = HashMap::new();
+
+        for run in self.runs.values() {
+            let mut since: HashMap<&Triplet, DateTime<Utc>> = HashMap::new();
+
+            for ((preceding, follower), count) in &self.followers {
+                if run.queued.contains(follower) {
+                    continue;
+                }
+
+                let started = match run.started.get(preceding) {
+                    Some(started) => started,
+                    None => continue,
+                };
+
+                let starts = self.starts.get(preceding).copied().unwrap_or(0);
+
+                if starts < MIN_RUNS || f64::from(*count) < MIN_SHARE * f64::from(starts) {
+                    continue;
+                }
+
+                let entry = since.entry(follower).or_insert(*started);
+                *entry = (*entry).min(*started);
+            }
+
+            for (follower, started) in since {
+                expected.entry(follower.clone()).or_default().push(started);
+            }
+        }
+
+        expected
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::webhooks::JobResult;
+
+// The completed jobs, as one JSON record per line.
+const HISTORY_FILE: &str = "job-history.jsonl";
+
+/// The path of the job history kept in `base_dir`
+///
+/// This is what e.g. `forrest simulate` replays.
+pub fn path(base_dir: &Path) -> PathBuf {
+    base_dir.join(HISTORY_FILE)
+}
+
+/// Append `result` to the job history kept in `base_dir`
+pub(super) fn record(base_dir: &Path, result: &JobResult) {
+    let path = path(base_dir);
+
+    let res = serde_json::to_string(result)
+        .map_err(std::io::Error::other)
+        .and_then(|line| {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+
+            writeln!(file, "{line}")
+        });
+
+    if let Err(e) = res {
+        warn!(
+            "Failed to record job {} in {}: {e}",
+            result.job_id,
+            path.display()
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
 use octocrab::models::workflows::Status;
 use octocrab::models::{JobId, RunId};
 
@@ -7,16 +10,34 @@ pub(super) struct Job {
     triplet: Triplet,
     job_id: JobId,
     run_id: RunId,
+    queued_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
     status: Status,
+    runner_name: Option<String>,
+    demand_created: bool,
+    wait_noticed: bool,
+    given_up: bool,
 }
 
 impl Job {
-    pub(super) fn new(triplet: Triplet, job_id: JobId, run_id: RunId, status: Status) -> Self {
+    pub(super) fn new(
+        triplet: Triplet,
+        job_id: JobId,
+        run_id: RunId,
+        queued_at: DateTime<Utc>,
+        status: Status,
+    ) -> Self {
         Self {
             triplet,
             job_id,
             run_id,
+            queued_at,
+            started_at: matches!(status, Status::InProgress).then(Utc::now),
             status,
+            runner_name: None,
+            demand_created: false,
+            wait_noticed: false,
+            given_up: false,
         }
     }
 
@@ -32,6 +53,53 @@ impl Job {
         self.run_id
     }
 
+    pub(super) fn queued_at(&self) -> DateTime<Utc> {
+        self.queued_at
+    }
+
+    /// When we learned that the job started running, if it did
+    pub(super) fn started_at(&self) -> Option<DateTime<Utc>> {
+        self.started_at
+    }
+
+    /// Mark that this job was counted as demand for a machine
+    ///
+    /// Returns `true` if this is the first time.
+    pub(super) fn create_demand(&mut self) -> bool {
+        !std::mem::replace(&mut self.demand_created, true)
+    }
+
+    /// Mark that the job was told about its long wait for a machine
+    ///
+    /// Returns `true` if this is the first time.
+    pub(super) fn notice_wait(&mut self) -> bool {
+        !std::mem::replace(&mut self.wait_noticed, true)
+    }
+
+    /// Mark that no more machines are started for this job
+    ///
+    /// Returns `true` if this is the first time.
+    pub(super) fn give_up(&mut self) -> bool {
+        !std::mem::replace(&mut self.given_up, true)
+    }
+
+    pub(super) fn is_given_up(&self) -> bool {
+        self.given_up
+    }
+
+    pub(super) fn status(&self) -> &Status {
+        &self.status
+    }
+
+    /// The runner that picked up the job, once it did
+    pub(super) fn runner_name(&self) -> Option<&str> {
+        self.runner_name.as_deref()
+    }
+
+    pub(super) fn set_runner_name(&mut self, runner_name: &str) {
+        self.runner_name = Some(runner_name.to_owned());
+    }
+
     pub(super) fn is_queued(&self) -> bool {
         matches!(self.status, Status::Queued)
     }
@@ -44,8 +112,17 @@ impl Job {
         }
     }
 
+    /// Update the status of the job, if `status` is more recent
+    ///
+    /// Jobs only ever move on from pending to queued, in progress and
+    /// completed, so an update to an earlier status must stem from an
+    /// event that was processed out of order and is ignored.
     pub(super) fn update_status(&mut self, status: Status) -> bool {
-        if self.status != status {
+        if stage(&status) > stage(&self.status) {
+            if status == Status::InProgress {
+                self.started_at = Some(Utc::now());
+            }
+
             self.status = status;
             true
         } else {
@@ -53,3 +130,103 @@ impl Job {
         }
     }
 }
+
+/// The position of `status` in the life of a job
+pub(super) fn stage(status: &Status) -> u8 {
+    match status {
+        Status::Pending => 0,
+        Status::Queued => 1,
+        Status::InProgress => 2,
+        Status::Completed | Status::Failed => 3,
+        _ => panic!("Got unexpected job status from octocrab"),
+    }
+}
+
+/// Is the event for attempt `run_attempt` of `run_id` older than ones we have seen?
+///
+/// Re-running a workflow run starts a new attempt with new jobs.
+/// Events for the jobs of earlier attempts that arrive afterwards are stale.
+/// The most recent attempt per run is kept track of in `attempts`.
+pub(super) fn is_stale_attempt(
+    attempts: &mut HashMap<RunId, u32>,
+    run_id: RunId,
+    run_attempt: Option<u32>,
+) -> bool {
+    let run_attempt = match run_attempt {
+        Some(run_attempt) => run_attempt,
+        None => return false,
+    };
+
+    let latest = attempts.entry(run_id).or_insert(run_attempt);
+
+    *latest = (*latest).max(run_attempt);
+
+    run_attempt < *latest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued_job() -> Job {
+        let triplet = Triplet::new("owner", "repository", "machine");
+
+        Job::new(triplet, JobId(1), RunId(1), Utc::now(), Status::Queued)
+    }
+
+    #[test]
+    fn completed_before_in_progress() {
+        let mut job = queued_job();
+
+        assert!(job.update_status(Status::Completed));
+        assert!(!job.update_status(Status::InProgress));
+
+        assert_eq!(job.status(), &Status::Completed);
+        assert!(!job.is_interesting());
+        assert!(job.started_at().is_none());
+    }
+
+    #[test]
+    fn late_queued_after_in_progress() {
+        let mut job = queued_job();
+
+        assert!(job.update_status(Status::InProgress));
+        let started_at = job.started_at();
+        assert!(started_at.is_some());
+
+        assert!(!job.update_status(Status::Queued));
+        assert!(!job.update_status(Status::Pending));
+
+        assert_eq!(job.status(), &Status::InProgress);
+        assert!(!job.is_queued());
+        assert_eq!(job.started_at(), started_at);
+    }
+
+    #[test]
+    fn repeated_status_is_no_change() {
+        let mut job = queued_job();
+
+        assert!(!job.update_status(Status::Queued));
+        assert!(job.update_status(Status::InProgress));
+        assert!(!job.update_status(Status::InProgress));
+    }
+
+    #[test]
+    fn stale_attempt_after_rerun() {
+        let mut attempts = HashMap::new();
+        let run_id = RunId(1);
+
+        assert!(!is_stale_attempt(&mut attempts, run_id, Some(1)));
+
+        // The run was re-run and the first event of the new attempt arrives.
+        assert!(!is_stale_attempt(&mut attempts, run_id, Some(2)));
+
+        // Late events of the first attempt must not bring its jobs back.
+        assert!(is_stale_attempt(&mut attempts, run_id, Some(1)));
+        assert!(!is_stale_attempt(&mut attempts, run_id, Some(2)));
+
+        // Other runs and events without an attempt are not affected.
+        assert!(!is_stale_attempt(&mut attempts, RunId(2), Some(1)));
+        assert!(!is_stale_attempt(&mut attempts, run_id, None));
+    }
+}
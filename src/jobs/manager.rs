@@ -2,12 +2,20 @@ use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, error, info, warn};
 use octocrab::models::workflows::Status;
 use octocrab::models::{JobId, RunId};
+use serde::Serialize;
 use tokio::task::JoinHandle;
 
-use super::job::Job;
+use super::anticipation::Anticipation;
+use super::history;
+use super::job::{self, Job};
+use super::webhooks::{self, JobResult};
+use crate::config::{BudgetPolicy, Config};
 use crate::machines::{Manager as MachineManager, OwnerAndRepo, Triplet};
+use crate::metrics::Metrics;
 
 // The `status_feedback()` method is called for each webhook event
 // and each job that comes up in a poll.
@@ -17,28 +25,183 @@ use crate::machines::{Manager as MachineManager, OwnerAndRepo, Triplet};
 // the machine manager.
 const UPDATE_SOON_DELAY: Duration = Duration::from_secs(5);
 
+// Events for jobs that have completed may still trickle in afterwards,
+// e.g. webhooks that were delivered late or a poll that raced with them.
+// Remember completed jobs this long so that these events do not bring them back.
+const COMPLETED_RETENTION: TimeDelta = TimeDelta::hours(24);
+
+// Reported to the `job_webhooks` for jobs that are given up on because their
+// machines repeatedly failed to come up (see `start_retries`).
+const START_FAILED_CONCLUSION: &str = "start_failed";
+
+/// A snapshot of the state of a job for use in e.g. the admin API
+#[derive(Serialize)]
+pub struct JobInfo {
+    pub triplet: String,
+    pub job_id: JobId,
+    pub run_id: RunId,
+    pub status: Status,
+    /// The runner the job runs on, e.g. to look up why its machine was stopped
+    pub runner_name: Option<String>,
+    pub budget_exceeded: bool,
+    /// Why the job only runs on the quarantine machine type, if it does
+    pub quarantine_reason: Option<String>,
+    /// Whether the job is of a protected branch and may use reserved slots
+    pub reserved: bool,
+    /// Whether the run of the job was boosted to go before all others
+    pub boosted: bool,
+}
+
+/// A workflow run whose jobs go before all others, for use in e.g. the admin API
+#[derive(Serialize, Clone)]
+pub struct BoostInfo {
+    pub run_id: RunId,
+    /// Who boosted the run, e.g. the name of the admin API token
+    pub by: String,
+    pub since: DateTime<Utc>,
+}
+
+/// Jobs that have completed, and when we learned about it
+type Completed = HashMap<(Triplet, JobId), DateTime<Utc>>;
+
 #[derive(Clone)]
 pub struct Manager {
+    config: Config,
     machine_manager: MachineManager,
     jobs: Arc<Mutex<Vec<Job>>>,
+    /// Which machine types the runs in progress will likely need later on
+    anticipation: Arc<Mutex<Anticipation>>,
+    /// The run attempt of the most recent event per workflow run
+    attempts: Arc<Mutex<HashMap<RunId, u32>>>,
+    boosts: Arc<Mutex<HashMap<RunId, BoostInfo>>>,
+    /// Runs that are still being checked for their verdicts, by number of checks
+    checking: Arc<Mutex<HashMap<RunId, u32>>>,
+    completed: Arc<Mutex<Completed>>,
+    held_back: Arc<Mutex<HashSet<String>>>,
+    metrics: Metrics,
+    quarantine_verdicts: Arc<Mutex<HashMap<RunId, Option<String>>>>,
+    reservation_verdicts: Arc<Mutex<HashMap<RunId, bool>>>,
     update_soon_task: Arc<Mutex<JoinHandle<()>>>,
 }
 
 impl Manager {
-    pub fn new(machine_manager: MachineManager) -> Self {
+    pub fn new(config: Config, machine_manager: MachineManager, metrics: Metrics) -> Self {
         let jobs = Arc::new(Mutex::new(Vec::new()));
+        let anticipation = Arc::new(Mutex::new(Anticipation::default()));
+        let attempts = Arc::new(Mutex::new(HashMap::new()));
+        let boosts = Arc::new(Mutex::new(HashMap::new()));
+        let checking = Arc::new(Mutex::new(HashMap::new()));
+        let completed = Arc::new(Mutex::new(HashMap::new()));
+        let held_back = Arc::new(Mutex::new(HashSet::new()));
+        let quarantine_verdicts = Arc::new(Mutex::new(HashMap::new()));
+        let reservation_verdicts = Arc::new(Mutex::new(HashMap::new()));
 
         // A placeholder task that finishes immediately.
         // Later an actual task will be placed in this spot.
         let update_soon_task = Arc::new(Mutex::new(tokio::spawn(async {})));
 
         Self {
+            config,
             machine_manager,
             jobs,
+            anticipation,
+            attempts,
+            boosts,
+            checking,
+            completed,
+            held_back,
+            metrics,
+            quarantine_verdicts,
+            reservation_verdicts,
             update_soon_task,
         }
     }
 
+    /// Get a snapshot of the state of all jobs we currently track
+    pub fn job_info(&self) -> Vec<JobInfo> {
+        let verdicts = self.quarantine_verdicts.lock().unwrap();
+        let reservation_verdicts = self.reservation_verdicts.lock().unwrap();
+        let boosts = self.boosts.lock().unwrap();
+
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| JobInfo {
+                triplet: job.triplet().to_string(),
+                job_id: job.job_id(),
+                run_id: job.run_id(),
+                status: job.status().clone(),
+                runner_name: job.runner_name().map(str::to_owned),
+                budget_exceeded: job.is_queued()
+                    && self
+                        .machine_manager
+                        .budget_exceeded(job.triplet().owner())
+                        .is_some(),
+                quarantine_reason: verdicts.get(&job.run_id()).cloned().flatten(),
+                reserved: reservation_verdicts
+                    .get(&job.run_id())
+                    .copied()
+                    .unwrap_or(false),
+                boosted: boosts.contains_key(&job.run_id()),
+            })
+            .collect()
+    }
+
+    /// Get the workflow runs that are currently boosted
+    pub fn boosts(&self) -> Vec<BoostInfo> {
+        self.boosts.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Let the queued jobs of the workflow run `run_id` go before all others
+    ///
+    /// E.g. for urgent hotfixes.
+    /// `by` names who asked for it and is logged for later reference.
+    /// The boost ends when we no longer track jobs of the run.
+    /// Returns `false` if we do not track any jobs of the run.
+    pub fn boost_run(&self, run_id: RunId, by: &str) -> bool {
+        let tracked = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|job| job.run_id() == run_id);
+
+        if !tracked {
+            return false;
+        }
+
+        warn!("Run {run_id} was boosted by {by}");
+
+        self.boosts.lock().unwrap().insert(
+            run_id,
+            BoostInfo {
+                run_id,
+                by: by.to_owned(),
+                since: Utc::now(),
+            },
+        );
+
+        self.update_demand();
+
+        true
+    }
+
+    /// Remove the boost of the workflow run `run_id`
+    ///
+    /// Returns `false` if the run was not boosted.
+    pub fn unboost_run(&self, run_id: RunId, by: &str) -> bool {
+        if self.boosts.lock().unwrap().remove(&run_id).is_none() {
+            return false;
+        }
+
+        warn!("The boost of run {run_id} was removed by {by}");
+
+        self.update_demand();
+
+        true
+    }
+
     /// Get GitHub workflow run ids for which we are interested in updates.
     ///
     /// This more or less means all runs with jobs that are not known to
@@ -63,17 +226,143 @@ impl Manager {
         res
     }
 
+    /// Hold back the jobs of the workflow run `run_id` until `checked_run()`
+    ///
+    /// This allows reporting the jobs of a run via `status_feedback()` while
+    /// its quarantine and reservation verdicts are still being determined,
+    /// without them being started on a regular machine in the meantime.
+    pub fn checking_run(&self, run_id: RunId) {
+        *self.checking.lock().unwrap().entry(run_id).or_default() += 1;
+    }
+
+    /// The verdicts for the workflow run `run_id` are in, see `checking_run()`
+    pub fn checked_run(&self, run_id: RunId) {
+        {
+            let mut checking = self.checking.lock().unwrap();
+
+            if let Some(count) = checking.get_mut(&run_id) {
+                *count -= 1;
+
+                if *count == 0 {
+                    checking.remove(&run_id);
+                }
+            }
+        }
+
+        self.update_demand_soon();
+    }
+
+    /// Has the workflow run `run_id` not been checked against the quarantine rules yet?
+    pub fn needs_quarantine_verdict(&self, run_id: RunId) -> bool {
+        !self
+            .quarantine_verdicts
+            .lock()
+            .unwrap()
+            .contains_key(&run_id)
+    }
+
+    /// Record whether the jobs of the workflow run `run_id` are quarantined and why
+    ///
+    /// This has to happen before the first `status_feedback()` of the jobs of
+    /// the run (or while the run is held back via `checking_run()`),
+    /// so they are never run on a regular machine.
+    pub fn quarantine_verdict(&self, run_id: RunId, reason: Option<String>) {
+        if let Some(reason) = &reason {
+            warn!("Quarantining the jobs of run {run_id}: {reason}");
+        }
+
+        self.quarantine_verdicts
+            .lock()
+            .unwrap()
+            .insert(run_id, reason);
+    }
+
+    /// Has the workflow run `run_id` not been checked for being of a protected branch yet?
+    pub fn needs_reservation_verdict(&self, run_id: RunId) -> bool {
+        !self
+            .reservation_verdicts
+            .lock()
+            .unwrap()
+            .contains_key(&run_id)
+    }
+
+    /// Record whether the jobs of the workflow run `run_id` may use reserved slots
+    ///
+    /// Like `quarantine_verdict()` this has to happen before the first
+    /// `status_feedback()` of the jobs of the run, or while it is held back.
+    pub fn reservation_verdict(&self, run_id: RunId, protected: bool) {
+        if protected {
+            info!("Jobs of run {run_id} may use the reserved slots of their repository");
+        }
+
+        self.reservation_verdicts
+            .lock()
+            .unwrap()
+            .insert(run_id, protected);
+    }
+
+    /// Should the job be told why it waited long for a machine?
+    ///
+    /// Returns `true` only once per job, so that webhook events and polls
+    /// of the same job do not result in multiple notices.
+    pub fn needs_wait_notice(&self, triplet: &Triplet, job_id: JobId) -> bool {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|job| job.triplet() == triplet && job.job_id() == job_id)
+            .is_some_and(|job| job.notice_wait())
+    }
+
+    /// Get the resource that held back the start of the machine `runner_name`, if any
+    pub fn postponed_by(&self, triplet: &Triplet, runner_name: &str) -> Option<String> {
+        self.machine_manager.postponed_by(triplet, runner_name)
+    }
+
+    /// Tell the machine manager whether the repository `oar` was archived or disabled
+    pub fn set_inactive(&self, oar: &OwnerAndRepo, inactive: bool) {
+        self.machine_manager.set_inactive(oar, inactive)
+    }
+
     /// Update the status of a job
     ///
     /// This is called by the poller and webhook ingres tasks.
+    /// The `run_attempt` of the workflow run the event is for is used to
+    /// detect stale events, if the source of the event provides it.
+    ///
+    /// Events may be processed out of order, e.g. a job may be reported as
+    /// completed before it is reported as in progress.
+    /// Updates that would move a job back to an earlier status,
+    /// or bring back a job that has completed, are ignored.
+    ///
+    /// The `conclusion` of completed jobs (e.g. `success`) is passed on to
+    /// the `job_webhooks`, if the source of the event provides it.
+    #[allow(clippy::too_many_arguments)]
     pub fn status_feedback(
         &self,
         triplet: &Triplet,
         job_id: JobId,
         run_id: RunId,
+        run_attempt: Option<u32>,
+        queued_at: DateTime<Utc>,
         status: Status,
+        conclusion: Option<&str>,
         runner_name: Option<&str>,
     ) {
+        let key = (triplet.clone(), job_id);
+
+        // Events for a job that already completed, or for an earlier attempt
+        // of the run, are stale and must neither bring the job back nor
+        // mark its runner as busy again.
+        let stale = self.completed.lock().unwrap().contains_key(&key)
+            || (job::stage(&status) < job::stage(&Status::Completed)
+                && job::is_stale_attempt(&mut self.attempts.lock().unwrap(), run_id, run_attempt));
+
+        if stale {
+            debug!("Ignoring out of order {status:?} event for job {job_id} of {triplet}");
+            return;
+        }
+
         if let (Status::InProgress, Some(runner_name)) = (&status, runner_name) {
             // We know that the runner this job is running on must be online and busy,
             // even though that information may not have trickled through yet.
@@ -89,7 +378,30 @@ impl Manager {
                 .status_feedback(triplet, runner_name, None, false);
         }
 
+        // Owners that have used up their budget and are configured to
+        // have their new jobs rejected do not get their jobs tracked at all.
+        let rejected =
+            self.machine_manager.budget_exceeded(triplet.owner()) == Some(BudgetPolicy::Reject);
+
+        // Jobs of owners that were held back due to their budget may be
+        // eligible again, e.g. because a new month has started or the
+        // budget was raised.
+        let budget_restored = self.held_back.lock().unwrap().contains(triplet.owner())
+            && self
+                .machine_manager
+                .budget_exceeded(triplet.owner())
+                .is_none();
+
+        match status {
+            Status::Pending | Status::Queued => {
+                self.anticipation.lock().unwrap().queued(run_id, triplet)
+            }
+            Status::InProgress => self.anticipation.lock().unwrap().started(run_id, triplet),
+            _ => {}
+        }
+
         let mut jobs = self.jobs.lock().unwrap();
+        let mut completed = self.completed.lock().unwrap();
 
         let index = jobs
             .iter()
@@ -98,18 +410,42 @@ impl Manager {
         let has_changed = match (&status, index) {
             // Track the status of this job by either adding it to our index
             // or updating its state if we already know it.
+            (Status::Pending | Status::Queued, None) if rejected => {
+                info!("Rejecting job {job_id} for {triplet}: monthly budget exceeded");
+                false
+            }
             (Status::Pending | Status::Queued | Status::InProgress, None) => {
-                jobs.push(Job::new(triplet.clone(), job_id, run_id, status));
+                if let Some(target) = self.machine_manager.alias_of(triplet) {
+                    warn!("Job {job_id} uses the deprecated machine type {triplet}, which was renamed to {target}");
+                    self.metrics.count_alias(&triplet.to_string());
+                }
+
+                jobs.push(Job::new(triplet.clone(), job_id, run_id, queued_at, status));
                 true
             }
             (Status::Pending | Status::Queued | Status::InProgress, Some(index)) => {
-                jobs[index].update_status(status)
+                let job = &mut jobs[index];
+
+                // Record how long the job waited for a machine.
+                if job.is_queued() && status == Status::InProgress {
+                    self.metrics
+                        .record_wait(&triplet.to_string(), Utc::now() - job.queued_at());
+                }
+
+                job.update_status(status)
             }
 
             // The job does not need further tracking from our side.
-            (Status::Completed | Status::Failed, None) => false,
+            (Status::Completed | Status::Failed, None) => {
+                completed.insert(key, Utc::now());
+                false
+            }
             (Status::Completed | Status::Failed, Some(index)) => {
-                jobs.swap_remove(index);
+                completed.insert(key, Utc::now());
+
+                let job = jobs.swap_remove(index);
+                self.report_result(&job, conclusion, runner_name.or(job.runner_name()));
+
                 true
             }
 
@@ -119,11 +455,60 @@ impl Manager {
             _ => panic!("Got unexpected workflow status from octocrab"),
         };
 
-        if has_changed {
+        // Remember the runner of the job, so that e.g. the history of its
+        // machine can be looked up if the job is aborted.
+        if let Some(runner_name) = runner_name {
+            let job = jobs
+                .iter_mut()
+                .find(|job| job.triplet() == triplet && job.job_id() == job_id);
+
+            if let Some(job) = job {
+                job.set_runner_name(runner_name);
+            }
+        }
+
+        if has_changed || budget_restored {
             self.update_demand_soon();
         }
     }
 
+    /// Record a summary of the completed `job` and send it to the `job_webhooks`
+    ///
+    /// Jobs that were given up on are reported with the
+    /// `START_FAILED_CONCLUSION`, even though they are still queued.
+    fn report_result(&self, job: &Job, conclusion: Option<&str>, runner_name: Option<&str>) {
+        let cfg = self.config.get();
+
+        let triplet = job.triplet();
+        let completed_at = Utc::now();
+        let started_at = job.started_at();
+
+        let machine = runner_name
+            .and_then(|runner_name| self.machine_manager.machine_summary(triplet, runner_name));
+
+        let result = JobResult {
+            triplet: triplet.to_string(),
+            owner: triplet.owner().to_owned(),
+            repository: triplet.repository().to_owned(),
+            job_id: job.job_id(),
+            run_id: job.run_id(),
+            runner_name: runner_name.map(str::to_owned),
+            conclusion: conclusion.map(str::to_owned),
+            queued_at: job.queued_at(),
+            started_at,
+            completed_at,
+            wait_seconds: started_at.map(|started| (started - job.queued_at()).num_seconds()),
+            run_seconds: started_at.map(|started| (completed_at - started).num_seconds()),
+            machine,
+        };
+
+        history::record(&cfg.host.base_dir, &result);
+
+        if !cfg.job_webhooks.is_empty() {
+            webhooks::emit(cfg, result);
+        }
+    }
+
     /// Schedule telling the machine manager how many machines we need
     ///
     /// When a workflow is started it may kick of multiple jobs at once.
@@ -148,13 +533,125 @@ impl Manager {
     }
 
     /// Tell the machine manager how many machines of which kind we need
+    ///
+    /// Jobs of owners that have used up their monthly budget stay queued,
+    /// but do not create demand for machines.
     fn update_demand(&self) {
-        let jobs = self.jobs.lock().unwrap();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut held_back = self.held_back.lock().unwrap();
+        let mut verdicts = self.quarantine_verdicts.lock().unwrap();
+        let mut reservation_verdicts = self.reservation_verdicts.lock().unwrap();
+        let mut boosts = self.boosts.lock().unwrap();
+        let checking = self.checking.lock().unwrap();
 
-        let triplets = jobs
-            .iter()
-            .filter_map(|job| job.is_queued().then_some(job.triplet()));
+        held_back.clear();
+
+        // Forget about runs we no longer track jobs of.
+        verdicts.retain(|run_id, _| jobs.iter().any(|job| job.run_id() == *run_id));
+        reservation_verdicts.retain(|run_id, _| jobs.iter().any(|job| job.run_id() == *run_id));
+
+        boosts.retain(|run_id, boost| {
+            let tracked = jobs.iter().any(|job| job.run_id() == *run_id);
+
+            if !tracked {
+                info!("Run {run_id} boosted by {} has no more jobs", boost.by);
+            }
+
+            tracked
+        });
+
+        self.attempts
+            .lock()
+            .unwrap()
+            .retain(|run_id, _| jobs.iter().any(|job| job.run_id() == *run_id));
+
+        let now = Utc::now();
+
+        self.completed
+            .lock()
+            .unwrap()
+            .retain(|_, at| now - *at < COMPLETED_RETENTION);
+
+        let anticipated = {
+            let mut anticipation = self.anticipation.lock().unwrap();
+
+            anticipation.prune(COMPLETED_RETENTION);
+            anticipation.expected()
+        };
+
+        let mut quarantined = HashSet::new();
+        let mut protected: HashMap<Triplet, u64> = HashMap::new();
+        let mut boosted: HashMap<Triplet, u64> = HashMap::new();
+
+        let triplets: Vec<&Triplet> = jobs
+            .iter_mut()
+            .filter_map(|job| {
+                if !job.is_queued() {
+                    return None;
+                }
+
+                let owner = job.triplet().owner();
+
+                if self.machine_manager.budget_exceeded(owner).is_some() {
+                    if held_back.insert(owner.to_owned()) {
+                        info!("Holding back jobs of {owner}: monthly budget exceeded");
+                    }
+
+                    return None;
+                }
+
+                // Machines for the job repeatedly failed to come up.
+                // Stop trying, even once the machine type gets another chance.
+                if job.is_given_up() || self.machine_manager.start_failed(job.triplet()) {
+                    if job.give_up() {
+                        error!(
+                            "Giving up on job {} for {}: its machines repeatedly failed to start",
+                            job.job_id(),
+                            job.triplet()
+                        );
+
+                        self.report_result(job, Some(START_FAILED_CONCLUSION), None);
+                    }
+
+                    return None;
+                }
+
+                // The run may still turn out to be quarantined.
+                if checking.contains_key(&job.run_id()) {
+                    return None;
+                }
+
+                // Record how long it took from the job being queued on GitHub
+                // until we asked for a machine for it.
+                if job.create_demand() {
+                    self.metrics
+                        .record_delay("queued_to_demand", now - job.queued_at());
+                }
+
+                let job: &Job = job;
+
+                if verdicts.get(&job.run_id()).is_some_and(Option::is_some) {
+                    quarantined.insert(job.triplet().clone());
+                }
+
+                if reservation_verdicts.get(&job.run_id()) == Some(&true) {
+                    *protected.entry(job.triplet().clone()).or_default() += 1;
+                }
+
+                if boosts.contains_key(&job.run_id()) {
+                    *boosted.entry(job.triplet().clone()).or_default() += 1;
+                }
+
+                Some(job.triplet())
+            })
+            .collect();
 
-        self.machine_manager.update_demand(triplets);
+        self.machine_manager.update_demand(
+            triplets.into_iter(),
+            quarantined,
+            protected,
+            boosted,
+            anticipated,
+        );
     }
 }
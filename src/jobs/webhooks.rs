@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http::header::CONTENT_TYPE;
+use http::Method;
+use http_body_util::BodyExt;
+use log::{debug, warn};
+use octocrab::models::{JobId, RunId};
+use octocrab::Octocrab;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::config::{ConfigFile, JobWebhook};
+use crate::machines::MachineSummary;
+
+// Named after the header GitHub signs its webhooks with,
+// so that receivers can re-use their verification code.
+const SIGNATURE_HEADER: &str = "X-Forrest-Signature-256";
+
+/// A summary of a completed job, as sent to the `job_webhooks`
+#[derive(Serialize)]
+pub(super) struct JobResult {
+    pub triplet: String,
+    pub owner: String,
+    pub repository: String,
+    pub job_id: JobId,
+    pub run_id: RunId,
+    pub runner_name: Option<String>,
+    /// E.g. `success` or `failure`, if the forge reports it
+    pub conclusion: Option<String>,
+    pub queued_at: DateTime<Utc>,
+    /// When Forrest learned that the job started
+    pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: DateTime<Utc>,
+    pub wait_seconds: Option<i64>,
+    pub run_seconds: Option<i64>,
+    /// The machine the job ran on, if it is still known
+    pub machine: Option<MachineSummary>,
+}
+
+/// The `sha256=<hex>` HMAC of `body` using `secret`
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut hmac: Hmac<Sha256> = Hmac::new_from_slice(secret.as_bytes()).unwrap();
+    hmac.update(body);
+
+    format!("sha256={}", hex::encode(hmac.finalize().into_bytes()))
+}
+
+async fn post(client: &Octocrab, webhook: &JobWebhook, body: &str) -> Result<(), String> {
+    let mut request = http::Request::builder()
+        .method(Method::POST)
+        .uri(&webhook.url)
+        .header(CONTENT_TYPE, "application/json");
+
+    if let Some(secret) = &webhook.secret {
+        request = request.header(SIGNATURE_HEADER, signature(secret, body.as_bytes()));
+    }
+
+    let request = request.body(body.to_owned()).map_err(|e| e.to_string())?;
+    let response = client.execute(request).await.map_err(|e| e.to_string())?;
+    let status = response.status();
+
+    if !status.is_success() {
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map(|body| String::from_utf8_lossy(&body.to_bytes()).into_owned())
+            .unwrap_or_default();
+
+        return Err(format!("Responded with status {status}: {}", body.trim()));
+    }
+
+    Ok(())
+}
+
+/// Send `result` to all `job_webhooks` of `cfg` in the background
+///
+/// Failed deliveries are logged, but not retried.
+pub(super) fn emit(cfg: Arc<ConfigFile>, result: JobResult) {
+    tokio::spawn(async move {
+        let body = match serde_json::to_string(&result) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(
+                    "Failed to serialize the result of job {}: {e}",
+                    result.job_id
+                );
+                return;
+            }
+        };
+
+        // An unauthenticated client, so we do not leak our credentials
+        // to the downstream systems.
+        let client = match Octocrab::builder().build() {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Failed to set up a client for the job webhooks: {e}");
+                return;
+            }
+        };
+
+        for webhook in &cfg.job_webhooks {
+            match post(&client, webhook, &body).await {
+                Ok(()) => debug!("Sent result of job {} to {}", result.job_id, webhook.url),
+                Err(e) => warn!(
+                    "Failed to send result of job {} to {}: {e}",
+                    result.job_id, webhook.url
+                ),
+            }
+        }
+    });
+}
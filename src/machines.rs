@@ -1,8 +1,42 @@
+mod accounting;
+mod adoption;
+mod backend;
+mod calibration;
+mod cloud_init;
+mod concurrency;
 mod config_fs;
+mod devices;
+mod dry_run;
+mod error;
+mod fallback;
+mod helper;
 mod machine;
 mod manager;
+mod metadata;
+mod pinning;
+mod pressure;
+mod registration;
+mod registration_limit;
+mod reservation;
+mod resources;
+mod retention;
 mod run_dir;
+mod simulation;
+mod sizing;
+mod state;
+mod tenancy;
 mod triplet;
 
-pub use manager::Manager;
+pub use accounting::UsageReport;
+pub use backend::BackendReadiness;
+pub use calibration::calibrate;
+pub use dry_run::dry_run;
+pub use error::{Error, Result};
+pub use helper::serve as serve_helper;
+pub use manager::{MachineInfo, MachineSummary, Manager};
+pub use metadata::proxy as metadata_proxy;
+pub use run_dir::job_sbom;
+pub use simulation::simulate;
+pub use sizing::recommendations;
+pub use state::{export_state, import_state};
 pub use triplet::{OwnerAndRepo, Triplet};
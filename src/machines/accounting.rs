@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{BudgetPolicy, ConfigFile};
+
+/// The resources used by an owner in a month
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Usage {
+    /// The time machines of this owner were running
+    pub machine_seconds: u64,
+    /// The machine running time weighted by the `cost` of the machine type
+    pub cost: f64,
+}
+
+/// Usage per owner for each month, keyed by e.g. "2024-08"
+pub type UsageReport = BTreeMap<String, HashMap<String, Usage>>;
+
+/// Keeps track of the resources used by each owner
+///
+/// The usage is persisted to disk, so it survives restarts of Forrest.
+pub struct Accounting {
+    path: PathBuf,
+    months: Mutex<UsageReport>,
+}
+
+fn current_month() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+impl Accounting {
+    pub fn new(base_dir: &Path) -> Self {
+        let path = base_dir.join("accounting.json");
+
+        let months = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                error!("Failed to parse {}, starting over: {e}", path.display());
+                UsageReport::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => UsageReport::new(),
+            Err(e) => {
+                error!("Failed to read {}, starting over: {e}", path.display());
+                UsageReport::new()
+            }
+        };
+
+        Self {
+            path,
+            months: Mutex::new(months),
+        }
+    }
+
+    fn persist(&self, months: &UsageReport) {
+        // Write to a temporary file first and move it into place,
+        // so we never leave a half written file behind.
+        let tmp_path = self.path.with_extension("json.tmp");
+
+        let res = serde_json::to_vec_pretty(months)
+            .map_err(std::io::Error::other)
+            .and_then(|content| std::fs::write(&tmp_path, content))
+            .and_then(|()| std::fs::rename(&tmp_path, &self.path));
+
+        if let Err(e) = res {
+            error!(
+                "Failed to persist accounting to {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+
+    /// Account for a machine of `owner` that ran for `duration` and has a cost
+    /// of `cost_per_hour`
+    pub fn record(&self, owner: &str, duration: Duration, cost_per_hour: f64) {
+        let mut months = self.months.lock().unwrap();
+
+        let usage = months
+            .entry(current_month())
+            .or_default()
+            .entry(owner.to_owned())
+            .or_default();
+
+        let cost = duration.as_secs_f64() / 3600.0 * cost_per_hour;
+
+        usage.machine_seconds += duration.as_secs();
+        usage.cost += cost;
+
+        debug!(
+            "Accounted {}s ({cost:.2}) to {owner}. Monthly total {:.2}",
+            duration.as_secs(),
+            usage.cost
+        );
+
+        self.persist(&months);
+    }
+
+    /// Take over the usage from a previously exported report
+    ///
+    /// Entries in `report` replace the entries for the same month and owner,
+    /// so importing the same report twice does not count the usage twice.
+    pub fn import(&self, report: UsageReport) {
+        let mut months = self.months.lock().unwrap();
+
+        for (month, owners) in report {
+            months.entry(month).or_default().extend(owners);
+        }
+
+        self.persist(&months);
+    }
+
+    /// Get the resources used by `owner` in the current month
+    pub fn usage(&self, owner: &str) -> Usage {
+        self.months
+            .lock()
+            .unwrap()
+            .get(&current_month())
+            .and_then(|owners| owners.get(owner))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Check if `owner` has used up their monthly budget
+    ///
+    /// Returns what to do with new jobs of this owner if they have.
+    pub fn budget_exceeded(&self, cfg: &ConfigFile, owner: &str) -> Option<BudgetPolicy> {
+        let budget = cfg.budgets.get(owner)?;
+
+        (self.usage(owner).cost >= budget.monthly).then_some(budget.when_exceeded)
+    }
+
+    /// Get the usage of all owners for all recorded months
+    pub fn report(&self) -> UsageReport {
+        self.months.lock().unwrap().clone()
+    }
+}
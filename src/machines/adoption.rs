@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+use super::helper;
+use super::pinning::Pinning;
+use super::retention::walk;
+use super::run_dir::RunDirState;
+use super::triplet::Triplet;
+use crate::config::ConfigFile;
+
+// Written to the run directory of machines that may outlive this instance.
+const RECORD_FILE: &str = "machine.json";
+
+// The executables the backends run machines with.
+// Other processes in a run directory, like the shell of an operator looking
+// into a kept run directory, are left alone.
+const MACHINE_COMMANDS: &[&str] = &[
+    "qemu-kvm",
+    "podman",
+    "kubectl",
+    "systemd-nspawn",
+    "virtiofsd",
+];
+
+/// Is `exe` the executable of a machine process?
+fn is_machine_command(exe: &Path) -> bool {
+    let name = match exe.file_name().and_then(|name| name.to_str()) {
+        // The executable may have been replaced by an update in the meantime.
+        Some(name) => name.trim_end_matches(" (deleted)"),
+        None => return false,
+    };
+
+    name.starts_with("qemu-system-") || MACHINE_COMMANDS.contains(&name)
+}
+
+/// What a later Forrest instance needs to know to take over a running machine
+#[derive(Serialize, Deserialize)]
+pub(super) struct Record {
+    pub(super) triplet: Triplet,
+    pub(super) config_triplet: Triplet,
+    pub(super) quarantined: bool,
+    pub(super) runner_name: String,
+    pub(super) runner_id: String,
+    pub(super) pid: u32,
+    /// Since when the running time of the machine was not accounted yet
+    pub(super) unaccounted_since: DateTime<Utc>,
+    pub(super) run_dir: RunDirState,
+    /// The host CPU cores the machine is pinned to, if any
+    #[serde(default)]
+    pub(super) pinning: Option<Pinning>,
+}
+
+/// Is the process `pid` still running the machine `runner_name`?
+///
+/// The process is identified by its id and by running inside of the
+/// run directory of the machine, in case the id was re-used since.
+pub(super) fn is_alive(pid: u32, runner_name: &str) -> bool {
+    match std::fs::read_link(format!("/proc/{pid}/cwd")) {
+        Ok(cwd) => cwd.ends_with(runner_name),
+        // Only root may look into the processes of tenant users.
+        Err(e) if e.kind() == ErrorKind::PermissionDenied && helper::is_used() => {
+            helper::is_alive(pid, runner_name)
+        }
+        Err(_) => false,
+    }
+}
+
+impl Record {
+    /// Write the record to the run directory of the machine
+    pub(super) fn write(&self, run_dir: &Path) -> std::io::Result<()> {
+        // Write to a temporary file first and move it into place,
+        // so a later instance never reads a half written file.
+        let path = run_dir.join(RECORD_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+
+        let content = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, &path)
+    }
+
+    /// Remove the record from `run_dir` once the machine has stopped
+    pub(super) fn remove(run_dir: &Path) {
+        let path = run_dir.join(RECORD_FILE);
+
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Find the machines a previous instance left running
+///
+/// The records of machines that have stopped since are removed.
+pub(super) fn leftovers(cfg: &ConfigFile) -> Vec<Record> {
+    walk(&cfg.host.base_dir.join("runs"), 4)
+        .into_iter()
+        .filter_map(|run_dir| {
+            let path = run_dir.join(RECORD_FILE);
+
+            let content = match std::fs::read(&path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => return None,
+                Err(e) => {
+                    error!("Failed to read {}: {e}", path.display());
+                    return None;
+                }
+            };
+
+            let record: Record = match serde_json::from_slice(&content) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Ignoring unreadable machine record {}: {e}", path.display());
+                    Record::remove(&run_dir);
+                    return None;
+                }
+            };
+
+            if !is_alive(record.pid, &record.runner_name) {
+                info!(
+                    "Machine {} of a previous instance has stopped in the meantime",
+                    record.runner_name
+                );
+                Record::remove(&run_dir);
+                return None;
+            }
+
+            Some(record)
+        })
+        .collect()
+}
+
+/// Find machine processes a previous instance left running without a record
+///
+/// This happens e.g. if it crashed between starting a machine and writing
+/// the record, or if the record could not be read.
+/// All backends run their machine processes inside of the run directory
+/// of the machine, so every process with its working directory in one of the
+/// run directories and a known machine executable is considered a machine,
+/// unless it belongs to one of the `adopted` runner names
+/// (e.g. helper processes started by the machine).
+/// Returns the process ids along with the runner names.
+pub(super) fn strays(cfg: &ConfigFile, adopted: &HashSet<String>) -> Vec<(u32, String)> {
+    // The working directories of the processes of tenant users are only
+    // visible to root.
+    if helper::is_used() {
+        return helper::strays(adopted).unwrap_or_else(|e| {
+            error!("Failed to ask the helper for leftover machine processes: {e}");
+            Vec::new()
+        });
+    }
+
+    // runs/<owner>/<repository>/<machine>/<runner name>
+    // The working directories in /proc are absolute and free of symlinks.
+    let run_dirs: HashSet<_> = walk(&cfg.host.base_dir.join("runs"), 4)
+        .into_iter()
+        .filter_map(|run_dir| run_dir.canonicalize().ok())
+        .collect();
+
+    let proc = match std::fs::read_dir("/proc") {
+        Ok(proc) => proc,
+        Err(e) => {
+            error!("Failed to list the running processes: {e}");
+            return Vec::new();
+        }
+    };
+
+    proc.filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| *pid != std::process::id())
+        .filter_map(|pid| {
+            let cwd = std::fs::read_link(format!("/proc/{pid}/cwd")).ok()?;
+
+            if !run_dirs.contains(&cwd) {
+                return None;
+            }
+
+            let exe = std::fs::read_link(format!("/proc/{pid}/exe")).ok()?;
+
+            if !is_machine_command(&exe) {
+                return None;
+            }
+
+            let runner_name = cwd.file_name()?.to_string_lossy().into_owned();
+
+            match adopted.contains(&runner_name) {
+                true => None,
+                false => Some((pid, runner_name)),
+            }
+        })
+        .collect()
+}
+
+/// The process of a machine a previous instance left running
+///
+/// Unlike the processes we spawn ourselves it is not our child,
+/// so it can not be waited for and has to be polled instead.
+/// Like spawned processes (see `kill_on_drop`) it is killed if it is
+/// still running when this is dropped, e.g. because the machine was killed.
+pub(super) struct Process {
+    pid: u32,
+    runner_name: String,
+}
+
+impl Process {
+    pub(super) fn new(pid: u32, runner_name: &str) -> Self {
+        Self {
+            pid,
+            runner_name: runner_name.to_owned(),
+        }
+    }
+
+    pub(super) fn is_alive(&self) -> bool {
+        is_alive(self.pid, &self.runner_name)
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        if !self.is_alive() {
+            return;
+        }
+
+        let res = match kill(Pid::from_raw(self.pid as i32), Signal::SIGKILL) {
+            Err(Errno::EPERM) if helper::is_used() => {
+                helper::kill_machine(self.pid, &self.runner_name)
+            }
+            res => res.map_err(std::io::Error::from),
+        };
+
+        if let Err(e) = res {
+            error!(
+                "Failed to kill process {} of machine {}: {e}",
+                self.pid, self.runner_name
+            );
+        }
+    }
+}
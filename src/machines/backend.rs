@@ -0,0 +1,519 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::process::Command;
+
+use super::pinning::Pinning;
+use super::run_dir;
+use crate::config::{
+    Accel, Arch, Backend, ConfigFile, HostConfig, HostDevice, MachineConfig, Tenant,
+};
+
+mod container_images;
+mod cpu;
+mod kata;
+mod kubernetes;
+mod nspawn;
+mod qemu;
+mod qmp;
+mod shares;
+
+/// Check that the backend of a machine supports its configuration
+pub(super) fn check(machine_config: &MachineConfig) -> Result<(), String> {
+    // Only qemu can emulate other architectures.
+    let emulated = !machine_config.arch.is_native();
+
+    if emulated && matches!(machine_config.backend, Backend::Nspawn | Backend::Kata) {
+        return Err(format!(
+            "The {} backend can not run {} machines on this host",
+            machine_config.backend, machine_config.arch
+        ));
+    }
+
+    if machine_config.accel != Accel::Auto && machine_config.backend != Backend::Qemu {
+        return Err(format!(
+            "The {} backend does not support choosing an accelerator",
+            machine_config.backend
+        ));
+    }
+
+    let cpu_options = machine_config.nested || !machine_config.cpu_features.is_empty();
+
+    if cpu_options && machine_config.backend != Backend::Qemu {
+        return Err(format!(
+            "The {} backend does not support nested virtualization or CPU features",
+            machine_config.backend
+        ));
+    }
+
+    match machine_config.backend {
+        Backend::Qemu => {
+            let accel = qemu::accelerator(machine_config)?;
+            cpu::check(machine_config, Some(accel))
+        }
+        Backend::Nspawn => nspawn::check(machine_config),
+        Backend::Kata => kata::check(machine_config),
+        Backend::Kubernetes => kubernetes::check(machine_config),
+    }
+}
+
+/// The accelerator a machine would currently run with
+///
+/// Returns `None` for backends that do not let us choose one.
+pub(super) fn accelerator(machine_config: &MachineConfig) -> Option<Accel> {
+    match machine_config.backend {
+        Backend::Qemu => qemu::accelerator(machine_config).ok(),
+        Backend::Nspawn | Backend::Kata | Backend::Kubernetes => None,
+    }
+}
+
+/// Does the backend run machines on this host?
+///
+/// Machines that run elsewhere, like on a Kubernetes cluster,
+/// do not consume the RAM of the host.
+pub(super) fn runs_on_host(backend: Backend) -> bool {
+    match backend {
+        Backend::Qemu | Backend::Nspawn | Backend::Kata => true,
+        Backend::Kubernetes => false,
+    }
+}
+
+/// Does the backend boot from a `disk.img` in the run directory?
+pub(super) fn uses_disk_image(backend: Backend) -> bool {
+    match backend {
+        Backend::Qemu | Backend::Nspawn => true,
+        Backend::Kata | Backend::Kubernetes => false,
+    }
+}
+
+/// Can the backend boot from a qcow2 overlay (`disk.qcow2`) instead of a `disk.img`?
+pub(super) fn can_boot_overlay(backend: Backend) -> bool {
+    match backend {
+        Backend::Qemu => true,
+        Backend::Nspawn | Backend::Kata | Backend::Kubernetes => false,
+    }
+}
+
+/// Does the backend need the cloud-init and job config as directories
+/// instead of disk images?
+pub(super) fn uses_config_dirs(backend: Backend) -> bool {
+    match backend {
+        Backend::Qemu => false,
+        Backend::Nspawn | Backend::Kata | Backend::Kubernetes => true,
+    }
+}
+
+/// Describe how a machine with `machine_config` is connected to the network
+///
+/// A `bridge` in the `machine_config` takes precedence over the `tenant`'s,
+/// like it does in `command()`.
+pub(super) fn network(machine_config: &MachineConfig, tenant: Option<&Tenant>) -> String {
+    let bridge = machine_config
+        .bridge
+        .as_deref()
+        .or(tenant.and_then(|t| t.bridge.as_deref()));
+
+    match (machine_config.backend, bridge) {
+        (Backend::Qemu | Backend::Nspawn, Some(bridge)) => format!("bridge {bridge}"),
+        (Backend::Qemu, None) => "qemu user mode networking".to_owned(),
+        (Backend::Nspawn, None) => "shared with the host".to_owned(),
+        (Backend::Kata, _) => "the default network of the container runtime".to_owned(),
+        (Backend::Kubernetes, _) => "the pod network of the cluster".to_owned(),
+    }
+}
+
+/// Can machines of the backend be paused and resumed?
+pub(super) fn can_pause(backend: Backend) -> bool {
+    match backend {
+        Backend::Qemu => true,
+        Backend::Nspawn | Backend::Kata | Backend::Kubernetes => false,
+    }
+}
+
+/// Can machines of the backend be asked to shut down, like pressing the power button?
+pub(super) fn can_power_down(backend: Backend) -> bool {
+    match backend {
+        Backend::Qemu => true,
+        Backend::Nspawn | Backend::Kata | Backend::Kubernetes => false,
+    }
+}
+
+/// Can machines with `machine_config` keep running across a restart of Forrest
+/// and be taken over by the next instance?
+///
+/// The virtiofsd instances serving virtiofs shares do not survive a restart.
+pub(super) fn can_adopt(machine_config: &MachineConfig) -> bool {
+    match machine_config.backend {
+        Backend::Qemu => !shares::uses_virtiofs(machine_config),
+        Backend::Nspawn | Backend::Kata | Backend::Kubernetes => false,
+    }
+}
+
+/// Stop or continue the CPUs of the machine running in `run_dir`
+///
+/// A paused machine keeps its RAM, but does not use any CPU time.
+/// Only supported by backends where `can_pause()` is true.
+pub(super) async fn set_paused(run_dir: &Path, paused: bool) -> std::io::Result<()> {
+    qmp::execute(run_dir, if paused { "stop" } else { "cont" }).await
+}
+
+/// Ask the guest of the machine running in `run_dir` to shut down
+///
+/// Only supported by backends where `can_power_down()` is true.
+pub(super) async fn power_down(run_dir: &Path) -> std::io::Result<()> {
+    qmp::execute(run_dir, "system_powerdown").await
+}
+
+/// Get the run state of the machine running in `run_dir`, e.g. `running` or `paused`
+///
+/// Only supported by backends where `can_power_down()` is true.
+pub(super) async fn guest_status(run_dir: &Path) -> std::io::Result<String> {
+    let status = qmp::query(run_dir, "query-status", serde_json::json!({})).await?;
+
+    status["status"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| std::io::Error::other("QMP returned no run state"))
+}
+
+/// Wait for the guest of the machine running in `run_dir` to shut down
+///
+/// Returns the reason reported by qemu, e.g. `guest-shutdown`.
+/// Only supported by backends where `can_power_down()` is true.
+pub(super) async fn wait_for_shutdown(run_dir: &Path) -> std::io::Result<String> {
+    let data = qmp::wait_for_event(run_dir, "SHUTDOWN").await?;
+
+    Ok(data["reason"].as_str().unwrap_or("unknown").to_owned())
+}
+
+/// Set the RAM (in bytes) the machine running in `run_dir` may use via its balloon device
+///
+/// The guest hands back the memory above `ram` to the host.
+/// Only supported by machine types with a `balloon` config,
+/// which `check()` only allows for the qemu backend.
+pub(super) async fn set_balloon(run_dir: &Path, ram: u64) -> std::io::Result<()> {
+    qmp::execute_with(run_dir, "balloon", serde_json::json!({ "value": ram })).await
+}
+
+/// Assemble the command to run a machine with `machine_config` in `run_dir`
+/// with its `scratch` disk (if it has one)
+///
+/// The `devices` are the host devices to pass through to the machine,
+/// which only the qemu backend supports (see `check()`).
+/// The same goes for the `pinning` to host CPU cores.
+/// The command completes once the machine has powered itself off.
+pub(super) fn command(
+    host: &HostConfig,
+    machine_config: &MachineConfig,
+    tenant: Option<&Tenant>,
+    devices: &[&HostDevice],
+    pinning: Option<&Pinning>,
+    run_dir: &Path,
+    scratch: Option<&Path>,
+) -> std::io::Result<Command> {
+    match machine_config.backend {
+        Backend::Qemu => qemu::command(machine_config, tenant, devices, pinning, run_dir, scratch),
+        Backend::Nspawn => nspawn::command(machine_config, tenant, run_dir),
+        Backend::Kata => kata::command(host, machine_config, run_dir),
+        Backend::Kubernetes => kubernetes::command(machine_config, run_dir),
+    }
+}
+
+/// Removes what the machine in a run dir left behind, should its process be killed
+///
+/// Killing `podman` or `kubectl` does not stop the container or pod they
+/// started and keeps `--rm` from removing it,
+/// so it is removed explicitly once this is dropped.
+/// Use `disarm()` once the process exited on its own.
+/// The secrets of pods are always removed.
+pub(super) struct Cleanup {
+    command: Option<Command>,
+    always: bool,
+}
+
+impl Cleanup {
+    pub(super) fn new(machine_config: &MachineConfig, run_dir: &Path) -> Self {
+        let (command, always) = match machine_config.backend {
+            Backend::Qemu | Backend::Nspawn => (None, false),
+            Backend::Kata => (Some(kata::remove_command(run_dir)), false),
+            Backend::Kubernetes => (
+                Some(kubernetes::delete_command(machine_config, run_dir)),
+                true,
+            ),
+        };
+
+        Self { command, always }
+    }
+
+    /// The machine process exited on its own and cleaned up after itself
+    pub(super) fn disarm(&mut self) {
+        if !self.always {
+            self.command = None;
+        }
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        // The removal runs in the background, there is nothing left
+        // to wait for it.
+        if let Some(command) = &mut self.command {
+            if let Err(e) = command.spawn() {
+                warn!("Failed to remove the container of a machine: {e}");
+            }
+        }
+    }
+}
+
+/// Hand the configuration to a machine that can not access the run dir
+///
+/// This has to happen before its `command()` is run,
+/// but after the `Cleanup` is set up, which removes it again.
+pub(super) async fn prepare_config(
+    machine_config: &MachineConfig,
+    run_dir: &Path,
+) -> std::io::Result<()> {
+    match machine_config.backend {
+        Backend::Kubernetes => kubernetes::create_secrets(machine_config, run_dir).await,
+        Backend::Qemu | Backend::Nspawn | Backend::Kata => Ok(()),
+    }
+}
+
+/// Prepare the shared directories of a machine before its `command()` is run
+///
+/// Directories with a `size_limit` are trimmed down to it and,
+/// for the qemu backend, a virtiofsd is started for each virtiofs share.
+/// The returned daemons have to be kept around while the machine runs.
+pub(super) async fn prepare_shares(
+    machine_config: &MachineConfig,
+    tenant: Option<&Tenant>,
+    run_dir: &Path,
+) -> std::io::Result<Option<shares::Daemons>> {
+    shares::enforce_limits(machine_config).await;
+
+    match machine_config.backend {
+        Backend::Qemu => shares::start(machine_config, tenant, run_dir)
+            .await
+            .map(Some),
+        Backend::Nspawn | Backend::Kata | Backend::Kubernetes => Ok(None),
+    }
+}
+
+/// Pull the container images of the machine types that run locally via podman
+///
+/// See `container_images::update()`.
+pub(super) async fn update_container_images(cfg: &ConfigFile) {
+    container_images::update(cfg).await
+}
+
+/// Whether the host has the tooling required to run the machines of a backend
+#[derive(Serialize, Clone, PartialEq)]
+pub struct BackendReadiness {
+    pub backend: Backend,
+    pub machines: Vec<String>,
+    pub problems: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl BackendReadiness {
+    pub fn is_ready(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check that `path` is an executable file
+///
+/// Returns a description of the problem if not.
+fn check_executable(path: &str) -> Option<String> {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => None,
+        Ok(_) => Some(format!("{path} is not executable")),
+        Err(e) => Some(format!("{path} is not available: {e}")),
+    }
+}
+
+/// Check that the device at `path` can be opened for reading and writing
+fn check_device(path: &str) -> Option<String> {
+    std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .err()
+        .map(|e| format!("Can not access {path}: {e}"))
+}
+
+fn host_problems(cfg: &ConfigFile, backend: Backend) -> Vec<String> {
+    let problems = match backend {
+        Backend::Qemu => {
+            let qemu_configs: Vec<&MachineConfig> = cfg
+                .triplets()
+                .iter()
+                .filter_map(|triplet| cfg.machine_config(triplet))
+                .filter(|mc| mc.backend == Backend::Qemu)
+                .collect();
+
+            let arches: BTreeSet<Arch> = qemu_configs.iter().map(|mc| mc.arch).collect();
+
+            // Machines with `accel: auto` fall back to TCG without KVM,
+            // see `host_warnings()`.
+            let kvm_problem = qemu_configs
+                .iter()
+                .any(|mc| mc.accel == Accel::Kvm)
+                .then(|| check_device(qemu::KVM_DEVICE))
+                .flatten();
+
+            let firmware: BTreeSet<PathBuf> = qemu_configs
+                .iter()
+                .filter_map(|mc| qemu::firmware(mc))
+                .collect();
+
+            let mut problems: Vec<Option<String>> = arches
+                .iter()
+                .map(|arch| check_executable(qemu::qemu_cmd(*arch)))
+                .collect();
+
+            problems.extend(firmware.iter().map(|path| {
+                (!path.is_file()).then(|| format!("Firmware {} is not available", path.display()))
+            }));
+
+            problems.push(kvm_problem);
+
+            problems.push(
+                cfg.host
+                    .overlay_dir
+                    .as_ref()
+                    .and_then(|_| check_executable(run_dir::QEMU_IMG_CMD)),
+            );
+
+            problems.push(
+                qemu_configs
+                    .iter()
+                    .any(|mc| shares::uses_virtiofs(mc))
+                    .then(|| check_executable(shares::VIRTIOFSD_CMD))
+                    .flatten(),
+            );
+
+            problems
+        }
+        Backend::Nspawn => vec![check_executable(nspawn::NSPAWN_CMD)],
+        Backend::Kata => vec![
+            check_executable(kata::PODMAN_CMD),
+            kata::check_runtime(),
+            cfg.triplets()
+                .iter()
+                .filter_map(|triplet| cfg.machine_config(triplet))
+                .any(container_images::needs_cosign)
+                .then(|| check_executable(container_images::COSIGN_CMD))
+                .flatten(),
+        ],
+        Backend::Kubernetes => vec![check_executable(kubernetes::KUBECTL_CMD)],
+    };
+
+    problems.into_iter().flatten().collect()
+}
+
+/// Report host shortcomings that do not keep a backend from running machines
+fn host_warnings(cfg: &ConfigFile, backend: Backend) -> Vec<String> {
+    match backend {
+        Backend::Qemu => {
+            let falling_back: Vec<String> = cfg
+                .triplets()
+                .into_iter()
+                .filter(|triplet| {
+                    cfg.machine_config(triplet).is_some_and(|mc| {
+                        mc.backend == Backend::Qemu
+                            && mc.accel == Accel::Auto
+                            && mc.arch.is_native()
+                    })
+                })
+                .map(|triplet| triplet.to_string())
+                .collect();
+
+            let kvm_problem = match falling_back.is_empty() {
+                true => None,
+                false => check_device(qemu::KVM_DEVICE),
+            };
+
+            let mut warnings: Vec<String> = kvm_problem
+                .map(|problem| {
+                    format!(
+                        "{problem}. Emulating {} via TCG instead",
+                        falling_back.join(", ")
+                    )
+                })
+                .into_iter()
+                .collect();
+
+            // Machine types asking for CPU features the host can not
+            // provide are refused when their machines are created,
+            // report them as soon as they are configured instead.
+            for triplet in cfg.triplets() {
+                let mc = match cfg.machine_config(&triplet) {
+                    Some(mc) if mc.backend == Backend::Qemu => mc,
+                    _ => continue,
+                };
+
+                if !mc.nested && mc.cpu_features.is_empty() {
+                    continue;
+                }
+
+                if let Err(e) = cpu::check(mc, qemu::accelerator(mc).ok()) {
+                    warnings.push(format!("Machines of {triplet} can not be started: {e}"));
+                }
+            }
+
+            warnings
+        }
+        Backend::Nspawn | Backend::Kata | Backend::Kubernetes => Vec::new(),
+    }
+}
+
+/// Check the host tooling for all backends used by the configured machines
+///
+/// The report contains one entry per backend that is in use,
+/// listing the machines that use it.
+pub(super) fn readiness(cfg: &ConfigFile) -> Vec<BackendReadiness> {
+    let mut machines: BTreeMap<Backend, Vec<String>> = BTreeMap::new();
+
+    for triplet in cfg.triplets() {
+        if let Some(machine_config) = cfg.machine_config(&triplet) {
+            machines
+                .entry(machine_config.backend)
+                .or_default()
+                .push(triplet.to_string());
+        }
+    }
+
+    machines
+        .into_iter()
+        .map(|(backend, machines)| BackendReadiness {
+            backend,
+            machines,
+            problems: host_problems(cfg, backend),
+            warnings: host_warnings(cfg, backend),
+        })
+        .collect()
+}
+
+/// Log a backend readiness report
+pub(super) fn log_readiness(report: &[BackendReadiness]) {
+    for entry in report {
+        let machines = entry.machines.join(", ");
+
+        match entry.is_ready() {
+            true => info!("Backend {} is ready for {machines}", entry.backend),
+            false => error!(
+                "Backend {} is not ready for {machines}: {}",
+                entry.backend,
+                entry.problems.join("; ")
+            ),
+        }
+
+        for warning in &entry.warnings {
+            warn!("Backend {}: {warning}", entry.backend);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::path::Path;
+
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::process::Command;
+
+use super::kata::PODMAN_CMD;
+use crate::config::{Backend, ConfigFile, ContainerSignature, MachineConfig};
+
+pub(super) const COSIGN_CMD: &str = "/usr/bin/cosign";
+
+// Maps the configured container images to the digests they were resolved to.
+const PINS_FILE: &str = "container-images.json";
+
+// Maps the digests to the `container_signature`s they were verified with.
+const SIGNATURES_FILE: &str = "container-signatures.json";
+
+/// Is `image` already pinned to a digest in the config?
+fn is_digest(image: &str) -> bool {
+    image.contains('@')
+}
+
+/// The repository part of `image`, without the tag
+///
+/// E.g. `ghcr.io/actions/actions-runner` for
+/// `ghcr.io/actions/actions-runner:latest`.
+/// A colon before the last slash belongs to the port of the registry.
+fn repository(image: &str) -> &str {
+    let name_start = image.rfind('/').map(|slash| slash + 1).unwrap_or(0);
+
+    match image[name_start..].rfind(':') {
+        Some(colon) => &image[..name_start + colon],
+        None => image,
+    }
+}
+
+fn read_state<T: DeserializeOwned + Default>(base_dir: &Path, file: &str) -> T {
+    let path = base_dir.join(file);
+
+    match std::fs::read(&path) {
+        Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+            error!("Failed to parse {}, starting over: {e}", path.display());
+            T::default()
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => T::default(),
+        Err(e) => {
+            error!("Failed to read {}, starting over: {e}", path.display());
+            T::default()
+        }
+    }
+}
+
+fn write_state<T: Serialize>(base_dir: &Path, file: &str, state: &T) {
+    let path = base_dir.join(file);
+
+    // Write to a temporary file first and move it into place,
+    // so we never leave a half written file behind.
+    let tmp_path = path.with_extension("json.tmp");
+
+    let res = serde_json::to_vec_pretty(state)
+        .map_err(std::io::Error::other)
+        .and_then(|content| std::fs::write(&tmp_path, content))
+        .and_then(|()| std::fs::rename(&tmp_path, &path));
+
+    if let Err(e) = res {
+        error!(
+            "Failed to persist container image state to {}: {e}",
+            path.display()
+        );
+    }
+}
+
+/// The image to actually start a machine with the container `image` from
+///
+/// This is the digest the image was last pulled as by `update()`,
+/// so that all machines use the same, already pulled image until the next
+/// update is pulled completely.
+/// Images that were not pulled yet are used as configured.
+///
+/// With a `signature` only a digest that `update()` verified against it is
+/// used, and `None` is returned until there is one.
+pub(super) fn pinned(
+    base_dir: &Path,
+    image: &str,
+    signature: Option<&ContainerSignature>,
+) -> Option<String> {
+    let reference = match is_digest(image) {
+        true => Some(image.to_owned()),
+        false => read_state::<BTreeMap<String, String>>(base_dir, PINS_FILE).remove(image),
+    };
+
+    let Some(signature) = signature else {
+        return Some(reference.unwrap_or_else(|| image.to_owned()));
+    };
+
+    let reference = reference?;
+    let signatures: BTreeMap<String, BTreeSet<String>> = read_state(base_dir, SIGNATURES_FILE);
+
+    signatures
+        .get(&reference)?
+        .contains(&signature.to_string())
+        .then_some(reference)
+}
+
+/// Do machines with `machine_config` need `cosign` to verify their image?
+pub(super) fn needs_cosign(machine_config: &MachineConfig) -> bool {
+    machine_config.backend == Backend::Kata && machine_config.container_signature.is_some()
+}
+
+/// Pull `image` and get the digest reference it resolved to
+async fn pull(image: &str) -> std::io::Result<String> {
+    let output = Command::new(PODMAN_CMD)
+        .arg("pull")
+        .arg("--quiet")
+        .arg(image)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(format!(
+            "podman pull failed: {}",
+            stderr.trim()
+        )));
+    }
+
+    let output = Command::new(PODMAN_CMD)
+        .arg("image")
+        .arg("inspect")
+        .arg("--format={{.Digest}}")
+        .arg(image)
+        .output()
+        .await?;
+
+    let digest = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+    if !output.status.success() || !digest.starts_with("sha256:") {
+        return Err(std::io::Error::other(format!(
+            "Failed to get the digest of the pulled image: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(format!("{}@{digest}", repository(image)))
+}
+
+/// Verify that the image `reference` is signed as described by `signature`
+///
+/// `reference` should be a digest reference, so that exactly the image
+/// that was pulled is verified.
+async fn verify(reference: &str, signature: &ContainerSignature) -> std::io::Result<()> {
+    let mut cosign = Command::new(COSIGN_CMD);
+
+    cosign.arg("verify").arg("--output=text");
+
+    match signature {
+        ContainerSignature::Key(key) => {
+            cosign.arg("--key").arg(key);
+        }
+        ContainerSignature::Keyless { identity, issuer } => {
+            cosign
+                .arg("--certificate-identity")
+                .arg(identity)
+                .arg("--certificate-oidc-issuer")
+                .arg(issuer);
+        }
+    }
+
+    let output = cosign.arg(reference).output().await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(format!(
+            "cosign verify failed: {}",
+            stderr.trim()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Pull the container images of all machine types that run via podman
+///
+/// Images that are referenced by tag are pinned to the digest they resolved
+/// to, so that new machines switch to an updated image only once it is
+/// available locally.
+/// Images that fail to pull keep their previous digest.
+///
+/// Images with a `container_signature` are verified using cosign after
+/// the pull, and only switch to a new digest once it verified against all
+/// of their signatures.
+/// With `host.require_signed_images` images without one are not pulled.
+pub(super) async fn update(cfg: &ConfigFile) {
+    let mut images: BTreeMap<&str, Vec<&ContainerSignature>> = BTreeMap::new();
+
+    for mc in cfg
+        .repositories
+        .values()
+        .flat_map(|repos| repos.values())
+        .flat_map(|repo| repo.machines.values())
+        .filter(|mc| mc.backend == Backend::Kata)
+    {
+        if let Some(image) = mc.container_image.as_deref() {
+            let signatures = images.entry(image).or_default();
+            signatures.extend(mc.container_signature.as_ref());
+        }
+    }
+
+    let base_dir = &cfg.host.base_dir;
+    let mut pins: BTreeMap<String, String> = read_state(base_dir, PINS_FILE);
+    let mut verified: BTreeMap<String, BTreeSet<String>> = read_state(base_dir, SIGNATURES_FILE);
+
+    // Forget about images that are no longer configured.
+    pins.retain(|image, _| images.contains_key(image.as_str()));
+
+    for (&image, signatures) in &images {
+        if signatures.is_empty() && cfg.host.require_signed_images {
+            warn!("Not pulling container image {image}, it has no container_signature");
+            continue;
+        }
+
+        let pinned = match pull(image).await {
+            Ok(pinned) => pinned,
+            Err(e) => {
+                warn!("Failed to pull container image {image}: {e}");
+                continue;
+            }
+        };
+
+        // Verify the reference machines are actually started from.
+        let reference = match is_digest(image) {
+            true => image.to_owned(),
+            false => pinned.clone(),
+        };
+
+        let mut all_verified = true;
+
+        for signature in signatures {
+            match verify(&reference, signature).await {
+                Ok(()) => {
+                    verified
+                        .entry(reference.clone())
+                        .or_default()
+                        .insert(signature.to_string());
+                }
+                Err(e) => {
+                    error!("Container image {reference} is not signed by {signature}: {e}");
+                    all_verified = false;
+                }
+            }
+        }
+
+        if is_digest(image) || !all_verified {
+            continue;
+        }
+
+        let previous = pins.insert(image.to_owned(), pinned.clone());
+
+        if previous.as_ref() != Some(&pinned) {
+            info!("New machines using container image {image} now start from {pinned}");
+        }
+    }
+
+    // Only keep the verifications of references that are still in use.
+    verified.retain(|reference, _| {
+        images.contains_key(reference.as_str()) || pins.values().any(|p| p == reference)
+    });
+
+    write_state(base_dir, PINS_FILE, &pins);
+    write_state(base_dir, SIGNATURES_FILE, &verified);
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+
+use crate::config::{Accel, Arch, MachineConfig};
+
+const CPUINFO_PATH: &str = "/proc/cpuinfo";
+
+/// The CPU flag that exposes hardware virtualization and the kvm module
+/// that has to allow nesting it, per CPU vendor
+const NESTED: &[(&str, &str)] = &[
+    ("vmx", "/sys/module/kvm_intel/parameters/nested"),
+    ("svm", "/sys/module/kvm_amd/parameters/nested"),
+];
+
+/// qemu and the kernel do not always agree on how to spell CPU features,
+/// e.g. `sse4.1` and `sse4_1`.
+fn normalize(feature: &str) -> String {
+    feature.replace(['.', '-'], "_")
+}
+
+/// The CPU features of the host as listed in `/proc/cpuinfo`
+fn host_flags() -> Result<BTreeSet<String>, String> {
+    let cpuinfo = std::fs::read_to_string(CPUINFO_PATH)
+        .map_err(|e| format!("Failed to read {CPUINFO_PATH}: {e}"))?;
+
+    let flags = cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "flags")
+        .map(|(_, flags)| flags.split_whitespace().map(normalize).collect())
+        .unwrap_or_default();
+
+    Ok(flags)
+}
+
+/// The CPU feature to pass on to machines that run virtual machines of their own
+///
+/// Returns an error if the host does not support nested virtualization.
+pub(super) fn nested_feature() -> Result<&'static str, String> {
+    let flags = host_flags()?;
+
+    let (flag, parameter) = NESTED
+        .iter()
+        .find(|(flag, _)| flags.contains(*flag))
+        .ok_or("The host CPU does not support hardware virtualization")?;
+
+    let enabled = std::fs::read_to_string(parameter)
+        .map_err(|e| format!("Failed to read {parameter}: {e}"))?;
+
+    match enabled.trim() {
+        "Y" | "1" => Ok(*flag),
+        _ => Err(format!(
+            "Nested virtualization is disabled in the kvm module ({parameter})"
+        )),
+    }
+}
+
+/// Check that the host can provide the CPU features a machine asks for
+///
+/// `accel` is the accelerator the machine runs with, if it can run at all.
+/// Emulated machines can have any feature qemu knows about, while machines
+/// running via KVM only get those the host CPU has.
+pub(super) fn check(machine_config: &MachineConfig, accel: Option<Accel>) -> Result<(), String> {
+    if let Some(feature) = machine_config
+        .cpu_features
+        .iter()
+        .find(|f| !f.starts_with(['+', '-']))
+    {
+        return Err(format!(
+            "CPU feature {feature} has to start with + to enable or - to disable it"
+        ));
+    }
+
+    if machine_config.nested {
+        if machine_config.arch != Arch::X86_64 {
+            return Err(format!(
+                "Nested virtualization is not supported for {} machines",
+                machine_config.arch
+            ));
+        }
+
+        if accel != Some(Accel::Kvm) {
+            return Err("Nested virtualization requires KVM".to_owned());
+        }
+
+        nested_feature()?;
+    }
+
+    let enabled: Vec<&str> = machine_config
+        .cpu_features
+        .iter()
+        .filter_map(|f| f.strip_prefix('+'))
+        .collect();
+
+    if accel != Some(Accel::Kvm) || enabled.is_empty() {
+        return Ok(());
+    }
+
+    let flags = host_flags()?;
+
+    let missing: Vec<&str> = enabled
+        .into_iter()
+        .filter(|f| !flags.contains(&normalize(f)))
+        .collect();
+
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "The host CPU does not have the features {}",
+            missing.join(", ")
+        )),
+    }
+}
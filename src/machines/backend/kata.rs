@@ -0,0 +1,170 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use tokio::process::Command;
+
+use super::container_images;
+use crate::config::{HostConfig, MachineConfig};
+
+pub(super) const PODMAN_CMD: &str = "/usr/bin/podman";
+const KATA_RUNTIME: &str = "kata";
+
+// The places the kata runtime is installed to by distribution packages
+// and by the upstream release tarballs.
+const KATA_RUNTIME_PATHS: &[&str] = &[
+    "/usr/bin/kata-runtime",
+    "/usr/local/bin/kata-runtime",
+    "/opt/kata/bin/kata-runtime",
+];
+
+// Where the directories are mounted inside of the container.
+// These match the nspawn backend, so that the same setup templates work
+// for both.
+const CLOUD_INIT_DIR: &str = "/var/lib/cloud/seed/nocloud";
+const JOB_CONFIG_DIR: &str = "/var/lib/forrest/job-config";
+const SHARED_DIR: &str = "/var/lib/forrest/shared";
+
+/// Check that a machine can be run as Kata container
+pub(super) fn check(machine_config: &MachineConfig) -> Result<(), String> {
+    if machine_config.container_image.is_none() {
+        return Err("The kata backend requires a container_image".to_owned());
+    }
+
+    if machine_config.scratch.is_some() {
+        return Err("The kata backend does not support scratch disks".to_owned());
+    }
+
+    if !machine_config.devices.is_empty() {
+        return Err("The kata backend does not support device passthrough".to_owned());
+    }
+
+    if machine_config.bridge.is_some() {
+        return Err("The kata backend does not support network bridges".to_owned());
+    }
+
+    if machine_config.balloon.is_some() {
+        return Err("The kata backend does not support memory ballooning".to_owned());
+    }
+
+    if machine_config.cpu_pin || machine_config.numa_node.is_some() {
+        return Err("The kata backend does not support CPU pinning".to_owned());
+    }
+
+    Ok(())
+}
+
+/// Check that the kata runtime is installed on the host
+pub(super) fn check_runtime() -> Option<String> {
+    let installed = KATA_RUNTIME_PATHS
+        .iter()
+        .any(|path| std::path::Path::new(path).is_file());
+
+    (!installed).then(|| {
+        format!(
+            "The kata runtime is not installed (looked in {})",
+            KATA_RUNTIME_PATHS.join(", ")
+        )
+    })
+}
+
+fn volume_arg(writable: bool, host: &Path, container: &str) -> OsString {
+    let mut arg = OsString::from("--volume=");
+
+    arg.push(host.as_os_str());
+    arg.push(":");
+    arg.push(container);
+
+    if !writable {
+        arg.push(":ro");
+    }
+
+    arg
+}
+
+/// Assemble the podman command to remove the container of a machine
+///
+/// This is needed if podman was killed, which stops neither the container
+/// nor its conmon process and keeps `--rm` from taking effect.
+pub(super) fn remove_command(path: &Path) -> Command {
+    let mut podman = Command::new(PODMAN_CMD);
+
+    podman
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .arg("rm")
+        .arg("--force")
+        .arg("--ignore")
+        .arg(path.file_name().unwrap());
+
+    podman
+}
+
+/// Assemble the podman command to run the container image of a machine using the kata runtime
+///
+/// Kata runs every container in a lightweight virtual machine of its own,
+/// so the jobs are isolated from the host like they are with qemu,
+/// while starting from a container image.
+/// The image has to boot systemd and cloud-init like a disk image would.
+/// It is started from the digest it was last pulled as (see `container_images`),
+/// which has to be verified if the machine has a `container_signature`.
+pub(super) fn command(
+    host: &HostConfig,
+    machine_config: &MachineConfig,
+    path: &Path,
+) -> std::io::Result<Command> {
+    let log = File::create(path.join("log.txt"))?;
+
+    let mut name_arg = OsString::from("--name=");
+    name_arg.push(path.file_name().unwrap());
+
+    // This is made sure of in `check()`.
+    let image = machine_config.container_image.as_deref().unwrap();
+    let signature = machine_config.container_signature.as_ref();
+
+    if signature.is_none() && host.require_signed_images {
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Container image {image} has no container_signature, which host.require_signed_images requires"),
+        ));
+    }
+
+    let image = container_images::pinned(&host.base_dir, image, signature).ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("No verified digest of container image {image} available (yet)"),
+        )
+    })?;
+
+    let shared_args = machine_config.shared.iter().map(|dir| {
+        let container = format!("{SHARED_DIR}/{}", dir.tag);
+
+        volume_arg(dir.writable, &dir.path, &container)
+    });
+
+    let mut podman = Command::new(PODMAN_CMD);
+
+    podman
+        .kill_on_drop(true)
+        .current_dir(path)
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .arg("run")
+        .arg("--rm")
+        .arg("--pull=missing")
+        .arg(format!("--runtime={KATA_RUNTIME}"))
+        .arg("--systemd=always")
+        .arg(name_arg)
+        .arg(format!("--memory={}", machine_config.ram.bytes()))
+        .arg(format!("--cpus={}", machine_config.cpus))
+        .arg(volume_arg(false, &path.join("cloud-init"), CLOUD_INIT_DIR))
+        .arg(volume_arg(true, &path.join("job-config"), JOB_CONFIG_DIR))
+        .args(shared_args)
+        .arg(&image)
+        .arg("/sbin/init");
+
+    Ok(podman)
+}
@@ -0,0 +1,256 @@
+use std::fs::File;
+use std::path::Path;
+use std::process::Stdio;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::config::MachineConfig;
+
+pub(super) const KUBECTL_CMD: &str = "/usr/bin/kubectl";
+
+// Where the configuration is mounted inside of the pod.
+// These match the nspawn and kata backends, so that the same images and
+// setup templates work for all of them.
+const CLOUD_INIT_DIR: &str = "/var/lib/cloud/seed/nocloud";
+const JOB_CONFIG_DIR: &str = "/var/lib/forrest/job-config";
+
+// Allow for slow image pulls and cluster auto-scaling before giving up
+// on a pod that does not start.
+const POD_RUNNING_TIMEOUT: &str = "15m";
+
+/// Check that a machine can be run as Kubernetes pod
+pub(super) fn check(machine_config: &MachineConfig) -> Result<(), String> {
+    if machine_config.container_image.is_none() {
+        return Err("The kubernetes backend requires a container_image".to_owned());
+    }
+
+    if machine_config.scratch.is_some() {
+        return Err("The kubernetes backend does not support scratch disks".to_owned());
+    }
+
+    if !machine_config.devices.is_empty() {
+        return Err("The kubernetes backend does not support device passthrough".to_owned());
+    }
+
+    if machine_config.bridge.is_some() {
+        return Err("The kubernetes backend does not support network bridges".to_owned());
+    }
+
+    if machine_config.balloon.is_some() {
+        return Err("The kubernetes backend does not support memory ballooning".to_owned());
+    }
+
+    // The image is pulled by the cluster, which has to verify it itself,
+    // e.g. using an admission policy.
+    if machine_config.container_signature.is_some() {
+        return Err("The kubernetes backend does not support container_signature".to_owned());
+    }
+
+    if machine_config.cpu_pin || machine_config.numa_node.is_some() {
+        return Err("The kubernetes backend does not support CPU pinning".to_owned());
+    }
+
+    if !machine_config.shared.is_empty() {
+        return Err("The kubernetes backend does not support shared directories".to_owned());
+    }
+
+    Ok(())
+}
+
+/// Get the name of the secret holding the files of a config directory of pod `name`
+fn secret_name(name: &str, dir_name: &str) -> String {
+    format!("{name}-{dir_name}")
+}
+
+/// Turn the files in a config directory into a secret
+///
+/// The pod may run on any node of the cluster, so the files can not be
+/// bind mounted from the host.
+/// They contain credentials like the JIT runner config,
+/// so they are passed as secret instead of e.g. in the pod spec.
+fn config_secret(dir: &Path, name: &str) -> std::io::Result<Value> {
+    let mut data = serde_json::Map::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let content = std::fs::read(entry.path())?;
+
+        data.insert(file_name, Value::String(STANDARD.encode(content)));
+    }
+
+    Ok(json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {
+            "name": name,
+            "labels": { "app.kubernetes.io/managed-by": "forrest" },
+        },
+        "type": "Opaque",
+        "data": data,
+    }))
+}
+
+/// Get a kubectl command talking to the configured cluster and namespace
+fn kubectl(machine_config: &MachineConfig) -> Command {
+    let kubernetes = &machine_config.kubernetes;
+
+    let mut kubectl = Command::new(KUBECTL_CMD);
+
+    if let Some(kubeconfig) = &kubernetes.kubeconfig {
+        kubectl.arg("--kubeconfig").arg(kubeconfig);
+    }
+
+    if let Some(namespace) = &kubernetes.namespace {
+        kubectl.arg(format!("--namespace={namespace}"));
+    }
+
+    kubectl
+}
+
+/// Create the secrets holding the cloud-init and job configuration of a machine
+///
+/// This has to happen before the `command()` is run.
+pub(super) async fn create_secrets(
+    machine_config: &MachineConfig,
+    path: &Path,
+) -> std::io::Result<()> {
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+    let secrets = json!({
+        "apiVersion": "v1",
+        "kind": "List",
+        "items": [
+            config_secret(&path.join("cloud-init"), &secret_name(&name, "cloud-init"))?,
+            config_secret(&path.join("job-config"), &secret_name(&name, "job-config"))?,
+        ],
+    });
+
+    let mut kubectl = kubectl(machine_config);
+
+    kubectl
+        .kill_on_drop(true)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .arg("create")
+        .arg("--filename=-");
+
+    let mut child = kubectl.spawn()?;
+
+    // Close stdin once written, so that kubectl knows we are done.
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(secrets.to_string().as_bytes()).await?;
+    }
+
+    let output = child.wait_with_output().await?;
+
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(std::io::Error::other(format!(
+            "Failed to create the secrets of pod {name}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+/// Assemble the kubectl command to remove the pod and secrets of a machine
+///
+/// Killing `kubectl run` leaves the pod running on the cluster,
+/// and the secrets are not removed along with the pod either.
+pub(super) fn delete_command(machine_config: &MachineConfig, path: &Path) -> Command {
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+    let mut kubectl = kubectl(machine_config);
+
+    kubectl
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .arg("delete")
+        .arg(format!("pod/{name}"))
+        .arg(format!("secret/{}", secret_name(&name, "cloud-init")))
+        .arg(format!("secret/{}", secret_name(&name, "job-config")))
+        .arg("--ignore-not-found")
+        .arg("--wait=false");
+
+    kubectl
+}
+
+/// Assemble the kubectl command to run the container image of a machine as pod
+///
+/// The pod is created in the configured cluster and namespace,
+/// kubectl waits for it to complete and removes it afterwards.
+/// The secrets it mounts have to be created first, see `create_secrets()`.
+/// The image has to pick up the cloud-init and job configuration from the
+/// same directories as with the kata backend and exit once the job is done.
+pub(super) fn command(machine_config: &MachineConfig, path: &Path) -> std::io::Result<Command> {
+    let log = File::create(path.join("log.txt"))?;
+
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+    // This is made sure of in `check()`.
+    let image = machine_config.container_image.as_deref().unwrap();
+
+    // Only root inside of the pod may read the secrets.
+    let volume = |dir_name| {
+        json!({
+            "name": dir_name,
+            "secret": { "secretName": secret_name(&name, dir_name), "defaultMode": 0o400 },
+        })
+    };
+
+    let resources = json!({
+        "memory": machine_config.ram.bytes().to_string(),
+        "cpu": machine_config.cpus.to_string(),
+    });
+
+    // `kubectl run` merges this into the pod it generates.
+    // Lists are replaced as a whole, so the container is described completely.
+    let overrides = json!({
+        "apiVersion": "v1",
+        "metadata": {
+            "labels": {
+                "app.kubernetes.io/managed-by": "forrest",
+                "forrest/runner-name": name,
+            },
+        },
+        "spec": {
+            "automountServiceAccountToken": false,
+            "containers": [{
+                "name": "runner",
+                "image": image,
+                "resources": { "requests": resources, "limits": resources },
+                "volumeMounts": [
+                    { "name": "cloud-init", "mountPath": CLOUD_INIT_DIR, "readOnly": true },
+                    { "name": "job-config", "mountPath": JOB_CONFIG_DIR, "readOnly": true },
+                ],
+            }],
+            "volumes": [volume("cloud-init"), volume("job-config")],
+        },
+    });
+
+    let mut kubectl = kubectl(machine_config);
+
+    kubectl
+        .kill_on_drop(true)
+        .current_dir(path)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .arg("run")
+        .arg(&name)
+        .arg(format!("--image={image}"))
+        .arg("--restart=Never")
+        .arg("--rm")
+        .arg("--attach")
+        .arg("--quiet")
+        .arg(format!("--pod-running-timeout={POD_RUNNING_TIMEOUT}"))
+        .arg(format!("--overrides={overrides}"));
+
+    Ok(kubectl)
+}
@@ -0,0 +1,113 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::Path;
+
+use tokio::process::Command;
+
+use crate::config::{MachineConfig, Tenant};
+
+pub(super) const NSPAWN_CMD: &str = "/usr/bin/systemd-nspawn";
+
+// Where the directories are bind mounted inside of the container.
+// The cloud-init NoCloud datasource picks up its seed directory on its own,
+// the job config directory has to be mounted in place by the setup template.
+const CLOUD_INIT_DIR: &str = "/var/lib/cloud/seed/nocloud";
+const JOB_CONFIG_DIR: &str = "/var/lib/forrest/job-config";
+const SHARED_DIR: &str = "/var/lib/forrest/shared";
+
+/// Check that a machine can be run as container
+///
+/// Containers share the kernel with the host and are thus only suitable
+/// for trusted jobs.
+pub(super) fn check(machine_config: &MachineConfig) -> Result<(), String> {
+    if !machine_config.trusted {
+        return Err("The nspawn backend may only be used for trusted machines".to_owned());
+    }
+
+    if machine_config.scratch.is_some() {
+        return Err("The nspawn backend does not support scratch disks".to_owned());
+    }
+
+    if !machine_config.devices.is_empty() {
+        return Err("The nspawn backend does not support device passthrough".to_owned());
+    }
+
+    if machine_config.balloon.is_some() {
+        return Err("The nspawn backend does not support memory ballooning".to_owned());
+    }
+
+    if machine_config.cpu_pin || machine_config.numa_node.is_some() {
+        return Err("The nspawn backend does not support CPU pinning".to_owned());
+    }
+
+    Ok(())
+}
+
+fn bind_arg(writable: bool, host: &std::path::Path, container: &str) -> OsString {
+    let mut arg = OsString::from(match writable {
+        true => "--bind=",
+        false => "--bind-ro=",
+    });
+
+    arg.push(host.as_os_str());
+    arg.push(":");
+    arg.push(container);
+
+    arg
+}
+
+/// Assemble the systemd-nspawn command to boot the disk image in the run directory `path` as container
+///
+/// The container is connected to the network bridge of the machine or
+/// tenant (if configured) and shares the network with the host otherwise.
+pub(super) fn command(
+    machine_config: &MachineConfig,
+    tenant: Option<&Tenant>,
+    path: &Path,
+) -> std::io::Result<Command> {
+    // Unlike qemu systemd-nspawn can not write the console output to a file
+    // on its own.
+    let log = File::create(path.join("log.txt"))?;
+
+    let machine_name = path.file_name().unwrap().to_os_string();
+
+    let mut machine_arg = OsString::from("--machine=");
+    machine_arg.push(machine_name);
+
+    let ram = machine_config.ram.bytes();
+    let cpu_quota = machine_config.cpus * 100;
+
+    let shared_args = machine_config.shared.iter().map(|dir| {
+        let container = format!("{SHARED_DIR}/{}", dir.tag);
+
+        bind_arg(dir.writable, &dir.path, &container)
+    });
+
+    let network_args = machine_config
+        .bridge
+        .as_deref()
+        .or(tenant.and_then(|t| t.bridge.as_deref()))
+        .map(|bridge| format!("--network-bridge={bridge}"));
+
+    let mut nspawn = Command::new(NSPAWN_CMD);
+
+    nspawn
+        .kill_on_drop(true)
+        .current_dir(path)
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .arg("--quiet")
+        .arg("--boot")
+        .arg("--console=pipe")
+        .arg("--image=disk.img")
+        .arg(machine_arg)
+        .arg(format!("--property=MemoryMax={ram}"))
+        .arg(format!("--property=CPUQuota={cpu_quota}%"))
+        .arg(bind_arg(false, &path.join("cloud-init"), CLOUD_INIT_DIR))
+        .arg(bind_arg(true, &path.join("job-config"), JOB_CONFIG_DIR))
+        .args(shared_args)
+        .args(network_args);
+
+    Ok(nspawn)
+}
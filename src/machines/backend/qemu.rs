@@ -0,0 +1,403 @@
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use tokio::process::Command;
+
+use super::super::metadata;
+use super::super::pinning::Pinning;
+use super::super::tenancy;
+use super::cpu;
+use super::shares;
+use crate::config::{Accel, Arch, HostDevice, MachineConfig, SharedFs, Tenant};
+
+// The arguments used to start the qemu process.
+//
+// These assume a specific filesystem structure,
+// as set up by `RunDir`.
+// More arguments are added in `command()` based on
+// the machine configuration and its architecture.
+pub(super) const KVM_DEVICE: &str = "/dev/kvm";
+// Used to start qemu with its threads restricted to the pinned cores.
+// taskset executes qemu in place, so its pid is the one of qemu.
+const TASKSET_CMD: &str = "/usr/bin/taskset";
+const QEMU_DRIVE_DISK: &str = "if=virtio,format=raw,discard=unmap,cache=unsafe,file=disk.img";
+const QEMU_DRIVE_OVERLAY: &str =
+    "if=virtio,format=qcow2,discard=unmap,cache=unsafe,file=disk.qcow2";
+const QEMU_NETDEV_USER: &str = "user,id=uplink,ipv4=on,ipv6=on,ipv6-net=::/0";
+const QEMU_ARGS: &[&[&str]] = &[
+    &["-nodefaults"],
+    &["-nographic"],
+    &["-device", "virtio-net-pci,netdev=uplink"],
+    &["-object", "rng-random,filename=/dev/urandom,id=rng0"],
+    &["-device", "virtio-rng-pci,rng=rng0,id=rng-device0"],
+    &["-chardev", "file,id=bootlog,path=log.txt"],
+    &[
+        "-chardev",
+        "socket,id=telnet,server=on,wait=off,path=shell.sock",
+    ],
+    // Used to pause, resume and power down the machine.
+    &["-qmp", "unix:qmp.sock,server=on,wait=off"],
+    // Used to learn when the guest has shut down.
+    &["-qmp", "unix:qmp-events.sock,server=on,wait=off"],
+    &[
+        "-drive",
+        "if=virtio,format=raw,discard=unmap,cache=unsafe,file=cloud-init.img",
+    ],
+    &[
+        "-drive",
+        "if=virtio,format=raw,discard=unmap,cache=unsafe,file=job-config.img",
+    ],
+];
+
+/// How to run machines of an architecture with qemu
+struct ArchProfile {
+    qemu_cmd: &'static str,
+    machine: &'static str,
+    cpu_model: &'static str,
+    /// The option to pass the firmware with and its default path, if any
+    firmware: Option<(&'static str, &'static str)>,
+    /// Connects the `bootlog` and `telnet` chardevs to serial devices
+    serial_args: &'static [&'static str],
+}
+
+const X86_64: ArchProfile = ArchProfile {
+    qemu_cmd: "/usr/bin/qemu-system-x86_64",
+    machine: "type=q35,smm=on",
+    cpu_model: "max",
+    firmware: None,
+    serial_args: &[
+        "-global",
+        "ICH9-LPC.disable_s3=1",
+        "-device",
+        "isa-serial,chardev=bootlog",
+        "-device",
+        "isa-serial,chardev=telnet",
+    ],
+};
+
+// The virt machines only have a single UART,
+// the shell is provided via a virtio console (hvc0) instead.
+const VIRT_SERIAL_ARGS: &[&str] = &[
+    "-serial",
+    "chardev:bootlog",
+    "-device",
+    "virtio-serial-pci",
+    "-device",
+    "virtconsole,chardev=telnet",
+];
+
+const AARCH64: ArchProfile = ArchProfile {
+    qemu_cmd: "/usr/bin/qemu-system-aarch64",
+    machine: "type=virt,gic-version=max",
+    cpu_model: "max",
+    firmware: Some(("-bios", "/usr/share/qemu-efi-aarch64/QEMU_EFI.fd")),
+    serial_args: VIRT_SERIAL_ARGS,
+};
+
+// OpenSBI is built into qemu and starts u-boot, which boots the disk image.
+const RISCV64: ArchProfile = ArchProfile {
+    qemu_cmd: "/usr/bin/qemu-system-riscv64",
+    machine: "type=virt",
+    cpu_model: "max",
+    firmware: Some(("-kernel", "/usr/lib/u-boot/qemu-riscv64_smode/uboot.elf")),
+    serial_args: VIRT_SERIAL_ARGS,
+};
+
+fn profile(arch: Arch) -> &'static ArchProfile {
+    match arch {
+        Arch::X86_64 => &X86_64,
+        Arch::Aarch64 => &AARCH64,
+        Arch::Riscv64 => &RISCV64,
+    }
+}
+
+/// The qemu binary that runs machines of `arch`
+pub(super) fn qemu_cmd(arch: Arch) -> &'static str {
+    profile(arch).qemu_cmd
+}
+
+/// The firmware a machine with `machine_config` boots from, if its arch needs one
+pub(super) fn firmware(machine_config: &MachineConfig) -> Option<PathBuf> {
+    let (_, default) = profile(machine_config.arch).firmware?;
+
+    let firmware = machine_config
+        .firmware
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(default));
+
+    Some(firmware)
+}
+
+/// The accelerator qemu runs a machine with, either `Kvm` or `Tcg`
+///
+/// With `accel: auto` machines of the host architecture run accelerated via
+/// KVM if it is usable, all others are emulated via TCG.
+/// Returns an error if the machine requires KVM, but can not use it.
+pub(super) fn accelerator(machine_config: &MachineConfig) -> Result<Accel, String> {
+    let native = machine_config.arch.is_native();
+
+    match machine_config.accel {
+        Accel::Kvm if !native => Err(format!(
+            "KVM can not run {} machines on this host",
+            machine_config.arch
+        )),
+        Accel::Kvm => match super::check_device(KVM_DEVICE) {
+            Some(problem) => Err(format!("The machine requires KVM: {problem}")),
+            None => Ok(Accel::Kvm),
+        },
+        Accel::Auto if native && super::check_device(KVM_DEVICE).is_none() => Ok(Accel::Kvm),
+        Accel::Auto | Accel::Tcg => Ok(Accel::Tcg),
+    }
+}
+
+/// The arguments that differ between architectures and accelerators
+fn arch_args(machine_config: &MachineConfig) -> std::io::Result<Vec<OsString>> {
+    let profile = profile(machine_config.arch);
+
+    let accel = accelerator(machine_config).map_err(std::io::Error::other)?;
+
+    // Nested virtualization passes the virtualization extensions of the
+    // host CPU on to the machine, which is what the `host` model is for.
+    let default_cpu_model = match machine_config.nested {
+        true => "host",
+        false => profile.cpu_model,
+    };
+
+    let mut cpu = machine_config
+        .cpu_model
+        .as_deref()
+        .unwrap_or(default_cpu_model)
+        .to_owned();
+
+    if machine_config.nested {
+        let feature = cpu::nested_feature().map_err(std::io::Error::other)?;
+        write!(cpu, ",+{feature}").unwrap();
+    }
+
+    for feature in &machine_config.cpu_features {
+        write!(cpu, ",{feature}").unwrap();
+    }
+
+    let mut args: Vec<OsString> = vec![
+        "-M".into(),
+        format!("{},accel={accel}", profile.machine).into(),
+        "-cpu".into(),
+        cpu.into(),
+    ];
+
+    if let (Some((option, _)), Some(firmware)) = (profile.firmware, firmware(machine_config)) {
+        args.push(option.into());
+        args.push(firmware.into_os_string());
+    }
+
+    args.extend(profile.serial_args.iter().map(OsString::from));
+
+    Ok(args)
+}
+
+/// The option to forward guest connections to the metadata address to the metadata socket
+///
+/// qemu starts a `forrest metadata-proxy` for each connection.
+/// Returns an empty string if the path of our own executable is unknown.
+fn metadata_forward() -> String {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            warn!("Not forwarding machine metadata, failed to locate the forrest binary: {e}");
+            return String::new();
+        }
+    };
+
+    format!(
+        ",guestfwd=tcp:{}-cmd:{} metadata-proxy {}",
+        metadata::GUEST_ADDRESS,
+        exe.display(),
+        metadata::SOCKET
+    )
+}
+
+/// Assemble the qemu command to run a machine with `machine_config` in `run_dir`
+///
+/// If a `tenant` is given qemu is run as the tenant's user and connected
+/// to the tenant's network bridge (if configured).
+/// A `bridge` in the `machine_config` takes precedence over the tenant's.
+/// The `devices` are passed through to the machine.
+/// With a `pinning` qemu only runs on the pinned cores and its RAM is
+/// allocated on their NUMA node.
+/// The `scratch` disk is attached in addition to the images in the `run_dir`.
+pub(super) fn command(
+    machine_config: &MachineConfig,
+    tenant: Option<&Tenant>,
+    devices: &[&HostDevice],
+    pinning: Option<&Pinning>,
+    run_dir: &Path,
+    scratch: Option<&Path>,
+) -> std::io::Result<Command> {
+    // Set up virtfs directory forwarding from the host to the machine.
+    let virtfs_args = machine_config
+        .shared
+        .iter()
+        .filter(|dir| dir.fs == SharedFs::NineP)
+        .flat_map(|dir| {
+            let mut arg = OsString::new();
+
+            let tag = &dir.tag;
+            let readonly = if dir.writable { "off" } else { "on" };
+
+            write!(&mut arg, "local,security_model=none,",).unwrap();
+            write!(&mut arg, "mount_tag={tag},readonly={readonly},path=",).unwrap();
+
+            arg.push(dir.path.as_os_str());
+
+            ["-virtfs".into(), arg].into_iter()
+        });
+
+    // virtiofs shares are served by the virtiofsd started via `shares::start()`.
+    let virtiofs_args = shares::virtiofs(machine_config).flat_map(|(index, dir)| {
+        let socket = shares::socket_name(index);
+        let tag = &dir.tag;
+
+        [
+            "-chardev".to_owned(),
+            format!("socket,id=virtiofs{index},path={socket}"),
+            "-device".to_owned(),
+            format!("vhost-user-fs-pci,chardev=virtiofs{index},tag={tag}"),
+        ]
+    });
+
+    // Attach the scratch disk, which lives outside of the run dir.
+    let scratch_args = scratch
+        .map(|scratch| {
+            let mut arg = OsString::from("if=virtio,format=raw,discard=unmap,cache=unsafe,file=");
+            arg.push(scratch.as_os_str());
+
+            ["-drive".into(), arg]
+        })
+        .into_iter()
+        .flatten();
+
+    // Pass USB devices through via an xHCI controller and PCI devices via vfio.
+    let has_usb = devices.iter().any(|dev| matches!(dev, HostDevice::Usb(_)));
+
+    let usb_controller_args = has_usb
+        .then_some(["-device".to_owned(), "qemu-xhci,id=xhci".to_owned()])
+        .into_iter()
+        .flatten();
+
+    // Allow reclaiming the RAM of idle machines.
+    // The guest may take the memory back if it would otherwise run out of it.
+    let balloon_args = machine_config
+        .balloon
+        .is_some()
+        .then_some([
+            "-device",
+            "virtio-balloon-pci,id=balloon0,deflate-on-oom=on",
+        ])
+        .into_iter()
+        .flatten();
+
+    let device_args = devices.iter().flat_map(|dev| {
+        let arg = match dev {
+            HostDevice::Usb(path) => {
+                // A path like "1-2.3" is bus 1, port 2.3.
+                let (bus, port) = path.split_once('-').unwrap_or((path, ""));
+                format!("usb-host,bus=xhci.0,hostbus={bus},hostport={port}")
+            }
+            HostDevice::Pci(address) => format!("vfio-pci,host={address}"),
+        };
+
+        ["-device".to_owned(), arg]
+    });
+
+    // Either use user mode networking (the default) or connect the machine
+    // to a dedicated bridge via the qemu bridge helper.
+    let bridge = machine_config
+        .bridge
+        .as_deref()
+        .or(tenant.and_then(|t| t.bridge.as_deref()));
+
+    let netdev = match bridge {
+        Some(bridge) => format!("bridge,id=uplink,br={bridge}"),
+        None => format!("{QEMU_NETDEV_USER}{}", metadata_forward()),
+    };
+
+    // Boot from the qcow2 overlay instead of a copy of the image, if one was set up.
+    // The disk has to be the first drive, so it shows up as the first disk
+    // in the machine.
+    let disk = match run_dir.join("disk.qcow2").is_symlink() {
+        true => QEMU_DRIVE_OVERLAY,
+        false => QEMU_DRIVE_DISK,
+    };
+
+    // Assemble the complete set of arguments to pass to the qemu command.
+    let ram = machine_config.ram.megabytes().to_string();
+    let smp = machine_config.cpus.to_string();
+
+    // Bind the RAM of pinned machines to the NUMA node of their cores.
+    // virtiofsd needs access to the RAM of the machine, so it has to be shared.
+    let numa = pinning.map(|pinning| format!(",host-nodes={},policy=bind", pinning.node));
+    let virtiofs = shares::uses_virtiofs(machine_config);
+
+    let memory_backend = match (virtiofs, numa) {
+        (true, numa) => Some(format!(
+            "memory-backend-memfd,id=ram0,size={ram}M,share=on{}",
+            numa.unwrap_or_default()
+        )),
+        (false, Some(numa)) => Some(format!("memory-backend-ram,id=ram0,size={ram}M{numa}")),
+        (false, None) => None,
+    };
+
+    let memory_backend_args = memory_backend
+        .map(|backend| {
+            [
+                "-object".to_owned(),
+                backend,
+                "-machine".to_owned(),
+                "memory-backend=ram0".to_owned(),
+            ]
+        })
+        .into_iter()
+        .flatten();
+
+    let qemu_cmd = qemu_cmd(machine_config.arch);
+
+    let mut qemu = match pinning {
+        Some(pinning) => {
+            let mut taskset = Command::new(TASKSET_CMD);
+            taskset
+                .arg("--cpu-list")
+                .arg(pinning.cpu_list())
+                .arg(qemu_cmd);
+            taskset
+        }
+        None => Command::new(qemu_cmd),
+    };
+
+    qemu.kill_on_drop(true)
+        .current_dir(run_dir)
+        .arg("-m")
+        .arg(&ram)
+        .arg("-smp")
+        .arg(&smp)
+        .arg("-drive")
+        .arg(disk)
+        .args(arch_args(machine_config)?)
+        .args(QEMU_ARGS.iter().flat_map(|arg_list| *arg_list))
+        .arg("-netdev")
+        .arg(&netdev)
+        .args(scratch_args)
+        .args(virtfs_args)
+        .args(virtiofs_args)
+        .args(usb_controller_args)
+        .args(device_args)
+        .args(balloon_args)
+        .args(memory_backend_args);
+
+    if let Some(user) = tenancy::user(tenant)? {
+        qemu.uid(user.uid.as_raw()).gid(user.gid.as_raw());
+    }
+
+    Ok(qemu)
+}
@@ -0,0 +1,162 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+// The QEMU Machine Protocol socket qemu creates in the run directory.
+const QMP_SOCKET: &str = "qmp.sock";
+
+// A second QMP socket that is kept connected to receive events on.
+// qemu only serves one client per socket at a time, so this keeps
+// `QMP_SOCKET` free for commands.
+const QMP_EVENTS_SOCKET: &str = "qmp-events.sock";
+
+// Commands like `stop` and `cont` complete right away.
+// Do not hang on a machine that does not respond.
+const QMP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the next reply from QMP, skipping asynchronous events
+async fn reply(lines: &mut (impl AsyncBufReadExt + Unpin)) -> std::io::Result<Value> {
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+
+        if lines.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "QMP connection closed",
+            ));
+        }
+
+        let msg: Value = serde_json::from_str(&line)?;
+
+        if msg.get("event").is_none() {
+            return Ok(msg);
+        }
+    }
+}
+
+/// Execute a QMP command via `write` and wait for its reply on `read`
+///
+/// Returns the value the command returned.
+async fn command(
+    read: &mut (impl AsyncBufReadExt + Unpin),
+    write: &mut (impl AsyncWriteExt + Unpin),
+    command: &str,
+    arguments: Value,
+) -> std::io::Result<Value> {
+    let msg = json!({ "execute": command, "arguments": arguments }).to_string() + "\n";
+    write.write_all(msg.as_bytes()).await?;
+
+    let mut res = reply(read).await?;
+
+    if let Some(error) = res.get("error") {
+        return Err(std::io::Error::other(format!(
+            "QMP command {command} failed: {error}"
+        )));
+    }
+
+    Ok(res["return"].take())
+}
+
+/// Connect to the QMP `socket` and enable the command mode
+async fn connect(
+    socket: &Path,
+) -> std::io::Result<(
+    BufReader<tokio::net::unix::OwnedReadHalf>,
+    tokio::net::unix::OwnedWriteHalf,
+)> {
+    let stream = UnixStream::connect(socket).await?;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    // The greeting, which has to be answered by enabling the command mode.
+    reply(&mut read).await?;
+    command(&mut read, &mut write, "qmp_capabilities", json!({})).await?;
+
+    Ok((read, write))
+}
+
+/// Execute a QMP command with `arguments` and wait for its completion
+async fn execute_inner(socket: &Path, cmd: &str, arguments: Value) -> std::io::Result<Value> {
+    let (mut read, mut write) = connect(socket).await?;
+
+    command(&mut read, &mut write, cmd, arguments).await
+}
+
+/// Execute `command` via the QMP socket of the machine in `run_dir`
+pub(super) async fn execute(run_dir: &Path, command: &str) -> std::io::Result<()> {
+    execute_with(run_dir, command, json!({})).await
+}
+
+/// Execute `command` with `arguments` via the QMP socket of the machine in `run_dir`
+pub(super) async fn execute_with(
+    run_dir: &Path,
+    command: &str,
+    arguments: Value,
+) -> std::io::Result<()> {
+    query(run_dir, command, arguments).await.map(|_| ())
+}
+
+/// Execute `command` with `arguments` via the QMP socket of the machine in `run_dir`
+/// and return what it returned
+pub(super) async fn query(
+    run_dir: &Path,
+    command: &str,
+    arguments: Value,
+) -> std::io::Result<Value> {
+    let socket = run_dir.join(QMP_SOCKET);
+
+    timeout(QMP_TIMEOUT, execute_inner(&socket, command, arguments))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "QMP command timed out"))?
+}
+
+/// Wait for the QMP `event` of the machine in `run_dir`
+///
+/// Returns the data of the event.
+/// This waits for as long as it takes, so it should be raced against
+/// the exit of the machine process.
+pub(super) async fn wait_for_event(run_dir: &Path, event: &str) -> std::io::Result<Value> {
+    let socket = run_dir.join(QMP_EVENTS_SOCKET);
+
+    // qemu creates the socket shortly after it was started.
+    let (mut read, _write) = timeout(QMP_TIMEOUT, async {
+        loop {
+            match connect(&socket).await {
+                Ok(connection) => break Ok(connection),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    tokio::time::sleep(Duration::from_millis(100)).await
+                }
+                Err(e) => break Err(e),
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, "QMP socket did not appear")
+    })??;
+
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+
+        if read.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "QMP connection closed",
+            ));
+        }
+
+        let mut msg: Value = serde_json::from_str(&line)?;
+
+        if msg["event"] == event {
+            return Ok(msg["data"].take());
+        }
+    }
+}
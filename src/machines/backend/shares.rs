@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+use tokio::process::{Child, Command};
+
+use super::super::tenancy;
+use crate::config::{ExposedDirectory, MachineConfig, SharedFs, Tenant};
+
+pub(super) const VIRTIOFSD_CMD: &str = "/usr/libexec/virtiofsd";
+
+// virtiofsd creates its socket right after it started.
+const VIRTIOFSD_TIMEOUT: Duration = Duration::from_secs(5);
+const VIRTIOFSD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The virtiofsd instances serving the shared directories of a machine
+///
+/// The daemons are killed once this is dropped.
+pub struct Daemons {
+    _children: Vec<Child>,
+}
+
+/// The socket qemu connects to for the `index`th entry of `shared`
+///
+/// The socket is named after the index instead of the tag,
+/// which may contain characters that do not belong in a path.
+pub(super) fn socket_name(index: usize) -> String {
+    format!("virtiofs-{index}.sock")
+}
+
+/// The shared directories of a machine that are passed through via virtiofs
+pub(super) fn virtiofs(
+    machine_config: &MachineConfig,
+) -> impl Iterator<Item = (usize, &ExposedDirectory)> {
+    machine_config
+        .shared
+        .iter()
+        .enumerate()
+        .filter(|(_, dir)| dir.fs == SharedFs::Virtiofs)
+}
+
+/// Does the machine use any virtiofs shares?
+///
+/// These require the RAM of the machine to be shared with virtiofsd.
+pub(super) fn uses_virtiofs(machine_config: &MachineConfig) -> bool {
+    virtiofs(machine_config).next().is_some()
+}
+
+/// Collect all regular files below `dir` with their size and last use
+fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, u64, SystemTime)>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+
+        if meta.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if meta.is_file() {
+            let used = meta.accessed().or_else(|_| meta.modified())?;
+            files.push((entry.path(), meta.len(), used));
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the least recently used files below `dir` until it fits into `limit` bytes
+///
+/// Returns the number of bytes removed.
+fn trim(dir: &Path, limit: u64) -> std::io::Result<u64> {
+    let mut all = Vec::new();
+    collect_files(dir, &mut all)?;
+
+    let mut total: u64 = all.iter().map(|(_, size, _)| size).sum();
+    let mut removed = 0;
+
+    all.sort_by_key(|(_, _, used)| *used);
+
+    for (path, size, _) in all {
+        if total <= limit {
+            break;
+        }
+
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                total -= size;
+                removed += size;
+            }
+            Err(e) => warn!(
+                "Failed to evict {} from shared directory: {e}",
+                path.display()
+            ),
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Bring the shared directories of a machine with a `size_limit` back below it
+///
+/// This happens before each start of a machine,
+/// so only directories whose contents can be re-created, like caches,
+/// should have a `size_limit`.
+pub(super) async fn enforce_limits(machine_config: &MachineConfig) {
+    for dir in &machine_config.shared {
+        let limit = match &dir.size_limit {
+            Some(limit) => limit.bytes(),
+            None => continue,
+        };
+
+        // Walking a large cache takes a while, do not block the runtime meanwhile.
+        let path = dir.path.clone();
+        let res = tokio::task::spawn_blocking(move || trim(&path, limit))
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|res| res);
+
+        match res {
+            Ok(0) => {}
+            Ok(removed) => info!(
+                "Evicted {removed} bytes from shared directory {} to stay below its size_limit",
+                dir.path.display()
+            ),
+            Err(e) => warn!(
+                "Failed to trim shared directory {}: {e}",
+                dir.path.display()
+            ),
+        }
+    }
+}
+
+/// Wait for virtiofsd to create its `socket`
+async fn wait_for_socket(child: &mut Child, socket: &Path) -> std::io::Result<()> {
+    let start = Instant::now();
+
+    while !socket.exists() {
+        if let Some(status) = child.try_wait()? {
+            return Err(std::io::Error::other(format!(
+                "virtiofsd for {} exited with {status}",
+                socket.display()
+            )));
+        }
+
+        if start.elapsed() > VIRTIOFSD_TIMEOUT {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("virtiofsd did not create {} in time", socket.display()),
+            ));
+        }
+
+        tokio::time::sleep(VIRTIOFSD_POLL_INTERVAL).await;
+    }
+
+    Ok(())
+}
+
+/// Start a virtiofsd for each virtiofs share of a machine in `run_dir`
+///
+/// The sockets are handed over to the `tenant`'s user, which qemu runs as.
+pub(super) async fn start(
+    machine_config: &MachineConfig,
+    tenant: Option<&Tenant>,
+    run_dir: &Path,
+) -> std::io::Result<Daemons> {
+    let user = tenancy::user(tenant)?;
+
+    // The namespace sandbox of virtiofsd requires root privileges.
+    let sandbox = match nix::unistd::geteuid().is_root() {
+        true => "namespace",
+        false => "none",
+    };
+
+    let mut children = Vec::new();
+
+    for (index, dir) in virtiofs(machine_config) {
+        let socket = run_dir.join(socket_name(index));
+
+        let mut virtiofsd = Command::new(VIRTIOFSD_CMD);
+
+        virtiofsd
+            .kill_on_drop(true)
+            .arg("--socket-path")
+            .arg(&socket)
+            .arg("--shared-dir")
+            .arg(&dir.path)
+            .args(["--sandbox", sandbox, "--cache", "auto"]);
+
+        if !dir.writable {
+            virtiofsd.arg("--readonly");
+        }
+
+        let mut child = virtiofsd.spawn()?;
+
+        wait_for_socket(&mut child, &socket).await?;
+
+        if let Some(user) = &user {
+            tenancy::hand_over(&socket, user)?;
+        }
+
+        children.push(child);
+    }
+
+    Ok(Daemons {
+        _children: children,
+    })
+}
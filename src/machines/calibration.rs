@@ -0,0 +1,179 @@
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde_json::json;
+
+use super::backend;
+use super::manager::Machines;
+use super::run_dir::RunDir;
+use super::triplet::Triplet;
+use super::{Error, Result};
+use crate::config::{Config, ConfigFile};
+
+// The name of the setup template sub-directory that contains the
+// benchmark job.
+// It replaces the `job-config` directory for calibration runs.
+const CALIBRATION_TEMPLATE: &str = "calibration";
+
+// The file the benchmark job leaves in the job config file system.
+const RESULTS_FILE: &str = "results.json";
+
+// Benchmarks should not take forever.
+// Stop the machine if it does not power itself off after this time.
+const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+pub(super) fn results_path(base_dir: &Path, triplet: &Triplet) -> PathBuf {
+    base_dir
+        .join("calibration")
+        .join(triplet.owner())
+        .join(triplet.repository())
+        .join(format!("{}.jsonl", triplet.machine_name()))
+}
+
+/// Boot a single machine with the calibration job and return its results
+///
+/// Returns Ok(None) if the machine can not be calibrated,
+/// e.g. because its setup template does not contain a calibration job.
+async fn calibrate_machine(
+    cfg: &ConfigFile,
+    triplet: &Triplet,
+) -> Result<Option<serde_json::Value>> {
+    let machine_config = cfg
+        .machine_config(triplet)
+        .ok_or_else(|| Error::UnknownTriplet(triplet.to_string()))?;
+
+    let template_path = machine_config
+        .setup_template
+        .path
+        .join(CALIBRATION_TEMPLATE);
+
+    if !template_path.is_dir() {
+        warn!(
+            "Skipping {triplet}: setup template has no {} directory",
+            template_path.display()
+        );
+        return Ok(None);
+    }
+
+    let tenant = cfg.tenancy.owners.get(triplet.owner());
+
+    let runner_name = format!(
+        "forrest-calibrate-{}-{}",
+        triplet.machine_name(),
+        Utc::now().timestamp()
+    );
+    let machines = Machines::new();
+
+    let run_dir = RunDir::with_job_template(
+        cfg,
+        triplet,
+        &runner_name,
+        &machines,
+        String::new(),
+        CALIBRATION_TEMPLATE,
+    )?;
+
+    let mut run_dir = match run_dir {
+        Some(rd) => rd,
+        None => {
+            warn!("Skipping {triplet}: no disk image available (yet)");
+            return Ok(None);
+        }
+    };
+
+    let image = match run_dir.source_image() {
+        Some(image) => image.to_owned(),
+        None => {
+            warn!("Skipping {triplet}: its backend does not boot from a disk image");
+            return Ok(None);
+        }
+    };
+    let image_modified: DateTime<Utc> = image.metadata()?.modified()?.into();
+
+    info!("Calibrating {triplet} using image {}", image.display());
+
+    let started = Utc::now();
+
+    // The benchmarks do not need any host devices,
+    // which may also be in use by a running Forrest instance.
+    let status = tokio::time::timeout(
+        CALIBRATION_TIMEOUT,
+        backend::command(
+            &cfg.host,
+            machine_config,
+            tenant,
+            &[],
+            None,
+            run_dir.path(),
+            run_dir.scratch(),
+        )?
+        .status(),
+    )
+    .await
+    .map_err(|_| Error::Timeout(format!("Calibration of {triplet}")))??;
+
+    if !status.success() {
+        return Err(Error::Exited {
+            triplet: triplet.to_string(),
+            status: status.to_string(),
+        });
+    }
+
+    let duration = Utc::now() - started;
+    let results = run_dir.read_job_file(RESULTS_FILE)?;
+    let results: serde_json::Value = serde_json::from_str(&results)?;
+
+    let record = json!({
+        "timestamp": started.to_rfc3339(),
+        "duration": duration.num_seconds(),
+        "image": image,
+        "image_modified": image_modified.to_rfc3339(),
+        "cpus": machine_config.cpus,
+        "ram": machine_config.ram.bytes(),
+        "results": results,
+    });
+
+    Ok(Some(record))
+}
+
+/// Run the calibration benchmark on all configured machine types
+///
+/// The machines are booted one after the other, so the results are not
+/// skewed by other machines competing for the same resources.
+/// The results are appended to a file per machine type in the
+/// `calibration` directory, so performance regressions after image
+/// updates can be spotted by comparing the records.
+/// Each record is also handed to `report` as soon as it is available.
+pub async fn calibrate(
+    config: Config,
+    mut report: impl FnMut(&Triplet, &serde_json::Value),
+) -> Result<()> {
+    let cfg = config.get();
+    let base_dir = &cfg.host.base_dir;
+
+    for triplet in cfg.triplets() {
+        let record = match calibrate_machine(&cfg, &triplet).await {
+            Ok(Some(record)) => record,
+            Ok(None) => continue,
+            Err(err) => {
+                error!("Failed to calibrate {triplet} ({}): {err}", err.category());
+                continue;
+            }
+        };
+
+        let path = results_path(base_dir, &triplet);
+
+        create_dir_all(path.parent().unwrap())?;
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{record}")?;
+
+        report(&triplet, &record);
+    }
+
+    Ok(())
+}
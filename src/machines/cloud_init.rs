@@ -0,0 +1,97 @@
+use std::io::ErrorKind;
+
+use serde::Serialize;
+
+use crate::config::MachineConfig;
+
+use super::config_fs;
+
+const META_DATA_FILE: &str = "meta-data";
+const VENDOR_DATA_FILE: &str = "vendor-data";
+
+/// An entry of the `write_files` list of a cloud-config
+#[derive(Serialize)]
+struct WriteFile {
+    path: String,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct VendorData {
+    write_files: Vec<WriteFile>,
+}
+
+/// The `meta-data` to use if the setup template does not bring its own
+///
+/// Every machine run is a new instance to cloud-init,
+/// so it is also set up anew if it boots from a persisted image.
+fn meta_data(runner_name: &str) -> String {
+    format!("instance-id: {runner_name}\nlocal-hostname: {runner_name}\n")
+}
+
+/// The `vendor-data` that writes the `setup_template.files` into the machine
+///
+/// cloud-init merges the vendor-data with the user-data of the setup template,
+/// so the `user-data` does not have to know about the files.
+fn vendor_data(
+    machine_config: &MachineConfig,
+    substitutions: &[(&str, &str)],
+) -> std::io::Result<String> {
+    let write_files = machine_config
+        .setup_template
+        .files
+        .iter()
+        .map(|(path, source)| {
+            let content = std::fs::read_to_string(source).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to read {}: {e}", source.display()),
+                )
+            })?;
+
+            Ok(WriteFile {
+                path: path.clone(),
+                content: config_fs::substitute(content, substitutions),
+            })
+        })
+        .collect::<std::io::Result<_>>()?;
+
+    let vendor_data =
+        serde_yml::to_string(&VendorData { write_files }).map_err(std::io::Error::other)?;
+
+    Ok(format!("#cloud-config\n{vendor_data}"))
+}
+
+/// Complete the files rendered from the `cloud-init` setup template of a machine
+///
+/// This adds a `meta-data` file naming the machine after the runner if the
+/// template does not provide one and a `vendor-data` file if there are
+/// `setup_template.files` to place in the machine.
+/// Together with a `user-data` that sets up the runner this is all a stock
+/// cloud image needs to run a job.
+pub(super) fn seed(
+    machine_config: &MachineConfig,
+    runner_name: &str,
+    mut files: Vec<(String, String)>,
+    substitutions: &[(&str, &str)],
+) -> std::io::Result<Vec<(String, String)>> {
+    let has_file = |files: &[(String, String)], name: &str| files.iter().any(|(n, _)| n == name);
+
+    if !has_file(&files, META_DATA_FILE) {
+        files.push((META_DATA_FILE.to_owned(), meta_data(runner_name)));
+    }
+
+    if !machine_config.setup_template.files.is_empty() {
+        if has_file(&files, VENDOR_DATA_FILE) {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                "setup_template.files can not be used with a template that has a vendor-data file",
+            ));
+        }
+
+        let vendor_data = vendor_data(machine_config, substitutions)?;
+        files.push((VENDOR_DATA_FILE.to_owned(), vendor_data));
+    }
+
+    Ok(files)
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use super::machine::Status;
+use super::manager::Machines;
+use super::triplet::{OwnerAndRepo, Triplet};
+use crate::config::ConfigFile;
+
+/// Does a machine in `status` take up one of the concurrent machines of its repository?
+///
+/// Machines that were only requested yet are the ones waiting for a slot.
+fn takes_slot(status: Status) -> bool {
+    match status {
+        Status::Requested | Status::Stopped => false,
+        Status::Registering
+        | Status::Registered
+        | Status::Starting
+        | Status::Waiting
+        | Status::Paused
+        | Status::Running
+        | Status::Draining
+        | Status::Stopping => true,
+    }
+}
+
+/// Keeps a single owner or repository from taking up the whole host
+///
+/// Owners and repositories with a `max_concurrent_machines` config may only
+/// have this many machines at once.
+/// Requested machines beyond that wait until one of the others has stopped,
+/// which leaves room for the machines of other repositories.
+pub(super) struct ConcurrencyLimits {
+    /// How many more machines may be started per owner with a limit
+    owners: HashMap<String, u32>,
+    /// How many more machines may be started per repository with a limit
+    repositories: HashMap<OwnerAndRepo, u32>,
+}
+
+impl ConcurrencyLimits {
+    /// Get the limits left given the current `machines`
+    pub(super) fn new(cfg: &ConfigFile, machines: &Machines) -> Self {
+        let mut owners: HashMap<String, u32> = cfg
+            .owners
+            .iter()
+            .filter_map(|(owner, settings)| {
+                Some((owner.clone(), settings.max_concurrent_machines?))
+            })
+            .collect();
+
+        let mut repositories = HashMap::new();
+
+        for (owner, repos) in &cfg.repositories {
+            for (repository, repo) in repos {
+                if let Some(max) = repo.max_concurrent_machines {
+                    repositories.insert(OwnerAndRepo::new(owner, repository), max);
+                }
+            }
+        }
+
+        for machine in machines.values().flatten() {
+            if !takes_slot(machine.status()) {
+                continue;
+            }
+
+            let triplet = machine.triplet();
+
+            if let Some(left) = owners.get_mut(triplet.owner()) {
+                *left = left.saturating_sub(1);
+            }
+
+            if let Some(left) = repositories.get_mut(&triplet.clone().into_owner_and_repo()) {
+                *left = left.saturating_sub(1);
+            }
+        }
+
+        Self {
+            owners,
+            repositories,
+        }
+    }
+
+    /// The limit that keeps another machine of `triplet` from being started, if any
+    pub(super) fn exhausted(&self, triplet: &Triplet) -> Option<String> {
+        if self.owners.get(triplet.owner()) == Some(&0) {
+            return Some(format!("concurrent machines of {}", triplet.owner()));
+        }
+
+        let oar = triplet.clone().into_owner_and_repo();
+
+        if self.repositories.get(&oar) == Some(&0) {
+            return Some(format!("concurrent machines of {oar}"));
+        }
+
+        None
+    }
+
+    /// Take up one of the concurrent machines of the owner and repository of `triplet`
+    pub(super) fn claim(&mut self, triplet: &Triplet) {
+        if let Some(left) = self.owners.get_mut(triplet.owner()) {
+            *left = left.saturating_sub(1);
+        }
+
+        if let Some(left) = self
+            .repositories
+            .get_mut(&triplet.clone().into_owner_and_repo())
+        {
+            *left = left.saturating_sub(1);
+        }
+    }
+}
@@ -1,4 +1,6 @@
+use std::fs::Permissions;
 use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
 
 use fatfs::{format_volume, FileSystem, FormatVolumeOptions, FsOptions};
@@ -6,33 +8,88 @@ use log::warn;
 
 pub struct ConfigFs {
     path: PathBuf,
+    is_dir: bool,
 }
 
-pub struct ConfigFsInspect {
-    filesystem: FileSystem<std::fs::File>,
+pub enum ConfigFsInspect {
+    Image(FileSystem<std::fs::File>),
+    // Keep the `ConfigFs` around, so that the directory is not removed
+    // while we still want to read from it.
+    Dir(ConfigFs),
+}
+
+/// Replace placeholders like `<REPO_OWNER>` in `content` based on `substitutions`
+pub(super) fn substitute(mut content: String, substitutions: &[(&str, &str)]) -> String {
+    for (from, to) in substitutions {
+        content = content.replace(&format!("<{from}>"), to);
+    }
+
+    content
+}
+
+/// Read all files from a template directory and apply the `substitutions` to them
+///
+/// Returns pairs of file names and their content.
+pub(super) fn render(
+    template_path: PathBuf,
+    substitutions: &[(&str, &str)],
+) -> std::io::Result<Vec<(String, String)>> {
+    let mut files = Vec::new();
+
+    for entry in std::fs::read_dir(template_path)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let path = entry.path();
+
+        if !entry.metadata()?.is_file() {
+            let p = path.display();
+
+            warn!("Ignoring non-file entry '{p}' during assembly of config fs",);
+            continue;
+        }
+
+        let name = match file_name.to_str() {
+            Some(name) => name,
+            None => {
+                warn!(
+                    "Ignoring file with non-utf8 name '{}' during assembly of config fs",
+                    file_name.to_string_lossy()
+                );
+                continue;
+            }
+        };
+
+        // Replace placeholders in the file, like <REPO_OWNER> or <JITCONFIG>
+        // with values provided in `substitutions`.
+        // This is not an efficient or elegant solution, but a simple one.
+        // This assumes that all files that should be placed in the config
+        // filesystems are utf-8 text.
+
+        let content = std::fs::read_to_string(path)?;
+
+        files.push((name.to_owned(), substitute(content, substitutions)));
+    }
+
+    Ok(files)
 }
 
 impl ConfigFs {
-    /// Create a FAT filesystem image and populate it with files from a template directory
+    /// Create a FAT filesystem image and populate it with files
     ///
     /// # Arguments
     ///
     /// * `path` - Where to place the disk image
     /// * `size` - The size in bytes of the disk image and filesystem.
     /// * `labels` - The volume label to use. This is truncated at 11 characters.
-    /// * `template_path` - The directory to scan for files to place into the image.
-    ///    Note that text in the files will be replaced based on `substitutions`.
-    ///    This means that only plain text files may be present in the `template_path`.
-    /// * `substitutions` - Pairs of from -> to text replacements to perform on all files
-    ///    in the `template_path`.
+    /// * `files` - Pairs of file names and their content to place into the image,
+    ///   e.g. as returned by `render()` for a template directory.
     ///
     /// The image file is removed from the file system as soon as the return value is dropped.
     pub fn new(
         path: PathBuf,
         size: u64,
         label: &str,
-        template_path: PathBuf,
-        substitutions: &[(&str, &str)],
+        files: Vec<(String, String)>,
     ) -> std::io::Result<Self> {
         let filesystem = {
             let mut image = std::fs::File::create_new(&path)?;
@@ -56,50 +113,57 @@ impl ConfigFs {
 
         let root_dir = filesystem.root_dir();
 
-        for entry in std::fs::read_dir(template_path)? {
-            let entry = entry?;
-            let file_name = entry.file_name();
-            let path = entry.path();
+        for (name, content) in files {
+            let mut file = root_dir.create_file(&name)?;
+            file.truncate()?;
+            file.write_all(content.as_bytes())?;
+        }
 
-            if !entry.metadata()?.is_file() {
-                let p = path.display();
+        std::mem::drop(root_dir);
+        filesystem.unmount()?;
 
-                warn!("Ignoring non-file entry '{p}' during assembly of config fs",);
-                continue;
-            }
+        Ok(Self {
+            path,
+            is_dir: false,
+        })
+    }
 
-            let name = match file_name.to_str() {
-                Some(name) => name,
-                None => {
-                    warn!(
-                        "Ignoring file with non-utf8 name '{}' during assembly of config fs",
-                        file_name.to_string_lossy()
-                    );
-                    continue;
-                }
-            };
+    /// Create a directory and populate it with files
+    ///
+    /// This is the counterpart to `new()` for machines that can not access
+    /// disk images, like containers, and get the directory bind mounted instead.
+    /// The arguments work like they do for `new()`.
+    ///
+    /// The directory is removed from the file system as soon as the return value is dropped.
+    pub fn new_dir(path: PathBuf, files: Vec<(String, String)>) -> std::io::Result<Self> {
+        std::fs::create_dir(&path)?;
 
-            // Replace placeholders in the file, like <REPO_OWNER> or <JITCONFIG>
-            // with values provided in `substitutions`.
-            // This is not an efficient or elegant solution, but a simple one.
-            // This assumes that all files that should be placed in the config
-            // filesystems are utf-8 text.
+        // Create the value right away, so that the directory is cleaned up
+        // if populating it fails.
+        let config_fs = Self { path, is_dir: true };
 
-            let mut content = std::fs::read_to_string(path)?;
+        // The files contain credentials like the JIT runner config,
+        // so keep them from other users on the host.
+        // Root inside of the container can still access them and hands them
+        // on to the runner user, see the setup templates.
+        std::fs::set_permissions(&config_fs.path, Permissions::from_mode(0o750))?;
 
-            for (from, to) in substitutions {
-                content = content.replace(&format!("<{from}>"), to);
-            }
+        for (name, content) in files {
+            let file_path = config_fs.path.join(name);
 
-            let mut file = root_dir.create_file(name)?;
-            file.truncate()?;
-            file.write_all(content.as_bytes())?;
+            std::fs::write(&file_path, content)?;
+            std::fs::set_permissions(&file_path, Permissions::from_mode(0o640))?;
         }
 
-        std::mem::drop(root_dir);
-        filesystem.unmount()?;
+        Ok(config_fs)
+    }
 
-        Ok(Self { path })
+    /// Take over an image or directory that was created by a previous instance
+    ///
+    /// Like for `new()` and `new_dir()` it is removed from the file system
+    /// as soon as the return value is dropped.
+    pub fn existing(path: PathBuf, is_dir: bool) -> Self {
+        Self { path, is_dir }
     }
 
     /// Inspect the file system
@@ -108,7 +172,12 @@ impl ConfigFs {
     /// This opens the image file and allows reading files from it.
     /// The image will be removed from the filesystem as `self` is dropped
     /// inside of this method.
+    /// Directories are only removed once the returned value is dropped.
     pub fn inspect(self) -> std::io::Result<ConfigFsInspect> {
+        if self.is_dir {
+            return Ok(ConfigFsInspect::Dir(self));
+        }
+
         let filesystem = {
             let image = std::fs::File::options()
                 .read(true)
@@ -118,20 +187,39 @@ impl ConfigFs {
             FileSystem::new(image, FsOptions::new())?
         };
 
-        Ok(ConfigFsInspect { filesystem })
+        Ok(ConfigFsInspect::Image(filesystem))
     }
 }
 
 impl Drop for ConfigFs {
     fn drop(&mut self) {
-        std::fs::remove_file(&self.path).unwrap();
+        match self.is_dir {
+            true => std::fs::remove_dir_all(&self.path).unwrap(),
+            false => std::fs::remove_file(&self.path).unwrap(),
+        }
     }
 }
 
 impl ConfigFsInspect {
     pub fn read_file(&self, path: &str, buf: &mut [u8]) -> std::io::Result<()> {
-        let root_dir = self.filesystem.root_dir();
+        match self {
+            Self::Image(filesystem) => filesystem.root_dir().open_file(path)?.read_exact(buf),
+            Self::Dir(dir) => std::fs::File::open(dir.path.join(path))?.read_exact(buf),
+        }
+    }
+
+    pub fn read_to_string(&self, path: &str) -> std::io::Result<String> {
+        match self {
+            Self::Image(filesystem) => {
+                let mut content = String::new();
+                filesystem
+                    .root_dir()
+                    .open_file(path)?
+                    .read_to_string(&mut content)?;
 
-        root_dir.open_file(path)?.read_exact(buf)
+                Ok(content)
+            }
+            Self::Dir(dir) => std::fs::read_to_string(dir.path.join(path)),
+        }
     }
 }
@@ -0,0 +1,175 @@
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::adoption;
+
+/// The machine a host device is assigned to
+#[derive(Serialize, Deserialize, Clone)]
+struct Assignment {
+    runner_name: String,
+    /// The process id of the machine, once it was spawned
+    pid: Option<u32>,
+}
+
+impl Assignment {
+    /// Is the process this device was handed to still running?
+    ///
+    /// The process is identified by its id and by running inside of the
+    /// run directory of the machine, in case the id was re-used since.
+    fn is_alive(&self) -> bool {
+        self.pid
+            .is_some_and(|pid| adoption::is_alive(pid, &self.runner_name))
+    }
+}
+
+/// Keeps track of which host devices are assigned to which machines
+///
+/// The assignments are persisted to disk, so that after a restart of Forrest
+/// devices that are still attached to a leftover machine are not handed out
+/// to a second one.
+pub(super) struct Devices {
+    path: PathBuf,
+    assignments: Mutex<HashMap<String, Assignment>>,
+}
+
+impl Devices {
+    pub(super) fn new(base_dir: &Path) -> Self {
+        let path = base_dir.join("devices.json");
+
+        let assignments: HashMap<String, Assignment> = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                error!("Failed to parse {}, starting over: {e}", path.display());
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                error!("Failed to read {}, starting over: {e}", path.display());
+                HashMap::new()
+            }
+        };
+
+        // Only keep the devices that are still attached to a running machine
+        // of a previous Forrest instance.
+        let assignments: HashMap<String, Assignment> = assignments
+            .into_iter()
+            .filter(|(device, assignment)| {
+                let alive = assignment.is_alive();
+
+                if alive {
+                    warn!(
+                        "Device {device} is still attached to leftover machine {}",
+                        assignment.runner_name
+                    );
+                }
+
+                alive
+            })
+            .collect();
+
+        let devices = Self {
+            path,
+            assignments: Mutex::new(assignments),
+        };
+
+        devices.persist(&devices.assignments.lock().unwrap());
+
+        devices
+    }
+
+    fn persist(&self, assignments: &HashMap<String, Assignment>) {
+        // Write to a temporary file first and move it into place,
+        // so we never leave a half written file behind.
+        let tmp_path = self.path.with_extension("json.tmp");
+
+        let res = serde_json::to_vec_pretty(assignments)
+            .map_err(std::io::Error::other)
+            .and_then(|content| std::fs::write(&tmp_path, content))
+            .and_then(|()| std::fs::rename(&tmp_path, &self.path));
+
+        if let Err(e) = res {
+            error!(
+                "Failed to persist device assignments to {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+
+    /// Get the devices that are currently assigned to a machine
+    ///
+    /// Devices held by leftover machines of a previous instance are
+    /// released once these machines have stopped.
+    pub(super) fn in_use(&self) -> HashSet<String> {
+        let mut assignments = self.assignments.lock().unwrap();
+        let mut released = false;
+
+        assignments.retain(|device, assignment| {
+            let keep = assignment.pid.is_none() || assignment.is_alive();
+
+            if !keep {
+                info!(
+                    "Released device {device} of stopped machine {}",
+                    assignment.runner_name
+                );
+                released = true;
+            }
+
+            keep
+        });
+
+        if released {
+            self.persist(&assignments);
+        }
+
+        assignments.keys().cloned().collect()
+    }
+
+    /// Assign `devices` to the machine `runner_name` that is about to be spawned
+    pub(super) fn assign(&self, devices: &[String], runner_name: &str) {
+        let mut assignments = self.assignments.lock().unwrap();
+
+        for device in devices {
+            let assignment = Assignment {
+                runner_name: runner_name.to_owned(),
+                pid: None,
+            };
+
+            assignments.insert(device.clone(), assignment);
+        }
+
+        self.persist(&assignments);
+    }
+
+    /// Note the process id of the machine `runner_name` once it was spawned
+    pub(super) fn spawned(&self, runner_name: &str, pid: u32) {
+        let mut assignments = self.assignments.lock().unwrap();
+        let mut changed = false;
+
+        for assignment in assignments.values_mut() {
+            if assignment.runner_name == runner_name {
+                assignment.pid = Some(pid);
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.persist(&assignments);
+        }
+    }
+
+    /// Release all devices assigned to the machine `runner_name`
+    pub(super) fn release(&self, runner_name: &str) {
+        let mut assignments = self.assignments.lock().unwrap();
+        let len_before = assignments.len();
+
+        assignments.retain(|_, assignment| assignment.runner_name != runner_name);
+
+        if assignments.len() != len_before {
+            self.persist(&assignments);
+        }
+    }
+}
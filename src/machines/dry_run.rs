@@ -0,0 +1,194 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use super::backend;
+use super::manager::Machines;
+use super::pinning::FreeCores;
+use super::run_dir::{self, RunDir};
+use super::tenancy;
+use super::triplet::Triplet;
+use super::{Error, Result};
+use crate::config::{Backend, ConfigFile};
+
+// Left in place of the runner registration, which a dry run does not create.
+const JITCONFIG_PLACEHOLDER: &str = "<JITCONFIG>";
+
+/// A file in the cloud-init or job config image of a machine
+#[derive(Serialize)]
+pub struct SeedFile {
+    pub name: String,
+    pub content: String,
+}
+
+/// The cloud-init or job config image of a machine and the files in it
+#[derive(Serialize)]
+pub struct SeedImage {
+    pub name: String,
+    pub files: Vec<SeedFile>,
+}
+
+/// How a machine would be started, without starting it
+#[derive(Serialize)]
+pub struct DryRun {
+    pub machine: String,
+    pub backend: Backend,
+    /// The directory the command is run in
+    pub working_dir: PathBuf,
+    /// The user the command is run as, if not the one Forrest runs as
+    pub user: Option<String>,
+    /// The program and its arguments
+    pub command: Vec<String>,
+    pub network: String,
+    pub seed: Vec<SeedImage>,
+}
+
+/// Quote `arg` so it can be pasted into a shell
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_=+,.:/@%".contains(c);
+
+    match !arg.is_empty() && arg.chars().all(plain) {
+        true => arg.to_owned(),
+        false => format!("'{}'", arg.replace('\'', r"'\''")),
+    }
+}
+
+impl std::fmt::Display for DryRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Machine:     {} ({:?} backend)",
+            self.machine, self.backend
+        )?;
+        writeln!(f, "Working dir: {}", self.working_dir.display())?;
+
+        if let Some(user) = &self.user {
+            writeln!(f, "User:        {user}")?;
+        }
+
+        writeln!(f, "Network:     {}", self.network)?;
+
+        let command: Vec<String> = self.command.iter().map(|arg| shell_quote(arg)).collect();
+
+        writeln!(f, "\nCommand:\n  {}", command.join(" \\\n    "))?;
+
+        for image in &self.seed {
+            for file in &image.files {
+                writeln!(f, "\n--- {}/{} ---", image.name, file.name)?;
+                write!(f, "{}", file.content)?;
+
+                if !file.content.ends_with('\n') {
+                    writeln!(f)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Render how a machine of type `triplet` would be started, without starting it
+///
+/// This sets up a run directory like for a real machine, which is
+/// removed again before returning.
+/// The runner registration is left as a placeholder in the seed files,
+/// as a dry run does not register a runner.
+pub fn dry_run(cfg: &ConfigFile, triplet: &Triplet) -> Result<DryRun> {
+    let machine_config = cfg
+        .machine_config(triplet)
+        .ok_or_else(|| Error::UnknownTriplet(triplet.to_string()))?;
+
+    let invalid = |e: String| std::io::Error::new(ErrorKind::InvalidInput, e);
+
+    backend::check(machine_config).map_err(invalid)?;
+    let tenant = tenancy::check(cfg, triplet).map_err(invalid)?;
+
+    let devices: Vec<_> = machine_config
+        .devices
+        .iter()
+        .filter_map(|name| cfg.host.devices.get(name))
+        .collect();
+
+    // Pick cores as if no other machine was running.
+    let pinning = match machine_config.cpu_pin || machine_config.numa_node.is_some() {
+        true => {
+            FreeCores::new(&Machines::new()).pick(machine_config.cpus, machine_config.numa_node)
+        }
+        false => None,
+    };
+
+    let runner_name = format!(
+        "forrest-dry-run-{}-{}",
+        triplet.machine_name(),
+        Utc::now().timestamp()
+    );
+
+    let run_dir = RunDir::with_job_template(
+        cfg,
+        triplet,
+        &runner_name,
+        &Machines::new(),
+        JITCONFIG_PLACEHOLDER.to_owned(),
+        "job-config",
+    )?
+    .ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("No disk image available for {triplet} (yet)"),
+        )
+    })?;
+
+    let working_dir = run_dir.path().to_owned();
+
+    let command = backend::command(
+        &cfg.host,
+        machine_config,
+        tenant,
+        &devices,
+        pinning.as_ref(),
+        run_dir.path(),
+        run_dir.scratch(),
+    );
+
+    // Clean up before looking at the result, so a failure leaves nothing behind.
+    // The run dir itself is kept for real machines, but is of no use here.
+    std::mem::drop(run_dir);
+    let _ = std::fs::remove_dir_all(&working_dir);
+
+    if cfg.host.console_log_dir.is_some() {
+        let _ = std::fs::remove_file(run_dir::console_log_path(cfg, triplet, &runner_name));
+    }
+
+    let command = command?;
+    let command = command.as_std();
+
+    let command = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+
+    let user = tenant.and_then(|t| t.user.clone());
+
+    let seed = run_dir::seed_files(machine_config, triplet, &runner_name, JITCONFIG_PLACEHOLDER)?
+        .into_iter()
+        .map(|(name, files)| SeedImage {
+            name: name.to_owned(),
+            files: files
+                .into_iter()
+                .map(|(name, content)| SeedFile { name, content })
+                .collect(),
+        })
+        .collect();
+
+    Ok(DryRun {
+        machine: triplet.to_string(),
+        backend: machine_config.backend,
+        working_dir,
+        user,
+        command,
+        network: backend::network(machine_config, tenant),
+        seed,
+    })
+}
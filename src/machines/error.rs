@@ -0,0 +1,31 @@
+use crate::error::Category;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Unknown machine triplet {0}")]
+    UnknownTriplet(String),
+    #[error("Unsupported snapshot version {found} (expected {expected})")]
+    SnapshotVersion { found: u32, expected: u32 },
+    #[error("The machine process for {triplet} exited with: {status}")]
+    Exited { triplet: String, status: String },
+    #[error("{0} timed out")]
+    Timeout(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    pub fn category(&self) -> Category {
+        match self {
+            Self::UnknownTriplet(_) | Self::SnapshotVersion { .. } | Self::Json(_) => {
+                Category::Config
+            }
+            Self::Exited { .. } | Self::Io(_) => Category::Backend,
+            Self::Timeout(_) => Category::Transient,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
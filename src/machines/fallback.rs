@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use super::triplet::Triplet;
+use crate::config::ConfigFile;
+
+// Give a machine type that failed to spawn another chance after this time,
+// e.g. because the capacity problem has been resolved in the meantime.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Consecutive spawn failures of a machine type
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// Keeps track of spawn failures per machine type to decide when to
+/// fall back to the configured alternative machine type
+#[derive(Default)]
+pub(super) struct SpawnFailures {
+    failures: Mutex<HashMap<Triplet, Failures>>,
+}
+
+impl SpawnFailures {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note that a machine of type `triplet` failed to come up
+    pub(super) fn failure(&self, triplet: &Triplet) {
+        let mut failures = self.failures.lock().unwrap();
+
+        let entry = failures.entry(triplet.clone()).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+
+        entry.count += 1;
+        entry.last = Instant::now();
+
+        warn!(
+            "Machine type {triplet} failed to spawn {} time(s) in a row",
+            entry.count
+        );
+    }
+
+    /// Note that a machine of type `triplet` came up successfully
+    pub(super) fn success(&self, triplet: &Triplet) {
+        self.failures.lock().unwrap().remove(triplet);
+    }
+
+    /// Pick the machine type whose config to use for a machine requested as `triplet`
+    ///
+    /// This is `triplet` itself, unless it failed to spawn `after_failures`
+    /// times in a row recently and has a `fallback` configured.
+    /// Fallbacks of fallbacks are followed as well.
+    /// The machine still registers with the labels of `triplet`,
+    /// so that it picks up the jobs it was requested for.
+    pub(super) fn pick(&self, cfg: &ConfigFile, triplet: &Triplet) -> Triplet {
+        let mut failures = self.failures.lock().unwrap();
+
+        failures.retain(|_, f| f.last.elapsed() < RETRY_INTERVAL);
+
+        let current = Self::resolve(&failures, cfg, triplet);
+
+        if current != *triplet {
+            info!("Using machine type {current} as fallback for {triplet}");
+        }
+
+        current
+    }
+
+    /// Did machines requested as `triplet` fail to come up too often to try again?
+    ///
+    /// This is the case once the machine type that would be used for `triplet`,
+    /// after following its fallbacks, failed to come up more than its
+    /// `start_retries` times in a row recently.
+    pub(super) fn exhausted(&self, cfg: &ConfigFile, triplet: &Triplet) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+
+        failures.retain(|_, f| f.last.elapsed() < RETRY_INTERVAL);
+
+        let current = Self::resolve(&failures, cfg, triplet);
+
+        let retries = match cfg.machine_config(&current).and_then(|mc| mc.start_retries) {
+            Some(retries) => retries,
+            None => return false,
+        };
+
+        failures.get(&current).is_some_and(|f| f.count > retries)
+    }
+
+    /// Follow the fallbacks of `triplet` based on its recent `failures`
+    fn resolve(
+        failures: &HashMap<Triplet, Failures>,
+        cfg: &ConfigFile,
+        triplet: &Triplet,
+    ) -> Triplet {
+        let mut current = triplet.clone();
+        let mut visited = HashSet::new();
+
+        loop {
+            visited.insert(current.clone());
+
+            let fallback = match cfg
+                .machine_config(&current)
+                .and_then(|mc| mc.fallback.as_ref())
+            {
+                Some(fallback) => fallback,
+                None => break,
+            };
+
+            let failed = failures
+                .get(&current)
+                .map(|f| f.count >= fallback.after_failures)
+                .unwrap_or(false);
+
+            if !failed {
+                break;
+            }
+
+            let next = Triplet::new(current.owner(), current.repository(), &fallback.machine);
+
+            if visited.contains(&next) || cfg.machine_config(&next).is_none() {
+                break;
+            }
+
+            current = next;
+        }
+
+        current
+    }
+}
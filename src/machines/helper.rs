@@ -0,0 +1,671 @@
+use std::collections::HashSet;
+use std::fs::Permissions;
+use std::io::{BufRead, ErrorKind, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{chown, fchown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{Pid, User};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::Command;
+
+use super::adoption;
+use super::backend;
+use super::pinning::Pinning;
+use super::run_dir;
+use super::tenancy;
+use super::triplet::Triplet;
+use super::Result;
+use crate::config::{Backend, Config, ConfigFile, MachineConfig, Tenant};
+
+// The socket in the `host.base_dir` the helper listens on.
+const SOCKET: &str = "helper.sock";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_SIZE_LIMIT: u64 = 64 * 1024;
+
+// Set once at startup if `host.helper` is enabled.
+static SOCKET_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// What the unprivileged instance may ask the helper to do
+///
+/// The helper does not take commands or arbitrary paths,
+/// it derives what to do from its own copy of the config.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+enum Request {
+    /// Hand a file in a run directory, scratch pool or the overlay dir over to a tenant user
+    HandOver { path: PathBuf, user: String },
+    /// Start the process of a machine whose run directory was set up
+    Spawn {
+        triplet: Triplet,
+        config_triplet: Triplet,
+        runner_name: String,
+        pinning: Option<Pinning>,
+    },
+    /// Is the process `pid` still running the machine `runner_name`?
+    Alive { pid: u32, runner_name: String },
+    /// Kill the process `pid` of the machine `runner_name`
+    Kill { pid: u32, runner_name: String },
+    /// List the machine processes that are not one of the `adopted` machines
+    Strays { adopted: HashSet<String> },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+enum Response {
+    Done,
+    Spawned {
+        pid: u32,
+    },
+    /// The machine process exited with the raw wait `status`
+    Exited {
+        status: i32,
+    },
+    Alive {
+        alive: bool,
+    },
+    Strays {
+        strays: Vec<(u32, String)>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+fn unexpected() -> std::io::Error {
+    std::io::Error::other("Unexpected response from the helper")
+}
+
+/// Leave privileged operations to the helper from now on, if `host.helper` is enabled
+///
+/// Changes to `host.helper` only take effect after a restart.
+pub(super) fn init(cfg: &ConfigFile) {
+    if cfg.host.helper.is_some() {
+        let _ = SOCKET_PATH.set(cfg.host.base_dir.join(SOCKET));
+    }
+}
+
+/// Are privileged operations left to the helper?
+pub(super) fn is_used() -> bool {
+    SOCKET_PATH.get().is_some()
+}
+
+/// Does the process of a machine of type `machine_config` have to be started by the helper?
+///
+/// Only root may start processes as another user, so this is the case for
+/// `qemu` machines of tenants with a `user` of their own.
+/// Everything else is started by the unprivileged instance itself.
+pub(super) fn spawns(machine_config: &MachineConfig, tenant: Option<&Tenant>) -> bool {
+    is_used()
+        && machine_config.backend == Backend::Qemu
+        && tenant.is_some_and(|tenant| tenant.user.is_some())
+}
+
+/// Send a single `request` to the helper and wait for the response
+///
+/// This blocks, but the helper answers everything but `Spawn` right away.
+fn request(request: &Request) -> std::io::Result<Response> {
+    let path = SOCKET_PATH
+        .get()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No helper is configured"))?;
+
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut response = String::new();
+    std::io::BufReader::new(stream).read_line(&mut response)?;
+
+    match serde_json::from_str(&response)? {
+        Response::Failed { error } => Err(std::io::Error::other(error)),
+        response => Ok(response),
+    }
+}
+
+/// Have the helper hand `path` over to the tenant `user`
+pub(super) fn hand_over(path: &Path, user: &User) -> std::io::Result<()> {
+    let req = Request::HandOver {
+        path: path.to_owned(),
+        user: user.name.clone(),
+    };
+
+    match request(&req)? {
+        Response::Done => Ok(()),
+        _ => Err(unexpected()),
+    }
+}
+
+/// Ask the helper if the process `pid` still runs the machine `runner_name`
+///
+/// Failing to ask is logged and treated like the machine not running.
+pub(super) fn is_alive(pid: u32, runner_name: &str) -> bool {
+    let req = Request::Alive {
+        pid,
+        runner_name: runner_name.to_owned(),
+    };
+
+    match request(&req) {
+        Ok(Response::Alive { alive }) => alive,
+        Ok(_) => {
+            warn!("Failed to check on machine {runner_name}: {}", unexpected());
+            false
+        }
+        Err(e) => {
+            warn!("Failed to check on machine {runner_name}: {e}");
+            false
+        }
+    }
+}
+
+/// Have the helper kill the process `pid` of the machine `runner_name`
+pub(super) fn kill_machine(pid: u32, runner_name: &str) -> std::io::Result<()> {
+    let req = Request::Kill {
+        pid,
+        runner_name: runner_name.to_owned(),
+    };
+
+    match request(&req)? {
+        Response::Done => Ok(()),
+        _ => Err(unexpected()),
+    }
+}
+
+/// Have the helper find the machine processes that do not belong to the `adopted` machines
+///
+/// See `adoption::strays()`.
+pub(super) fn strays(adopted: &HashSet<String>) -> std::io::Result<Vec<(u32, String)>> {
+    let req = Request::Strays {
+        adopted: adopted.clone(),
+    };
+
+    match request(&req)? {
+        Response::Strays { strays } => Ok(strays),
+        _ => Err(unexpected()),
+    }
+}
+
+/// Read the next response from the helper into `buf` and parse it
+///
+/// Reading into `buf` keeps what was read so far if this is cancelled,
+/// so it can be used in `tokio::select!`.
+async fn read_response(
+    stream: &mut BufReader<UnixStream>,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<Response> {
+    stream.read_until(b'\n', buf).await?;
+
+    if !buf.ends_with(b"\n") {
+        return Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "The helper closed the connection",
+        ));
+    }
+
+    let response = serde_json::from_slice(buf);
+    buf.clear();
+
+    match response? {
+        Response::Failed { error } => Err(std::io::Error::other(error)),
+        response => Ok(response),
+    }
+}
+
+/// A machine process the helper started for us
+///
+/// Like processes we spawn ourselves (see `kill_on_drop`) it is killed
+/// if it is still running when this is dropped.
+pub(super) struct Child {
+    pid: u32,
+    runner_name: String,
+    stream: BufReader<UnixStream>,
+    buf: Vec<u8>,
+    exited: bool,
+}
+
+impl Child {
+    /// Wait for the machine process to exit
+    pub(super) async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        match read_response(&mut self.stream, &mut self.buf).await? {
+            Response::Exited { status } => {
+                self.exited = true;
+                Ok(ExitStatus::from_raw(status))
+            }
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if self.exited {
+            return;
+        }
+
+        if let Err(e) = kill_machine(self.pid, &self.runner_name) {
+            warn!(
+                "Failed to kill process {} of machine {}: {e}",
+                self.pid, self.runner_name
+            );
+        }
+    }
+}
+
+/// Have the helper start the process of machine `runner_name`
+///
+/// The run directory has to be set up already.
+pub(super) async fn spawn(
+    triplet: &Triplet,
+    config_triplet: &Triplet,
+    runner_name: &str,
+    pinning: Option<Pinning>,
+) -> std::io::Result<Child> {
+    let path = SOCKET_PATH
+        .get()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No helper is configured"))?;
+
+    let mut stream = BufReader::new(UnixStream::connect(path).await?);
+
+    let request = Request::Spawn {
+        triplet: triplet.clone(),
+        config_triplet: config_triplet.clone(),
+        runner_name: runner_name.to_owned(),
+        pinning,
+    };
+
+    let mut line = serde_json::to_vec(&request)?;
+    line.push(b'\n');
+    stream.get_mut().write_all(&line).await?;
+
+    let mut buf = Vec::new();
+
+    match read_response(&mut stream, &mut buf).await? {
+        Response::Spawned { pid } => Ok(Child {
+            pid,
+            runner_name: runner_name.to_owned(),
+            stream,
+            buf,
+            exited: false,
+        }),
+        _ => Err(unexpected()),
+    }
+}
+
+/// The process of a machine, started either by us or by the helper
+pub(super) enum Spawned {
+    Directly(tokio::process::Child),
+    ViaHelper(Child),
+}
+
+impl Spawned {
+    pub(super) fn id(&self) -> Option<u32> {
+        match self {
+            Self::Directly(child) => child.id(),
+            Self::ViaHelper(child) => Some(child.pid),
+        }
+    }
+
+    pub(super) async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        match self {
+            Self::Directly(child) => child.wait().await,
+            Self::ViaHelper(child) => child.wait().await,
+        }
+    }
+}
+
+/// Make sure `runner_name` names a run directory and does not lead out of one
+fn check_runner_name(runner_name: &str) -> std::result::Result<(), String> {
+    let plain = !runner_name.is_empty()
+        && runner_name != "."
+        && runner_name != ".."
+        && !runner_name.contains('/');
+
+    match plain {
+        true => Ok(()),
+        false => Err(format!("Invalid runner name {runner_name:?}")),
+    }
+}
+
+/// Is `pid` a process running inside of the run directory of machine `runner_name`?
+fn is_machine(cfg: &ConfigFile, pid: u32, runner_name: &str) -> bool {
+    let runs = match cfg.host.base_dir.join("runs").canonicalize() {
+        Ok(runs) => runs,
+        Err(_) => return false,
+    };
+
+    std::fs::read_link(format!("/proc/{pid}/cwd"))
+        .is_ok_and(|cwd| cwd.starts_with(&runs) && cwd.ends_with(runner_name))
+}
+
+/// Hand `path` over to `user`, if it is a tenant user and the path is ours to give away
+fn serve_hand_over(cfg: &ConfigFile, path: &Path, user: &str) -> std::result::Result<(), String> {
+    let is_tenant = cfg
+        .tenancy
+        .owners
+        .values()
+        .any(|tenant| tenant.user.as_deref() == Some(user));
+
+    if !is_tenant {
+        return Err(format!("{user} is not the user of a tenant"));
+    }
+
+    // Only files in run directories, scratch pools, the overlay dir and the
+    // console log dir are handed over.
+    // Symlinks could lead anywhere else, e.g. to files only root may own.
+    let allowed: Vec<PathBuf> = std::iter::once(cfg.host.base_dir.join("runs"))
+        .chain(cfg.host.scratch.values().map(|pool| pool.path.clone()))
+        .chain(cfg.host.overlay_dir.clone())
+        .chain(cfg.host.console_log_dir.clone())
+        .filter_map(|dir| dir.canonicalize().ok())
+        .collect();
+
+    let refused = || format!("Refusing to hand {} over", path.display());
+
+    // Everything below works on the opened file, so that the path can not be
+    // swapped for something else between checking and changing it.
+    // Non-blocking, so that opening e.g. a FIFO does not hang.
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags((OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK).bits())
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(errno) if errno == Errno::ELOOP as i32 => refused(),
+            _ => e.to_string(),
+        })?;
+
+    // Where the file actually is, with all symlinks along the way resolved.
+    let canonical = std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        .map_err(|e| e.to_string())?;
+
+    let is_allowed = allowed
+        .iter()
+        .any(|dir| canonical.starts_with(dir) && canonical != *dir);
+
+    let metadata = file.metadata().map_err(|e| e.to_string())?;
+
+    // A hard link in one of the allowed directories could point to a file
+    // outside of them.
+    let is_linked = !metadata.is_dir() && metadata.nlink() > 1;
+
+    if !is_allowed || is_linked {
+        return Err(refused());
+    }
+
+    let user = User::from_name(user)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Unknown tenant user {user}"))?;
+
+    let mode = tenancy::hand_over_mode(metadata.is_dir());
+
+    file.set_permissions(Permissions::from_mode(mode))
+        .and_then(|()| fchown(&file, Some(user.uid.as_raw()), None))
+        .map_err(|e| e.to_string())
+}
+
+/// Assemble the command of a machine like the unprivileged instance would
+fn machine_command(
+    cfg: &ConfigFile,
+    triplet: &Triplet,
+    config_triplet: &Triplet,
+    runner_name: &str,
+    pinning: Option<&Pinning>,
+) -> std::result::Result<Command, String> {
+    check_runner_name(runner_name)?;
+
+    // Both triplets come from the unprivileged instance.
+    // A machine may run with the config of another machine type (e.g. for
+    // quarantine), but never with the one of another owner, which would run
+    // it as the user of the wrong tenant.
+    if config_triplet.owner() != triplet.owner() {
+        return Err(format!(
+            "Machine {runner_name} for {triplet} can not use the config of {config_triplet}"
+        ));
+    }
+
+    let tenant = tenancy::check(cfg, config_triplet)?;
+
+    let machine_config = cfg
+        .machine_config(config_triplet)
+        .ok_or_else(|| format!("Unknown machine triplet {config_triplet}"))?;
+
+    backend::check(machine_config)?;
+
+    if !spawns(machine_config, tenant) {
+        return Err(format!(
+            "Machine {runner_name} does not need the helper to be started"
+        ));
+    }
+
+    // The machine process never runs as root.
+    let user = tenancy::user(tenant)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("No tenant user for machine {runner_name}"))?;
+
+    if user.uid.is_root() {
+        return Err(format!("Refusing to start machine {runner_name} as root"));
+    }
+
+    let devices: Vec<_> = machine_config
+        .devices
+        .iter()
+        .filter_map(|name| cfg.host.devices.get(name))
+        .collect();
+
+    let run_dir = config_triplet.run_dir_path(&cfg.host.base_dir, runner_name);
+
+    if !run_dir.is_dir() {
+        return Err(format!("No run directory for machine {runner_name}"));
+    }
+
+    let scratch = run_dir::scratch_path(cfg, machine_config, config_triplet, runner_name)
+        .map_err(|e| e.to_string())?;
+
+    let mut command = backend::command(
+        &cfg.host,
+        machine_config,
+        tenant,
+        &devices,
+        pinning,
+        &run_dir,
+        scratch.as_deref(),
+    )
+    .map_err(|e| e.to_string())?;
+
+    command.uid(user.uid.as_raw()).gid(user.gid.as_raw());
+
+    Ok(command)
+}
+
+async fn respond(write: &mut OwnedWriteHalf, response: &Response) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+
+    write.write_all(&line).await
+}
+
+/// Start a machine process and report back once it has exited
+async fn serve_spawn(
+    cfg: &ConfigFile,
+    write: &mut OwnedWriteHalf,
+    triplet: &Triplet,
+    config_triplet: &Triplet,
+    runner_name: &str,
+    pinning: Option<&Pinning>,
+) -> std::io::Result<()> {
+    let spawned = machine_command(cfg, triplet, config_triplet, runner_name, pinning)
+        .and_then(|mut command| command.spawn().map_err(|e| e.to_string()));
+
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(error) => return respond(write, &Response::Failed { error }).await,
+    };
+
+    let pid = child.id().unwrap_or_default();
+
+    info!("Started machine {runner_name} as process {pid}");
+
+    // The instance that asked for the machine may be gone by now,
+    // e.g. because it was restarted.
+    // The machine keeps running regardless, like it would without the helper.
+    let _ = respond(write, &Response::Spawned { pid }).await;
+
+    let status = child.wait().await?;
+
+    debug!("Machine {runner_name} exited with {status}");
+
+    let _ = respond(
+        write,
+        &Response::Exited {
+            status: status.into_raw(),
+        },
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Is the peer of `sock` the unprivileged instance configured in `host.helper.user`?
+fn is_instance(cfg: &ConfigFile, sock: &UnixStream) -> std::io::Result<bool> {
+    let name = match &cfg.host.helper {
+        Some(helper) => &helper.user,
+        None => return Ok(false),
+    };
+
+    let user = User::from_name(name)?
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, format!("Unknown user {name}")))?;
+
+    Ok(sock.peer_cred()?.uid() == user.uid.as_raw())
+}
+
+/// Answer a single request of the unprivileged instance on `sock`
+async fn handle(cfg: &ConfigFile, sock: UnixStream) -> std::io::Result<()> {
+    if !is_instance(cfg, &sock)? {
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Connection from a user other than host.helper.user",
+        ));
+    }
+
+    let (read, mut write) = sock.into_split();
+    let mut read = BufReader::new(read.take(REQUEST_SIZE_LIMIT));
+
+    let mut line = String::new();
+    read.read_line(&mut line).await?;
+
+    let request: Request = serde_json::from_str(&line)?;
+
+    let response = match request {
+        Request::Spawn {
+            triplet,
+            config_triplet,
+            runner_name,
+            pinning,
+        } => {
+            return serve_spawn(
+                cfg,
+                &mut write,
+                &triplet,
+                &config_triplet,
+                &runner_name,
+                pinning.as_ref(),
+            )
+            .await
+        }
+        Request::HandOver { path, user } => {
+            serve_hand_over(cfg, &path, &user).map(|()| Response::Done)
+        }
+        Request::Alive { pid, runner_name } => Ok(Response::Alive {
+            alive: is_machine(cfg, pid, &runner_name),
+        }),
+        Request::Kill { pid, runner_name } => match is_machine(cfg, pid, &runner_name) {
+            true => kill(Pid::from_raw(pid as i32), Signal::SIGKILL)
+                .map(|()| Response::Done)
+                .map_err(|e| e.to_string()),
+            false => Err(format!("Process {pid} is not machine {runner_name}")),
+        },
+        Request::Strays { adopted } => Ok(Response::Strays {
+            strays: adoption::strays(cfg, &adopted),
+        }),
+    };
+
+    let response = response.unwrap_or_else(|error| Response::Failed { error });
+
+    respond(&mut write, &response).await
+}
+
+/// Serve the privileged requests of an unprivileged Forrest instance
+///
+/// This is meant to run as root, next to an instance with `host.helper`
+/// enabled that runs as an unprivileged user.
+/// The socket is only accessible to the group owning the `host.base_dir`.
+/// This never completes unless the socket can not be set up.
+pub async fn serve(config: Config) -> Result<()> {
+    let cfg = config.get();
+    let path = cfg.host.base_dir.join(SOCKET);
+
+    let helper = cfg
+        .host
+        .helper
+        .as_ref()
+        .ok_or_else(|| std::io::Error::other("host.helper is not configured"))?;
+
+    match User::from_name(&helper.user).map_err(std::io::Error::from)? {
+        Some(user) if !user.uid.is_root() => {}
+        Some(_) => {
+            return Err(std::io::Error::other("host.helper.user must not be root").into());
+        }
+        None => {
+            return Err(
+                std::io::Error::other(format!("Unknown host.helper.user {}", helper.user)).into(),
+            )
+        }
+    }
+
+    // Leave the files the machine processes create (logs, sockets, …)
+    // accessible to the group of the run directory.
+    umask(Mode::from_bits_truncate(0o007));
+
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    let gid = cfg.host.base_dir.metadata()?.gid();
+    chown(&path, None, Some(gid))?;
+    std::fs::set_permissions(&path, Permissions::from_mode(0o660))?;
+
+    info!("Serving privileged requests on {}", path.display());
+
+    loop {
+        let sock = match listener.accept().await {
+            Ok((sock, _)) => sock,
+            Err(e) => {
+                warn!("Failed to accept helper connection: {e}");
+                continue;
+            }
+        };
+
+        // Machines may be added to the config while we run.
+        let cfg = config.get();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(&cfg, sock).await {
+                warn!("Failed to handle a privileged request: {e}");
+            }
+        });
+    }
+}
@@ -1,88 +1,142 @@
-use std::ffi::OsString;
-use std::fmt::Write;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
-use octocrab::models::RunnerGroupId;
-use octocrab::models::{actions::SelfHostedRunnerJitConfig, RunnerId};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::{process::Command, task::AbortHandle};
-
+use serde::Serialize;
+use tokio::task::AbortHandle;
+
+use super::accounting::Accounting;
+use super::adoption;
+use super::backend;
+use super::concurrency::ConcurrencyLimits;
+use super::devices::Devices;
+use super::fallback::SpawnFailures;
+use super::helper::{self, Spawned};
 use super::manager::{Machines, Rescheduler};
+use super::metadata::{self, Metadata};
+use super::pinning::{FreeCores, Pinning};
+use super::registration::{Batch, Batches};
+use super::reservation::Reservations;
+use super::resources::{ResourceUsage, Source};
 use super::run_dir::RunDir;
+use super::tenancy;
 use super::triplet::Triplet;
-use crate::auth::Auth;
-use crate::config::{ConfigFile, MachineConfig};
-
-// The arguments used to start the qemu process.
-//
-// These assume a specific filesystem structure,
-// as set up by `RunDir`.
-// More arguments are added in the `Machine::qemu()` method based on
-// the machine configuration.
-const QEMU_CMD: &str = "/usr/bin/qemu-system-x86_64";
-const QEMU_ARGS: &[&[&str]] = &[
-    &["-enable-kvm"],
-    &["-nodefaults"],
-    &["-nographic"],
-    &["-M", "type=q35,accel=kvm,smm=on"],
-    &["-cpu", "max"],
-    &["-global", "ICH9-LPC.disable_s3=1"],
-    &["-device", "virtio-net-pci,netdev=uplink"],
-    &["-netdev", "user,id=uplink,ipv4=on,ipv6=on,ipv6-net=::/0"],
-    &["-object", "rng-random,filename=/dev/urandom,id=rng0"],
-    &["-device", "virtio-rng-pci,rng=rng0,id=rng-device0"],
-    &["-device", "isa-serial,chardev=bootlog"],
-    &["-device", "isa-serial,chardev=telnet"],
-    &["-chardev", "file,id=bootlog,path=log.txt"],
-    &[
-        "-chardev",
-        "socket,id=telnet,server=on,wait=off,path=shell.sock",
-    ],
-    &[
-        "-drive",
-        "if=virtio,format=raw,discard=unmap,cache=unsafe,file=disk.img",
-    ],
-    &[
-        "-drive",
-        "if=virtio,format=raw,discard=unmap,cache=unsafe,file=cloud-init.img",
-    ],
-    &[
-        "-drive",
-        "if=virtio,format=raw,discard=unmap,cache=unsafe,file=job-config.img",
-    ],
-];
+use crate::config::{Accel, ConfigFile, MachineConfig};
+use crate::forge::{Forge, Registration};
+use crate::metrics::Metrics;
 
 #[derive(PartialEq, Clone, Copy, Debug)]
+#[repr(u8)]
 pub(super) enum Status {
     Requested,
     Registering,
     Registered,
     Starting,
     Waiting,
+    Paused,
     Running,
+    Draining,
     Stopping,
     Stopped,
 }
 
+// The number of state transitions to keep in the history of each machine.
+const HISTORY_LENGTH: usize = 32;
+
+// How often the resources used by a running machine are sampled.
+const RESOURCE_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Only shrink the RAM of machines that waited this long for a job.
+// Machines often get a job right after booting.
+const BALLOON_IDLE_DELAY: Duration = Duration::from_secs(60);
+
+// The number of random characters at the end of the runner names.
+const RUNNER_NAME_SUFFIX_LEN: usize = 16;
+
+// How long a guest that was asked to shut down gets to do so,
+// before its machine process is killed.
+const POWER_DOWN_GRACE: Duration = Duration::from_secs(60);
+
+// How long qemu gets to exit after its guest has shut down.
+const QEMU_EXIT_GRACE: Duration = Duration::from_secs(10);
+
+// How much lower the default priority of emulated machines is
+// than that of the native machines of their repository.
+const EMULATED_PRIORITY_PENALTY: i32 = 1;
+
+/// A state transition of a machine and what caused it
+#[derive(Serialize, Clone)]
+pub struct Transition {
+    pub at: DateTime<Utc>,
+    pub from: String,
+    pub to: String,
+    pub reason: String,
+}
+
+/// A `Status` that can be read without locking `Inner`
+///
+/// The status is read a lot when the `machines::Manager` goes through its
+/// list of machines, e.g. to sum up the RAM consumed.
+/// It is only ever written while holding the lock on `Inner` though,
+/// so code holding the lock sees a consistent state.
+struct AtomicStatus(AtomicU8);
+
+/// An `Option<Instant>` that can be read without locking `Inner`
+///
+/// Stored as the nanoseconds since `base`, with `NONE` meaning `None`.
+struct AtomicInstant {
+    base: Instant,
+    nanos: AtomicU64,
+}
+
 /// The mutable part of `Machine`.
 /// These are modified when the machine transitiones through the different states.
 struct Inner {
     abort: Option<AbortHandle>,
-    jit_config: Option<SelfHostedRunnerJitConfig>,
+    history: VecDeque<Transition>,
+    jit_config: Option<Registration>,
+    /// The host CPU cores the machine is pinned to, if any
+    pinning: Option<Pinning>,
+    /// The resource that last held back the start of the machine, if any
+    postponed_by: Option<String>,
+    /// Was the guest asked to shut down, instead of being killed right away?
+    powering_down: bool,
+    resources: Option<ResourceUsage>,
     run_dir: Option<RunDir>,
-    started: Option<Instant>,
-    status: Status,
 }
 
 pub(super) struct Machine {
-    auth: Arc<Auth>,
+    /// The accelerator picked for the machine, if its backend has a choice
+    accel: Option<Accel>,
+    accounting: Arc<Accounting>,
+    /// Is the machine meant for a job of a boosted workflow run?
+    boosted: AtomicBool,
+    /// Was the RAM of the idle machine shrunk via its balloon device?
+    ballooned: AtomicBool,
+    /// When the machine entered its current status
+    entered: AtomicInstant,
+    /// When the machine last entered the running status
+    running_since: AtomicInstant,
+    /// Was the machine started in a slot reserved for protected branches?
+    reserved_slot: AtomicBool,
+    /// When the machine process was spawned or adopted, until it is accounted for
+    started: AtomicInstant,
     cfg: Arc<ConfigFile>,
+    devices: Arc<Devices>,
+    forge: Arc<dyn Forge>,
     inner: Mutex<Inner>,
+    metrics: Metrics,
     rescheduler: Rescheduler,
     runner_name: String,
+    status: AtomicStatus,
+    spawn_failures: Arc<SpawnFailures>,
     triplet: Triplet,
+    config_triplet: Triplet,
+    quarantined: bool,
 }
 
 impl Status {
@@ -95,8 +149,9 @@ impl Status {
             | Self::Registering
             | Self::Registered
             | Self::Starting
-            | Self::Waiting => true,
-            Self::Running | Self::Stopping | Self::Stopped => false,
+            | Self::Waiting
+            | Self::Paused => true,
+            Self::Running | Self::Draining | Self::Stopping | Self::Stopped => false,
         }
     }
 
@@ -110,6 +165,73 @@ impl Status {
     }
 }
 
+impl AtomicStatus {
+    const ALL: [Status; 10] = [
+        Status::Requested,
+        Status::Registering,
+        Status::Registered,
+        Status::Starting,
+        Status::Waiting,
+        Status::Paused,
+        Status::Running,
+        Status::Draining,
+        Status::Stopping,
+        Status::Stopped,
+    ];
+
+    fn new(status: Status) -> Self {
+        Self(AtomicU8::new(status as u8))
+    }
+
+    fn load(&self) -> Status {
+        Self::ALL[self.0.load(Ordering::Acquire) as usize]
+    }
+
+    fn store(&self, status: Status) {
+        self.0.store(status as u8, Ordering::Release)
+    }
+}
+
+impl AtomicInstant {
+    const NONE: u64 = u64::MAX;
+
+    fn new() -> Self {
+        Self {
+            base: Instant::now(),
+            nanos: AtomicU64::new(Self::NONE),
+        }
+    }
+
+    fn encode(&self, instant: Option<Instant>) -> u64 {
+        match instant {
+            Some(instant) => {
+                let nanos = instant.saturating_duration_since(self.base).as_nanos();
+                u64::try_from(nanos).unwrap_or(Self::NONE - 1)
+            }
+            None => Self::NONE,
+        }
+    }
+
+    fn decode(&self, nanos: u64) -> Option<Instant> {
+        (nanos != Self::NONE).then(|| self.base + Duration::from_nanos(nanos))
+    }
+
+    fn load(&self) -> Option<Instant> {
+        self.decode(self.nanos.load(Ordering::Acquire))
+    }
+
+    fn store(&self, instant: Option<Instant>) {
+        self.nanos.store(self.encode(instant), Ordering::Release)
+    }
+
+    /// Take the instant out, leaving `None`
+    ///
+    /// Only one of multiple concurrent callers gets `Some`.
+    fn take(&self) -> Option<Instant> {
+        self.decode(self.nanos.swap(Self::NONE, Ordering::AcqRel))
+    }
+}
+
 impl std::fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.write_str(match self {
@@ -118,7 +240,9 @@ impl std::fmt::Display for Status {
             Self::Registered => "registered",
             Self::Starting => "starting",
             Self::Waiting => "waiting",
+            Self::Paused => "paused",
             Self::Running => "running",
+            Self::Draining => "draining",
             Self::Stopping => "stopping",
             Self::Stopped => "stopped",
         })
@@ -133,41 +257,88 @@ impl Inner {
             .map(|jc| jc.encoded_jit_config.clone())
     }
 
-    fn runner_id(&self) -> Option<RunnerId> {
-        self.jit_config.as_ref().map(|jc| jc.runner.id)
+    fn runner_id(&self) -> Option<String> {
+        self.jit_config.as_ref().map(|jc| jc.runner_id.clone())
     }
 }
 
 impl Machine {
+    /// Does `runner_name` look like the name of a runner we created for `triplet`?
+    ///
+    /// See `new()` for how the names are built.
+    pub(super) fn is_runner_name_of(triplet: &Triplet, runner_name: &str) -> bool {
+        let suffix = runner_name
+            .strip_prefix("forrest-")
+            .and_then(|rest| rest.strip_prefix(triplet.machine_name()))
+            .and_then(|rest| rest.strip_prefix('-'));
+
+        suffix.is_some_and(|suffix| {
+            suffix.len() == RUNNER_NAME_SUFFIX_LEN
+                && suffix.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+    }
+
     /// Get a new machine in the `Requested` state.
     ///
     /// # Arguments
     ///
     /// * `cfg` - The version of the config file this machine will use throughout
     ///   its lifetime.
-    /// * `auth` - The authentication cache we use to register the jit runner with
-    ///   GitHub. This has to know about the user in `triplet` already.
+    /// * `accounting` - Where the running time of the machine is accounted to
+    ///   its owner once it stops.
+    /// * `devices` - Keeps track of the host devices assigned to machines.
+    /// * `spawn_failures` - Decides if a fallback machine type is used instead
+    ///   of the one in `triplet` and is told if the machine came up.
+    /// * `forge` - The forge to register the jit runner with.
+    ///   For GitHub this has to know about the user in `triplet` already.
+    /// * `metrics` - Where the resources used by the machine are recorded.
     /// * `rescheduler` - Used to trigger a reschedule from the `machines::Manager`
     ///   once the machine exits and its resources are available to other machines.
     /// * `triplet` - The (owner, repository, machine name) triplet that requested
     ///   this machine.
+    /// * `quarantine` - The machine type to use the config of, because the
+    ///   machine may pick up quarantined jobs of `triplet`.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         cfg: Arc<ConfigFile>,
-        auth: Arc<Auth>,
+        accounting: Arc<Accounting>,
+        devices: Arc<Devices>,
+        spawn_failures: Arc<SpawnFailures>,
+        forge: Arc<dyn Forge>,
+        metrics: Metrics,
         rescheduler: Rescheduler,
         triplet: Triplet,
+        quarantine: Option<Triplet>,
     ) -> Option<Arc<Self>> {
-        let machine_config = cfg
-            .repositories
-            .get(triplet.owner())
-            .and_then(|repos| repos.get(triplet.repository()))
-            .and_then(|repo| repo.machines.get(triplet.machine_name()));
-
-        if machine_config.is_none() {
+        if cfg.machine_config(&triplet).is_none() {
             error!("Got request for unknown machine triplet: {triplet}");
             return None;
         }
 
+        // The machine may be run using the config of a quarantine or fallback
+        // machine type, of the machine type `triplet` is an alias of,
+        // or of one of its variants, but is still registered for the jobs
+        // of `triplet`.
+        let quarantined = quarantine.is_some();
+        let canonical = cfg
+            .alias_of(&triplet)
+            .or_else(|| cfg.variant_of(&triplet))
+            .unwrap_or_else(|| triplet.clone());
+        let config_triplet = spawn_failures.pick(&cfg, quarantine.as_ref().unwrap_or(&canonical));
+        let machine_config = cfg.machine_config(&config_triplet).unwrap();
+
+        if let Err(err) = tenancy::check(&cfg, &config_triplet) {
+            error!("Refusing to create machine for {triplet}: {err}");
+            return None;
+        }
+
+        if let Err(err) = backend::check(machine_config) {
+            error!("Refusing to create machine for {triplet}: {err}");
+            return None;
+        }
+
+        let accel = backend::accelerator(machine_config);
+
         let runner_name = {
             // Build a runner name like "forrest-build-rHCiNOhFdypjtnfj"
 
@@ -175,30 +346,172 @@ impl Machine {
 
             name.extend(triplet.machine_name().as_bytes());
             name.push(b'-');
-            name.extend(thread_rng().sample_iter(&Alphanumeric).take(16));
+            name.extend(
+                thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(RUNNER_NAME_SUFFIX_LEN),
+            );
 
             String::from_utf8(name).unwrap()
         };
 
         let inner = Mutex::new(Inner {
-            status: Status::Requested,
+            history: VecDeque::new(),
             run_dir: None,
             abort: None,
             jit_config: None,
-            started: None,
+            pinning: None,
+            postponed_by: None,
+            powering_down: false,
+            resources: None,
         });
 
         Some(Arc::new(Self {
+            accel,
             triplet,
+            config_triplet,
+            quarantined,
             rescheduler,
             runner_name,
-            auth,
+            status: AtomicStatus::new(Status::Requested),
+            accounting,
+            boosted: AtomicBool::new(false),
+            ballooned: AtomicBool::new(false),
+            entered: AtomicInstant::new(),
+            running_since: AtomicInstant::new(),
+            reserved_slot: AtomicBool::new(false),
+            started: AtomicInstant::new(),
             cfg,
+            devices,
+            forge,
+            metrics,
+            spawn_failures,
             inner,
         }))
     }
 
-    fn inner(&self) -> std::sync::MutexGuard<Inner> {
+    /// Take over a machine a previous Forrest instance left running
+    ///
+    /// The machine starts out in the `Starting` state and is moved on to
+    /// `Waiting` or `Running` by the next feedback about its runner.
+    /// The arguments work like they do for `new()`, with the `triplet`s and
+    /// the runner taken from the `record` the previous instance left behind.
+    ///
+    /// Returns `None` if the machine can not be taken over, e.g. because its
+    /// machine type was removed from the config in the meantime.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn adopt(
+        cfg: Arc<ConfigFile>,
+        accounting: Arc<Accounting>,
+        devices: Arc<Devices>,
+        spawn_failures: Arc<SpawnFailures>,
+        forge: Arc<dyn Forge>,
+        metrics: Metrics,
+        rescheduler: Rescheduler,
+        mut record: adoption::Record,
+    ) -> Option<Arc<Self>> {
+        let machine_config = match cfg.machine_config(&record.config_triplet) {
+            Some(mc) if backend::can_adopt(mc) => mc,
+            _ => {
+                error!(
+                    "Can not take over machine {}, {} is no longer configured for it",
+                    record.runner_name, record.config_triplet
+                );
+                return None;
+            }
+        };
+
+        // The previous instance did not get to account the time the machine
+        // ran for it, so do it now and make sure it is not accounted twice.
+        let unaccounted = (Utc::now() - record.unaccounted_since)
+            .to_std()
+            .unwrap_or_default();
+
+        accounting.record(record.triplet.owner(), unaccounted, machine_config.cost);
+
+        record.unaccounted_since = Utc::now();
+
+        let accel = backend::accelerator(machine_config);
+
+        let run_dir_path = record
+            .config_triplet
+            .run_dir_path(&cfg.host.base_dir, &record.runner_name);
+
+        if let Err(e) = record.write(&run_dir_path) {
+            warn!(
+                "Failed to update the adoption record of {}: {e}",
+                record.runner_name
+            );
+        }
+
+        let adoption::Record {
+            triplet,
+            config_triplet,
+            quarantined,
+            runner_name,
+            runner_id,
+            pid,
+            run_dir,
+            pinning,
+            ..
+        } = record;
+
+        let run_dir = RunDir::reopen(&cfg, &config_triplet, &runner_name, run_dir);
+
+        // The machine still holds its host devices, so they must not be
+        // handed out to a new one, even if the previous instance did not
+        // get to persist their assignment.
+        if !machine_config.devices.is_empty() {
+            devices.assign(&machine_config.devices, &runner_name);
+            devices.spawned(&runner_name, pid);
+        }
+
+        // The encoded config was only needed to start the runner.
+        let jit_config = Registration {
+            runner_id,
+            encoded_jit_config: String::new(),
+        };
+
+        let inner = Mutex::new(Inner {
+            history: VecDeque::new(),
+            run_dir: Some(run_dir),
+            abort: None,
+            jit_config: Some(jit_config),
+            pinning,
+            postponed_by: None,
+            powering_down: false,
+            resources: None,
+        });
+
+        let machine = Arc::new(Self {
+            accel,
+            triplet,
+            config_triplet,
+            quarantined,
+            rescheduler,
+            runner_name,
+            status: AtomicStatus::new(Status::Registered),
+            accounting,
+            boosted: AtomicBool::new(false),
+            ballooned: AtomicBool::new(false),
+            entered: AtomicInstant::new(),
+            running_since: AtomicInstant::new(),
+            reserved_slot: AtomicBool::new(false),
+            started: AtomicInstant::new(),
+            cfg,
+            devices,
+            forge,
+            metrics,
+            spawn_failures,
+            inner,
+        });
+
+        machine.watch(pid);
+
+        Some(machine)
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
         self.inner.lock().unwrap()
     }
 
@@ -209,13 +522,15 @@ impl Machine {
     /// It makes more sense to kill a machine that is e.g. not yet registered as runner
     /// instead of one that is already booted and waiting for a job.
     pub(super) fn cost_to_kill(&self) -> u32 {
-        match self.inner().status {
+        match self.status() {
             Status::Requested => 0,
             Status::Registering => 1,
             Status::Registered => 2,
             Status::Starting => 3,
-            Status::Waiting => 4,
-            Status::Running | Status::Stopping | Status::Stopped => u32::MAX,
+            // A paused machine is of no use right now.
+            Status::Paused => 4,
+            Status::Waiting => 5,
+            Status::Running | Status::Draining | Status::Stopping | Status::Stopped => u32::MAX,
         }
     }
 
@@ -227,64 +542,369 @@ impl Machine {
         &self.triplet
     }
 
-    pub(super) fn machine_config(&self) -> &MachineConfig {
-        let cfg = self.cfg();
-        let triplet = self.triplet();
+    /// The machine type whose config this machine uses
+    ///
+    /// This differs from `triplet()` if a fallback machine type is used.
+    pub(super) fn config_triplet(&self) -> &Triplet {
+        &self.config_triplet
+    }
 
-        let machine_config = cfg
-            .repositories
-            .get(triplet.owner())
-            .and_then(|repos| repos.get(triplet.repository()))
-            .and_then(|repo| repo.machines.get(triplet.machine_name()));
+    /// Does this machine use the quarantine config of its machine type?
+    pub(super) fn is_quarantined(&self) -> bool {
+        self.quarantined
+    }
 
-        machine_config.unwrap()
+    pub(super) fn machine_config(&self) -> &MachineConfig {
+        self.cfg().machine_config(self.config_triplet()).unwrap()
     }
 
     /// The amount of RAM (in bytes) the machine may currently consume
+    ///
+    /// Idle machines whose RAM was shrunk via their balloon device only
+    /// consume their `balloon.idle_ram`.
     pub(super) fn ram_consumed(&self) -> u64 {
-        match self.inner().status {
+        let ballooned_ram = self
+            .machine_config()
+            .balloon
+            .as_ref()
+            .filter(|_| self.ballooned.load(Ordering::Relaxed))
+            .map(|balloon| balloon.idle_ram.bytes().min(self.ram_required()));
+
+        match (self.status(), ballooned_ram) {
+            (Status::Waiting | Status::Paused, Some(ram)) => ram,
+            _ => self.ram_consumed_nominal(),
+        }
+    }
+
+    /// The amount of RAM (in bytes) the machine may consume, ignoring its balloon
+    pub(super) fn ram_consumed_nominal(&self) -> u64 {
+        match self.status() {
             Status::Requested | Status::Registering | Status::Registered | Status::Stopped => 0,
-            Status::Starting | Status::Waiting | Status::Running | Status::Stopping => {
-                self.ram_required()
-            }
+            Status::Starting
+            | Status::Waiting
+            | Status::Paused
+            | Status::Running
+            | Status::Draining
+            | Status::Stopping => self.ram_required(),
+        }
+    }
+
+    /// The priority of the machine type, falling back to that of the repository
+    ///
+    /// Machines for jobs of boosted workflow runs go before all others.
+    /// Emulated machines, which use a lot more host CPU time for the same work,
+    /// go after the native machines of their repository unless their machine
+    /// type sets a priority.
+    pub(super) fn priority(&self) -> i32 {
+        if self.boosted.load(Ordering::Relaxed) {
+            return i32::MAX;
         }
+
+        let repo_priority = self
+            .cfg
+            .repositories
+            .get(self.config_triplet.owner())
+            .and_then(|repos| repos.get(self.config_triplet.repository()))
+            .and_then(|repo| repo.priority);
+
+        let emulation_penalty = match self.machine_config().arch.is_native() {
+            true => 0,
+            false => EMULATED_PRIORITY_PENALTY,
+        };
+
+        self.machine_config()
+            .priority
+            .unwrap_or_else(|| repo_priority.unwrap_or(0) - emulation_penalty)
+    }
+
+    /// Mark the machine as being meant for a job of a boosted workflow run
+    pub(super) fn set_boosted(&self, boosted: bool) {
+        self.boosted.store(boosted, Ordering::Relaxed);
+    }
+
+    /// The resource that last held back the start of the machine, if any
+    pub(super) fn postponed_by(&self) -> Option<String> {
+        self.inner().postponed_by.clone()
+    }
+
+    /// Does the machine currently occupy a slot reserved for protected branches?
+    pub(super) fn in_reserved_slot(&self) -> bool {
+        self.reserved_slot.load(Ordering::Relaxed) && self.ram_consumed() > 0
     }
 
     /// Get the amount of RAM (in bytes) the machine would consume if it were started
     pub(super) fn ram_required(&self) -> u64 {
-        self.machine_config().ram.bytes()
+        let machine_config = self.machine_config();
+
+        match backend::runs_on_host(machine_config.backend) {
+            true => machine_config.ram.bytes(),
+            false => 0,
+        }
+    }
+
+    /// The number of host CPU cores the machine may currently consume
+    pub(super) fn cpus_consumed(&self) -> u32 {
+        match self.status() {
+            Status::Requested | Status::Registering | Status::Registered | Status::Stopped => 0,
+            Status::Starting
+            | Status::Waiting
+            | Status::Paused
+            | Status::Running
+            | Status::Draining
+            | Status::Stopping => self.cpus_required(),
+        }
+    }
+
+    /// Get the number of host CPU cores the machine would consume if it were started
+    pub(super) fn cpus_required(&self) -> u32 {
+        let machine_config = self.machine_config();
+
+        match backend::runs_on_host(machine_config.backend) {
+            true => machine_config.cpus,
+            false => 0,
+        }
+    }
+
+    /// The amount of space (in bytes) the disk image of the machine may currently take up
+    pub(super) fn disk_consumed(&self) -> u64 {
+        match self.status() {
+            Status::Requested | Status::Registering | Status::Registered | Status::Stopped => 0,
+            Status::Starting
+            | Status::Waiting
+            | Status::Paused
+            | Status::Running
+            | Status::Draining
+            | Status::Stopping => self.disk_required(),
+        }
+    }
+
+    /// Get the amount of space (in bytes) the disk image would take up if the machine were started
+    ///
+    /// The reflink copy initially shares all blocks with its source image,
+    /// but may in the worst case diverge up to the full disk size.
+    pub(super) fn disk_required(&self) -> u64 {
+        let machine_config = self.machine_config();
+
+        match backend::uses_disk_image(machine_config.backend) {
+            true => machine_config.disk.bytes(),
+            false => 0,
+        }
+    }
+
+    /// The scratch pool and amount of space in it (in bytes) the machine may currently consume
+    pub(super) fn scratch_consumed(&self) -> Option<(&str, u64)> {
+        match self.status() {
+            Status::Requested | Status::Registering | Status::Registered | Status::Stopped => None,
+            Status::Starting
+            | Status::Waiting
+            | Status::Paused
+            | Status::Running
+            | Status::Draining
+            | Status::Stopping => self.scratch_required(),
+        }
+    }
+
+    /// The host CPU cores the machine currently occupies, if it is pinned
+    pub(super) fn pinned_cores(&self) -> Option<Pinning> {
+        match self.status() {
+            Status::Requested | Status::Registering | Status::Registered | Status::Stopped => None,
+            Status::Starting
+            | Status::Waiting
+            | Status::Paused
+            | Status::Running
+            | Status::Draining
+            | Status::Stopping => self.inner().pinning.clone(),
+        }
+    }
+
+    /// Get the scratch pool and amount of space in it (in bytes) the machine would
+    /// consume if it were started
+    pub(super) fn scratch_required(&self) -> Option<(&str, u64)> {
+        self.machine_config()
+            .scratch
+            .as_ref()
+            .map(|scratch| (scratch.pool.as_str(), scratch.size.bytes()))
     }
 
     pub(super) fn runner_name(&self) -> &str {
         &self.runner_name
     }
 
+    /// The forge this machine registers with as runner
+    pub(super) fn forge(&self) -> &Arc<dyn Forge> {
+        &self.forge
+    }
+
     /// The amount of time the machine has already spent in the starting state
     ///
     /// E.g. the machine was booted but we did not observe it registering as
     /// runner yet via the API.
+    /// Machines whose runner registration with the forge is still pending
+    /// count as starting as well.
     pub(super) fn starting_duration(&self) -> Option<Duration> {
+        match self.status() {
+            Status::Registering => self.time_in_state(),
+            Status::Starting => self.started.load().map(|s| s.elapsed()),
+            _ => None,
+        }
+    }
+
+    /// How long the machine has been in its current state
+    ///
+    /// Like the other durations this is read without locking `Inner`,
+    /// as the `machines::Manager` checks them for every machine periodically.
+    fn time_in_state(&self) -> Option<Duration> {
+        self.entered.load().map(|entered| entered.elapsed())
+    }
+
+    /// How long the machine has been waiting for a job, if it is
+    pub(super) fn waiting_duration(&self) -> Option<Duration> {
+        match self.status() {
+            Status::Waiting => self.time_in_state(),
+            _ => None,
+        }
+    }
+
+    /// How long the machine has been running its job, if it is
+    pub(super) fn running_duration(&self) -> Option<Duration> {
+        match self.status() {
+            Status::Running => self.time_in_state(),
+            // The job was picked up before the machine started draining.
+            Status::Draining => self.running_since.load().map(|since| since.elapsed()),
+            _ => None,
+        }
+    }
+
+    /// Why the machine is retired after its current job, if it is
+    pub(super) fn draining_reason(&self) -> Option<String> {
         let inner = self.inner();
 
-        match inner.status {
-            Status::Starting => inner.started.map(|s| s.elapsed()),
+        match self.status() {
+            Status::Draining => inner.history.back().map(|t| t.reason.clone()),
             _ => None,
         }
     }
 
+    /// Is the machine left running for the next Forrest instance to take over?
+    ///
+    /// This is the case if `host.keep_machines_on_restart` is enabled
+    /// and the backend supports it.
+    pub(super) fn outlives_instance(&self) -> bool {
+        let spawned = matches!(
+            self.status(),
+            Status::Starting
+                | Status::Waiting
+                | Status::Paused
+                | Status::Running
+                | Status::Draining
+        );
+
+        spawned
+            && self.cfg.host.keep_machines_on_restart
+            && backend::can_adopt(self.machine_config())
+    }
+
+    /// Is the runner of the machine de-registered (or was it never registered)?
+    pub(super) fn is_deregistered(&self) -> bool {
+        self.inner().runner_id().is_none()
+    }
+
     pub(super) fn status(&self) -> Status {
-        self.inner().status
+        self.status.load()
+    }
+
+    /// The forge specific runner id of a machine that was spawned
+    /// and whose runner may have come online since
+    pub(super) fn spawned_runner_id(&self) -> Option<String> {
+        let inner = self.inner();
+
+        match self.status() {
+            Status::Starting
+            | Status::Waiting
+            | Status::Paused
+            | Status::Running
+            | Status::Draining => inner.runner_id(),
+            _ => None,
+        }
+    }
+
+    /// Get the most recent state transitions of this machine, oldest first
+    pub(super) fn history(&self) -> Vec<Transition> {
+        self.inner().history.iter().cloned().collect()
+    }
+
+    /// Move the machine to the `new` state and note the `reason` in its history
+    ///
+    /// Takes `inner` to make sure the lock is held while changing the state.
+    fn transition(&self, inner: &mut Inner, new: Status, reason: impl Into<String>) {
+        let old = self.status();
+
+        if old == new {
+            return;
+        }
+
+        if inner.history.len() >= HISTORY_LENGTH {
+            inner.history.pop_front();
+        }
+
+        inner.history.push_back(Transition {
+            at: Utc::now(),
+            from: old.to_string(),
+            to: new.to_string(),
+            reason: reason.into(),
+        });
+
+        // Stored before the status, so that a reader seeing the new status
+        // also sees when it was entered.
+        let now = Some(Instant::now());
+
+        self.entered.store(now);
+
+        if new == Status::Running {
+            self.running_since.store(now);
+        }
+
+        self.status.store(new);
+    }
+
+    /// Is this machine waiting for a job while a newer image is available?
+    ///
+    /// Such machines are better replaced by ones booted from the new image
+    /// before they pick up a job.
+    pub(super) fn is_stale(&self, cfg: &ConfigFile) -> bool {
+        self.status() == Status::Waiting && self.is_outdated(cfg)
+    }
+
+    /// Was the machine booted from an image that has been updated since?
+    pub(super) fn is_outdated(&self, cfg: &ConfigFile) -> bool {
+        let inner = self.inner();
+
+        let outdated = inner
+            .run_dir
+            .as_ref()
+            .map(|run_dir| run_dir.is_outdated(cfg, &self.config_triplet));
+
+        match outdated {
+            Some(Ok(outdated)) => outdated,
+            Some(Err(err)) => {
+                warn!("Failed to check if the image of {self} is outdated: {err}");
+                false
+            }
+            None => false,
+        }
     }
 
     /// Register this machine as a JIT GitHub runner
-    fn register(self: &Arc<Self>, inner: &mut Inner) {
-        assert_eq!(inner.status, Status::Requested);
+    ///
+    /// The registration is sent as part of `batch`, so that many machines
+    /// requested at once share the installation token.
+    fn register(self: &Arc<Self>, inner: &mut Inner, batch: Arc<Batch>) {
+        assert_eq!(self.status(), Status::Requested);
 
         let machine = self.clone();
 
         let task = tokio::spawn(async move {
             let triplet = machine.triplet();
-            let installation_octocrab = machine.auth.user(machine.triplet.owner()).unwrap();
 
             let labels = vec![
                 "self-hosted".to_owned(),
@@ -292,18 +912,12 @@ impl Machine {
                 triplet.machine_name().into(),
             ];
 
-            let runner_group = RunnerGroupId(1);
-
-            let jit_config = installation_octocrab
-                .actions()
-                .create_repo_jit_runner_config(
-                    triplet.owner(),
-                    triplet.repository(),
-                    &machine.runner_name,
-                    runner_group,
-                    labels,
+            let jit_config = batch
+                .send(
+                    machine
+                        .forge
+                        .register_runner(triplet, &machine.runner_name, labels),
                 )
-                .send()
                 .await;
 
             let mut inner = machine.inner();
@@ -312,19 +926,21 @@ impl Machine {
                 Ok(jc) => {
                     debug!(
                         "Registered jit runner for {}: {} {}",
-                        machine.triplet, machine.runner_name, jc.runner.id
+                        machine.triplet, machine.runner_name, jc.runner_id
                     );
 
-                    inner.status = Status::Registered;
+                    machine.transition(&mut inner, Status::Registered, "registered jit runner");
                     inner.jit_config = Some(jc);
                 }
                 Err(err) => {
                     error!(
-                        "Failed to register jit runner for {}: {err}",
-                        machine.triplet
+                        "Failed to register jit runner for {} ({}): {err}",
+                        machine.triplet,
+                        err.category()
                     );
 
-                    inner.status = Status::Stopped;
+                    let reason = format!("failed to register jit runner: {err}");
+                    machine.transition(&mut inner, Status::Stopped, reason);
                 }
             }
 
@@ -337,52 +953,139 @@ impl Machine {
             machine.rescheduler.reschedule();
         });
 
-        inner.status = Status::Registering;
+        self.transition(inner, Status::Registering, "scheduled");
         inner.abort = Some(task.abort_handle());
     }
 
-    /// Spawn the qemu process and wait for its completion
-    async fn qemu(&self) -> std::io::Result<()> {
+    /// What the machine can learn about itself via the metadata endpoint
+    fn metadata(&self) -> Metadata {
         let machine_config = self.machine_config();
 
-        // Set up virtfs directory forwarding from the host to the machine.
-        let virtfs_args = machine_config.shared.iter().flat_map(|dir| {
-            let mut arg = OsString::new();
+        let remaining_job_time = machine_config.max_job_duration.map(|max| {
+            let running = self.running_duration().unwrap_or_default();
+            max.saturating_sub(running).as_secs()
+        });
+
+        Metadata {
+            machine: self.triplet.to_string(),
+            runner_name: self.runner_name.clone(),
+            cpus: machine_config.cpus,
+            ram: machine_config.ram.bytes(),
+            disk: machine_config.disk.bytes(),
+            remaining_job_time,
+        }
+    }
 
-            let tag = &dir.tag;
-            let readonly = if dir.writable { "off" } else { "on" };
+    /// Spawn the qemu or systemd-nspawn process and wait for its completion
+    async fn run_backend(&self) -> std::io::Result<()> {
+        let (command, pinning, run_dir_path) = {
+            let inner = self.inner();
+            let run_dir = inner.run_dir.as_ref().unwrap();
+            let tenant = self.cfg.tenancy.owners.get(self.triplet.owner());
+
+            let devices: Vec<_> = self
+                .machine_config()
+                .devices
+                .iter()
+                .filter_map(|name| self.cfg.host.devices.get(name))
+                .collect();
+
+            // The helper assembles the command on its own.
+            let command = match helper::spawns(self.machine_config(), tenant) {
+                true => None,
+                false => Some(backend::command(
+                    &self.cfg.host,
+                    self.machine_config(),
+                    tenant,
+                    &devices,
+                    inner.pinning.as_ref(),
+                    run_dir.path(),
+                    run_dir.scratch(),
+                )?),
+            };
+
+            (command, inner.pinning.clone(), run_dir.path().to_owned())
+        };
 
-            write!(&mut arg, "local,security_model=none,",).unwrap();
-            write!(&mut arg, "mount_tag={tag},readonly={readonly},path=",).unwrap();
+        // The daemons serving virtiofs shares are stopped once we return.
+        let tenant = self.cfg.tenancy.owners.get(self.triplet.owner());
+        let _shares = backend::prepare_shares(self.machine_config(), tenant, &run_dir_path).await?;
 
-            arg.push(dir.path.as_os_str());
+        // Containers are not stopped along with the process that started them.
+        let mut cleanup = backend::Cleanup::new(self.machine_config(), &run_dir_path);
 
-            ["-virtfs".into(), arg].into_iter()
-        });
+        backend::prepare_config(self.machine_config(), &run_dir_path).await?;
 
-        // Assemble the complete set of arguments to pass to the qemu command.
-        let mut qemu = {
-            let inner = self.inner();
-            let ram = machine_config.ram.megabytes().to_string();
-            let smp = machine_config.cpus.to_string();
-            let pwd = inner.run_dir.as_ref().unwrap();
-
-            let mut qemu = Command::new(QEMU_CMD);
-
-            qemu.kill_on_drop(true)
-                .current_dir(pwd.path())
-                .arg("-m")
-                .arg(&ram)
-                .arg("-smp")
-                .arg(&smp)
-                .args(QEMU_ARGS.iter().flat_map(|arg_list| *arg_list))
-                .args(virtfs_args);
-
-            qemu
+        // Actually run the command and wait for its completion.
+        let mut child = match command {
+            Some(mut command) => Spawned::Directly(command.spawn()?),
+            None => Spawned::ViaHelper(
+                helper::spawn(
+                    &self.triplet,
+                    &self.config_triplet,
+                    &self.runner_name,
+                    pinning,
+                )
+                .await?,
+            ),
         };
 
-        // Actually run the qemu command and wait for its completion.
-        let status = qemu.status().await?;
+        // Remember which process holds the host devices,
+        // in case we are restarted while the machine is still running.
+        if let Some(pid) = child.id() {
+            self.devices.spawned(&self.runner_name, pid);
+            self.write_adoption_record(pid);
+        }
+
+        let source = child
+            .id()
+            .and_then(|pid| Source::new(self.machine_config().backend, pid, &self.runner_name));
+
+        let started = Instant::now();
+
+        let metadata = metadata::serve(&run_dir_path, || self.metadata());
+        tokio::pin!(metadata);
+
+        // Backends that tell us when their guest has shut down confirm
+        // that the machine stopped, even if the process takes a while to exit.
+        let shutdown = async {
+            if !backend::can_power_down(self.machine_config().backend) {
+                return std::future::pending().await;
+            }
+
+            match backend::wait_for_shutdown(&run_dir_path).await {
+                Ok(reason) => reason,
+                Err(e) => {
+                    warn!("Can not watch for the shutdown of machine {self}: {e}");
+                    std::future::pending().await
+                }
+            }
+        };
+        tokio::pin!(shutdown);
+
+        let status = loop {
+            tokio::select! {
+                status = child.wait() => break status?,
+                reason = &mut shutdown => {
+                    info!("The guest of machine {self} has shut down ({reason})");
+
+                    match tokio::time::timeout(QEMU_EXIT_GRACE, child.wait()).await {
+                        Ok(status) => break status?,
+                        Err(_) => {
+                            // The process is killed when `child` is dropped.
+                            warn!("The process of machine {self} did not exit after its guest shut down");
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = &mut metadata => {}
+                _ = tokio::time::sleep(RESOURCE_SAMPLE_INTERVAL) => {
+                    self.sample_resources(source.as_ref(), started);
+                }
+            }
+        };
+
+        cleanup.disarm();
 
         match status.success() {
             true => Ok(()),
@@ -390,71 +1093,367 @@ impl Machine {
                 let code = status.code().map(|c| c.to_string());
                 let dpc = code.as_deref().unwrap_or("<None>");
 
-                let msg = format!("The qemu process for job {self} exited with code: {dpc}",);
+                let msg = format!("The machine process for job {self} exited with code: {dpc}",);
 
                 Err(std::io::Error::other(msg))
             }
         }
     }
 
-    // Spawn qemu in the background and keep the machine state updated
-    fn spawn(self: &Arc<Self>, inner: &mut Inner) {
-        assert_eq!(inner.status, Status::Registered);
+    /// Leave a record for the next instance about the machine running as `pid`
+    ///
+    /// The next instance takes the machine over if `host.keep_machines_on_restart`
+    /// is enabled and the backend supports it.
+    /// Otherwise, e.g. after this instance crashed, it stops the machine.
+    fn write_adoption_record(&self, pid: u32) {
+        let inner = self.inner();
 
-        let machine = self.clone();
+        let (run_dir, runner_id) = match (&inner.run_dir, inner.runner_id()) {
+            (Some(run_dir), Some(runner_id)) => (run_dir, runner_id),
+            _ => return,
+        };
 
-        let task = tokio::spawn(async move {
-            match machine.qemu().await {
-                Ok(()) => {
-                    info!("Machine {machine} has completed");
+        let record = adoption::Record {
+            triplet: self.triplet.clone(),
+            config_triplet: self.config_triplet.clone(),
+            quarantined: self.quarantined,
+            runner_name: self.runner_name.clone(),
+            runner_id,
+            pid,
+            unaccounted_since: Utc::now(),
+            run_dir: run_dir.state(),
+            pinning: inner.pinning.clone(),
+        };
 
-                    let mut inner = machine.inner();
-                    inner.run_dir.as_mut().unwrap().maybe_persist();
-                }
-                Err(err) => error!("Failed to run machine {machine}: {err}",),
-            }
+        if let Err(e) = record.write(run_dir.path()) {
+            warn!("Failed to write the adoption record of {self}: {e}");
+        }
+    }
 
-            // We are about to exit anyways.
-            // No need to abort this task anymore.
-            machine.inner().abort = None;
+    /// The resources the machine used so far, if they can be measured
+    pub(super) fn resources(&self) -> Option<ResourceUsage> {
+        self.inner().resources.clone()
+    }
 
-            // Update our status to stopped and some other cleanup.
-            machine.kill();
+    /// Sample the resources used by the machine while it runs
+    ///
+    /// The counters are gone once the machine has exited,
+    /// so the last sample is what we report.
+    fn sample_resources(&self, source: Option<&Source>, started: Instant) {
+        if let Some(mut usage) = source.and_then(Source::sample) {
+            usage.wall_seconds = started.elapsed().as_secs_f64();
+            self.inner().resources = Some(usage);
+        }
+    }
 
-            // Maybe schedule new machines in the space we freed.
-            machine.rescheduler.reschedule();
+    /// Record the resources the machine used in the metrics and its run directory
+    ///
+    /// The run directory is kept around for a while after the machine stopped,
+    /// so `resources.json` serves as a record of what the job needed.
+    fn report_resources(&self) {
+        let inner = self.inner();
+
+        let usage = match &inner.resources {
+            Some(usage) => usage,
+            None => return,
+        };
+
+        info!(
+            "Machine {self} used {:.0}s of CPU time and {} MB of RAM",
+            usage.cpu_seconds,
+            usage.peak_rss_bytes / 1_000_000
+        );
+
+        let machine = self.config_triplet.to_string();
+
+        self.metrics
+            .record_stat(&machine, "cpu_seconds", usage.cpu_seconds);
+        self.metrics
+            .record_stat(&machine, "peak_rss_bytes", usage.peak_rss_bytes as f64);
+        self.metrics
+            .record_stat(&machine, "disk_read_bytes", usage.disk_read_bytes as f64);
+        self.metrics
+            .record_stat(&machine, "disk_write_bytes", usage.disk_write_bytes as f64);
+
+        if let Some(run_dir) = &inner.run_dir {
+            let path = run_dir.path().join("resources.json");
+
+            let res = serde_json::to_vec_pretty(usage)
+                .map_err(std::io::Error::other)
+                .and_then(|content| std::fs::write(&path, content));
+
+            if let Err(e) = res {
+                warn!("Failed to write {}: {e}", path.display());
+            }
+        }
+    }
+
+    /// Clean up after the machine process has exited with the result `res`
+    fn exited(self: &Arc<Self>, res: std::io::Result<()>) {
+        self.report_resources();
+
+        let reason = match res {
+            Ok(()) => {
+                info!("Machine {self} has completed");
+
+                let mut inner = self.inner();
+                inner.run_dir.as_mut().unwrap().maybe_persist();
+
+                "machine process completed".to_owned()
+            }
+            Err(err) => {
+                error!("Failed to run machine {self}: {err}",);
+                self.spawn_failures.failure(&self.config_triplet);
+
+                let machine = self.triplet.to_string();
+
+                // A machine that fails while running a job takes the job down with it.
+                match self.status() {
+                    Status::Starting => self.metrics.record_boot(&machine, false),
+                    Status::Running | Status::Draining => self.metrics.record_job(&machine, false),
+                    _ => {}
+                }
+
+                format!("machine process failed: {err}")
+            }
+        };
+
+        // We are about to exit anyways.
+        // No need to abort this task anymore.
+        self.inner().abort = None;
+
+        // Update our status to stopped and some other cleanup.
+        self.kill(&reason);
+
+        // Maybe schedule new machines in the space we freed.
+        self.rescheduler.reschedule();
+    }
+
+    // Spawn the backend in the background and keep the machine state updated
+    fn spawn(self: &Arc<Self>, inner: &mut Inner) {
+        assert_eq!(self.status(), Status::Registered);
+
+        let machine = self.clone();
+
+        let task = tokio::spawn(async move {
+            let res = machine.run_backend().await;
+
+            machine.exited(res);
         });
 
-        inner.status = Status::Starting;
-        inner.started = Some(Instant::now());
+        self.transition(inner, Status::Starting, "spawned machine process");
+        self.started.store(Some(Instant::now()));
+        inner.abort = Some(task.abort_handle());
+    }
+
+    /// Wait for the completion of the adopted machine process `pid`
+    async fn wait_adopted(&self, pid: u32) -> std::io::Result<()> {
+        // Killed once this future is dropped, e.g. because the task was aborted.
+        let process = adoption::Process::new(pid, &self.runner_name);
+
+        let run_dir = self.inner().run_dir.as_ref().unwrap().path().to_owned();
+
+        // Make sure the machine is responsive and continue it,
+        // in case it was paused when the previous instance stopped.
+        backend::set_paused(&run_dir, false).await?;
+
+        // Whether the previous instance shrunk its RAM is not known,
+        // so give the machine its full RAM back.
+        if self.machine_config().balloon.is_some() {
+            backend::set_balloon(&run_dir, self.ram_required()).await?;
+        }
+
+        let source = Source::new(self.machine_config().backend, pid, &self.runner_name);
+
+        let started = Instant::now();
+
+        // Keep answering the metadata requests of the machine.
+        let metadata = metadata::serve(&run_dir, || self.metadata());
+        tokio::pin!(metadata);
+
+        while process.is_alive() {
+            tokio::select! {
+                _ = tokio::time::sleep(RESOURCE_SAMPLE_INTERVAL) => {}
+                _ = &mut metadata => {}
+            }
+
+            self.sample_resources(source.as_ref(), started);
+        }
+
+        // The exit status of a process that is not our child is unknown.
+        // A job that was cut short does not leave a persist file behind,
+        // so treating this as success does not persist broken images.
+        Ok(())
+    }
+
+    /// Watch the process of an adopted machine in the background
+    /// and keep the machine state updated
+    fn watch(self: &Arc<Self>, pid: u32) {
+        let mut inner = self.inner();
+
+        assert_eq!(self.status(), Status::Registered);
+
+        let machine = self.clone();
+
+        let task = tokio::spawn(async move {
+            let res = machine.wait_adopted(pid).await;
+
+            machine.exited(res);
+        });
+
+        self.transition(
+            &mut inner,
+            Status::Starting,
+            "adopted from previous instance",
+        );
+        self.started.store(Some(Instant::now()));
         inner.abort = Some(task.abort_handle());
     }
 
     /// Stop this machine, set the status to stopped and maybe de-register the jit runner.
-    pub(super) fn kill(self: &Arc<Self>) {
+    ///
+    /// Guests of backends that support it are asked to shut down first
+    /// and only killed if they did not do so within `POWER_DOWN_GRACE`.
+    /// Until then the machine is stopping.
+    /// The `reason` is noted in the history of the machine.
+    pub(super) fn kill(self: &Arc<Self>, reason: &str) {
+        let mut inner_locked = self.inner();
+
+        // The machine is already on its way down.
+        if inner_locked.powering_down && inner_locked.abort.is_some() {
+            return;
+        }
+
+        if self.power_down(&mut inner_locked, reason) {
+            return;
+        }
+
+        self.terminate(&mut inner_locked, reason);
+    }
+
+    /// Stop this machine right away, without giving the guest a chance to shut down
+    ///
+    /// This is for when Forrest exits and can not wait for `POWER_DOWN_GRACE`,
+    /// e.g. because the machine is already on its way down via `kill()`,
+    /// or for when the RAM of the machine is needed right away.
+    pub(super) fn kill_now(self: &Arc<Self>, reason: &str) {
         let mut inner_locked = self.inner();
 
+        if !self.status().is_stopped() {
+            self.terminate(&mut inner_locked, reason);
+        }
+    }
+
+    /// Ask the guest of this machine to shut down
+    ///
+    /// Returns `false` if the machine has to be killed right away instead,
+    /// e.g. because its process is not running (anymore) or its backend
+    /// does not support this.
+    fn power_down(self: &Arc<Self>, inner: &mut Inner, reason: &str) -> bool {
+        let running = matches!(
+            self.status(),
+            Status::Starting
+                | Status::Waiting
+                | Status::Running
+                | Status::Draining
+                | Status::Stopping
+        );
+
+        if !running
+            || inner.abort.is_none()
+            || !backend::can_power_down(self.machine_config().backend)
+        {
+            return false;
+        }
+
+        let run_dir = match &inner.run_dir {
+            Some(run_dir) => run_dir.path().to_owned(),
+            None => return false,
+        };
+
+        inner.powering_down = true;
+
+        if self.status() != Status::Stopping {
+            self.transition(inner, Status::Stopping, reason);
+        }
+
+        // Make sure the runner does not pick up a job while shutting down.
+        self.deregister(inner);
+
+        let machine = self.clone();
+        let reason = reason.to_owned();
+
+        tokio::spawn(async move {
+            // A guest that is e.g. paused or crashed would not react anyways.
+            let res = match backend::guest_status(&run_dir).await {
+                Ok(status) if status == "running" => backend::power_down(&run_dir).await,
+                Ok(status) => Err(std::io::Error::other(format!("the guest is {status}"))),
+                Err(e) => Err(e),
+            };
+
+            match res {
+                Ok(()) => {
+                    debug!("Asked the guest of machine {machine} to shut down");
+                    tokio::time::sleep(POWER_DOWN_GRACE).await;
+                }
+                Err(ref e) => {
+                    info!("Can not shut down the guest of machine {machine} cleanly: {e}")
+                }
+            }
+
+            let mut inner = machine.inner();
+
+            // The machine process has exited in the meantime.
+            if inner.abort.is_none() {
+                return;
+            }
+
+            if res.is_ok() {
+                warn!("The guest of machine {machine} did not shut down in time. Killing it");
+            }
+
+            machine.terminate(&mut inner, &reason);
+
+            // We must release the lock before calling reschedule
+            std::mem::drop(inner);
+            machine.rescheduler.reschedule();
+        });
+
+        true
+    }
+
+    /// Kill the process of this machine right away and clean up after it
+    fn terminate(self: &Arc<Self>, inner_locked: &mut Inner, reason: &str) {
         if let Some(abort) = inner_locked.abort.take() {
             abort.abort()
         }
 
-        inner_locked.status = Status::Stopped;
+        self.transition(inner_locked, Status::Stopped, reason);
 
-        if let Some(runner_id) = inner_locked.runner_id() {
-            // We have to de-register the runner
+        self.devices.release(&self.runner_name);
 
+        // Account the time the machine was running to its owner.
+        // Taking `started` makes sure this only happens once.
+        if let Some(started) = self.started.take() {
+            self.accounting.record(
+                self.triplet.owner(),
+                started.elapsed(),
+                self.machine_config().cost,
+            );
+        }
+
+        self.deregister(inner_locked);
+    }
+
+    /// De-register the jit runner of this machine, if it has one
+    fn deregister(self: &Arc<Self>, inner: &Inner) {
+        if let Some(runner_id) = inner.runner_id() {
             let machine = self.clone();
 
             tokio::spawn(async move {
-                let octocrab = machine.auth.user(machine.triplet.owner()).unwrap();
-
-                let res = octocrab
-                    .actions()
-                    .delete_repo_runner(
-                        machine.triplet.owner(),
-                        machine.triplet.repository(),
-                        runner_id,
-                    )
+                let res = machine
+                    .forge
+                    .deregister_runner(&machine.triplet, &runner_id)
                     .await;
 
                 machine.inner().jit_config = None;
@@ -475,37 +1474,342 @@ impl Machine {
         }
     }
 
+    /// Retire this machine, e.g. because its image or config is outdated
+    ///
+    /// A machine that is running a job gets to complete it and then stops,
+    /// instead of waiting for another job.
+    /// All other machines are stopped right away.
+    /// The `reason` is noted in the history of the machine.
+    pub(super) fn drain(self: &Arc<Self>, reason: &str) {
+        let mut inner = self.inner();
+
+        match self.status() {
+            Status::Running => {
+                info!("Draining machine {self}: {reason}");
+                self.transition(&mut inner, Status::Draining, reason);
+            }
+            Status::Draining | Status::Stopping | Status::Stopped => {}
+            _ => {
+                std::mem::drop(inner);
+                self.kill(reason);
+            }
+        }
+    }
+
+    /// Stop the CPUs of a machine that is waiting for a job
+    ///
+    /// The machine keeps its RAM and stays registered as runner,
+    /// but does not consume CPU time until it is resumed.
+    /// This is used to shed load from the host without throwing away
+    /// already booted machines.
+    pub(super) fn pause(self: &Arc<Self>) {
+        let inner = self.inner();
+
+        if self.status() != Status::Waiting || !backend::can_pause(self.machine_config().backend) {
+            return;
+        }
+
+        let run_dir = match &inner.run_dir {
+            Some(run_dir) => run_dir.path().to_owned(),
+            None => return,
+        };
+
+        let machine = self.clone();
+
+        tokio::spawn(async move {
+            let res = backend::set_paused(&run_dir, true).await;
+
+            let mut inner = machine.inner();
+
+            match (res, machine.status()) {
+                (Ok(()), Status::Waiting) => {
+                    info!("Paused machine {machine}");
+                    machine.transition(&mut inner, Status::Paused, "paused due to host pressure");
+                }
+                // The machine picked up a job while being paused.
+                (Ok(()), Status::Running) => machine.continue_cpus(&inner),
+                (Ok(()), _) => {}
+                (Err(err), _) => warn!("Failed to pause machine {machine}: {err}"),
+            }
+        });
+    }
+
+    /// Continue the CPUs of a paused machine, so that it can pick up jobs again
+    pub(super) fn resume(self: &Arc<Self>) {
+        let inner = self.inner();
+
+        if self.status() != Status::Paused {
+            return;
+        }
+
+        let run_dir = match &inner.run_dir {
+            Some(run_dir) => run_dir.path().to_owned(),
+            None => return,
+        };
+
+        let machine = self.clone();
+
+        tokio::spawn(async move {
+            let res = backend::set_paused(&run_dir, false).await;
+
+            let mut inner = machine.inner();
+
+            match res {
+                Ok(()) if machine.status() == Status::Paused => {
+                    info!("Resumed machine {machine}");
+                    machine.transition(&mut inner, Status::Waiting, "resumed");
+                }
+                Ok(()) => {}
+                Err(err) => warn!("Failed to resume machine {machine}: {err}"),
+            }
+        });
+    }
+
+    /// Shrink the RAM of the machine to its `balloon.idle_ram` if it waited long enough for a job
+    ///
+    /// The RAM handed back to the host can be used to start other machines.
+    pub(super) fn inflate_balloon(self: &Arc<Self>) {
+        let balloon = match &self.machine_config().balloon {
+            Some(balloon) => balloon,
+            None => return,
+        };
+
+        let idle_long_enough = self
+            .waiting_duration()
+            .is_some_and(|waiting| waiting > BALLOON_IDLE_DELAY);
+
+        if !idle_long_enough || self.ballooned.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let run_dir = match &self.inner().run_dir {
+            Some(run_dir) => run_dir.path().to_owned(),
+            None => return,
+        };
+
+        let idle_ram = balloon.idle_ram.bytes();
+        let machine = self.clone();
+
+        tokio::spawn(async move {
+            let res = backend::set_balloon(&run_dir, idle_ram).await;
+
+            let inner = machine.inner();
+
+            match (res, machine.status()) {
+                (Ok(()), Status::Waiting | Status::Paused) => {
+                    info!("Shrunk the RAM of idle machine {machine}");
+                    machine.ballooned.store(true, Ordering::Relaxed);
+
+                    // We must release the lock before calling reschedule
+                    std::mem::drop(inner);
+                    machine.rescheduler.reschedule();
+                }
+                // The machine picked up a job in the meantime.
+                (Ok(()), Status::Running) => machine.deflate_balloon(&inner),
+                (Ok(()), _) => {}
+                (Err(err), _) => warn!("Failed to shrink the RAM of machine {machine}: {err}"),
+            }
+        });
+    }
+
+    /// Give the machine back its full RAM in the background
+    ///
+    /// This is used when an idle machine with a shrunk RAM got a job.
+    fn deflate_balloon(&self, inner: &Inner) {
+        self.ballooned.store(false, Ordering::Relaxed);
+
+        let run_dir = match &inner.run_dir {
+            Some(run_dir) => run_dir.path().to_owned(),
+            None => return,
+        };
+
+        let name = self.to_string();
+        let ram = self.ram_required();
+
+        tokio::spawn(async move {
+            if let Err(err) = backend::set_balloon(&run_dir, ram).await {
+                warn!("Failed to restore the RAM of machine {name}: {err}");
+            }
+        });
+    }
+
+    /// Continue the CPUs of the machine in the background
+    ///
+    /// This is used when a paused machine got a job assigned anyway,
+    /// which it can only process once it runs again.
+    fn continue_cpus(&self, inner: &Inner) {
+        let run_dir = match &inner.run_dir {
+            Some(run_dir) => run_dir.path().to_owned(),
+            None => return,
+        };
+
+        let name = self.to_string();
+
+        tokio::spawn(async move {
+            if let Err(err) = backend::set_paused(&run_dir, false).await {
+                error!("Failed to resume machine {name}: {err}");
+            }
+        });
+    }
+
     /// Reguest a move of the machine through its state machine
     ///
     /// This either triggers the registration as a jit runner or spawns the qemu process.
     /// Other progress in the state machine is made via `status_feedback`.
     ///
-    /// The `ram_available` argument is used to decide if the machine can be spawned
-    /// and is updated _if_ the machine was spawned.
+    /// The `ram_available`, `cpus_available`, `disk_available`, `scratch_available`
+    /// and `devices_available` arguments are used to decide if the machine can be
+    /// spawned and are updated _if_ the machine was spawned.
+    /// A `cpus_available` or `disk_available` of `None` means that the respective
+    /// resource is not limited.
+    ///
+    /// The `concurrency` argument keeps track of the machines each owner and
+    /// repository may still have, and is updated if the machine is registered.
+    ///
+    /// The `registrations` argument collects the runner registrations started
+    /// in this scheduling pass into batches.
     ///
     /// The `machines` argument is checked if the machine this machine is based on is
     /// currently running.
     /// If so the startup of this machine is delayed since a new base image is likely to
     /// be available soon, which should be used instead of the current base image or
     /// the machine image.
-    pub(super) fn reschedule(self: &Arc<Self>, ram_available: &mut u64, machines: &Machines) {
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn reschedule(
+        self: &Arc<Self>,
+        ram_available: &mut u64,
+        cpus_available: &mut Option<u32>,
+        disk_available: &mut Option<u64>,
+        scratch_available: &mut HashMap<String, u64>,
+        devices_available: &mut HashSet<String>,
+        cores_available: &mut FreeCores,
+        reservations: &mut Reservations,
+        concurrency: &mut ConcurrencyLimits,
+        registrations: &mut Batches,
+        machines: &Machines,
+    ) {
         let mut inner = self.inner();
 
-        match inner.status {
-            Status::Requested => self.register(&mut inner),
+        match self.status() {
+            Status::Requested => {
+                if let Some(limit) = concurrency.exhausted(&self.triplet) {
+                    debug!("Postpone registering {self}, the {limit} are at their limit");
+                    inner.postponed_by = Some(limit);
+                    return;
+                }
+
+                concurrency.claim(&self.triplet);
+
+                let batch = registrations.get(self.triplet.owner());
+                self.register(&mut inner, batch)
+            }
             Status::Registered => {
                 let ram_required = self.ram_required();
 
                 if ram_required > *ram_available {
                     debug!("Postpone starting {self} due to insufficient RAM {ram_available} vs. {ram_required}");
+                    inner.postponed_by = Some("RAM".to_owned());
                     return;
                 }
 
+                let cpus_required = self.cpus_required();
+
+                if let Some(available) = cpus_available {
+                    if cpus_required > *available {
+                        debug!("Postpone starting {self} due to insufficient CPU cores {available} vs. {cpus_required}");
+                        inner.postponed_by = Some("CPU cores".to_owned());
+                        return;
+                    }
+                }
+
+                let disk_required = self.disk_required();
+
+                if let Some(available) = disk_available {
+                    if disk_required > *available {
+                        debug!("Postpone starting {self} due to insufficient disk space {available} vs. {disk_required}");
+                        inner.postponed_by = Some("disk space".to_owned());
+                        return;
+                    }
+                }
+
+                // The RAM held back for reserved slots is only available to
+                // machines for jobs of protected branches and for jobs that
+                // workflow runs in progress are expected to queue.
+                let ram_shared = ram_available.saturating_sub(reservations.held());
+                let needs_slot = ram_required > ram_shared;
+
+                if needs_slot && !reservations.can_claim(&self.triplet, ram_required) {
+                    debug!("Postpone starting {self}, the remaining RAM is reserved");
+                    inner.postponed_by = Some("reserved RAM".to_owned());
+                    return;
+                }
+
+                let scratch_required = self.scratch_required();
+
+                if let Some((pool, size)) = scratch_required {
+                    match scratch_available.get(pool) {
+                        Some(available) if size > *available => {
+                            debug!("Postpone starting {self} due to insufficient space in scratch pool {pool} {available} vs. {size}");
+                            inner.postponed_by = Some(format!("scratch pool {pool}"));
+                            return;
+                        }
+                        Some(_) => {}
+                        None => {
+                            error!("Can not start {self} due to unknown scratch pool {pool}");
+                            let reason = format!("unknown scratch pool {pool}");
+                            self.transition(&mut inner, Status::Stopped, reason);
+                            return;
+                        }
+                    }
+                }
+
+                let devices_required = &self.machine_config().devices;
+
+                for device in devices_required {
+                    if !self.cfg.host.devices.contains_key(device) {
+                        error!("Can not start {self} due to unknown host device {device}");
+                        let reason = format!("unknown host device {device}");
+                        self.transition(&mut inner, Status::Stopped, reason);
+                        return;
+                    }
+
+                    if !devices_available.contains(device) {
+                        debug!("Postpone starting {self} because device {device} is in use");
+                        inner.postponed_by = Some(format!("host device {device}"));
+                        return;
+                    }
+                }
+
+                let machine_config = self.machine_config();
+
+                if let Some(node) = machine_config.numa_node {
+                    if !cores_available.has_node(node) {
+                        error!("Can not start {self} due to unknown NUMA node {node}");
+                        let reason = format!("unknown NUMA node {node}");
+                        self.transition(&mut inner, Status::Stopped, reason);
+                        return;
+                    }
+                }
+
+                let pin = machine_config.cpu_pin || machine_config.numa_node.is_some();
+
+                let pinning = match pin {
+                    true => match cores_available.pick(cpus_required, machine_config.numa_node) {
+                        Some(pinning) => Some(pinning),
+                        None => {
+                            debug!("Postpone starting {self} due to insufficient free CPU cores to pin it to");
+                            inner.postponed_by = Some("pinned CPU cores".to_owned());
+                            return;
+                        }
+                    },
+                    false => None,
+                };
+
                 let encoded_jit_config = match inner.encoded_jit_config() {
                     Some(ejc) => ejc,
                     None => {
                         error!("Can not set up run dir for {self} due to missing jit config");
-                        inner.status = Status::Stopped;
+                        self.transition(&mut inner, Status::Stopped, "missing jit config");
                         return;
                     }
                 };
@@ -516,20 +1820,55 @@ impl Machine {
                     Ok(run_dir) => inner.run_dir = run_dir,
                     Err(err) => {
                         error!("Failed to set up run dir for {self}: {err}");
-                        inner.status = Status::Stopped;
+                        self.spawn_failures.failure(&self.config_triplet);
+                        self.metrics.record_boot(&self.triplet.to_string(), false);
+                        let reason = format!("failed to set up run dir: {err}");
+                        self.transition(&mut inner, Status::Stopped, reason);
                         return;
                     }
                 }
 
                 if inner.run_dir.is_some() {
+                    if needs_slot {
+                        let claimed = reservations.claim(&self.triplet, ram_required);
+                        self.reserved_slot.store(claimed, Ordering::Relaxed);
+                    }
+
+                    if let Some(pinning) = &pinning {
+                        cores_available.claim(pinning);
+                    }
+
+                    inner.pinning = pinning;
+
+                    self.devices.assign(devices_required, &self.runner_name);
                     self.spawn(&mut inner);
                     *ram_available -= ram_required;
+
+                    if let Some(available) = cpus_available {
+                        *available -= cpus_required;
+                    }
+
+                    if let Some(available) = disk_available {
+                        *available -= disk_required;
+                    }
+
+                    for device in devices_required {
+                        devices_available.remove(device);
+                    }
+
+                    if let Some((pool, size)) = scratch_required {
+                        if let Some(available) = scratch_available.get_mut(pool) {
+                            *available -= size;
+                        }
+                    }
                 }
             }
             Status::Registering
             | Status::Starting
             | Status::Waiting
+            | Status::Paused
             | Status::Running
+            | Status::Draining
             | Status::Stopping
             | Status::Stopped => {}
         }
@@ -543,46 +1882,209 @@ impl Machine {
     pub(super) fn status_feedback(&self, online: Option<bool>, busy: bool) {
         let mut inner = self.inner();
 
-        let new = match (&inner.status, online, busy) {
+        let old = self.status();
+
+        let new = match (&old, online, busy) {
             // Stay in the current state
             (Status::Requested, _, _) => Status::Requested,
             (Status::Registering, _, _) => Status::Registering,
             (Status::Registered, _, _) => Status::Registered,
             (Status::Starting, Some(false) | None, _) => Status::Starting,
             (Status::Waiting, Some(true) | None, false) => Status::Waiting,
+            // The runner of a paused machine can not talk to the forge,
+            // so it going offline is expected.
+            (Status::Paused, _, false) => Status::Paused,
             (Status::Running, Some(true) | None, true) => Status::Running,
+            (Status::Draining, Some(true) | None, true) => Status::Draining,
             (Status::Stopping, _, _) => Status::Stopping,
             (Status::Stopped, _, _) => Status::Stopped,
 
             // The action runner on the machine has registered itself
             // but does not run a job yet.
-            (Status::Starting, Some(true), false) => Status::Waiting,
+            (Status::Starting, Some(true), false) => {
+                self.spawn_failures.success(&self.config_triplet);
+                self.metrics.record_boot(&self.triplet.to_string(), true);
+                Status::Waiting
+            }
 
             // The action runner has taken up a job
-            (Status::Starting | Status::Waiting, _, true) => Status::Running,
+            (Status::Starting, _, true) => {
+                self.spawn_failures.success(&self.config_triplet);
+                self.metrics.record_boot(&self.triplet.to_string(), true);
+                Status::Running
+            }
+            (Status::Waiting, _, true) => {
+                if self.ballooned.load(Ordering::Relaxed) {
+                    self.deflate_balloon(&inner);
+                }
+
+                Status::Running
+            }
+            (Status::Paused, _, true) => {
+                if self.ballooned.load(Ordering::Relaxed) {
+                    self.deflate_balloon(&inner);
+                }
+
+                self.continue_cpus(&inner);
+                Status::Running
+            }
 
             // The job is complete and the machine about to stop
             (Status::Waiting, Some(false), _)
-            | (Status::Running, Some(false), _)
-            | (Status::Running, _, false) => {
+            | (Status::Running | Status::Draining, Some(false), _)
+            | (Status::Running | Status::Draining, _, false) => {
                 inner.jit_config = None;
 
+                if old == Status::Running || old == Status::Draining {
+                    self.metrics.record_job(&self.triplet.to_string(), true);
+                }
+
                 Status::Stopping
             }
         };
 
-        if inner.status != new {
-            info!(
-                "Machine {self} transitioned from state {} to {new}",
-                inner.status
-            );
-            inner.status = new;
+        if old != new {
+            info!("Machine {self} transitioned from state {old} to {new}");
+
+            let reason = match online {
+                Some(online) => format!("runner feedback: online={online}, busy={busy}"),
+                None => format!("job feedback: busy={busy}"),
+            };
+
+            self.transition(&mut inner, new, reason);
         }
     }
 }
 
 impl std::fmt::Display for Machine {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{} {}", self.triplet, self.runner_name)
+        write!(f, "{} {}", self.triplet, self.runner_name)?;
+
+        match self.accel {
+            Some(accel) => write!(f, " ({accel})"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use super::{AtomicInstant, AtomicStatus, Status};
+
+    /// Lock `mutex` for a moment, like a state transition would
+    fn hold<T>(mutex: &Mutex<T>) {
+        let _guard = mutex.lock().unwrap();
+        std::hint::spin_loop();
+    }
+
+    #[test]
+    fn atomic_status_round_trip() {
+        let status = AtomicStatus::new(Status::Requested);
+
+        for s in AtomicStatus::ALL {
+            status.store(s);
+            assert_eq!(status.load(), s);
+        }
+    }
+
+    #[test]
+    fn atomic_instant_is_taken_once() {
+        let started = AtomicInstant::new();
+        assert_eq!(started.load(), None);
+
+        let now = Instant::now();
+        started.store(Some(now));
+
+        // Nanosecond resolution relative to the base, so this is exact.
+        assert_eq!(started.load(), Some(now));
+        assert_eq!(started.take(), Some(now));
+        assert_eq!(started.take(), None);
+        assert_eq!(started.load(), None);
+    }
+
+    /// Measure reading the status and start time of a fleet of machines
+    ///
+    /// Compares the atomics against a mutex per machine, while another
+    /// thread keeps locking the machines like state transitions do.
+    /// Run with `cargo test --release -- --ignored --nocapture fleet_reads`.
+    #[test]
+    #[ignore]
+    fn fleet_reads() {
+        const MACHINES: usize = 1000;
+        const ROUNDS: u32 = 1000;
+
+        struct Locked(Mutex<(Status, Option<Instant>)>);
+        struct Atomic(Mutex<()>, AtomicStatus, AtomicInstant);
+
+        fn measure<M: Send + Sync + 'static>(
+            name: &str,
+            fleet: Vec<M>,
+            lock: fn(&M),
+            read: fn(&M) -> bool,
+        ) {
+            let fleet = Arc::new(fleet);
+            let done = Arc::new(AtomicBool::new(false));
+
+            let writer = {
+                let fleet = fleet.clone();
+                let done = done.clone();
+
+                std::thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        fleet.iter().for_each(lock);
+                    }
+                })
+            };
+
+            let start = Instant::now();
+            let mut running = 0;
+
+            for _ in 0..ROUNDS {
+                running += fleet.iter().filter(|m| read(m)).count();
+            }
+
+            let elapsed = start.elapsed();
+
+            done.store(true, Ordering::Relaxed);
+            writer.join().unwrap();
+
+            let reads = MACHINES as u32 * ROUNDS;
+            println!("{name}: {:?} per read ({running} running)", elapsed / reads);
+        }
+
+        let now = Some(Instant::now());
+
+        let locked = (0..MACHINES)
+            .map(|_| Locked(Mutex::new((Status::Starting, now))))
+            .collect();
+
+        measure(
+            "mutex",
+            locked,
+            |m| hold(&m.0),
+            |m| {
+                let inner = m.0.lock().unwrap();
+                inner.0 == Status::Starting && inner.1.is_some()
+            },
+        );
+
+        let atomic = (0..MACHINES)
+            .map(|_| {
+                let started = AtomicInstant::new();
+                started.store(now);
+                Atomic(Mutex::new(()), AtomicStatus::new(Status::Starting), started)
+            })
+            .collect();
+
+        measure(
+            "atomic",
+            atomic,
+            |m| hold(&m.0),
+            |m| m.1.load() == Status::Starting && m.2.load().is_some(),
+        );
     }
 }
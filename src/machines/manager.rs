@@ -1,47 +1,334 @@
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
     io::ErrorKind,
     path::Path,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
-
-use super::machine::Machine;
+use serde::Serialize;
+
+use super::accounting::{Accounting, Usage, UsageReport};
+use super::adoption;
+use super::backend::{self, BackendReadiness};
+use super::concurrency::ConcurrencyLimits;
+use super::devices::Devices;
+use super::fallback::SpawnFailures;
+use super::helper;
+use super::machine::{Machine, Status, Transition};
+use super::pinning::FreeCores;
+use super::pressure;
+use super::registration;
+use super::registration_limit::{Decision, RegistrationLimitInfo, RegistrationLimits};
+use super::reservation::Reservations;
+use super::resources::ResourceUsage;
+use super::retention;
+use super::run_dir::{self, RunDir};
 use super::{OwnerAndRepo, Triplet};
-use crate::{auth::Auth, config::Config};
+use crate::{
+    api_health::DegradedInfo,
+    auth::Auth,
+    config::{Backend, BudgetPolicy, Config, ConfigFile},
+    forge::Forges,
+    metrics::Metrics,
+    subsystems::{Subsystem, Subsystems},
+};
 
 // Machines should go from being booted to being registered with GitHub
-// in less than 15 minutes.
+// in less than 15 minutes, unless a `start_timeout` is configured.
 // The timeout is quite generous because new machines have to download
 // and unpack the runner binary first.
-const START_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const DEFAULT_START_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+// How often to check for machines that exceeded their start timeout.
+const START_WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often to check if rolling restarts were enabled in the config.
+const ROLLING_RESTART_DISABLED_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often to check if container image updates were enabled in the config.
+const IMAGE_UPDATES_DISABLED_INTERVAL: Duration = Duration::from_secs(60);
+
+// Scheduling passes go through the whole list of machines while holding
+// the lock on it. Warn if that takes long enough to be noticeable,
+// e.g. because a pass scales badly with the number of machines.
+const SLOW_PASS_THRESHOLD: Duration = Duration::from_millis(100);
+
+// Bucket boundaries for the scheduling pass duration (in seconds)
+// and machine list size histograms.
+const PASS_DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+const MACHINE_COUNT_BUCKETS: &[f64] = &[10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+// How often to ask forges that do not tell us about runner state changes
+// on their own about the state of the runners of our machines.
+const FORGE_FEEDBACK_INTERVAL: Duration = Duration::from_secs(15);
+
+// How often to check the host pressure when load shedding is enabled.
+// This matches the averaging window of the pressure values used.
+const LOAD_SHEDDING_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often to check for idle machines whose RAM can be shrunk.
+const BALLOON_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often to check if the running jobs have completed while shutting down.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long to wait for the runners of stopped machines to be de-registered
+// before exiting.
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Check the backend readiness again after this time, even if the config did
+// not change, e.g. to notice that missing tooling was installed.
+const READINESS_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 pub type Machines = HashMap<Triplet, Vec<Arc<Machine>>>;
 
 #[derive(Clone)]
 pub struct Manager {
+    accounting: Arc<Accounting>,
+    anticipated: Arc<Mutex<HashMap<Triplet, Vec<DateTime<Utc>>>>>,
     auth: Arc<Auth>,
+    boosted: Arc<Mutex<HashMap<Triplet, u64>>>,
     config: Config,
+    devices: Arc<Devices>,
+    draining: Arc<AtomicBool>,
+    forges: Forges,
+    inactive: Arc<Mutex<HashSet<OwnerAndRepo>>>,
+    job_demand: Arc<Mutex<HashMap<Triplet, u64>>>,
     machines: Arc<Mutex<Machines>>,
+    metrics: Metrics,
+    pins: Arc<Mutex<HashMap<Triplet, PinInfo>>>,
+    protected: Arc<Mutex<HashMap<Triplet, u64>>>,
+    quarantined: Arc<Mutex<HashSet<Triplet>>>,
+    readiness: Arc<Mutex<Option<ReadinessCheck>>>,
+    registration_limits: Arc<RegistrationLimits>,
+    scheduling: Arc<Mutex<()>>,
+    spawn_failures: Arc<SpawnFailures>,
+    subsystems: Subsystems,
+}
+
+/// The most recent backend readiness report and what it was based on
+struct ReadinessCheck {
+    config: Arc<ConfigFile>,
+    checked: Instant,
+    report: Vec<BackendReadiness>,
 }
 
 pub struct Rescheduler {
     manager: Manager,
 }
 
+/// A snapshot of the state of a machine for use in e.g. the admin API
+#[derive(Serialize)]
+pub struct MachineInfo {
+    pub triplet: String,
+    pub runner_name: String,
+    pub status: String,
+    /// Why the machine is retired after its current job, if it is draining
+    pub draining_reason: Option<String>,
+}
+
+/// What a machine was and what it used, e.g. to report on the job it ran
+#[derive(Serialize)]
+pub struct MachineSummary {
+    /// The machine type whose config the machine used
+    pub machine: String,
+    pub backend: Backend,
+    pub cpus: u32,
+    pub ram_bytes: u64,
+    pub cost: f64,
+    /// Not available for backends whose usage can not be measured
+    pub resources: Option<ResourceUsage>,
+}
+
+/// A manual override to keep a number of machines of a type available
+#[derive(Serialize, Clone)]
+pub struct PinInfo {
+    pub triplet: String,
+    pub count: u64,
+    pub expires: DateTime<Utc>,
+}
+
+/// The demand for and supply of machines of a type, e.g. for external autoscalers
+#[derive(Serialize)]
+pub struct DemandInfo {
+    pub triplet: String,
+    /// Machines requested by queued jobs
+    pub jobs: u64,
+    /// Machines requested by an active pin
+    pub pinned: u64,
+    /// Machines kept waiting for jobs on top of the ones requested by jobs
+    pub standby: u64,
+    /// Machines that are processing a job or shutting down
+    pub busy: u64,
+    /// Machines that are starting up or waiting for a job
+    pub available: u64,
+    /// The number of machines we want to have, busy or not
+    pub desired: u64,
+    /// The number of machines per state
+    pub machines: BTreeMap<String, u64>,
+}
+
+/// Get the point in time `duration` from now, e.g. for when an override expires
+///
+/// The `duration` may come from the admin API, so it is checked to be representable.
+pub(super) fn expiry(duration: Duration) -> Result<DateTime<Utc>, String> {
+    chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| Utc::now().checked_add_signed(duration))
+        .ok_or_else(|| format!("Duration {}s is too long", duration.as_secs()))
+}
+
 impl Manager {
-    pub fn new(config: Config, auth: Arc<Auth>) -> Self {
+    pub fn new(
+        config: Config,
+        auth: Arc<Auth>,
+        forges: Forges,
+        metrics: Metrics,
+        subsystems: Subsystems,
+    ) -> Self {
+        helper::init(&config.get());
+
         let machines = Arc::new(Mutex::new(HashMap::new()));
+        let accounting = Arc::new(Accounting::new(&config.get().host.base_dir));
+        let anticipated = Arc::new(Mutex::new(HashMap::new()));
+        let boosted = Arc::new(Mutex::new(HashMap::new()));
+        let devices = Arc::new(Devices::new(&config.get().host.base_dir));
+        let draining = Arc::new(AtomicBool::new(false));
+        let inactive = Arc::new(Mutex::new(HashSet::new()));
+        let job_demand = Arc::new(Mutex::new(HashMap::new()));
+        let pins = Arc::new(Mutex::new(HashMap::new()));
+        let protected = Arc::new(Mutex::new(HashMap::new()));
+        let quarantined = Arc::new(Mutex::new(HashSet::new()));
+        let readiness = Arc::new(Mutex::new(None));
+        let registration_limits = Arc::new(RegistrationLimits::new());
+        let scheduling = Arc::new(Mutex::new(()));
+        let spawn_failures = Arc::new(SpawnFailures::new());
 
         Self {
+            accounting,
+            anticipated,
             auth,
+            boosted,
             config,
+            devices,
+            draining,
+            forges,
+            inactive,
+            job_demand,
             machines,
+            metrics,
+            pins,
+            protected,
+            quarantined,
+            readiness,
+            registration_limits,
+            scheduling,
+            spawn_failures,
+            subsystems,
         }
     }
 
+    /// Take over the machines a previous instance left running
+    ///
+    /// Machines are only left running on purpose if `host.keep_machines_on_restart`
+    /// is enabled, so that e.g. upgrading Forrest does not kill the jobs
+    /// that are currently running.
+    /// Machines that are left over for other reasons, e.g. because the previous
+    /// instance crashed, are stopped instead.
+    /// This has to be called before the first runner sweep, which would
+    /// otherwise remove the runners of these machines.
+    pub fn adopt_machines(&self) {
+        let cfg = self.config.get();
+        let leftovers = adoption::leftovers(&cfg);
+
+        let mut adopted = HashSet::new();
+
+        let mut machines = self.machines();
+
+        for record in leftovers {
+            let adoptable = cfg.host.keep_machines_on_restart
+                && cfg
+                    .machine_config(&record.config_triplet)
+                    .is_some_and(backend::can_adopt);
+
+            if !adoptable {
+                Self::stop_leftover(&cfg, record);
+                continue;
+            }
+
+            let triplet = record.triplet.clone();
+            let runner_name = record.runner_name.clone();
+
+            let forge_kind = cfg.machine_config(&triplet).map(|mc| cfg.forge_of(mc));
+
+            let forge = match forge_kind.and_then(|kind| self.forges.get(kind)) {
+                Some(forge) => forge,
+                None => {
+                    error!("Can not take over machine {runner_name}, the forge of {triplet} is not set up");
+                    continue;
+                }
+            };
+
+            let machine = Machine::adopt(
+                cfg.clone(),
+                self.accounting.clone(),
+                self.devices.clone(),
+                self.spawn_failures.clone(),
+                forge,
+                self.metrics.clone(),
+                self.rescheduler(),
+                record,
+            );
+
+            if let Some(machine) = machine {
+                info!("Took over machine {machine} from a previous instance");
+
+                adopted.insert(runner_name);
+
+                machines.entry(triplet).or_default().push(machine);
+            }
+        }
+
+        // Everything else still running in a run directory, e.g. machines
+        // without a record or helper processes of the machines stopped above,
+        // is stopped too. The run directories are left to the retention policy.
+        for (pid, runner_name) in adoption::strays(&cfg, &adopted) {
+            warn!("Stopping process {pid} left running for machine {runner_name} by a previous instance");
+
+            // The process is killed once this is dropped.
+            std::mem::drop(adoption::Process::new(pid, &runner_name));
+        }
+    }
+
+    /// Stop a machine a previous instance left running, instead of taking it over
+    ///
+    /// Its runner is offline from then on and is removed by the runner sweep.
+    /// The disk images of the machine are removed right away.
+    fn stop_leftover(cfg: &ConfigFile, record: adoption::Record) {
+        warn!(
+            "Stopping machine {} of a previous instance, it can not be taken over",
+            record.runner_name
+        );
+
+        // The process is killed once this is dropped.
+        std::mem::drop(adoption::Process::new(record.pid, &record.runner_name));
+
+        // Dropping the run directory removes the disk images and the record.
+        std::mem::drop(RunDir::reopen(
+            cfg,
+            &record.config_triplet,
+            &record.runner_name,
+            record.run_dir,
+        ));
+    }
+
     /// Get an object that can be used to trigger a re-schedule on this manager.
     ///
     /// This makes it easier to reason about other parts of the software that may
@@ -58,7 +345,7 @@ impl Manager {
     /// The lock does however not include the job states,
     /// so machines may still enter the stopped state while this
     /// lock is held.
-    fn machines(&self) -> std::sync::MutexGuard<Machines> {
+    fn machines(&self) -> std::sync::MutexGuard<'_, Machines> {
         let mut machines = self.machines.lock().unwrap();
 
         // Use the opportunity to clean up the machines.
@@ -74,6 +361,178 @@ impl Manager {
         machines
     }
 
+    /// Get a snapshot of the list of machines
+    ///
+    /// The lock on the list is only held while cloning the `Arc` handles
+    /// to the machines.
+    /// Use this instead of `machines()` for everything that does not
+    /// add machines to the list, so that e.g. setting up run directories
+    /// or moving images around does not block other users of the list.
+    fn snapshot(&self) -> Machines {
+        self.machines().clone()
+    }
+
+    /// Get a snapshot of the state of all machines we currently manage
+    pub fn machine_info(&self) -> Vec<MachineInfo> {
+        self.snapshot()
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .map(|machine| MachineInfo {
+                triplet: machine.triplet().to_string(),
+                runner_name: machine.runner_name().to_owned(),
+                status: machine.status().to_string(),
+                draining_reason: machine.draining_reason(),
+            })
+            .collect()
+    }
+
+    /// Get the demand for and supply of machines per machine type
+    ///
+    /// All configured machine types are listed, even if there is no demand
+    /// for them, so that autoscalers can scale them down to zero.
+    pub fn demand_info(&self) -> Vec<DemandInfo> {
+        let now = Utc::now();
+        let machines = self.snapshot();
+        let job_demand = self.job_demand.lock().unwrap().clone();
+
+        let pins: HashMap<Triplet, u64> = self
+            .pins
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, pin)| pin.expires > now)
+            .map(|(triplet, pin)| (triplet.clone(), pin.count))
+            .collect();
+
+        let mut triplets: Vec<Triplet> = self.config.get().triplets();
+
+        for triplet in machines.keys().chain(job_demand.keys()) {
+            if !triplets.contains(triplet) {
+                triplets.push(triplet.clone());
+            }
+        }
+
+        triplets.sort_unstable_by_key(|triplet| triplet.to_string());
+
+        let standby = self.standby();
+
+        triplets
+            .into_iter()
+            .map(|triplet| {
+                let jobs = job_demand.get(&triplet).copied().unwrap_or(0);
+                let pinned = pins.get(&triplet).copied().unwrap_or(0);
+                let standby = standby.get(&triplet).copied().unwrap_or(0);
+
+                let mut counts = BTreeMap::new();
+                let mut busy = 0;
+                let mut available = 0;
+
+                for machine in machines.get(&triplet).into_iter().flatten() {
+                    let status = machine.status();
+
+                    *counts.entry(status.to_string()).or_default() += 1;
+
+                    if status.is_available() {
+                        available += 1;
+                    } else if !status.is_stopped() {
+                        busy += 1;
+                    }
+                }
+
+                DemandInfo {
+                    triplet: triplet.to_string(),
+                    jobs,
+                    pinned,
+                    standby,
+                    busy,
+                    available,
+                    desired: busy + (jobs + standby).max(pinned),
+                    machines: counts,
+                }
+            })
+            .collect()
+    }
+
+    /// Get the recent state transitions of the machine `runner_name`
+    ///
+    /// Returns `None` if there is no such machine (anymore).
+    pub fn machine_history(&self, runner_name: &str) -> Option<Vec<Transition>> {
+        self.snapshot()
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .find(|machine| machine.runner_name() == runner_name)
+            .map(|machine| machine.history())
+    }
+
+    /// Summarize the machine `runner_name` of type `triplet` and what it used so far
+    ///
+    /// Returns `None` if there is no such machine (anymore).
+    pub fn machine_summary(&self, triplet: &Triplet, runner_name: &str) -> Option<MachineSummary> {
+        let machine = self.machines().get(triplet).and_then(|triplet_machines| {
+            triplet_machines
+                .iter()
+                .find(|machine| machine.runner_name() == runner_name)
+                .cloned()
+        })?;
+
+        let machine_config = machine.machine_config();
+
+        Some(MachineSummary {
+            machine: machine.config_triplet().to_string(),
+            backend: machine_config.backend,
+            cpus: machine_config.cpus,
+            ram_bytes: machine_config.ram.bytes(),
+            cost: machine_config.cost,
+            resources: machine.resources(),
+        })
+    }
+
+    /// Retire the machine `runner_name` after its current job, e.g. for maintenance
+    ///
+    /// Returns `false` if there is no such machine.
+    pub fn drain_machine(&self, runner_name: &str, reason: &str) -> bool {
+        let machine = self
+            .snapshot()
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .find(|machine| machine.runner_name() == runner_name)
+            .cloned();
+
+        match machine {
+            Some(machine) => {
+                machine.drain(reason);
+
+                // Replace the machine if it is still needed.
+                self.apply_demand();
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Look up the machine type `triplet` is a (deprecated) alias of
+    pub fn alias_of(&self, triplet: &Triplet) -> Option<Triplet> {
+        self.config.get().alias_of(triplet)
+    }
+
+    /// Check if `owner` has used up their monthly budget
+    ///
+    /// Returns what to do with new jobs of this owner if they have.
+    pub fn budget_exceeded(&self, owner: &str) -> Option<BudgetPolicy> {
+        self.accounting.budget_exceeded(&self.config.get(), owner)
+    }
+
+    /// Get the resources used by `owner` in the current month
+    pub fn usage(&self, owner: &str) -> Usage {
+        self.accounting.usage(owner)
+    }
+
+    /// Get the resources used by each owner per month
+    pub fn usage_report(&self) -> UsageReport {
+        self.accounting.report()
+    }
+
     pub fn status_feedback(
         &self,
         triplet: &Triplet,
@@ -81,12 +540,11 @@ impl Manager {
         online: Option<bool>,
         busy: bool,
     ) -> bool {
-        let mut machines = self.machines();
-
-        let machine = machines.get_mut(triplet).and_then(|triplet_machines| {
+        let machine = self.machines().get(triplet).and_then(|triplet_machines| {
             triplet_machines
                 .iter()
                 .find(|machine| machine.runner_name() == runner_name)
+                .cloned()
         });
 
         match machine {
@@ -98,7 +556,42 @@ impl Manager {
         }
     }
 
-    pub fn update_demand<'a>(&self, requested: impl Iterator<Item = &'a Triplet>) {
+    /// Get the resource that last held back the start of the machine `runner_name`
+    ///
+    /// Returns `None` if the machine is unknown or was started right away.
+    pub fn postponed_by(&self, triplet: &Triplet, runner_name: &str) -> Option<String> {
+        self.machines()
+            .get(triplet)
+            .and_then(|triplet_machines| {
+                triplet_machines
+                    .iter()
+                    .find(|machine| machine.runner_name() == runner_name)
+                    .cloned()
+            })
+            .and_then(|machine| machine.postponed_by())
+    }
+
+    /// Set the machines needed by queued jobs
+    ///
+    /// Machine types in `quarantined` have queued jobs that must only run on
+    /// their `quarantine` machine type.
+    /// As any available machine may pick up these jobs, all new machines of
+    /// these types use the quarantine config and available regular
+    /// machines are replaced.
+    /// `protected` counts the requested machines per type that are for jobs
+    /// of protected branches and may use the reserved slots of their repository.
+    /// `boosted` counts the requested machines per type that are for jobs
+    /// of boosted workflow runs and go before all others.
+    /// `anticipated` lists per machine type since when each workflow run in
+    /// progress is expected to queue a job for it.
+    pub fn update_demand<'a>(
+        &self,
+        requested: impl Iterator<Item = &'a Triplet>,
+        quarantined: HashSet<Triplet>,
+        protected: HashMap<Triplet, u64>,
+        boosted: HashMap<Triplet, u64>,
+        anticipated: HashMap<Triplet, Vec<DateTime<Utc>>>,
+    ) {
         let mut demand: HashMap<Triplet, u64> = HashMap::new();
 
         for triplet in requested {
@@ -109,12 +602,261 @@ impl Manager {
             }
         }
 
+        *self.job_demand.lock().unwrap() = demand;
+        *self.quarantined.lock().unwrap() = quarantined;
+        *self.protected.lock().unwrap() = protected;
+        *self.boosted.lock().unwrap() = boosted;
+        *self.anticipated.lock().unwrap() = anticipated;
+
+        self.apply_demand();
+    }
+
+    /// Keep `count` machines of type `triplet` available for `duration`
+    ///
+    /// The pinned machines are a lower bound on top of the demand from jobs,
+    /// e.g. to have warm machines ready before a planned release.
+    /// A count of zero removes the pin.
+    pub fn pin(&self, triplet: Triplet, count: u64, duration: Duration) -> Result<(), String> {
+        if self.config.get().machine_config(&triplet).is_none() {
+            return Err(format!("Unknown machine triplet {triplet}"));
+        }
+
+        {
+            let mut pins = self.pins.lock().unwrap();
+
+            if count == 0 {
+                info!("Removing pin for {triplet}");
+                pins.remove(&triplet);
+            } else {
+                let expires = expiry(duration)?;
+
+                info!("Pinning {count} machines of {triplet} until {expires}");
+
+                let pin = PinInfo {
+                    triplet: triplet.to_string(),
+                    count,
+                    expires,
+                };
+
+                pins.insert(triplet, pin);
+
+                // Re-evaluate the demand once the pin expires.
+                let manager = self.clone();
+
+                tokio::spawn(async move {
+                    tokio::time::sleep(duration).await;
+                    manager.apply_demand();
+                });
+            }
+        }
+
+        self.apply_demand();
+
+        Ok(())
+    }
+
+    /// Replace the registration limit of a repository for `duration`
+    ///
+    /// A `limit` of `None` lifts the limit, a `duration` of zero removes
+    /// a previous override.
+    pub fn override_registration_limit(
+        &self,
+        oar: OwnerAndRepo,
+        limit: Option<u32>,
+        duration: Duration,
+    ) -> Result<(), String> {
+        let known = self
+            .config
+            .get()
+            .repositories
+            .get(oar.owner())
+            .is_some_and(|repos| repos.contains_key(oar.repository()));
+
+        if !known {
+            return Err(format!("Unknown repository {oar}"));
+        }
+
+        self.registration_limits
+            .set_override(oar, limit, duration)?;
+
+        self.apply_demand();
+
+        Ok(())
+    }
+
+    /// Get the state of the registration limits of all repositories
+    pub fn registration_limits(&self) -> Vec<RegistrationLimitInfo> {
+        self.registration_limits.info(&self.config.get())
+    }
+
+    /// Record whether the repository `oar` was archived or disabled
+    ///
+    /// Inactive repositories do not get the machines their config asks for,
+    /// like standby machines.
+    pub fn set_inactive(&self, oar: &OwnerAndRepo, inactive: bool) {
+        let changed = match inactive {
+            true => self.inactive.lock().unwrap().insert(oar.clone()),
+            false => self.inactive.lock().unwrap().remove(oar),
+        };
+
+        if changed {
+            self.apply_demand();
+        }
+    }
+
+    /// Get the number of standby machines to keep per machine type
+    ///
+    /// Owners that have used up their budget do not get standby machines,
+    /// like they do not get machines for their queued jobs.
+    /// Neither do repositories that were archived or disabled.
+    fn standby(&self) -> HashMap<Triplet, u64> {
+        let cfg = self.config.get();
+        let inactive = self.inactive.lock().unwrap().clone();
+
+        cfg.triplets()
+            .into_iter()
+            .filter(|triplet| !inactive.contains(&triplet.clone().into_owner_and_repo()))
+            .filter(|triplet| {
+                self.accounting
+                    .budget_exceeded(&cfg, triplet.owner())
+                    .is_none()
+            })
+            .filter_map(|triplet| {
+                let standby = cfg.machine_config(&triplet)?.standby;
+
+                (standby > 0).then_some((triplet, standby))
+            })
+            .collect()
+    }
+
+    /// Get the currently active pins
+    pub fn pins(&self) -> Vec<PinInfo> {
+        let mut pins: Vec<PinInfo> = self.pins.lock().unwrap().values().cloned().collect();
+
+        pins.sort_unstable_by(|a, b| a.triplet.cmp(&b.triplet));
+
+        pins
+    }
+
+    /// Get the state of degraded mode, or `None` if the GitHub API works
+    pub fn degraded(&self) -> Option<DegradedInfo> {
+        self.auth.health().degraded()
+    }
+
+    /// Check that the host has the tooling required by the backends of all configured machines
+    ///
+    /// The check is repeated when the config changes and every once in a while.
+    /// Changes in the outcome are logged, so that problems surface as soon as
+    /// a machine type is configured instead of when its first machine fails to start.
+    pub fn backend_readiness(&self) -> Vec<BackendReadiness> {
+        let cfg = self.config.get();
+        let mut readiness = self.readiness.lock().unwrap();
+
+        let up_to_date = readiness.as_ref().is_some_and(|rc| {
+            Arc::ptr_eq(&rc.config, &cfg) && rc.checked.elapsed() < READINESS_RECHECK_INTERVAL
+        });
+
+        if !up_to_date {
+            let report = backend::readiness(&cfg);
+
+            if readiness.as_ref().map(|rc| &rc.report) != Some(&report) {
+                backend::log_readiness(&report);
+            }
+
+            *readiness = Some(ReadinessCheck {
+                config: cfg,
+                checked: Instant::now(),
+                report,
+            });
+        }
+
+        readiness.as_ref().unwrap().report.clone()
+    }
+
+    /// Record the duration of a scheduling pass and warn if it was slow
+    fn record_pass(&self, name: &'static str, started: Instant, machine_count: usize) {
+        let duration = started.elapsed();
+
+        self.metrics
+            .record_histogram(name, PASS_DURATION_BUCKETS, duration.as_secs_f64());
+
+        if duration > SLOW_PASS_THRESHOLD {
+            warn!(
+                "Slow {name} pass: took {}ms for {machine_count} machines",
+                duration.as_millis()
+            );
+        }
+    }
+
+    /// Act on changed settings right away, e.g. after they were overridden via the admin API
+    pub fn apply_config(&self) {
+        self.apply_demand();
+    }
+
+    /// Start or stop machines based on the demand from jobs and the pins
+    fn apply_demand(&self) {
+        // Do not start new machines while shutting down.
+        if self.draining.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // Or while the machine manager was stopped via the admin API.
+        if !self.subsystems.is_running(Subsystem::MachineManager) {
+            return;
+        }
+
+        let started = Instant::now();
+
+        let mut demand = self.job_demand.lock().unwrap().clone();
+
+        // Standby machines are kept on top of the demand from jobs,
+        // so that a new one is started whenever one of them picks up a job.
+        for (triplet, standby) in self.standby() {
+            *demand.entry(triplet).or_default() += standby;
+        }
+
+        // Machine types that used up their `start_retries` get another chance
+        // once their failures expire. Their queued jobs are given up on.
+        {
+            let cfg = self.config.get();
+            demand.retain(|triplet, _| !self.spawn_failures.exhausted(&cfg, triplet));
+        }
+
+        {
+            let now = Utc::now();
+            let mut pins = self.pins.lock().unwrap();
+
+            pins.retain(|triplet, pin| {
+                let active = pin.expires > now;
+
+                if !active {
+                    info!("Pin for {triplet} has expired");
+                }
+
+                active
+            });
+
+            for (triplet, pin) in pins.iter() {
+                let count = demand.entry(triplet.clone()).or_default();
+                *count = (*count).max(pin.count);
+            }
+        }
+
         debug!("Updating the machine demand with:");
 
         for (triplet, count) in demand.iter() {
             debug!("  - {triplet}: {count}");
         }
 
+        let not_ready: Vec<_> = self
+            .backend_readiness()
+            .into_iter()
+            .filter(|br| !br.is_ready())
+            .map(|br| br.backend)
+            .collect();
+
+        let quarantined = self.quarantined.lock().unwrap().clone();
+
         let mut machines = self.machines();
 
         for (triplet, triplet_machines) in machines.iter_mut() {
@@ -135,10 +877,16 @@ impl Manager {
                     continue;
                 }
 
+                // A quarantined job could be picked up by this machine.
+                if quarantined.contains(triplet) && !machine.is_quarantined() {
+                    machine.kill("replaced by a quarantined machine");
+                    continue;
+                }
+
                 // Reduce the demand for this machine type by one.
                 // If the demand is already zero, then kill the machine.
                 match demand.get_mut(triplet) {
-                    Some(0) | None => machine.kill(),
+                    Some(0) | None => machine.kill("no more demand"),
                     Some(count) => *count -= 1,
                 }
             }
@@ -148,55 +896,245 @@ impl Manager {
         let cfg = self.config.get();
 
         for (triplet, count) in demand {
+            // Do not even try to spawn machines that are bound to fail.
+            // The problem was already logged by the readiness check.
+            let backend = cfg.machine_config(&triplet).map(|mc| mc.backend);
+
+            if backend.is_some_and(|b| not_ready.contains(&b)) {
+                debug!("Not spawning machines for {triplet}, its backend is not ready");
+                continue;
+            }
+
+            let forge_kind = cfg.machine_config(&triplet).map(|mc| cfg.forge_of(mc));
+
+            let forge = match forge_kind.and_then(|kind| self.forges.get(kind)) {
+                Some(forge) => forge,
+                None => {
+                    error!("Not spawning machines for {triplet}, its forge is not set up");
+                    continue;
+                }
+            };
+
+            // Rather not run any jobs of this type than run a quarantined
+            // job on a regular machine.
+            let quarantine = match quarantined.contains(&triplet) {
+                true => match cfg.quarantine_of(&triplet) {
+                    Some(quarantine) => Some(quarantine),
+                    None => {
+                        error!("Not spawning machines for {triplet}, it has quarantined jobs but no quarantine machine type");
+                        continue;
+                    }
+                },
+                false => None,
+            };
+
             if !machines.contains_key(&triplet) {
                 machines.insert(triplet.clone(), Vec::new());
             }
 
+            let oar = triplet.clone().into_owner_and_repo();
+
             for _ in 0..count {
+                match self.registration_limits.request(&cfg, &self.metrics, &oar) {
+                    Decision::Allowed => {}
+                    Decision::Limited => break,
+                    Decision::RetryIn(delay) => {
+                        // Start the machines once older registrations have
+                        // left the window.
+                        let manager = self.clone();
+
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            manager.apply_demand();
+                        });
+
+                        break;
+                    }
+                }
+
                 let cfg = cfg.clone();
-                let auth = self.auth.clone();
+                let accounting = self.accounting.clone();
+                let devices = self.devices.clone();
+                let spawn_failures = self.spawn_failures.clone();
+                let forge = forge.clone();
+                let metrics = self.metrics.clone();
                 let rescheduler = self.rescheduler();
 
-                if let Some(m) = Machine::new(cfg, auth, rescheduler, triplet.clone()) {
+                let machine = Machine::new(
+                    cfg,
+                    accounting,
+                    devices,
+                    spawn_failures,
+                    forge,
+                    metrics,
+                    rescheduler,
+                    triplet.clone(),
+                    quarantine.clone(),
+                );
+
+                if let Some(m) = machine {
                     machines.get_mut(&triplet).unwrap().push(m);
                 }
             }
         }
 
+        // Any available machine may pick up a job of a boosted run,
+        // so hand the boost to as many of them as there are such jobs.
+        // The ones furthest along in starting up go first.
+        {
+            let boosted = self.boosted.lock().unwrap();
+
+            for (triplet, triplet_machines) in machines.iter() {
+                let mut count = boosted.get(triplet).copied().unwrap_or(0);
+
+                let mut available: Vec<_> = triplet_machines
+                    .iter()
+                    .filter(|m| m.status().is_available())
+                    .collect();
+
+                available.sort_by_key(|m| Reverse(m.cost_to_kill()));
+
+                for machine in available {
+                    machine.set_boosted(count > 0);
+                    count = count.saturating_sub(1);
+                }
+            }
+        }
+
+        let machine_count = machines.values().map(Vec::len).sum();
+
         // We must release the lock before calling reschedule
         std::mem::drop(machines);
+
+        self.record_pass("apply_demand_seconds", started, machine_count);
+
         self.reschedule();
     }
 
     fn reschedule(&self) {
-        let machines = self.machines();
+        // Scheduling passes work on a snapshot of the machine list,
+        // but must not run concurrently, as they hand out the same resources.
+        let _scheduling = self.scheduling.lock().unwrap();
+
+        let started = Instant::now();
+        let machines = self.snapshot();
+        let cfg = self.config.get();
 
         let mut ram_available = {
-            let cfg = self.config.get();
-            let ram_total = cfg.host.ram.bytes();
+            let ram_total = cfg.host.ram_schedulable();
             let ram_consumed = machines
                 .values()
                 .flat_map(|triplet_machines| triplet_machines.iter())
                 .map(|m| Machine::ram_consumed(m))
                 .sum();
+            let ram_nominal: u64 = machines
+                .values()
+                .flat_map(|triplet_machines| triplet_machines.iter())
+                .map(|m| Machine::ram_consumed_nominal(m))
+                .sum();
             let ram_available = ram_total.saturating_sub(ram_consumed);
 
-            debug!("Re-scheduling machines. {ram_available} of {ram_total} available");
+            debug!("Re-scheduling machines. {ram_available} of {ram_total} available ({ram_nominal} consumed without balloons)");
 
             ram_available
         };
 
-        // We want to prioritize scheduling jobs requiring a lot of RAM,
-        // because they are harder to place if we start all smaller jobs first.
-        let mut machines_flat: Vec<_> = machines
+        // CPU cores are only tracked if the host config limits them.
+        let mut cpus_available = cfg.host.cpus.map(|cpus_total| {
+            let cpus_consumed: u32 = machines
+                .values()
+                .flat_map(|triplet_machines| triplet_machines.iter())
+                .map(|m| Machine::cpus_consumed(m))
+                .sum();
+            let cpus_available = cpus_total.saturating_sub(cpus_consumed);
+
+            debug!("{cpus_available} of {cpus_total} CPU cores available");
+
+            cpus_available
+        });
+
+        // The same goes for the space taken up by disk images in the `base_dir`.
+        let mut disk_available = cfg.host.disk.as_ref().map(|disk| {
+            let disk_total = disk.bytes();
+            let disk_consumed: u64 = machines
+                .values()
+                .flat_map(|triplet_machines| triplet_machines.iter())
+                .map(|m| Machine::disk_consumed(m))
+                .sum();
+            let disk_available = disk_total.saturating_sub(disk_consumed);
+
+            debug!("{disk_available} of {disk_total} bytes of disk space available");
+
+            disk_available
+        });
+
+        // The scratch pools are tracked the same way as RAM, just with one
+        // budget per pool.
+        let mut scratch_available: HashMap<String, u64> = cfg
+            .host
+            .scratch
+            .iter()
+            .map(|(name, pool)| (name.clone(), pool.size.bytes()))
+            .collect();
+
+        for (pool, size) in machines
             .values()
             .flat_map(|triplet_machines| triplet_machines.iter())
-            .collect();
+            .filter_map(|m| m.scratch_consumed())
+        {
+            if let Some(available) = scratch_available.get_mut(pool) {
+                *available = available.saturating_sub(size);
+            }
+        }
 
-        machines_flat.sort_unstable_by_key(|m| Machine::ram_required(m));
+        // Host devices are handed out exclusively.
+        // This includes devices still attached to leftover machines of a
+        // previous Forrest instance.
+        let mut devices_available: HashSet<String> = {
+            let in_use = self.devices.in_use();
+
+            cfg.host
+                .devices
+                .keys()
+                .filter(|device| !in_use.contains(*device))
+                .cloned()
+                .collect()
+        };
+
+        // Host CPU cores per NUMA node that are not pinned to a machine yet.
+        let mut cores_available = FreeCores::new(&machines);
+
+        // Room kept free for jobs of protected branches and for the jobs
+        // that workflow runs in progress are expected to queue.
+        let mut reservations = {
+            let protected = self.protected.lock().unwrap();
+            let anticipated = self.anticipated.lock().unwrap();
+
+            Reservations::new(&cfg, &machines, &protected, &anticipated)
+        };
+
+        // Machines per owner and repository, for those with a limit.
+        let mut concurrency = ConcurrencyLimits::new(&cfg, &machines);
+
+        let machines_flat = Self::scheduling_order(&machines);
+
+        // Runner registrations started in this pass share an installation
+        // token per owner.
+        let mut registrations = registration::Batches::new();
 
-        for machine in machines_flat.iter_mut().rev() {
-            machine.reschedule(&mut ram_available, &machines);
+        for machine in machines_flat.iter() {
+            machine.reschedule(
+                &mut ram_available,
+                &mut cpus_available,
+                &mut disk_available,
+                &mut scratch_available,
+                &mut devices_available,
+                &mut cores_available,
+                &mut reservations,
+                &mut concurrency,
+                &mut registrations,
+                &machines,
+            );
         }
 
         debug!("Machines and their new state:");
@@ -206,6 +1144,173 @@ impl Manager {
         }
 
         debug!("Available RAM after re-schedule: {ram_available}");
+
+        if let Some(cpus_available) = cpus_available {
+            debug!("Available CPU cores after re-schedule: {cpus_available}");
+        }
+
+        if let Some(disk_available) = disk_available {
+            debug!("Available disk space after re-schedule: {disk_available}");
+        }
+
+        for (pool, available) in scratch_available.iter() {
+            debug!("Available space in scratch pool {pool} after re-schedule: {available}");
+        }
+
+        Self::audit_ram(cfg.host.ram_schedulable(), ram_available, &machines_flat);
+
+        if Self::preempt(ram_available, &machines_flat) {
+            // The RAM of the stopped machines is handed out in the next pass.
+            let manager = self.clone();
+
+            tokio::spawn(async move {
+                manager.reschedule();
+            });
+        }
+
+        let machine_count = machines_flat.len();
+
+        self.metrics
+            .record_histogram("machines", MACHINE_COUNT_BUCKETS, machine_count as f64);
+
+        self.record_pass("reschedule_seconds", started, machine_count);
+    }
+
+    /// The order in which a scheduling pass hands out resources to the machines
+    ///
+    /// Machines of a higher priority are scheduled first.
+    /// Within a priority the repositories take turns, so that a repository
+    /// that queued many jobs at once does not hold up the jobs other
+    /// repositories queued afterwards.
+    /// A repository that already has `n` machines up gets its first waiting
+    /// machine scheduled in turn `n`, its second one in turn `n + 1` and so on.
+    /// Within a turn we want to prioritize scheduling jobs requiring a lot of
+    /// RAM, because they are harder to place if we start all smaller jobs first.
+    fn scheduling_order(machines: &Machines) -> Vec<&Arc<Machine>> {
+        let mut machines_flat: Vec<_> = machines
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .collect();
+
+        machines_flat.sort_unstable_by_key(|m| Reverse((m.priority(), m.ram_required())));
+
+        let is_waiting = |m: &Machine| matches!(m.status(), Status::Requested | Status::Registered);
+
+        let mut turns: HashMap<OwnerAndRepo, usize> = HashMap::new();
+
+        for machine in machines_flat.iter() {
+            if !is_waiting(machine) && machine.status() != Status::Stopped {
+                let oar = machine.triplet().clone().into_owner_and_repo();
+                *turns.entry(oar).or_default() += 1;
+            }
+        }
+
+        let mut keyed: Vec<_> = machines_flat
+            .into_iter()
+            .map(|machine| {
+                let turn = match is_waiting(machine) {
+                    true => {
+                        let oar = machine.triplet().clone().into_owner_and_repo();
+                        let next = turns.entry(oar).or_default();
+                        let turn = *next;
+                        *next += 1;
+                        turn
+                    }
+                    false => 0,
+                };
+
+                (machine, turn)
+            })
+            .collect();
+
+        // The sort is stable, so the order by RAM is kept within a turn.
+        keyed.sort_by_key(|(machine, turn)| (Reverse(machine.priority()), *turn));
+
+        keyed.into_iter().map(|(machine, _)| machine).collect()
+    }
+
+    /// Make room for a machine that waits for RAM by stopping machines of a lower priority
+    ///
+    /// Only machines that do not run a job are stopped, those of the lowest
+    /// priority first and among these the ones that are cheapest to kill.
+    /// Nothing is stopped unless this frees enough RAM for the machine of the
+    /// highest priority that waits for RAM.
+    /// Returns whether any machine was stopped.
+    fn preempt(ram_available: u64, machines: &[&Arc<Machine>]) -> bool {
+        let waiting = machines
+            .iter()
+            .filter(|m| m.status() == Status::Registered && m.ram_required() > ram_available)
+            .max_by_key(|m| m.priority());
+
+        let waiting = match waiting {
+            Some(waiting) => waiting,
+            None => return false,
+        };
+
+        let priority = waiting.priority();
+        let ram_required = waiting.ram_required();
+
+        let mut candidates: Vec<_> = machines
+            .iter()
+            .filter(|m| m.priority() < priority)
+            .filter(|m| m.cost_to_kill() < u32::MAX && m.ram_consumed() > 0)
+            .collect();
+
+        candidates.sort_unstable_by_key(|m| (m.priority(), m.cost_to_kill()));
+
+        let mut ram_freed = ram_available;
+        let mut victims = Vec::new();
+
+        for candidate in candidates {
+            if ram_freed >= ram_required {
+                break;
+            }
+
+            ram_freed += candidate.ram_consumed();
+            victims.push(candidate);
+        }
+
+        if ram_freed < ram_required {
+            return false;
+        }
+
+        // The victims are stopped right away instead of powering down
+        // gracefully, as they would keep their RAM for the whole grace period,
+        // making the next passes preempt even more machines.
+        // They did not run a job, so there is nothing to lose.
+        for victim in victims.iter() {
+            info!("Stopping {victim} to make room for {waiting}, which has a higher priority");
+            victim.kill_now("preempted by a machine of a higher priority");
+        }
+
+        !victims.is_empty()
+    }
+
+    /// Check that the RAM handed out in a scheduling pass adds up
+    ///
+    /// The RAM left after the pass (`ram_available`) has to match what the
+    /// machines report to consume by now.
+    /// A mismatch means that the bookkeeping in `Machine::reschedule()` and
+    /// `Machine::ram_consumed()` disagree, which would otherwise only show up
+    /// as capacity that mysteriously goes unused (or is overcommitted).
+    /// Every pass starts over from what the machines report, so the drift
+    /// does not carry over into the next pass.
+    fn audit_ram(ram_total: u64, ram_available: u64, machines: &[&Arc<Machine>]) {
+        let ram_consumed: u64 = machines.iter().map(|m| m.ram_consumed()).sum();
+
+        // The host may be overcommitted, e.g. because `host.ram` was lowered
+        // while machines were running, in which case nothing was handed out.
+        if ram_consumed > ram_total {
+            return;
+        }
+
+        let ram_expected = ram_total - ram_consumed;
+
+        if ram_available != ram_expected {
+            error!(
+                "RAM accounting drifted: {ram_available} bytes left after scheduling, but machines consume {ram_consumed} of {ram_total} bytes. Using the latter from the next pass on"
+            );
+        }
     }
 
     async fn sweep(&self) {
@@ -227,12 +1332,18 @@ impl Manager {
 
                 // ... and have a look at all of their registered runners ...
                 for page in 1u32.. {
-                    let runners_page = octocrab
-                        .actions()
-                        .list_repo_self_hosted_runners(oar.owner(), oar.repository())
-                        .page(page)
-                        .send()
-                        .await;
+                    let runners_page = {
+                        let _permit = self.auth.api_permit(owner).await;
+
+                        octocrab
+                            .actions()
+                            .list_repo_self_hosted_runners(oar.owner(), oar.repository())
+                            .page(page)
+                            .send()
+                            .await
+                    };
+
+                    self.auth.health().record(&runners_page);
 
                     let runners_page = match runners_page {
                         Ok(rp) => rp,
@@ -263,6 +1374,12 @@ impl Manager {
                             None => continue,
                         };
 
+                        // Leave e.g. hand-registered runners that happen to use
+                        // our prefix and labels alone.
+                        if !Machine::is_runner_name_of(&triplet, &runner_name) {
+                            continue;
+                        }
+
                         // Is the runner online (the action runner software on the machine is
                         // connected to GitHubs servers) right now?
                         let online = match runner.status.as_str() {
@@ -291,6 +1408,8 @@ impl Manager {
                         // was uncleanly shut down.
                         // Remove the runner to un-clutter the runner list.
                         if !found && !online && !busy {
+                            let _permit = self.auth.api_permit(owner).await;
+
                             let res = octocrab
                                 .actions()
                                 .delete_repo_runner(oar.owner(), oar.repository(), runner.id)
@@ -307,66 +1426,472 @@ impl Manager {
         }
 
         // Go through each machine and check for timeouts
-        let mut machines = self.machines();
+        let machines = self.snapshot();
+
+        // Machines that did not get a job may just not have been handed
+        // one by a GitHub that is down.
+        let healthy_for = self.auth.health().healthy_for();
+
+        // Standby and pinned machines are supposed to wait for jobs.
+        // Killing them after their idle timeout would only replace them
+        // with new ones right away.
+        let mut kept = self.standby();
+
+        {
+            let now = Utc::now();
+
+            for (triplet, pin) in self.pins.lock().unwrap().iter() {
+                if pin.expires > now {
+                    let count = kept.entry(triplet.clone()).or_default();
+                    *count = (*count).max(pin.count);
+                }
+            }
+        }
+
+        let mut killed = false;
+
+        for (triplet, triplet_machines) in machines.iter() {
+            for machine in triplet_machines {
+                let runner_name = machine.runner_name();
+
+                // E.g. the job the machine was started for was cancelled,
+                // but we missed the event telling us so.
+                let idle_timeout = machine.machine_config().idle_timeout;
+
+                let idle_timeout_elapsed = machine
+                    .waiting_duration()
+                    .zip(healthy_for)
+                    .map(|(waiting, healthy_for)| waiting.min(healthy_for))
+                    .zip(idle_timeout)
+                    .is_some_and(|(waiting, timeout)| waiting > timeout);
+
+                let exempt = match kept.get_mut(triplet) {
+                    Some(count) if *count > 0 && idle_timeout_elapsed => {
+                        *count -= 1;
+                        true
+                    }
+                    _ => false,
+                };
+
+                if idle_timeout_elapsed && !exempt {
+                    info!("Runner {runner_name} on {triplet} did not get a job in time");
+
+                    machine.kill("no job within the idle timeout");
+                    killed = true;
+                }
+
+                // A hung job would otherwise occupy the host resources forever.
+                let max_job_duration = machine.machine_config().max_job_duration;
+
+                let job_duration_exceeded = machine
+                    .running_duration()
+                    .zip(max_job_duration)
+                    .is_some_and(|(running, max)| running > max);
+
+                if job_duration_exceeded {
+                    error!("Runner {runner_name} on {triplet} exceeded the maximum job duration. Killing it");
+
+                    machine.kill("job exceeded the max_job_duration");
+                    killed = true;
+                }
+            }
+        }
+
+        // Machines that are still needed, e.g. standby machines, are replaced
+        // and the freed up resources are handed out again.
+        if killed {
+            self.apply_demand();
+        }
+    }
+
+    /// Kill machines that did not register as runner within their `start_timeout`
+    ///
+    /// The machines are replaced by new ones as long as there is demand for
+    /// them, until their machine type used up its `start_retries`.
+    fn check_start_timeouts(&self) {
+        // Machines can not register as runners while GitHub is down.
+        // Killing them would only count towards their `start_retries`.
+        let healthy_for = match self.auth.health().healthy_for() {
+            Some(healthy_for) => healthy_for,
+            None => return,
+        };
+
+        let cfg = self.config.get();
+        let machines = self.snapshot();
 
         let base_dir_path = Path::new(&cfg.host.base_dir);
+        let mut killed = false;
 
-        for (triplet, triplet_machines) in machines.iter_mut() {
+        for (triplet, triplet_machines) in machines.iter() {
             for machine in triplet_machines {
                 let runner_name = machine.runner_name();
+                let config_triplet = machine.config_triplet();
+
+                let start_timeout = machine
+                    .machine_config()
+                    .start_timeout
+                    .unwrap_or(DEFAULT_START_TIMEOUT);
 
                 let start_timeout_elapsed = machine
                     .starting_duration()
-                    .map(|rt| rt > START_TIMEOUT)
-                    .unwrap_or(false);
+                    .is_some_and(|starting| starting.min(healthy_for) > start_timeout);
+
+                if !start_timeout_elapsed {
+                    continue;
+                }
 
-                if start_timeout_elapsed {
-                    error!("Runner {runner_name} on {triplet} failed to come up in time");
+                // The machine was not booted yet if the forge did not
+                // hand out a runner registration for it in time.
+                let booted = machine.status() == Status::Starting;
 
-                    let machine_image_path = triplet.machine_image_path(base_dir_path);
+                match booted {
+                    true => {
+                        let console_log =
+                            run_dir::console_log_path(machine.cfg(), config_triplet, runner_name);
 
-                    machine.kill();
+                        error!(
+                            "Runner {runner_name} on {triplet} failed to come up in time, see its console log {}",
+                            console_log.display()
+                        );
+                    }
+                    false => error!(
+                        "Runner {runner_name} on {triplet} could not be registered with the forge in time"
+                    ),
+                }
 
-                    let broken_image_path = {
-                        let mut filename = machine_image_path.file_name().unwrap().to_os_string();
-                        filename.push(".broken");
-                        machine_image_path.parent().unwrap().join(filename)
-                    };
+                self.spawn_failures.failure(config_triplet);
+                self.metrics.record_boot(&triplet.to_string(), false);
 
-                    // Keep a copy of the broken image around for later investigation.
-                    // But move the original away so that later invocations run from seed
-                    // image again and hopefully succeed.
-                    let res = std::fs::rename(&machine_image_path, &broken_image_path);
+                // This also de-registers the runner, if it was registered.
+                machine.kill("failed to register as runner in time");
+                killed = true;
 
-                    let mip = machine_image_path.display();
-                    let bip = broken_image_path.display();
+                if !booted {
+                    continue;
+                }
 
-                    match res {
-                        Ok(()) => info!("Retained broken machine image as {bip}"),
-                        Err(e) if e.kind() == ErrorKind::NotFound => {
-                            info!(
-                                "Machine image {mip} not found. Machine likely started from seed."
-                            )
-                        }
-                        Err(e) => error!("Failed to remove broken disk image {bip}: {e}"),
+                let machine_image_path = config_triplet.machine_image_path(base_dir_path);
+
+                let broken_image_path = {
+                    let mut filename = machine_image_path.file_name().unwrap().to_os_string();
+                    filename.push(".broken");
+                    machine_image_path.parent().unwrap().join(filename)
+                };
+
+                // Keep a copy of the broken image around for later investigation.
+                // But move the original away so that later invocations run from seed
+                // image again and hopefully succeed.
+                let res = std::fs::rename(&machine_image_path, &broken_image_path);
+
+                let mip = machine_image_path.display();
+                let bip = broken_image_path.display();
+
+                match res {
+                    Ok(()) => info!("Retained broken machine image as {bip}"),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        info!("Machine image {mip} not found. Machine likely started from seed.")
                     }
+                    Err(e) => error!("Failed to remove broken disk image {bip}: {e}"),
                 }
             }
         }
+
+        // Start replacements for the killed machines, unless their
+        // machine type used up its retries.
+        if killed {
+            self.apply_demand();
+        }
+    }
+
+    /// Did machines requested as `triplet` fail to come up too often to try again?
+    ///
+    /// See `start_retries` in the machine config.
+    /// Jobs queued for such a machine type should no longer create demand.
+    pub fn start_failed(&self, triplet: &Triplet) -> bool {
+        self.spawn_failures.exhausted(&self.config.get(), triplet)
+    }
+
+    /// Replace one idle machine that runs from an outdated image
+    fn replace_stale_machine(&self) {
+        let cfg = self.config.get();
+        let machines = self.snapshot();
+
+        // Machines that are busy with a job can not be replaced right away,
+        // but are marked as such, so that it is visible in the status.
+        for machine in machines
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+        {
+            if machine.status() == Status::Running && machine.is_outdated(&cfg) {
+                machine.drain("a newer image is available");
+            }
+        }
+
+        let stale = machines
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .find(|machine| machine.is_stale(&cfg))
+            .cloned();
+
+        if let Some(machine) = stale {
+            info!("Replacing idle machine {machine} because a newer image is available");
+
+            machine.kill("replaced by a machine with a newer image");
+
+            // Start a machine from the new image in its place.
+            self.apply_demand();
+        }
+    }
+
+    /// Progressively replace idle machines after their image was updated
+    ///
+    /// One machine is replaced per `host.rolling_restart` interval,
+    /// so that not all warm machines are gone at once.
+    pub async fn rolling_restart(&self) -> std::io::Result<()> {
+        loop {
+            match self.config.get().host.rolling_restart {
+                Some(interval) => {
+                    tokio::time::sleep(interval).await;
+                    self.replace_stale_machine();
+                }
+                None => tokio::time::sleep(ROLLING_RESTART_DISABLED_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Pull the container images of the machine types and keep them up to date
+    ///
+    /// The images are pulled once on startup, so that the first machines do
+    /// not have to wait for the pull, and then every
+    /// `host.container_image_updates` interval, if set.
+    pub async fn container_image_updates(&self) -> std::io::Result<()> {
+        backend::update_container_images(&self.config.get()).await;
+
+        loop {
+            match self.config.get().host.container_image_updates {
+                Some(interval) => {
+                    tokio::time::sleep(interval).await;
+                    backend::update_container_images(&self.config.get()).await;
+                }
+                None => tokio::time::sleep(IMAGE_UPDATES_DISABLED_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Apply the retention policies to the persistent stores
+    fn prune(&self) {
+        let cfg = self.config.get();
+
+        let active: HashSet<String> = self
+            .snapshot()
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .map(|machine| machine.runner_name().to_owned())
+            .collect();
+
+        retention::prune(&cfg, &active);
     }
 
     /// Perform a periodic sweep on the machines.
     ///
     /// This means getting the list of runners from the API,
-    /// updating the state of our local runner structures and
-    /// killing machines that failed to register as runner;
+    /// updating the state of our local runner structures,
+    /// killing machines that exceeded their idle timeout or max job duration and
+    /// pruning old data from the base dir;
     pub async fn janitor(&self) -> std::io::Result<()> {
         loop {
             self.sweep().await;
+            self.prune();
 
             tokio::time::sleep(std::time::Duration::from_secs(15 * 60)).await;
         }
     }
+
+    /// Periodically look for machines that are stuck while starting up
+    ///
+    /// This runs more often than the `janitor`, so that a short `start_timeout`
+    /// is acted upon in time.
+    pub async fn start_watchdog(&self) -> std::io::Result<()> {
+        loop {
+            tokio::time::sleep(START_WATCHDOG_INTERVAL).await;
+            self.check_start_timeouts();
+        }
+    }
+
+    /// Periodically ask the forge about the state of the runners of our machines
+    ///
+    /// This is how machines move on from the starting state for forges that
+    /// do not report runner states via the runner sweep and job events,
+    /// like GitHub does.
+    pub async fn forge_feedback(&self) -> std::io::Result<()> {
+        loop {
+            tokio::time::sleep(FORGE_FEEDBACK_INTERVAL).await;
+
+            let machines = self.snapshot();
+
+            for machine in machines.values().flat_map(|tm| tm.iter()) {
+                let runner_id = match machine.spawned_runner_id() {
+                    Some(runner_id) => runner_id,
+                    None => continue,
+                };
+
+                let status = machine
+                    .forge()
+                    .runner_status(machine.triplet(), &runner_id)
+                    .await;
+
+                match status {
+                    Ok(Some(status)) => machine.status_feedback(Some(status.online), status.busy),
+                    // The forge reports runner states some other way.
+                    Ok(None) => {}
+                    Err(e) => warn!("Failed to get the runner state of {machine}: {e}"),
+                }
+            }
+        }
+    }
+
+    /// Pause or resume one idle machine, depending on the host pressure
+    fn shed_load(&self) {
+        let cfg = self.config.get();
+
+        let machines: Vec<Arc<Machine>> = self
+            .snapshot()
+            .into_values()
+            .flat_map(|triplet_machines| triplet_machines.into_iter())
+            .collect();
+
+        let find = |status| machines.iter().find(|m| m.status() == status);
+
+        let load_shedding = match &cfg.host.load_shedding {
+            Some(load_shedding) => load_shedding,
+            None => {
+                // Load shedding was disabled in the config.
+                // Do not leave machines paused forever.
+                for machine in machines.iter().filter(|m| m.status() == Status::Paused) {
+                    machine.resume();
+                }
+
+                return;
+            }
+        };
+
+        let pressure = match pressure::host_pressure() {
+            Ok(pressure) => pressure,
+            Err(e) => {
+                warn!("Failed to get the host pressure: {e}");
+                return;
+            }
+        };
+
+        if pressure > load_shedding.pause_above {
+            if let Some(machine) = find(Status::Waiting) {
+                info!("Host pressure is at {pressure:.1}%, pausing {machine}");
+                machine.pause();
+            }
+        } else if pressure < load_shedding.resume_below {
+            if let Some(machine) = find(Status::Paused) {
+                info!("Host pressure is at {pressure:.1}%, resuming {machine}");
+                machine.resume();
+            }
+        }
+    }
+
+    /// Stop all machines before Forrest exits
+    ///
+    /// No new machines are started from here on.
+    /// Machines that are not running a job are stopped right away,
+    /// machines that are get up to `host.drain_timeout` to complete it.
+    /// Machines that are left running for the next instance to take over
+    /// (see `host.keep_machines_on_restart`) are not stopped.
+    pub async fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+
+        let cfg = self.config.get();
+
+        let machines: Vec<Arc<Machine>> = self
+            .snapshot()
+            .into_values()
+            .flat_map(|triplet_machines| triplet_machines.into_iter())
+            .filter(|machine| !machine.outlives_instance())
+            .collect();
+
+        for machine in machines.iter() {
+            machine.drain("shutting down");
+        }
+
+        let deadline = Instant::now() + cfg.host.drain_timeout.unwrap_or_default();
+
+        loop {
+            let running: Vec<_> = machines
+                .iter()
+                .filter(|m| !m.status().is_stopped())
+                .collect();
+
+            if running.is_empty() {
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                // We exit right after draining, so there is no time to give
+                // the guests a chance to shut down cleanly.
+                // Machines that were already asked to do so are killed too.
+                for machine in running {
+                    warn!("Machine {machine} did not stop in time. Killing it");
+                    machine.kill_now("drain timeout elapsed while shutting down");
+                }
+
+                break;
+            }
+
+            info!(
+                "Waiting for {} machines to complete their jobs",
+                running.len()
+            );
+
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        // The runners are de-registered in the background.
+        // Give that a moment, so that they do not linger on the forge.
+        // This also gives the tasks of the killed machines a chance to be
+        // dropped, which is what actually kills their processes.
+        let deadline = Instant::now() + DEREGISTER_TIMEOUT;
+
+        loop {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            if Instant::now() >= deadline || machines.iter().all(|m| m.is_deregistered()) {
+                break;
+            }
+        }
+    }
+
+    /// Shrink the RAM of machines that have been waiting for a job for a while
+    ///
+    /// Only machine types with a `balloon` config are affected.
+    pub async fn ballooning(&self) -> std::io::Result<()> {
+        loop {
+            tokio::time::sleep(BALLOON_INTERVAL).await;
+
+            let machines = self.snapshot();
+
+            for machine in machines.values().flat_map(|m| m.iter()) {
+                machine.inflate_balloon();
+            }
+        }
+    }
+
+    /// Progressively pause idle machines while the host is under pressure
+    ///
+    /// One machine is paused or resumed per interval,
+    /// so that the effect on the pressure can be observed before
+    /// pausing even more machines.
+    pub async fn load_shedding(&self) -> std::io::Result<()> {
+        loop {
+            tokio::time::sleep(LOAD_SHEDDING_INTERVAL).await;
+            self.shed_load();
+        }
+    }
 }
 
 impl Rescheduler {
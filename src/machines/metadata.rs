@@ -0,0 +1,144 @@
+use std::fs::Permissions;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::time::timeout;
+
+// The socket in the run dir the machine's metadata is served on.
+pub(super) const SOCKET: &str = "metadata.sock";
+
+// Guests reach the metadata via this address and port.
+// It is part of the network qemu user mode networking sets up.
+pub(super) const GUEST_ADDRESS: &str = "10.0.2.100:80";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_SIZE_LIMIT: u64 = 16 * 1024;
+
+/// What a machine can learn about itself, e.g. to pick the build parallelism
+#[derive(Serialize)]
+pub(super) struct Metadata {
+    /// The machine type, as `<owner>/<repository>/<machine>`
+    pub machine: String,
+    pub runner_name: String,
+    pub cpus: u32,
+    /// The RAM of the machine in bytes
+    pub ram: u64,
+    /// The size of the disk image in bytes
+    pub disk: u64,
+    /// The time in seconds until the job is killed for exceeding the
+    /// `max_job_duration`, if one is configured
+    pub remaining_job_time: Option<u64>,
+}
+
+/// Answer a single request on `sock` with the current `metadata`
+async fn handle(
+    sock: tokio::net::UnixStream,
+    metadata: impl Fn() -> Metadata,
+) -> std::io::Result<()> {
+    let (read, mut write) = sock.into_split();
+    let mut read = BufReader::new(read.take(REQUEST_SIZE_LIMIT));
+
+    // Like the admin API this only understands as much HTTP as it needs to.
+    let mut line = String::new();
+    read.read_line(&mut line).await?;
+
+    let method = line
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+
+    loop {
+        line.clear();
+
+        if read.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = match method.as_str() {
+        "GET" => ("200 OK", serde_json::to_vec_pretty(&metadata()).unwrap()),
+        _ => ("405 Method Not Allowed", Vec::new()),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\nServer: Forrest\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+
+    write.write_all(head.as_bytes()).await?;
+    write.write_all(&body).await
+}
+
+/// Serve the `metadata` of a machine on the metadata socket in its `run_dir`
+///
+/// This never completes and is meant to run alongside the machine process.
+/// Problems setting up the socket are logged and leave the metadata
+/// unavailable to the machine.
+pub(super) async fn serve(run_dir: &Path, metadata: impl Fn() -> Metadata) {
+    let path = run_dir.join(SOCKET);
+
+    let _ = std::fs::remove_file(&path);
+
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(
+                "Failed to serve machine metadata on {}: {e}",
+                path.display()
+            );
+            return std::future::pending().await;
+        }
+    };
+
+    // The proxy connecting to the socket runs as the same user as qemu,
+    // which may be the user of a tenant.
+    // Access is still limited by the permissions of the run dir.
+    if let Err(e) = std::fs::set_permissions(&path, Permissions::from_mode(0o777)) {
+        warn!("Failed to open up {} to tenants: {e}", path.display());
+    }
+
+    loop {
+        let sock = match listener.accept().await {
+            Ok((sock, _)) => sock,
+            Err(e) => {
+                warn!("Failed to accept metadata connection: {e}");
+                continue;
+            }
+        };
+
+        // Requests are answered one after the other.
+        // Machines have no reason to ask for their metadata often.
+        match timeout(REQUEST_TIMEOUT, handle(sock, &metadata)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("Metadata request failed: {e}"),
+            Err(_) => debug!("Metadata request took too long"),
+        }
+    }
+}
+
+/// Connect stdin and stdout to the metadata `socket`
+///
+/// This is run by qemu for each connection of the guest to the
+/// `GUEST_ADDRESS`, as qemu can not forward multiple connections to a
+/// unix socket itself.
+pub fn proxy(socket: &Path) -> std::io::Result<()> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    let mut upstream = stream.try_clone()?;
+
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut std::io::stdin(), &mut upstream);
+        let _ = upstream.shutdown(std::net::Shutdown::Write);
+    });
+
+    let mut stdout = std::io::stdout();
+
+    std::io::copy(&mut stream, &mut stdout)?;
+    stdout.flush()
+}
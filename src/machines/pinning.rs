@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::manager::Machines;
+
+// Where the kernel lists the NUMA nodes of the host and their CPU cores.
+const NODES_DIR: &str = "/sys/devices/system/node";
+
+/// The host CPU cores a machine is pinned to, all on the same NUMA node
+#[derive(Serialize, Deserialize, Clone)]
+pub(super) struct Pinning {
+    pub(super) node: u32,
+    pub(super) cores: Vec<u32>,
+}
+
+impl Pinning {
+    /// The cores as a list like `taskset --cpu-list` expects it, e.g. `2,3,4,5`
+    pub(super) fn cpu_list(&self) -> String {
+        let cores: Vec<String> = self.cores.iter().map(u32::to_string).collect();
+        cores.join(",")
+    }
+}
+
+/// Parse a list of CPU cores like `0-3,8-11`
+fn parse_cpu_list(list: &str) -> Vec<u32> {
+    let mut cores = Vec::new();
+
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+
+        match (start.parse::<u32>(), end.parse::<u32>()) {
+            (Ok(start), Ok(end)) => cores.extend(start..=end),
+            _ => warn!("Ignoring malformed CPU range {range}"),
+        }
+    }
+
+    cores
+}
+
+/// Read the CPU cores of each NUMA node of the host
+///
+/// Hosts without NUMA support are treated as a single node 0 with all cores.
+fn read_topology() -> BTreeMap<u32, Vec<u32>> {
+    let mut nodes = BTreeMap::new();
+
+    if let Ok(entries) = std::fs::read_dir(NODES_DIR) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+
+            let node = name
+                .to_str()
+                .and_then(|n| n.strip_prefix("node"))
+                .and_then(|n| n.parse::<u32>().ok());
+
+            let node = match node {
+                Some(node) => node,
+                None => continue,
+            };
+
+            if let Ok(list) = std::fs::read_to_string(entry.path().join("cpulist")) {
+                nodes.insert(node, parse_cpu_list(&list));
+            }
+        }
+    }
+
+    if nodes.is_empty() {
+        let cores = std::thread::available_parallelism()
+            .map(|cores| cores.get() as u32)
+            .unwrap_or(1);
+
+        nodes.insert(0, (0..cores).collect());
+    }
+
+    for (node, cores) in &nodes {
+        info!("NUMA node {node} has {} CPU cores", cores.len());
+    }
+
+    nodes
+}
+
+/// The CPU cores of each NUMA node of the host, as read on first use
+fn topology() -> &'static BTreeMap<u32, Vec<u32>> {
+    static TOPOLOGY: OnceLock<BTreeMap<u32, Vec<u32>>> = OnceLock::new();
+
+    TOPOLOGY.get_or_init(read_topology)
+}
+
+/// The host CPU cores per NUMA node that are not pinned to a machine
+pub(super) struct FreeCores(BTreeMap<u32, Vec<u32>>);
+
+impl FreeCores {
+    /// Get the cores not pinned to any of the `machines` that currently consume resources
+    pub(super) fn new(machines: &Machines) -> Self {
+        let mut free = topology().clone();
+
+        let pinned = machines
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .filter_map(|m| m.pinned_cores());
+
+        for pinning in pinned {
+            if let Some(cores) = free.get_mut(&pinning.node) {
+                cores.retain(|core| !pinning.cores.contains(core));
+            }
+        }
+
+        Self(free)
+    }
+
+    /// Does the host have a NUMA node `node`?
+    pub(super) fn has_node(&self, node: u32) -> bool {
+        self.0.contains_key(&node)
+    }
+
+    /// Pick `count` free cores on `node`, or on the node with the most free cores
+    ///
+    /// Returns `None` if there are not enough free cores on a single node.
+    pub(super) fn pick(&self, count: u32, node: Option<u32>) -> Option<Pinning> {
+        let count = count as usize;
+
+        let (node, cores) = match node {
+            Some(node) => (node, self.0.get(&node)?),
+            None => self
+                .0
+                .iter()
+                .max_by_key(|(_, cores)| cores.len())
+                .map(|(node, cores)| (*node, cores))?,
+        };
+
+        (cores.len() >= count).then(|| Pinning {
+            node,
+            cores: cores[..count].to_vec(),
+        })
+    }
+
+    /// Mark the cores of `pinning` as taken
+    pub(super) fn claim(&mut self, pinning: &Pinning) {
+        if let Some(cores) = self.0.get_mut(&pinning.node) {
+            cores.retain(|core| !pinning.cores.contains(core));
+        }
+    }
+}
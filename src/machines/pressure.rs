@@ -0,0 +1,41 @@
+use std::path::Path;
+
+const PRESSURE_DIR: &str = "/proc/pressure";
+
+/// Get the `some avg10` value of a pressure stall information file
+///
+/// The file contains lines like
+/// `some avg10=1.23 avg60=0.50 avg300=0.10 total=123456`.
+fn some_avg10(content: &str) -> Option<f64> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|kv| kv.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// Get the current CPU or memory pressure of the host, whichever is higher
+///
+/// This is the share of time (in percent) over the last ten seconds in which
+/// at least one task was stalled waiting for the resource.
+pub(super) fn host_pressure() -> std::io::Result<f64> {
+    let mut pressure: f64 = 0.0;
+
+    for resource in ["cpu", "memory"] {
+        let path = Path::new(PRESSURE_DIR).join(resource);
+        let content = std::fs::read_to_string(&path)?;
+
+        let avg10 = some_avg10(&content).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to parse {}", path.display()),
+            )
+        })?;
+
+        pressure = pressure.max(avg10);
+    }
+
+    Ok(pressure)
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+/// Runner registrations of one owner that were started in the same
+/// scheduling pass
+///
+/// When many machines are requested at once, e.g. for a large build matrix,
+/// their jit runner registrations run in parallel
+/// (bounded by `github.api_concurrency`).
+/// The installation token used for them is however only fetched on the first
+/// request, so parallel first requests would each fetch a token of their own.
+/// A batch lets the first registration go ahead alone and releases the
+/// others once it has succeeded, so that they share the cached token.
+#[derive(Default)]
+pub(super) struct Batch {
+    token_fetched: Mutex<bool>,
+}
+
+impl Batch {
+    /// Send a registration `request` as part of this batch
+    ///
+    /// API permits have to be acquired inside of `request`
+    /// (as `Forge::register_runner()` does), so that requests waiting for the
+    /// first one to complete do not hold on to them.
+    pub(super) async fn send<T, E>(
+        &self,
+        request: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let mut token_fetched = self.token_fetched.lock().await;
+
+        if *token_fetched {
+            std::mem::drop(token_fetched);
+            return request.await;
+        }
+
+        // If the first request fails the next one takes its place.
+        let res = request.await;
+        *token_fetched = res.is_ok();
+
+        res
+    }
+}
+
+/// The registration batches of a scheduling pass, one per owner
+#[derive(Default)]
+pub(super) struct Batches {
+    batches: HashMap<String, Arc<Batch>>,
+}
+
+impl Batches {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the batch to add a registration on behalf of `owner` to
+    pub(super) fn get(&mut self, owner: &str) -> Arc<Batch> {
+        self.batches.entry(owner.to_owned()).or_default().clone()
+    }
+}
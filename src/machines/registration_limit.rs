@@ -0,0 +1,222 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+
+use super::manager::expiry;
+use super::triplet::OwnerAndRepo;
+use crate::config::ConfigFile;
+use crate::error::Category;
+use crate::metrics::Metrics;
+
+// The limits are per repository and hour, in a sliding window.
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// A temporary limit set via the admin API, replacing the configured one
+struct Override {
+    /// `None` lifts the limit completely
+    limit: Option<u32>,
+    expires: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct RepoState {
+    registrations: VecDeque<Instant>,
+    limit_override: Option<Override>,
+    /// Was the limit hit since the repository was last allowed a machine?
+    limited: bool,
+    /// When to have another look at the demand of a limited repository
+    retry_at: Option<Instant>,
+}
+
+/// The state of the registration limit of a repository, e.g. for the admin API
+#[derive(Serialize)]
+pub struct RegistrationLimitInfo {
+    pub repository: String,
+    /// The limit in effect, `None` if there is none
+    pub limit: Option<u32>,
+    /// Until when the limit in effect was set via the admin API
+    pub overridden_until: Option<DateTime<Utc>>,
+    /// The machines started for this repository in the last hour
+    pub registrations: usize,
+    pub limited: bool,
+}
+
+/// The decision whether another machine may be started for a repository
+pub(super) enum Decision {
+    Allowed,
+    Limited,
+    /// The limit was hit and the demand should be re-evaluated after this time
+    RetryIn(Duration),
+}
+
+/// Caps the machines started per repository and hour
+///
+/// This protects against runaway workflows, e.g. ones that re-trigger
+/// themselves, burning through resources.
+#[derive(Default)]
+pub(super) struct RegistrationLimits {
+    repositories: Mutex<HashMap<OwnerAndRepo, RepoState>>,
+}
+
+impl RegistrationLimits {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    fn limit(cfg: &ConfigFile, oar: &OwnerAndRepo, state: &RepoState) -> Option<u32> {
+        match &state.limit_override {
+            Some(lo) if lo.expires > Utc::now() => lo.limit,
+            _ => cfg
+                .repositories
+                .get(oar.owner())
+                .and_then(|repos| repos.get(oar.repository()))
+                .and_then(|repo| repo.registrations_per_hour),
+        }
+    }
+
+    /// Decide if another machine may be started for `oar` and account for it if so
+    ///
+    /// The first time the limit is hit this is logged as an error and counted
+    /// in the error metrics, so that it can be alerted on.
+    pub(super) fn request(
+        &self,
+        cfg: &ConfigFile,
+        metrics: &Metrics,
+        oar: &OwnerAndRepo,
+    ) -> Decision {
+        let mut repositories = self.repositories.lock().unwrap();
+        let state = repositories.entry(oar.clone()).or_default();
+        let now = Instant::now();
+
+        while state
+            .registrations
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= WINDOW)
+        {
+            state.registrations.pop_front();
+        }
+
+        let limit = Self::limit(cfg, oar, state);
+
+        let below_limit = match limit {
+            Some(limit) => state.registrations.len() < limit as usize,
+            None => true,
+        };
+
+        if below_limit {
+            state.registrations.push_back(now);
+            state.limited = false;
+            state.retry_at = None;
+
+            return Decision::Allowed;
+        }
+
+        if !state.limited {
+            error!(
+                "Repository {oar} hit its limit of {} machine registrations per hour. Not starting more machines for it.",
+                limit.unwrap_or(0)
+            );
+
+            metrics.count_error("registration_limit", Category::RateLimit);
+            state.limited = true;
+        }
+
+        if state.retry_at.is_some_and(|retry_at| retry_at > now) {
+            return Decision::Limited;
+        }
+
+        // A limit of zero has no registrations that could expire.
+        let retry_in = state
+            .registrations
+            .front()
+            .map(|at| WINDOW.saturating_sub(now.duration_since(*at)))
+            .unwrap_or(WINDOW);
+
+        state.retry_at = Some(now + retry_in);
+
+        Decision::RetryIn(retry_in)
+    }
+
+    /// Replace the configured limit of `oar` with `limit` for `duration`
+    ///
+    /// A `limit` of `None` lifts the limit.
+    /// A `duration` of zero removes a previous override.
+    pub(super) fn set_override(
+        &self,
+        oar: OwnerAndRepo,
+        limit: Option<u32>,
+        duration: Duration,
+    ) -> Result<(), String> {
+        let mut repositories = self.repositories.lock().unwrap();
+        let state = repositories.entry(oar.clone()).or_default();
+
+        if duration.is_zero() {
+            info!("Removing registration limit override for {oar}");
+            state.limit_override = None;
+        } else {
+            let expires = expiry(duration)?;
+
+            match limit {
+                Some(limit) => {
+                    info!("Limiting {oar} to {limit} registrations per hour until {expires}")
+                }
+                None => info!("Lifting the registration limit of {oar} until {expires}"),
+            }
+
+            state.limit_override = Some(Override { limit, expires });
+        }
+
+        // Give the new limit a chance right away.
+        state.retry_at = None;
+
+        Ok(())
+    }
+
+    /// Get the state of the registration limits of all configured repositories
+    pub(super) fn info(&self, cfg: &ConfigFile) -> Vec<RegistrationLimitInfo> {
+        let repositories = self.repositories.lock().unwrap();
+        let now = Instant::now();
+
+        let mut info: Vec<RegistrationLimitInfo> = cfg
+            .repositories
+            .iter()
+            .flat_map(|(owner, repos)| {
+                repos
+                    .keys()
+                    .map(move |repository| OwnerAndRepo::new(owner, repository))
+            })
+            .map(|oar| {
+                let default = RepoState::default();
+                let state = repositories.get(&oar).unwrap_or(&default);
+
+                let overridden_until = state
+                    .limit_override
+                    .as_ref()
+                    .map(|lo| lo.expires)
+                    .filter(|expires| *expires > Utc::now());
+
+                let registrations = state
+                    .registrations
+                    .iter()
+                    .filter(|at| now.duration_since(**at) < WINDOW)
+                    .count();
+
+                RegistrationLimitInfo {
+                    repository: oar.to_string(),
+                    limit: Self::limit(cfg, &oar, state),
+                    overridden_until,
+                    registrations,
+                    limited: state.limited,
+                }
+            })
+            .collect();
+
+        info.sort_unstable_by(|a, b| a.repository.cmp(&b.repository));
+
+        info
+    }
+}
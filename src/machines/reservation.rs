@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use super::backend;
+use super::manager::Machines;
+use super::triplet::{OwnerAndRepo, Triplet};
+use crate::config::ConfigFile;
+
+/// The reserved slots of a repository that are not in use
+struct FreeSlots {
+    count: u64,
+    /// The RAM (in bytes) held back per slot
+    size: u64,
+}
+
+/// Keeps room on the host for the jobs of protected branches
+///
+/// Each repository with a `reserved` config has a number of slots,
+/// each large enough for a machine of its largest machine type.
+/// The RAM of slots that are not in use is not available to other machines,
+/// so that jobs of protected branches can start right away even when
+/// e.g. pull request jobs have used up all other capacity.
+///
+/// Machine types with an `anticipate_for` config also get slots, one for
+/// each workflow run in progress that is expected to queue a job for them,
+/// for at most that long.
+/// These keep e.g. standby machines from taking up the RAM the later stage
+/// of a run needs.
+pub(super) struct Reservations {
+    free: HashMap<OwnerAndRepo, FreeSlots>,
+    /// How many more machines of a type may be started in a reserved slot
+    claimable: HashMap<Triplet, u64>,
+    /// The slots held for jobs that runs in progress are expected to queue
+    anticipated: HashMap<Triplet, FreeSlots>,
+}
+
+impl Reservations {
+    /// Get the reservations left given the current `machines`
+    ///
+    /// `protected` is the number of queued jobs of protected branches
+    /// per machine type.
+    /// `anticipated` lists per machine type since when each run in progress
+    /// is expected to queue a job for it.
+    pub(super) fn new(
+        cfg: &ConfigFile,
+        machines: &Machines,
+        protected: &HashMap<Triplet, u64>,
+        anticipated: &HashMap<Triplet, Vec<DateTime<Utc>>>,
+    ) -> Self {
+        let mut free = HashMap::new();
+
+        for (owner, repos) in &cfg.repositories {
+            for (repository, repo) in repos {
+                let reservation = match &repo.reserved {
+                    Some(reservation) => reservation,
+                    None => continue,
+                };
+
+                let size = repo
+                    .machines
+                    .values()
+                    .filter(|mc| backend::runs_on_host(mc.backend))
+                    .map(|mc| mc.ram.bytes())
+                    .max()
+                    .unwrap_or(0);
+
+                let slots = FreeSlots {
+                    count: reservation.slots.into(),
+                    size,
+                };
+
+                free.insert(OwnerAndRepo::new(owner, repository), slots);
+            }
+        }
+
+        let now = Utc::now();
+        let mut expected = HashMap::new();
+
+        for (triplet, since) in anticipated {
+            let mc = match cfg.machine_config(triplet) {
+                Some(mc) if backend::runs_on_host(mc.backend) => mc,
+                _ => continue,
+            };
+
+            let window = match mc.anticipate_for.and_then(|w| TimeDelta::from_std(w).ok()) {
+                Some(window) => window,
+                None => continue,
+            };
+
+            let count = since.iter().filter(|since| now - **since < window).count() as u64;
+
+            if count > 0 {
+                let slots = FreeSlots {
+                    count,
+                    size: mc.ram.bytes(),
+                };
+
+                expected.insert(triplet.clone(), slots);
+            }
+        }
+
+        let mut claimable = protected.clone();
+
+        for machine in machines.values().flatten() {
+            // Machines that are already up and do not run a job yet can take
+            // the expected jobs themselves.
+            if machine.status().is_available() && machine.ram_consumed() > 0 {
+                if let Some(slots) = expected.get_mut(machine.triplet()) {
+                    slots.count = slots.count.saturating_sub(1);
+                }
+            }
+
+            if !machine.in_reserved_slot() {
+                continue;
+            }
+
+            let oar = machine.triplet().clone().into_owner_and_repo();
+
+            if let Some(slots) = free.get_mut(&oar) {
+                slots.count = slots.count.saturating_sub(1);
+            }
+
+            // Machines in a reserved slot that did not pick up a job yet
+            // are already there for the queued jobs of protected branches.
+            if machine.status().is_available() {
+                if let Some(count) = claimable.get_mut(machine.triplet()) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+        }
+
+        Self {
+            free,
+            claimable,
+            anticipated: expected,
+        }
+    }
+
+    /// The RAM (in bytes) held back for the reserved slots not in use
+    pub(super) fn held(&self) -> u64 {
+        self.free
+            .values()
+            .chain(self.anticipated.values())
+            .map(|slots| slots.count * slots.size)
+            .sum()
+    }
+
+    /// Could a machine of `triplet` that needs `ram` bytes use a slot reserved for protected branches?
+    fn can_claim_protected(&self, triplet: &Triplet, ram: u64) -> bool {
+        let claimable = self.claimable.get(triplet).is_some_and(|count| *count > 0);
+
+        let free = self
+            .free
+            .get(&triplet.clone().into_owner_and_repo())
+            .is_some_and(|slots| slots.count > 0 && ram <= slots.size);
+
+        claimable && free
+    }
+
+    /// Could a machine of `triplet` that needs `ram` bytes use a reserved slot?
+    pub(super) fn can_claim(&self, triplet: &Triplet, ram: u64) -> bool {
+        let anticipated = self
+            .anticipated
+            .get(triplet)
+            .is_some_and(|slots| slots.count > 0 && ram <= slots.size);
+
+        anticipated || self.can_claim_protected(triplet, ram)
+    }
+
+    /// Use a reserved slot for a machine of `triplet` that needs `ram` bytes
+    ///
+    /// `can_claim()` has to be checked first.
+    /// Returns `true` if the slot is one reserved for protected branches.
+    pub(super) fn claim(&mut self, triplet: &Triplet, ram: u64) -> bool {
+        if !self.can_claim_protected(triplet, ram) {
+            if let Some(slots) = self.anticipated.get_mut(triplet) {
+                slots.count = slots.count.saturating_sub(1);
+            }
+
+            return false;
+        }
+
+        if let Some(count) = self.claimable.get_mut(triplet) {
+            *count = count.saturating_sub(1);
+        }
+
+        if let Some(slots) = self.free.get_mut(&triplet.clone().into_owner_and_repo()) {
+            slots.count = slots.count.saturating_sub(1);
+        }
+
+        true
+    }
+}
@@ -0,0 +1,151 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Backend;
+
+// The unit of the CPU times in `/proc/<pid>/stat`.
+// This is fixed at 100 for the userspace facing interfaces on Linux.
+const USER_HZ: f64 = 100.0;
+
+const CGROUP_MACHINE_SLICE: &str = "/sys/fs/cgroup/machine.slice";
+
+/// The resources used by a machine over its lifetime
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ResourceUsage {
+    /// The time the machine was running
+    #[serde(default)]
+    pub wall_seconds: f64,
+    pub cpu_seconds: f64,
+    pub peak_rss_bytes: u64,
+    pub disk_read_bytes: u64,
+    pub disk_write_bytes: u64,
+}
+
+/// Where to read the resource usage of a running machine from
+pub(super) enum Source {
+    /// The machine is a single process, like qemu
+    Process(u32),
+    /// The machine runs in its own cgroup, like systemd-nspawn containers
+    Cgroup(PathBuf),
+}
+
+/// Escape a unit name component like `systemd-escape` does
+fn systemd_escape(name: &str) -> String {
+    let mut escaped = String::new();
+
+    for (i, c) in name.chars().enumerate() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | ':' | '_' => escaped.push(c),
+            '.' if i != 0 => escaped.push(c),
+            c => {
+                let mut buf = [0; 4];
+
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    escaped.push_str(&format!("\\x{b:02x}"));
+                }
+            }
+        }
+    }
+
+    escaped
+}
+
+/// Get the value of `key` from a file of `key value` lines, like `/proc/<pid>/io`
+fn field(content: &str, key: &str) -> Option<u64> {
+    content.lines().find_map(|line| {
+        let (k, v) = line.split_once([':', ' '])?;
+
+        (k == key).then(|| v.trim().trim_end_matches(" kB").parse().ok())?
+    })
+}
+
+impl Source {
+    /// Get the source for a machine of `backend` running as process `pid`
+    ///
+    /// Returns `None` for backends where the usage of the machine can not be
+    /// told apart from that of other processes, like the podman and kubectl
+    /// processes of the kata and kubernetes backends.
+    pub(super) fn new(backend: Backend, pid: u32, runner_name: &str) -> Option<Self> {
+        match backend {
+            Backend::Qemu => Some(Self::Process(pid)),
+            Backend::Nspawn => {
+                let scope = format!("machine-{}.scope", systemd_escape(runner_name));
+
+                Some(Self::Cgroup(Path::new(CGROUP_MACHINE_SLICE).join(scope)))
+            }
+            Backend::Kata | Backend::Kubernetes => None,
+        }
+    }
+
+    /// Get the resources used by the machine so far
+    ///
+    /// Values that can not be read (e.g. because the machine runs as another
+    /// user) are reported as zero.
+    /// Returns `None` once the machine is gone.
+    pub(super) fn sample(&self) -> Option<ResourceUsage> {
+        match self {
+            Self::Process(pid) => Self::sample_process(*pid),
+            Self::Cgroup(path) => Self::sample_cgroup(path),
+        }
+    }
+
+    fn sample_process(pid: u32) -> Option<ResourceUsage> {
+        let proc = PathBuf::from(format!("/proc/{pid}"));
+
+        let stat = std::fs::read_to_string(proc.join("stat")).ok()?;
+
+        // The process name may contain spaces, the fields after it do not.
+        // utime and stime are fields 14 and 15, i.e. 12 and 13 after the name.
+        let cpu_ticks: u64 = stat
+            .rsplit_once(')')
+            .map(|(_, rest)| {
+                rest.split_whitespace()
+                    .skip(11)
+                    .take(2)
+                    .filter_map(|ticks| ticks.parse::<u64>().ok())
+                    .sum()
+            })
+            .unwrap_or(0);
+
+        let status = std::fs::read_to_string(proc.join("status")).unwrap_or_default();
+        let io = std::fs::read_to_string(proc.join("io")).unwrap_or_default();
+
+        Some(ResourceUsage {
+            wall_seconds: 0.0,
+            cpu_seconds: cpu_ticks as f64 / USER_HZ,
+            peak_rss_bytes: field(&status, "VmHWM").unwrap_or(0) * 1024,
+            disk_read_bytes: field(&io, "read_bytes").unwrap_or(0),
+            disk_write_bytes: field(&io, "write_bytes").unwrap_or(0),
+        })
+    }
+
+    fn sample_cgroup(path: &Path) -> Option<ResourceUsage> {
+        let cpu_stat = std::fs::read_to_string(path.join("cpu.stat")).ok()?;
+
+        let peak_rss_bytes = std::fs::read_to_string(path.join("memory.peak"))
+            .ok()
+            .and_then(|peak| peak.trim().parse().ok())
+            .unwrap_or(0);
+
+        // Lines like "8:0 rbytes=1234 wbytes=5678 rios=1 wios=2 ..."
+        // with one line per block device.
+        let io_stat = std::fs::read_to_string(path.join("io.stat")).unwrap_or_default();
+
+        let io_sum = |key: &str| -> u64 {
+            io_stat
+                .split_whitespace()
+                .filter_map(|kv| kv.strip_prefix(key)?.strip_prefix('='))
+                .filter_map(|v| v.parse::<u64>().ok())
+                .sum()
+        };
+
+        Some(ResourceUsage {
+            wall_seconds: 0.0,
+            cpu_seconds: field(&cpu_stat, "usage_usec").unwrap_or(0) as f64 / 1_000_000.0,
+            peak_rss_bytes,
+            disk_read_bytes: io_sum("rbytes"),
+            disk_write_bytes: io_sum("wbytes"),
+        })
+    }
+}
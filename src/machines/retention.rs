@@ -0,0 +1,296 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+
+use crate::config::{ConfigFile, RetentionPolicy};
+use crate::jobs;
+
+/// An item in one of the stores that are subject to retention
+///
+/// This is either a file or directory or a single record in a file.
+struct Entry<T> {
+    item: T,
+    modified: SystemTime,
+    size: u64,
+}
+
+/// List the paths exactly `depth` levels below `dir`
+///
+/// E.g. a depth of two on `runs` lists `runs/<owner>/<repository>`.
+pub(super) fn walk(dir: &Path, depth: usize) -> Vec<PathBuf> {
+    if depth == 0 {
+        return vec![dir.to_owned()];
+    }
+
+    let read_dir = match std::fs::read_dir(dir) {
+        Ok(rd) => rd,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            error!("Failed to list {}: {e}", dir.display());
+            return Vec::new();
+        }
+    };
+
+    read_dir
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) || depth == 1)
+        .flat_map(|entry| walk(&entry.path(), depth - 1))
+        .collect()
+}
+
+/// Get the accumulated size and latest modification time of a file or directory tree
+fn measure(path: &Path) -> std::io::Result<(u64, SystemTime)> {
+    let meta = std::fs::symlink_metadata(path)?;
+
+    let mut size = meta.len();
+    let mut modified = meta.modified()?;
+
+    if meta.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            let (s, m) = measure(&entry?.path())?;
+
+            size += s;
+            modified = modified.max(m);
+        }
+    }
+
+    Ok((size, modified))
+}
+
+/// Select the entries that violate the retention policy
+///
+/// Entries are kept newest first until either one of them is older than
+/// `max_age` or the accumulated size exceeds `max_size`.
+fn expired<T>(mut entries: Vec<Entry<T>>, policy: &RetentionPolicy) -> Vec<Entry<T>> {
+    let now = SystemTime::now();
+
+    entries.sort_unstable_by_key(|e| std::cmp::Reverse(e.modified));
+
+    let mut total = 0;
+
+    entries
+        .into_iter()
+        .filter(|entry| {
+            total += entry.size;
+
+            let too_old = policy
+                .max_age
+                .zip(now.duration_since(entry.modified).ok())
+                .map(|(max_age, age)| age > max_age)
+                .unwrap_or(false);
+
+            let too_large = policy
+                .max_size
+                .map(|max_size| total > max_size.bytes())
+                .unwrap_or(false);
+
+            too_old || too_large
+        })
+        .collect()
+}
+
+fn remove(entry: &Entry<PathBuf>) {
+    let path = entry.item.display();
+
+    let res = match entry.item.is_dir() {
+        true => std::fs::remove_dir_all(&entry.item),
+        false => std::fs::remove_file(&entry.item),
+    };
+
+    match res {
+        Ok(()) => info!("Pruned {path} ({} bytes)", entry.size),
+        Err(e) => error!("Failed to prune {path}: {e}"),
+    }
+}
+
+/// Remove old run directories with the qemu logs of previous machines
+///
+/// The run directories of machines that are still around are never removed.
+fn prune_run_dirs(base_dir: &Path, policy: &RetentionPolicy, active: &HashSet<String>) {
+    // runs/<owner>/<repository>/<machine>/<runner name>
+    let entries = walk(&base_dir.join("runs"), 4)
+        .into_iter()
+        .filter(|path| {
+            let runner_name = path.file_name().unwrap().to_string_lossy();
+            !active.contains(runner_name.as_ref())
+        })
+        .filter_map(|path| {
+            let (size, modified) = measure(&path).ok()?;
+            Some(Entry {
+                item: path,
+                modified,
+                size,
+            })
+        })
+        .collect();
+
+    for entry in expired(entries, policy) {
+        remove(&entry);
+    }
+}
+
+/// Remove old machine images that were retained after failing to boot
+fn prune_broken_images(base_dir: &Path, policy: &RetentionPolicy) {
+    // machines/<owner>/<repository>/<machine>.img.broken
+    let entries = walk(&base_dir.join("machines"), 3)
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "broken"))
+        .filter_map(|path| {
+            let (size, modified) = measure(&path).ok()?;
+            Some(Entry {
+                item: path,
+                modified,
+                size,
+            })
+        })
+        .collect();
+
+    for entry in expired(entries, policy) {
+        remove(&entry);
+    }
+}
+
+/// Remove old console logs from the `host.console_log_dir`
+///
+/// The logs of machines that are still around are never removed.
+fn prune_console_logs(dir: &Path, policy: &RetentionPolicy, active: &HashSet<String>) {
+    // <console log dir>/<runner name>.log
+    let entries = walk(dir, 1)
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .filter(|path| {
+            let runner_name = path.file_stem().unwrap().to_string_lossy();
+            !active.contains(runner_name.as_ref())
+        })
+        .filter_map(|path| {
+            let (size, modified) = measure(&path).ok()?;
+            Some(Entry {
+                item: path,
+                modified,
+                size,
+            })
+        })
+        .collect();
+
+    for entry in expired(entries, policy) {
+        remove(&entry);
+    }
+}
+
+/// Drop old records from a file with one JSON record per line
+///
+/// Records are kept newest first until they exceed the `max_age` or `max_size`.
+/// The age of a record is taken from its `timestamp_key` field.
+fn compact_records(
+    path: &Path,
+    policy: &RetentionPolicy,
+    timestamp_key: &str,
+) -> std::io::Result<()> {
+    let content = std::fs::read_to_string(path)?;
+    let lines: Vec<&str> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+
+    let entries = lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| {
+            // Records without a readable timestamp are treated as brand new,
+            // so that they are only ever removed due to the size limit.
+            let modified = serde_json::from_str::<serde_json::Value>(line)
+                .ok()
+                .and_then(|r| {
+                    r.get(timestamp_key)?
+                        .as_str()?
+                        .parse::<DateTime<Utc>>()
+                        .ok()
+                })
+                .map(SystemTime::from)
+                .unwrap_or_else(SystemTime::now);
+
+            Entry {
+                item: index,
+                modified,
+                size: line.len() as u64 + 1,
+            }
+        })
+        .collect();
+
+    let expired: HashSet<usize> = expired(entries, policy)
+        .into_iter()
+        .map(|entry| entry.item)
+        .collect();
+
+    if expired.is_empty() {
+        return Ok(());
+    }
+
+    let mut compacted = String::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        if !expired.contains(&index) {
+            compacted.push_str(line);
+            compacted.push('\n');
+        }
+    }
+
+    // Write to a temporary file first and move it into place,
+    // so we never leave a half written file behind.
+    let tmp_path = path.with_extension("jsonl.tmp");
+    std::fs::write(&tmp_path, compacted)?;
+    std::fs::rename(&tmp_path, path)?;
+
+    info!(
+        "Compacted {} by removing {} records",
+        path.display(),
+        expired.len()
+    );
+
+    Ok(())
+}
+
+fn compact_calibration(base_dir: &Path, policy: &RetentionPolicy) {
+    // calibration/<owner>/<repository>/<machine>.jsonl
+    let files = walk(&base_dir.join("calibration"), 3)
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"));
+
+    for path in files {
+        if let Err(e) = compact_records(&path, policy, "timestamp") {
+            error!("Failed to compact {}: {e}", path.display());
+        }
+    }
+}
+
+/// Drop old records from the history of completed jobs
+fn compact_job_history(base_dir: &Path, policy: &RetentionPolicy) {
+    let path = jobs::history_path(base_dir);
+
+    match compact_records(&path, policy, "completed_at") {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => error!("Failed to compact {}: {e}", path.display()),
+    }
+}
+
+/// Apply the configured retention policies to the persistent stores in the base dir
+///
+/// `active` contains the runner names of all machines that are currently
+/// around and whose run directories must not be removed.
+pub(super) fn prune(cfg: &ConfigFile, active: &HashSet<String>) {
+    let base_dir = &cfg.host.base_dir;
+    let retention = &cfg.retention;
+
+    debug!("Applying retention policies to {}", base_dir.display());
+
+    prune_run_dirs(base_dir, &retention.run_dirs, active);
+    prune_broken_images(base_dir, &retention.broken_images);
+    compact_calibration(base_dir, &retention.calibration);
+    compact_job_history(base_dir, &retention.job_history);
+
+    if let Some(dir) = &cfg.host.console_log_dir {
+        prune_console_logs(dir, &retention.console_logs, active);
+    }
+}
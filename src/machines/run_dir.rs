@@ -1,28 +1,71 @@
 use std::fs::{create_dir_all, File};
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use log::{debug, error, info, warn};
 use reflink_copy::reflink;
+use serde::{Deserialize, Serialize};
 
-use crate::config::SeedBasePolicy;
+use crate::config::{ConfigFile, MachineConfig, SeedBasePolicy, SetupTemplate};
 
-use super::config_fs::ConfigFs;
+use super::adoption;
+use super::backend;
+use super::cloud_init;
+use super::config_fs::{self, ConfigFs};
 use super::machine::Machine;
 use super::manager::Machines;
+use super::retention::walk;
+use super::tenancy;
+use super::triplet::Triplet;
 
-const JOB_CONFIG_IMAGE_SIZE: u64 = 1_000_000;
+// Large enough for the SBOM an image building job may leave behind.
+// The image file is sparse, so unused space does not take up disk space.
+const JOB_CONFIG_IMAGE_SIZE: u64 = 32_000_000;
 const JOB_CONFIG_IMAGE_LABEL: &str = "JOBDATA";
 const CLOUD_INIT_IMAGE_SIZE: u64 = 1_000_000;
 const CLOUD_INIT_IMAGE_LABEL: &str = "CIDATA";
 
+// The SPDX document describing an image, as left by the job that built it.
+// It is kept next to the image and copied to the run directory of each
+// machine booted from it.
+pub(super) const SBOM_FILE: &str = "sbom.spdx.json";
+
+// Used to create qcow2 overlays on top of the source image.
+pub(super) const QEMU_IMG_CMD: &str = "/usr/bin/qemu-img";
+
+// The backends write the console output of the machine to this file.
+const CONSOLE_LOG: &str = "log.txt";
+
+/// The disk image a machine boots from
+struct Disk {
+    path: PathBuf,
+    /// Is the disk a qcow2 overlay in the `host.overlay_dir`?
+    overlay: bool,
+    source_image: PathBuf,
+    source_modified: SystemTime,
+}
+
+/// What is needed to re-open the run directory of a machine that is still running
+///
+/// This is part of the `adoption::Record` of the machine.
+#[derive(Serialize, Deserialize)]
+pub(super) struct RunDirState {
+    source_image: Option<PathBuf>,
+    source_modified: Option<SystemTime>,
+    scratch: Option<PathBuf>,
+    #[serde(default)]
+    overlay: Option<PathBuf>,
+}
+
 pub(super) struct RunDir {
     run_dir: PathBuf,
-    disk: PathBuf,
+    disk: Option<Disk>,
     machine_image: PathBuf,
     _cloud_init: ConfigFs,
     job_config: Option<ConfigFs>,
     persistence_token: Option<String>,
+    scratch: Option<PathBuf>,
 }
 
 fn not_found_none<V>(res: std::io::Result<V>) -> std::io::Result<Option<V>> {
@@ -49,6 +92,297 @@ fn pick_newer<'p>(a: &'p Path, b: &'p Path) -> std::io::Result<&'p Path> {
     }
 }
 
+/// The image a machine is based on if its own machine image is not used
+///
+/// Returns `None` if neither a `base_machine` nor a `base_image` is configured.
+fn base_image_path(machine_config: &MachineConfig, base_dir: &Path) -> Option<PathBuf> {
+    match &machine_config.base_machine {
+        Some(base_triplet) => Some(base_triplet.machine_image_path(base_dir)),
+        None => machine_config.base_image.clone(),
+    }
+}
+
+/// The path of the SBOM describing the disk image at `image_path`
+///
+/// E.g. `machines/<owner>/<repository>/<machine>.sbom.spdx.json`
+/// for a machine image.
+fn sbom_path(image_path: &Path) -> PathBuf {
+    image_path.with_extension(SBOM_FILE)
+}
+
+/// Get the SBOM of the image the machine `runner_name` was booted from
+///
+/// The SBOM is looked up in the run directory of the machine, so it is
+/// available for as long as the run directory is kept.
+/// Returns `None` if there is no such machine or its image had no SBOM.
+pub fn job_sbom(cfg: &ConfigFile, runner_name: &str) -> Option<String> {
+    // Runner names end up in paths, make sure they can not escape the runs dir.
+    if runner_name.is_empty() || runner_name.starts_with('.') || runner_name.contains('/') {
+        return None;
+    }
+
+    walk(&cfg.host.base_dir.join("runs"), 3)
+        .into_iter()
+        .find_map(|dir| std::fs::read_to_string(dir.join(runner_name).join(SBOM_FILE)).ok())
+}
+
+/// The token a job has to leave behind to persist the disk image of a `triplet` machine
+fn persistence_token(cfg: &ConfigFile, triplet: &Triplet) -> Option<String> {
+    cfg.repositories
+        .get(triplet.owner())
+        .and_then(|repos| repos.get(triplet.repository()))
+        .and_then(|repo| repo.persistence_token.clone())
+}
+
+/// Pick the image to boot from based on the `use_base` policy
+fn pick_image<'p>(
+    policy: SeedBasePolicy,
+    base_image: &'p Path,
+    machine_image: &'p Path,
+) -> std::io::Result<&'p Path> {
+    match policy {
+        SeedBasePolicy::IfNewer => pick_newer(base_image, machine_image),
+        SeedBasePolicy::Always => Ok(base_image),
+        SeedBasePolicy::Never => Ok(machine_image),
+    }
+}
+
+/// Pick the image to copy the disk image of a machine run from
+///
+/// Returns Ok(None) if the startup of the machine has to be delayed,
+/// because the image is not present (yet) or is being updated.
+fn pick_source(
+    machine: &str,
+    machine_config: &MachineConfig,
+    base_dir: &Path,
+    machine_image: &Path,
+    machines: &Machines,
+) -> std::io::Result<Option<PathBuf>> {
+    if let Some(base_triplet) = &machine_config.base_machine {
+        if machines.contains_key(base_triplet) {
+            info!("Delaying the startup of {machine} because its base {base_triplet} is currently running");
+            return Ok(None);
+        }
+    }
+
+    let base_image = base_image_path(machine_config, base_dir).unwrap_or_else(|| {
+        warn!("Neither `base_machine` nor `base_image` configured for {machine}.");
+        warn!("Falling back to machine image");
+        machine_image.to_owned()
+    });
+
+    let image = pick_image(machine_config.use_base, &base_image, machine_image)?;
+
+    if !image.try_exists()? {
+        info!(
+            "Delaying the startup of {machine} because the image {} does not exist (yet)",
+            image.display()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(image.to_owned()))
+}
+
+/// The file the console output of the machine `runner_name` ends up in
+///
+/// This is the `log.txt` in its run directory, unless a `host.console_log_dir`
+/// is configured, which the `log.txt` then links to.
+pub(super) fn console_log_path(cfg: &ConfigFile, triplet: &Triplet, runner_name: &str) -> PathBuf {
+    match &cfg.host.console_log_dir {
+        Some(dir) => dir.join(format!("{runner_name}.log")),
+        None => triplet
+            .run_dir_path(&cfg.host.base_dir, runner_name)
+            .join(CONSOLE_LOG),
+    }
+}
+
+/// The path of the scratch disk of the machine `runner_name`, if its `machine_config` asks for one
+pub(super) fn scratch_path(
+    cfg: &ConfigFile,
+    machine_config: &MachineConfig,
+    triplet: &Triplet,
+    runner_name: &str,
+) -> std::io::Result<Option<PathBuf>> {
+    let scratch = match &machine_config.scratch {
+        Some(scratch) => scratch,
+        None => return Ok(None),
+    };
+
+    let pool = cfg.host.scratch.get(&scratch.pool).ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("Unknown scratch pool \"{}\"", scratch.pool),
+        )
+    })?;
+
+    // Keep the scratch disks of different tenants apart.
+    let scratch_dir = match cfg.tenancy.strict {
+        true => pool.path.join(triplet.owner()),
+        false => pool.path.clone(),
+    };
+
+    Ok(Some(scratch_dir.join(format!("{runner_name}.img"))))
+}
+
+/// The placeholders to replace in the files of the setup `template`
+fn substitutions<'a>(
+    triplet: &'a Triplet,
+    runner_name: &'a str,
+    template: &'a SetupTemplate,
+    encoded_jit_config: &'a str,
+) -> Vec<(&'a str, &'a str)> {
+    let mut sub = vec![
+        ("REPO_OWNER", triplet.owner()),
+        ("REPO_NAME", triplet.repository()),
+        ("MACHINE_NAME", triplet.machine_name()),
+        ("RUNNER_NAME", runner_name),
+        ("JITCONFIG", encoded_jit_config),
+    ];
+
+    let parameters = template
+        .parameters
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()));
+
+    sub.extend(parameters);
+
+    sub
+}
+
+/// Render the files of the `name` sub-directory of the setup template of a machine
+///
+/// The `cloud-init` files are completed by the provisioning logic in `cloud_init`,
+/// e.g. with a `meta-data` file naming the machine after `runner_name`.
+fn render_seed(
+    machine_config: &MachineConfig,
+    runner_name: &str,
+    substitutions: &[(&str, &str)],
+    name: &str,
+) -> std::io::Result<Vec<(String, String)>> {
+    let files = config_fs::render(machine_config.setup_template.path.join(name), substitutions)?;
+
+    match name {
+        "cloud-init" => cloud_init::seed(machine_config, runner_name, files, substitutions),
+        _ => Ok(files),
+    }
+}
+
+/// The names and contents of the files per seed image
+type SeedFiles = Vec<(&'static str, Vec<(String, String)>)>;
+
+/// Render the files `RunDir::new()` places in the `cloud-init` and `job-config`
+/// images (or directories) of a machine of type `triplet`
+///
+/// Returns the file names and contents per image.
+pub(super) fn seed_files(
+    machine_config: &MachineConfig,
+    triplet: &Triplet,
+    runner_name: &str,
+    encoded_jit_config: &str,
+) -> std::io::Result<SeedFiles> {
+    let template = &machine_config.setup_template;
+    let substitutions = substitutions(triplet, runner_name, template, encoded_jit_config);
+
+    ["cloud-init", "job-config"]
+        .into_iter()
+        .map(|name| {
+            let files = render_seed(machine_config, runner_name, &substitutions, name)?;
+            Ok((name, files))
+        })
+        .collect()
+}
+
+/// Create a qcow2 image at `path` that stores the changes to the read-only `source_image`
+///
+/// The overlay is at least `size` bytes large.
+fn create_overlay(path: &Path, source_image: &Path, size: u64) -> std::io::Result<()> {
+    // The overlay refers to its backing file by path.
+    let source_image = source_image.canonicalize()?;
+    let source_size = source_image.metadata()?.len();
+
+    let mut qemu_img = std::process::Command::new(QEMU_IMG_CMD);
+
+    qemu_img
+        .args(["create", "-q", "-f", "qcow2", "-F", "raw", "-b"])
+        .arg(&source_image)
+        .arg(path);
+
+    // qemu-img refuses to create overlays smaller than their backing file.
+    if size > source_size {
+        qemu_img.arg(size.to_string());
+    }
+
+    let output = qemu_img.output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(format!(
+            "Failed to create overlay {}: {}",
+            path.display(),
+            stderr.trim()
+        )));
+    }
+
+    Ok(())
+}
+
+impl Disk {
+    /// Create the `disk.img` in `run_dir` as copy of `source_image`
+    ///
+    /// If an `overlay` path is given a qcow2 overlay on top of the
+    /// `source_image` is created there instead and linked to as
+    /// `disk.qcow2` from the `run_dir`.
+    fn new(
+        run_dir: &Path,
+        source_image: PathBuf,
+        machine_config: &MachineConfig,
+        overlay: Option<PathBuf>,
+    ) -> std::io::Result<Self> {
+        // Remember the state of the image we boot from,
+        // so we can tell if a newer one becomes available.
+        let source_modified = source_image.metadata()?.modified()?;
+
+        if let Some(overlay) = overlay {
+            create_dir_all(overlay.parent().unwrap())?;
+            create_overlay(&overlay, &source_image, machine_config.disk.bytes())?;
+
+            if let Err(e) = std::os::unix::fs::symlink(&overlay, run_dir.join("disk.qcow2")) {
+                let _ = std::fs::remove_file(&overlay);
+                return Err(e);
+            }
+
+            return Ok(Self {
+                path: overlay,
+                overlay: true,
+                source_image,
+                source_modified,
+            });
+        }
+
+        let path = run_dir.join("disk.img");
+
+        // Create a copy on write copy of the disk image using reflink
+        reflink(&source_image, &path)?;
+
+        // Grow the disk image if required
+        let target_disk_size = machine_config.disk.bytes();
+        let current_disk_size = path.metadata()?.len();
+
+        if current_disk_size < target_disk_size {
+            let disk_file = File::options().append(true).open(&path)?;
+            disk_file.set_len(target_disk_size)?;
+        }
+
+        Ok(Self {
+            path,
+            overlay: false,
+            source_image,
+            source_modified,
+        })
+    }
+}
+
 impl RunDir {
     /// Create a directory for a machine run and populate it to match our qemu arguments
     ///
@@ -67,130 +401,301 @@ impl RunDir {
         machines: &Machines,
         encoded_jit_config: String,
     ) -> std::io::Result<Option<Self>> {
-        let triplet = machine.triplet();
-        let cfg = machine.cfg();
-        let machine_config = machine.machine_config();
+        Self::with_job_template(
+            machine.cfg(),
+            machine.config_triplet(),
+            machine.runner_name(),
+            machines,
+            encoded_jit_config,
+            "job-config",
+        )
+    }
+
+    /// Create a run directory like `new()`, but populate the job config image
+    /// from the `job_template` sub-directory of the setup template
+    ///
+    /// This is used to run something other than the action runner in a machine,
+    /// e.g. the benchmarks for a calibration run.
+    pub(super) fn with_job_template(
+        cfg: &ConfigFile,
+        triplet: &Triplet,
+        runner_name: &str,
+        machines: &Machines,
+        encoded_jit_config: String,
+        job_template: &str,
+    ) -> std::io::Result<Option<Self>> {
+        let machine = format!("{triplet} {runner_name}");
+        let machine_config = cfg.machine_config(triplet).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("Unknown machine triplet {triplet}"),
+            )
+        })?;
 
         let base_dir = &cfg.host.base_dir;
 
         let machine_image = triplet.machine_image_path(base_dir);
 
-        let base_image = match &machine_config.base_machine {
-            Some(base_triplet) if machines.contains_key(base_triplet) => {
-                info!("Delaying the startup of {machine} because its base {base_triplet} is currently running");
-                return Ok(None);
-            }
-            Some(base_triplet) => base_triplet.machine_image_path(base_dir),
-            None => match &machine_config.base_image {
-                Some(base_image) => base_image.clone(),
-                None => {
-                    warn!("Neither `base_machine` nor `base_image` configured for {machine}.");
-                    warn!("Falling back to machine image");
-                    machine_image.clone()
+        // Backends like kata run container images instead of disk images
+        // and do not need one in the run directory.
+        let source_image = match backend::uses_disk_image(machine_config.backend) {
+            true => {
+                match pick_source(&machine, machine_config, base_dir, &machine_image, machines)? {
+                    Some(image) => Some(image),
+                    None => return Ok(None),
                 }
-            },
-        };
-
-        let image = match machine_config.use_base {
-            SeedBasePolicy::IfNewer => pick_newer(&base_image, &machine_image)?,
-            SeedBasePolicy::Always => &base_image,
-            SeedBasePolicy::Never => &machine_image,
+            }
+            false => None,
         };
 
-        if !image.try_exists()? {
-            info!(
-                "Delaying the startup of {machine} because the image {} does not exist (yet)",
-                image.display()
-            );
-            return Ok(None);
-        }
-
-        let persistence_token = cfg
-            .repositories
-            .get(triplet.owner())
-            .and_then(|repos| repos.get(triplet.repository()))
-            .and_then(|repo| repo.persistence_token.clone());
+        let persistence_token = persistence_token(cfg, triplet);
 
-        let run_dir = triplet.run_dir_path(&cfg.host.base_dir, machine.runner_name());
+        let run_dir = triplet.run_dir_path(&cfg.host.base_dir, runner_name);
 
         create_dir_all(&run_dir)?;
 
-        let disk = run_dir.join("disk.img");
+        // The backends write to the `log.txt` in the run directory as usual,
+        // which leads to the file in the console log dir.
+        let console_log = console_log_path(cfg, triplet, runner_name);
 
-        // Create a copy on write copy of the disk image using reflink
-        reflink(image, &disk)?;
-
-        // Grow the disk image if required
-        let target_disk_size = machine_config.disk.bytes();
-        let current_disk_size = disk.metadata()?.len();
+        if let Some(dir) = &cfg.host.console_log_dir {
+            create_dir_all(dir)?;
+            File::create(&console_log)?;
+            std::os::unix::fs::symlink(&console_log, run_dir.join(CONSOLE_LOG))?;
+        }
 
-        if current_disk_size < target_disk_size {
-            let disk_file = File::options().append(true).open(&disk)?;
-            disk_file.set_len(target_disk_size)?;
+        // Overlays can not be persisted as new image,
+        // so machines that may persist their disk keep using copies.
+        let overlay = cfg
+            .host
+            .overlay_dir
+            .as_ref()
+            .filter(|_| backend::can_boot_overlay(machine_config.backend))
+            .filter(|_| persistence_token.is_none())
+            .map(|dir| dir.join(format!("{runner_name}.qcow2")));
+
+        let disk = source_image
+            .map(|source_image| Disk::new(&run_dir, source_image, machine_config, overlay))
+            .transpose()?;
+
+        // Record which SBOM applies to this run, should the image change
+        // while or after the machine runs.
+        if let Some(disk) = &disk {
+            match std::fs::copy(sbom_path(&disk.source_image), run_dir.join(SBOM_FILE)) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to copy the SBOM of {machine}: {e}"),
+            }
         }
 
         let template = &machine_config.setup_template;
+        let substitutions = substitutions(triplet, runner_name, template, &encoded_jit_config);
 
-        let substitutions = {
-            let mut sub = vec![
-                ("REPO_OWNER", triplet.owner()),
-                ("REPO_NAME", triplet.repository()),
-                ("MACHINE_NAME", triplet.machine_name()),
-                ("JITCONFIG", encoded_jit_config.as_str()),
-            ];
-
-            let parameters = template
-                .parameters
-                .iter()
-                .map(|(k, v)| (k.as_str(), v.as_str()));
-
-            sub.extend(parameters);
-
-            sub
-        };
+        // Containers get the configuration bind mounted as directories
+        // instead of attached as disk images.
+        let config_dirs = backend::uses_config_dirs(machine_config.backend);
 
         let _cloud_init = {
-            let cloud_init_path = run_dir.join("cloud-init.img");
-            let cloud_init_template_path = template.path.join("cloud-init");
-
-            ConfigFs::new(
-                cloud_init_path,
-                CLOUD_INIT_IMAGE_SIZE,
-                CLOUD_INIT_IMAGE_LABEL,
-                cloud_init_template_path,
-                &substitutions,
-            )?
+            let files = render_seed(machine_config, runner_name, &substitutions, "cloud-init")?;
+
+            match config_dirs {
+                true => ConfigFs::new_dir(run_dir.join("cloud-init"), files)?,
+                false => ConfigFs::new(
+                    run_dir.join("cloud-init.img"),
+                    CLOUD_INIT_IMAGE_SIZE,
+                    CLOUD_INIT_IMAGE_LABEL,
+                    files,
+                )?,
+            }
         };
 
         let job_config = {
-            let job_config_path = run_dir.join("job-config.img");
-            let job_config_template_path = template.path.join("job-config");
-
-            ConfigFs::new(
-                job_config_path,
-                JOB_CONFIG_IMAGE_SIZE,
-                JOB_CONFIG_IMAGE_LABEL,
-                job_config_template_path,
-                &substitutions,
-            )?
+            let files = render_seed(machine_config, runner_name, &substitutions, job_template)?;
+
+            match config_dirs {
+                true => ConfigFs::new_dir(run_dir.join("job-config"), files)?,
+                false => ConfigFs::new(
+                    run_dir.join("job-config.img"),
+                    JOB_CONFIG_IMAGE_SIZE,
+                    JOB_CONFIG_IMAGE_LABEL,
+                    files,
+                )?,
+            }
         };
 
+        // Create an empty scratch disk in the configured pool if requested.
+        // This allows placing e.g. the runner work folder on a dedicated
+        // (fast) filesystem instead of the one the disk images live on.
+        let scratch = scratch_path(cfg, machine_config, triplet, runner_name)?;
+
+        if let (Some(scratch_path), Some(scratch)) = (&scratch, &machine_config.scratch) {
+            create_dir_all(scratch_path.parent().unwrap())?;
+
+            let scratch_file = File::create_new(scratch_path)?;
+            scratch_file.set_len(scratch.size.bytes())?;
+        }
+
+        // Hand the files qemu needs to access over to the tenant's user,
+        // if qemu is run as a different user.
+        let tenant = tenancy::check(cfg, triplet)
+            .map_err(|e| std::io::Error::new(ErrorKind::PermissionDenied, e))?;
+
+        if let Some(user) = tenancy::user(tenant)? {
+            tenancy::hand_over(&run_dir, &user)?;
+
+            if cfg.host.console_log_dir.is_some() {
+                tenancy::hand_over(&console_log, &user)?;
+            }
+
+            if let Some(disk) = &disk {
+                tenancy::hand_over(&disk.path, &user)?;
+            }
+
+            if !config_dirs {
+                tenancy::hand_over(&run_dir.join("cloud-init.img"), &user)?;
+                tenancy::hand_over(&run_dir.join("job-config.img"), &user)?;
+            }
+
+            if let Some(scratch) = &scratch {
+                tenancy::hand_over(scratch, &user)?;
+            }
+        }
+
         let dir = Self {
             run_dir,
-            machine_image,
             disk,
+            machine_image,
             _cloud_init,
             job_config: Some(job_config),
             persistence_token,
+            scratch,
         };
 
         Ok(Some(dir))
     }
 
+    /// Re-open the run directory of a machine a previous instance left running
+    ///
+    /// The files in it are owned by the returned value from now on,
+    /// i.e. they are removed once it is dropped.
+    pub(super) fn reopen(
+        cfg: &ConfigFile,
+        triplet: &Triplet,
+        runner_name: &str,
+        state: RunDirState,
+    ) -> Self {
+        let run_dir = triplet.run_dir_path(&cfg.host.base_dir, runner_name);
+
+        let disk =
+            state
+                .source_image
+                .zip(state.source_modified)
+                .map(|(source_image, source_modified)| Disk {
+                    path: state
+                        .overlay
+                        .clone()
+                        .unwrap_or_else(|| run_dir.join("disk.img")),
+                    overlay: state.overlay.is_some(),
+                    source_image,
+                    source_modified,
+                });
+
+        let config_dirs = cfg
+            .machine_config(triplet)
+            .is_some_and(|mc| backend::uses_config_dirs(mc.backend));
+
+        let (_cloud_init, job_config) = match config_dirs {
+            true => (
+                ConfigFs::existing(run_dir.join("cloud-init"), true),
+                ConfigFs::existing(run_dir.join("job-config"), true),
+            ),
+            false => (
+                ConfigFs::existing(run_dir.join("cloud-init.img"), false),
+                ConfigFs::existing(run_dir.join("job-config.img"), false),
+            ),
+        };
+
+        Self {
+            machine_image: triplet.machine_image_path(&cfg.host.base_dir),
+            persistence_token: persistence_token(cfg, triplet),
+            run_dir,
+            disk,
+            _cloud_init,
+            job_config: Some(job_config),
+            scratch: state.scratch,
+        }
+    }
+
+    /// Get what is needed to `reopen()` this run directory later on
+    pub(super) fn state(&self) -> RunDirState {
+        RunDirState {
+            source_image: self.disk.as_ref().map(|disk| disk.source_image.clone()),
+            source_modified: self.disk.as_ref().map(|disk| disk.source_modified),
+            scratch: self.scratch.clone(),
+            overlay: self
+                .disk
+                .as_ref()
+                .filter(|disk| disk.overlay)
+                .map(|disk| disk.path.clone()),
+        }
+    }
+
     pub(super) fn path(&self) -> &Path {
         &self.run_dir
     }
 
+    /// The image the disk image of this run was copied from
+    ///
+    /// Returns `None` if the backend does not use disk images.
+    pub(super) fn source_image(&self) -> Option<&Path> {
+        self.disk.as_ref().map(|disk| disk.source_image.as_path())
+    }
+
+    /// Would a machine started right now boot from a newer image than this one?
+    ///
+    /// This is the case if e.g. a job has persisted a new machine image or
+    /// the base image was updated since this run dir was created.
+    pub(super) fn is_outdated(&self, cfg: &ConfigFile, triplet: &Triplet) -> std::io::Result<bool> {
+        let machine_config = match cfg.machine_config(triplet) {
+            Some(mc) => mc,
+            None => return Ok(false),
+        };
+
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => return Ok(false),
+        };
+
+        let base_image = base_image_path(machine_config, &cfg.host.base_dir)
+            .unwrap_or_else(|| self.machine_image.clone());
+
+        let image = pick_image(machine_config.use_base, &base_image, &self.machine_image)?;
+
+        let modified = match not_found_none(image.metadata().and_then(|m| m.modified()))? {
+            Some(modified) => modified,
+            None => return Ok(false),
+        };
+
+        Ok(image != disk.source_image || modified > disk.source_modified)
+    }
+
+    /// The path to the scratch disk image, if one was requested
+    pub(super) fn scratch(&self) -> Option<&Path> {
+        self.scratch.as_deref()
+    }
+
+    /// Read a file the job left in the job config image
+    ///
+    /// Like `maybe_persist()` this consumes the job config image,
+    /// so only one of them may be called.
+    pub(super) fn read_job_file(&mut self, name: &str) -> std::io::Result<String> {
+        self.job_config
+            .take()
+            .unwrap()
+            .inspect()?
+            .read_to_string(name)
+    }
+
     /// Persist the disk image as new machine image if the correct persist file was written
     pub(super) fn maybe_persist(&mut self) {
         let persistence_token = match &self.persistence_token {
@@ -198,7 +703,12 @@ impl RunDir {
             None => return,
         };
 
-        let dds = self.disk.display();
+        let disk = match &self.disk {
+            Some(disk) => &disk.path,
+            None => return,
+        };
+
+        let dds = disk.display();
         let mds = self.machine_image.display();
 
         let inspector = match self.job_config.take().unwrap().inspect() {
@@ -242,12 +752,29 @@ impl RunDir {
             return;
         }
 
-        if let Err(err) = std::fs::rename(&self.disk, &self.machine_image) {
+        if let Err(err) = std::fs::rename(disk, &self.machine_image) {
             error!("Failed to move image from {dds} to {mds}: {err}");
             return;
         }
 
         info!("Persisted disk file {dds} as {mds}");
+
+        // Keep the SBOM of the new image version, if the job generated one.
+        // An SBOM of a previous version must not be attributed to this one.
+        let sbom = sbom_path(&self.machine_image);
+
+        let res = match inspector.read_to_string(SBOM_FILE) {
+            Ok(content) => std::fs::write(&sbom, content),
+            Err(err) if err.kind() == ErrorKind::NotFound => match std::fs::remove_file(&sbom) {
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+                res => res,
+            },
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = res {
+            error!("Failed to update the SBOM {}: {err}", sbom.display());
+        }
     }
 }
 
@@ -259,7 +786,11 @@ impl Drop for RunDir {
         // as well as the run dir itself, because they take up little space and
         // may be useful for debugging failed jobs and machines.
 
-        let disk = self.run_dir.join("disk.img");
+        let disk = match &self.disk {
+            Some(disk) => disk.path.clone(),
+            None => self.run_dir.join("disk.img"),
+        };
+
         let ds = disk.display();
 
         match std::fs::remove_file(&disk) {
@@ -269,5 +800,23 @@ impl Drop for RunDir {
             }
             Err(e) => error!("Failed to remove disk image {ds}: {e}"),
         }
+
+        // Overlays live outside of the run dir, do not leave a dangling link behind.
+        if self.disk.as_ref().is_some_and(|disk| disk.overlay) {
+            let _ = std::fs::remove_file(self.run_dir.join("disk.qcow2"));
+        }
+
+        // The machine is gone, there is nothing left to adopt.
+        adoption::Record::remove(&self.run_dir);
+
+        // The scratch disk does not live in the run dir and is never persisted.
+        if let Some(scratch) = &self.scratch {
+            let ss = scratch.display();
+
+            match std::fs::remove_file(scratch) {
+                Ok(()) => debug!("Removed scratch file {ss}"),
+                Err(e) => error!("Failed to remove scratch image {ss}: {e}"),
+            }
+        }
     }
 }
@@ -0,0 +1,435 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::triplet::Triplet;
+use crate::config::ConfigFile;
+
+// The boot time assumed for machine types without recorded waits,
+// which would tell us how long booting takes.
+const DEFAULT_BOOT_SECONDS: f64 = 60.0;
+
+/// The parts of a job history record the simulation is based on
+#[derive(Deserialize)]
+struct Record {
+    triplet: String,
+    queued_at: DateTime<Utc>,
+    wait_seconds: Option<i64>,
+    run_seconds: Option<i64>,
+}
+
+/// A job to replay, with times in seconds since the first job was queued
+struct Job {
+    machine: usize,
+    queued: f64,
+    run: f64,
+}
+
+/// A machine type of the candidate config and the waits of its jobs
+struct MachineType {
+    triplet: Triplet,
+    ram: u64,
+    cpus: u32,
+    standby: u64,
+    boot: f64,
+    recorded_waits: Vec<f64>,
+    waits: Vec<f64>,
+}
+
+/// A simulated machine, which runs a single job or waits for one
+struct Machine {
+    machine: usize,
+    /// When the machine has booted
+    ready: f64,
+    /// When the job of the machine completes, if it got one
+    done: Option<f64>,
+}
+
+/// The simulated queue times of the jobs of a machine type
+#[derive(Serialize)]
+pub struct MachineSimulation {
+    pub machine: String,
+    pub jobs: usize,
+    /// Jobs that never fit on the host
+    pub never_started: usize,
+    /// The boot time assumed, the shortest recorded wait of the machine type
+    pub boot_seconds: f64,
+    /// The average recorded queue time, for comparison
+    pub recorded_mean_wait_seconds: f64,
+    pub mean_wait_seconds: f64,
+    pub p95_wait_seconds: f64,
+    pub max_wait_seconds: f64,
+}
+
+/// The expected queue times and host utilization with a candidate config
+#[derive(Serialize)]
+pub struct Simulation {
+    pub jobs: usize,
+    /// Records that could not be replayed, e.g. because their machine type
+    /// is not in the config or the job never ran
+    pub skipped: usize,
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// The average share of the `host.ram` used by machines
+    pub ram_utilization: f64,
+    /// The average share of the `host.cpus` used by machines, if they are limited
+    pub cpu_utilization: Option<f64>,
+    pub peak_machines: usize,
+    pub machines: Vec<MachineSimulation>,
+}
+
+impl std::fmt::Display for Simulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (from, until) = match self.from.zip(self.until) {
+            Some(period) => period,
+            None => return writeln!(f, "No jobs to replay ({} skipped)", self.skipped),
+        };
+
+        writeln!(
+            f,
+            "Replayed {} jobs queued from {from} until {until} ({} skipped).",
+            self.jobs, self.skipped
+        )?;
+
+        write!(f, "{:.0}% RAM", self.ram_utilization * 100.0)?;
+
+        if let Some(cpu_utilization) = self.cpu_utilization {
+            write!(f, ", {:.0}% CPU", cpu_utilization * 100.0)?;
+        }
+
+        writeln!(
+            f,
+            " used on average, up to {} machines at once.",
+            self.peak_machines
+        )?;
+
+        for machine in &self.machines {
+            write!(
+                f,
+                "{}: {} jobs, waiting {:.0}s on average (recorded: {:.0}s), {:.0}s at p95, {:.0}s at most.",
+                machine.machine,
+                machine.jobs,
+                machine.mean_wait_seconds,
+                machine.recorded_mean_wait_seconds,
+                machine.p95_wait_seconds,
+                machine.max_wait_seconds
+            )?;
+
+            if machine.never_started > 0 {
+                write!(f, " {} jobs never fit on the host.", machine.never_started)?;
+            }
+
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    match values.is_empty() {
+        true => 0.0,
+        false => values.iter().sum::<f64>() / values.len() as f64,
+    }
+}
+
+/// The value below which `share` of the sorted `values` are
+fn percentile(sorted: &[f64], share: f64) -> f64 {
+    match sorted.is_empty() {
+        true => 0.0,
+        false => sorted[((sorted.len() - 1) as f64 * share).round() as usize],
+    }
+}
+
+/// The host resources in use and the ones available
+struct Host {
+    ram: u64,
+    cpus: Option<u32>,
+    ram_used: u64,
+    cpus_used: u32,
+}
+
+impl Host {
+    fn fits(&self, machine_type: &MachineType) -> bool {
+        let ram_fits = self.ram_used + machine_type.ram <= self.ram;
+        let cpus_fit = match self.cpus {
+            Some(cpus) => self.cpus_used + machine_type.cpus <= cpus,
+            None => true,
+        };
+
+        ram_fits && cpus_fit
+    }
+
+    fn take(&mut self, machine_type: &MachineType) {
+        self.ram_used += machine_type.ram;
+        self.cpus_used += machine_type.cpus;
+    }
+
+    fn give_back(&mut self, machine_type: &MachineType) {
+        self.ram_used -= machine_type.ram;
+        self.cpus_used -= machine_type.cpus;
+    }
+}
+
+/// The machine types, the queued jobs, the start and the skipped records
+/// of a job history
+type History = (Vec<MachineType>, Vec<Job>, Option<DateTime<Utc>>, usize);
+
+/// Read the job history at `history` and group it by the machine types in `cfg`
+///
+/// Returns the machine types, the jobs sorted by when they were queued,
+/// when the first one was queued and how many records were skipped.
+fn load(cfg: &ConfigFile, history: &Path) -> std::io::Result<History> {
+    let content = std::fs::read_to_string(history)?;
+
+    let mut records = Vec::new();
+    let mut skipped = 0;
+
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        // Jobs that were given up on never ran and are skipped as well.
+        match serde_json::from_str::<Record>(line) {
+            Ok(record) if record.run_seconds.is_some() => records.push(record),
+            _ => skipped += 1,
+        }
+    }
+
+    records.sort_by_key(|record| record.queued_at);
+
+    let start = records.first().map(|record| record.queued_at);
+
+    let mut machine_types: Vec<MachineType> = Vec::new();
+    let mut jobs = Vec::new();
+
+    for record in records {
+        let triplet: Triplet = match record.triplet.parse() {
+            Ok(triplet) => triplet,
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let machine_config = match cfg.machine_config(&triplet) {
+            Some(mc) => mc,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let machine = match machine_types.iter().position(|mt| mt.triplet == triplet) {
+            Some(index) => index,
+            None => {
+                machine_types.push(MachineType {
+                    triplet,
+                    ram: machine_config.ram.bytes(),
+                    cpus: machine_config.cpus,
+                    standby: machine_config.standby,
+                    boot: DEFAULT_BOOT_SECONDS,
+                    recorded_waits: Vec::new(),
+                    waits: Vec::new(),
+                });
+
+                machine_types.len() - 1
+            }
+        };
+
+        if let Some(wait) = record.wait_seconds {
+            machine_types[machine]
+                .recorded_waits
+                .push(wait.max(0) as f64);
+        }
+
+        let queued = (record.queued_at - start.unwrap()).num_milliseconds() as f64 / 1000.0;
+
+        jobs.push(Job {
+            machine,
+            queued,
+            run: record.run_seconds.unwrap_or(0).max(0) as f64,
+        });
+    }
+
+    // A job that did not have to wait for other jobs only waited for
+    // its machine to boot, so the shortest wait is a good estimate.
+    for machine_type in &mut machine_types {
+        if let Some(boot) = machine_type.recorded_waits.iter().copied().reduce(f64::min) {
+            machine_type.boot = boot;
+        }
+    }
+
+    Ok((machine_types, jobs, start, skipped))
+}
+
+/// Replay the job history at `history` against the machine types and host resources of `cfg`
+///
+/// Each job gets a fresh machine of its type, which is started as soon as it
+/// fits into the `host.ram` and `host.cpus`, in the order the jobs were queued.
+/// Jobs may skip ahead of jobs whose machines do not fit yet.
+/// `standby` machines are kept booted on top of that.
+/// The jobs are assumed to run as long as they did when they were recorded,
+/// regardless of the size of their machines.
+/// Priorities, budgets, limits and pins are not taken into account.
+pub fn simulate(cfg: &ConfigFile, history: &Path) -> std::io::Result<Simulation> {
+    let (mut machine_types, jobs, start, skipped) = load(cfg, history)?;
+
+    let mut host = Host {
+        ram: cfg.host.ram.bytes(),
+        cpus: cfg.host.cpus,
+        ram_used: 0,
+        cpus_used: 0,
+    };
+
+    let mut arrivals: VecDeque<&Job> = jobs.iter().collect();
+    let mut queue: Vec<&Job> = Vec::new();
+    let mut machines: Vec<Machine> = Vec::new();
+
+    let mut now = 0.0;
+    let mut ram_seconds = 0.0;
+    let mut cpu_seconds = 0.0;
+    let mut peak_machines = 0;
+
+    loop {
+        // Machines stop once their job is done.
+        machines.retain(|machine| match machine.done {
+            Some(done) if done <= now => {
+                host.give_back(&machine_types[machine.machine]);
+                false
+            }
+            _ => true,
+        });
+
+        while arrivals.front().is_some_and(|job| job.queued <= now) {
+            queue.push(arrivals.pop_front().unwrap());
+        }
+
+        queue.retain(|job| {
+            let machine_type = &mut machine_types[job.machine];
+
+            // Prefer standby machines, even ones that are still booting.
+            let standby = machines
+                .iter_mut()
+                .filter(|machine| machine.machine == job.machine && machine.done.is_none())
+                .min_by(|a, b| a.ready.total_cmp(&b.ready));
+
+            let started = match standby {
+                Some(machine) => {
+                    let started = machine.ready.max(now);
+                    machine.done = Some(started + job.run);
+                    started
+                }
+                None if host.fits(machine_type) => {
+                    host.take(machine_type);
+
+                    let started = now + machine_type.boot;
+
+                    machines.push(Machine {
+                        machine: job.machine,
+                        ready: started,
+                        done: Some(started + job.run),
+                    });
+
+                    started
+                }
+                None => return true,
+            };
+
+            machine_type.waits.push(started - job.queued);
+
+            false
+        });
+
+        // Replace the standby machines that picked up jobs.
+        for (index, machine_type) in machine_types.iter().enumerate() {
+            let mut idle = machines
+                .iter()
+                .filter(|machine| machine.machine == index && machine.done.is_none())
+                .count() as u64;
+
+            while idle < machine_type.standby && host.fits(machine_type) {
+                host.take(machine_type);
+
+                machines.push(Machine {
+                    machine: index,
+                    ready: now + machine_type.boot,
+                    done: None,
+                });
+
+                idle += 1;
+            }
+        }
+
+        peak_machines = peak_machines.max(machines.len());
+
+        let next_done = machines
+            .iter()
+            .filter_map(|machine| machine.done)
+            .reduce(f64::min);
+
+        let next_arrival = arrivals.front().map(|job| job.queued);
+
+        let next = match (next_done, next_arrival) {
+            (Some(done), Some(arrival)) => done.min(arrival),
+            (Some(done), None) => done,
+            (None, Some(arrival)) => arrival,
+            // Only standby machines and jobs that never fit are left.
+            (None, None) => break,
+        };
+
+        ram_seconds += host.ram_used as f64 * (next - now);
+        cpu_seconds += host.cpus_used as f64 * (next - now);
+        now = next;
+    }
+
+    let mut never_started = vec![0; machine_types.len()];
+
+    for job in queue {
+        never_started[job.machine] += 1;
+    }
+
+    let ram_utilization = match now > 0.0 && host.ram > 0 {
+        true => ram_seconds / (now * host.ram as f64),
+        false => 0.0,
+    };
+
+    let cpu_utilization = host.cpus.map(|cpus| match now > 0.0 && cpus > 0 {
+        true => cpu_seconds / (now * cpus as f64),
+        false => 0.0,
+    });
+
+    let mut machines: BTreeMap<String, MachineSimulation> = BTreeMap::new();
+
+    for (machine_type, never_started) in machine_types.iter_mut().zip(never_started) {
+        machine_type.waits.sort_by(f64::total_cmp);
+
+        let waits = &machine_type.waits;
+
+        machines.insert(
+            machine_type.triplet.to_string(),
+            MachineSimulation {
+                machine: machine_type.triplet.to_string(),
+                jobs: waits.len() + never_started,
+                never_started,
+                boot_seconds: machine_type.boot,
+                recorded_mean_wait_seconds: mean(&machine_type.recorded_waits),
+                mean_wait_seconds: mean(waits),
+                p95_wait_seconds: percentile(waits, 0.95),
+                max_wait_seconds: waits.last().copied().unwrap_or(0.0),
+            },
+        );
+    }
+
+    Ok(Simulation {
+        jobs: jobs.len(),
+        skipped,
+        from: start,
+        until: jobs.last().zip(start).map(|(job, start)| {
+            start + chrono::TimeDelta::milliseconds((job.queued * 1000.0) as i64)
+        }),
+        ram_utilization,
+        cpu_utilization,
+        peak_machines,
+        machines: machines.into_values().collect(),
+    })
+}
@@ -0,0 +1,159 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+use super::resources::ResourceUsage;
+use super::retention::walk;
+use crate::config::{ConfigFile, SizeInBytes};
+
+// Do not draw conclusions from only a handful of jobs.
+const MIN_SAMPLES: usize = 5;
+
+// Machines using less than this share of their CPUs or RAM are oversized.
+const LOW_UTILIZATION: f64 = 0.25;
+const LOW_RAM_USAGE: f64 = 0.5;
+
+// Machines using more than this share of their CPUs are undersized.
+// There is no such limit for RAM, see `recommendations()`.
+const HIGH_UTILIZATION: f64 = 0.9;
+
+// Suggest CPU counts that result in this utilization.
+const TARGET_UTILIZATION: f64 = 0.5;
+
+// Leave some headroom above the observed peak when suggesting RAM sizes
+// and round to a multiple of this.
+const RAM_HEADROOM: f64 = 1.25;
+const RAM_GRANULARITY: u64 = 256 << 20;
+
+/// A suggestion to change the size of a machine type
+#[derive(Serialize)]
+pub struct Recommendation {
+    pub machine: String,
+    /// The number of jobs the recommendation is based on
+    pub samples: usize,
+    /// The CPU time used relative to the configured CPUs and running time
+    pub cpu_utilization: f64,
+    /// The highest RAM usage seen, relative to the configured RAM
+    pub peak_ram_usage: f64,
+    pub cpus: u32,
+    pub suggested_cpus: Option<u32>,
+    pub ram: String,
+    pub suggested_ram: Option<String>,
+}
+
+impl std::fmt::Display for Recommendation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {:.0}% CPU, {:.0}% peak RAM over {} jobs.",
+            self.machine,
+            self.cpu_utilization * 100.0,
+            self.peak_ram_usage * 100.0,
+            self.samples
+        )?;
+
+        if let Some(cpus) = self.suggested_cpus {
+            write!(f, " Suggested cpus: {} -> {cpus}.", self.cpus)?;
+        }
+
+        if let Some(ram) = &self.suggested_ram {
+            write!(f, " Suggested ram: {} -> {ram}.", self.ram)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the resource usage records from the run directories of a machine type
+fn records(base_dir: &Path, owner: &str, repository: &str, machine: &str) -> Vec<ResourceUsage> {
+    let dir = base_dir
+        .join("runs")
+        .join(owner)
+        .join(repository)
+        .join(machine);
+
+    walk(&dir, 1)
+        .into_iter()
+        .filter_map(|run_dir| std::fs::read(run_dir.join("resources.json")).ok())
+        .filter_map(|content| serde_json::from_slice(&content).ok())
+        .filter(|usage: &ResourceUsage| usage.wall_seconds > 0.0)
+        .collect()
+}
+
+/// Find machine types that consistently use far less or more CPU and RAM than configured
+///
+/// This is based on the `resources.json` records in the run directories
+/// of previous machines, so it only covers jobs within `retention.run_dirs`.
+/// Only machine types that should be resized are listed.
+///
+/// The peak RAM usage of a machine includes e.g. the page cache of its guest,
+/// which grows until it fills all of the RAM a machine has.
+/// A high peak is thus no sign of a machine having too little RAM,
+/// and more RAM is never suggested, as it would only fill up again.
+pub fn recommendations(cfg: &ConfigFile) -> Vec<Recommendation> {
+    let mut recommendations = Vec::new();
+
+    for triplet in cfg.triplets() {
+        let machine_config = match cfg.machine_config(&triplet) {
+            Some(mc) => mc,
+            None => continue,
+        };
+
+        let records = records(
+            &cfg.host.base_dir,
+            triplet.owner(),
+            triplet.repository(),
+            triplet.machine_name(),
+        );
+
+        if records.len() < MIN_SAMPLES {
+            continue;
+        }
+
+        let cpus = machine_config.cpus;
+        let ram = machine_config.ram.bytes();
+
+        let cpu_seconds: f64 = records.iter().map(|r| r.cpu_seconds).sum();
+        let available_seconds: f64 =
+            records.iter().map(|r| r.wall_seconds).sum::<f64>() * cpus as f64;
+        let cpu_utilization = cpu_seconds / available_seconds;
+
+        let peak_ram = records.iter().map(|r| r.peak_rss_bytes).max().unwrap_or(0);
+        let peak_ram_usage = peak_ram as f64 / ram as f64;
+
+        let suggested_cpus = if cpu_utilization < LOW_UTILIZATION && cpus > 1 {
+            let needed = (cpu_utilization * cpus as f64 / TARGET_UTILIZATION).ceil();
+            Some((needed as u32).clamp(1, cpus - 1))
+        } else if cpu_utilization > HIGH_UTILIZATION {
+            Some(cpus * 2)
+        } else {
+            None
+        };
+
+        let suggested_ram = if peak_ram_usage < LOW_RAM_USAGE {
+            let wanted = (peak_ram as f64 * RAM_HEADROOM) as u64;
+            let rounded = wanted.div_ceil(RAM_GRANULARITY).max(1) * RAM_GRANULARITY;
+
+            (rounded < ram).then(|| SizeInBytes::from_bytes(rounded).to_string())
+        } else {
+            None
+        };
+
+        if suggested_cpus.is_none() && suggested_ram.is_none() {
+            continue;
+        }
+
+        recommendations.push(Recommendation {
+            machine: triplet.to_string(),
+            samples: records.len(),
+            cpu_utilization,
+            peak_ram_usage,
+            cpus,
+            suggested_cpus,
+            ram: machine_config.ram.to_string(),
+            suggested_ram,
+        });
+    }
+
+    recommendations
+}
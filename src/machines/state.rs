@@ -0,0 +1,209 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::accounting::{Accounting, UsageReport};
+use super::calibration::results_path;
+use super::{Error, Result};
+use crate::config::Config;
+
+// Bump this when making incompatible changes to the snapshot format.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Metadata of a persisted machine image
+///
+/// The images themselves are too large to be part of a snapshot and have
+/// to be copied over separately.
+/// Their modification times do however decide whether a machine is started
+/// from its own image or from its base (see `use_base`), so we keep track
+/// of them.
+#[derive(Serialize, Deserialize)]
+struct ImageMetadata {
+    size: u64,
+    modified: DateTime<Utc>,
+}
+
+/// A portable snapshot of the persistent state of a Forrest instance
+///
+/// The per machine type maps are keyed by the `owner/repository/machine` triplet.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    exported: DateTime<Utc>,
+    accounting: UsageReport,
+    calibration: BTreeMap<String, Vec<serde_json::Value>>,
+    images: BTreeMap<String, ImageMetadata>,
+}
+
+fn read_records(path: &Path) -> std::io::Result<Vec<serde_json::Value>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut records = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        records.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(records)
+}
+
+/// Write a snapshot of the persistent state to `path`
+///
+/// This includes the resource usage accounting, the calibration records
+/// and the metadata of the persisted machine images of all configured
+/// machine types.
+pub fn export_state(config: Config, path: &Path) -> Result<()> {
+    let cfg = config.get();
+    let base_dir = &cfg.host.base_dir;
+
+    let mut calibration = BTreeMap::new();
+    let mut images = BTreeMap::new();
+
+    for triplet in cfg.triplets() {
+        let records = read_records(&results_path(base_dir, &triplet))?;
+
+        if !records.is_empty() {
+            calibration.insert(triplet.to_string(), records);
+        }
+
+        let image_path = triplet.machine_image_path(base_dir);
+
+        match image_path.metadata() {
+            Ok(meta) => {
+                let metadata = ImageMetadata {
+                    size: meta.len(),
+                    modified: meta.modified()?.into(),
+                };
+
+                images.insert(triplet.to_string(), metadata);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        exported: Utc::now(),
+        accounting: Accounting::new(base_dir).report(),
+        calibration,
+        images,
+    };
+
+    let file = File::create(path)?;
+    serde_json::to_writer_pretty(file, &snapshot)?;
+
+    info!(
+        "Exported state with {} calibrated machine types and {} machine images to {}",
+        snapshot.calibration.len(),
+        snapshot.images.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Restore the persistent state from a snapshot at `path`
+///
+/// Importing is meant to happen while Forrest is not running,
+/// and can safely be repeated, e.g. after copying over more machine images.
+pub fn import_state(config: Config, path: &Path) -> Result<()> {
+    let cfg = config.get();
+    let base_dir = &cfg.host.base_dir;
+
+    let snapshot: Snapshot = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(Error::SnapshotVersion {
+            found: snapshot.version,
+            expected: SNAPSHOT_VERSION,
+        });
+    }
+
+    info!("Importing state exported at {}", snapshot.exported);
+
+    Accounting::new(base_dir).import(snapshot.accounting);
+
+    let triplets = cfg.triplets();
+
+    for triplet in triplets.iter() {
+        let name = triplet.to_string();
+
+        if let Some(records) = snapshot.calibration.get(&name) {
+            let path = results_path(base_dir, triplet);
+
+            // Only append records we do not have yet,
+            // so that repeated imports do not duplicate them.
+            let known: HashSet<serde_json::Value> = read_records(&path)?
+                .into_iter()
+                .filter_map(|r| r.get("timestamp").cloned())
+                .collect();
+
+            let new: Vec<_> = records
+                .iter()
+                .filter(|r| !r.get("timestamp").is_some_and(|ts| known.contains(ts)))
+                .collect();
+
+            if !new.is_empty() {
+                create_dir_all(path.parent().unwrap())?;
+
+                let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+                for record in new {
+                    writeln!(file, "{record}")?;
+                }
+            }
+        }
+
+        if let Some(metadata) = snapshot.images.get(&name) {
+            let image_path = triplet.machine_image_path(base_dir);
+
+            let file = match OpenOptions::new().write(true).open(&image_path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    warn!(
+                        "Machine image for {name} is missing. Copy it to {} and re-run the import",
+                        image_path.display()
+                    );
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let size = file.metadata()?.len();
+
+            if size != metadata.size {
+                warn!(
+                    "Machine image for {name} has a size of {size} instead of {}. Not restoring its modification time",
+                    metadata.size
+                );
+                continue;
+            }
+
+            file.set_modified(metadata.modified.into())?;
+        }
+    }
+
+    for name in snapshot.calibration.keys().chain(snapshot.images.keys()) {
+        if !triplets.iter().any(|t| &t.to_string() == name) {
+            warn!("Skipping state of {name}, which is not configured on this host");
+        }
+    }
+
+    Ok(())
+}
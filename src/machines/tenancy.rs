@@ -0,0 +1,139 @@
+use std::io::ErrorKind;
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::path::{Component, Path};
+
+use nix::unistd::User;
+
+use super::helper;
+use super::triplet::Triplet;
+use crate::config::{Backend, ConfigFile, Tenant};
+
+/// Check if a machine for `triplet` may be created under the tenancy rules
+///
+/// In strict tenancy mode every owner needs a tenant entry with a dedicated
+/// user to run qemu as and a dedicated network bridge.
+/// Machine types may only use the bridges of their tenant.
+/// Machines may also not be based on machines of other owners and may only
+/// share directories inside of the owner's cache directory with the host.
+///
+/// Returns the tenant configuration for the owner, if there is any.
+pub(super) fn check<'a>(
+    cfg: &'a ConfigFile,
+    triplet: &Triplet,
+) -> Result<Option<&'a Tenant>, String> {
+    let owner = triplet.owner();
+    let tenant = cfg.tenancy.owners.get(owner);
+
+    if !cfg.tenancy.strict {
+        return Ok(tenant);
+    }
+
+    let tenant = tenant.ok_or_else(|| format!("No tenant configured for {owner}"))?;
+
+    if tenant.user.is_none() {
+        return Err(format!("No user configured for tenant {owner}"));
+    }
+
+    if tenant.bridge.is_none() {
+        return Err(format!("No network bridge configured for tenant {owner}"));
+    }
+
+    let machine_config = cfg
+        .machine_config(triplet)
+        .ok_or_else(|| format!("Unknown machine triplet {triplet}"))?;
+
+    // Containers run as root and can not be confined to the tenant's user.
+    if machine_config.backend != Backend::Qemu {
+        return Err(format!(
+            "Machine {triplet} uses a backend that is not supported in strict tenancy mode"
+        ));
+    }
+
+    // The bridge of a machine type goes over the one of its tenant,
+    // so it could put the machine on the network of another tenant.
+    if let Some(bridge) = &machine_config.bridge {
+        let allowed = tenant.bridge.as_ref() == Some(bridge)
+            || tenant.quarantine_bridge.as_ref() == Some(bridge);
+
+        if !allowed {
+            return Err(format!(
+                "Machine {triplet} uses bridge {bridge}, which is not one of tenant {owner}"
+            ));
+        }
+    }
+
+    if let Some(base) = &machine_config.base_machine {
+        if base.owner() != owner {
+            return Err(format!("Base machine {base} belongs to another tenant"));
+        }
+    }
+
+    for dir in machine_config.shared.iter() {
+        // `starts_with()` compares components, so `..` could lead out of
+        // the cache dir.
+        let escapes = dir
+            .path
+            .components()
+            .any(|component| component == Component::ParentDir);
+
+        let in_cache_dir = tenant
+            .cache_dir
+            .as_ref()
+            .map(|cache_dir| !escapes && dir.path.starts_with(cache_dir))
+            .unwrap_or(false);
+
+        if !in_cache_dir {
+            return Err(format!(
+                "Shared directory {} is outside of the cache dir of tenant {owner}",
+                dir.path.display()
+            ));
+        }
+    }
+
+    Ok(Some(tenant))
+}
+
+/// Look up the user id and group id to run qemu as for a tenant
+pub(super) fn user(tenant: Option<&Tenant>) -> std::io::Result<Option<User>> {
+    let name = match tenant.and_then(|t| t.user.as_deref()) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    match User::from_name(name)? {
+        Some(user) => Ok(Some(user)),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Unknown tenant user {name}"),
+        )),
+    }
+}
+
+/// The permissions of files and directories handed over to a tenant user
+pub(super) fn hand_over_mode(is_dir: bool) -> u32 {
+    match is_dir {
+        true => 0o2770,
+        false => 0o660,
+    }
+}
+
+/// Hand a file or directory created by us over to the tenant user
+///
+/// The group is kept and given write access,
+/// so that we can still inspect and clean up after the machine.
+/// Directories pass the group on to the files the tenant creates in them.
+/// Only root may give files away, so this is left to the helper if
+/// we are not allowed to.
+pub(super) fn hand_over(path: &Path, user: &User) -> std::io::Result<()> {
+    // Change the permissions first, because we are no longer allowed to
+    // once the file belongs to someone else.
+    let mode = hand_over_mode(path.is_dir());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+    match chown(path, Some(user.uid.as_raw()), None) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied && helper::is_used() => {
+            helper::hand_over(path, user)
+        }
+        res => res,
+    }
+}
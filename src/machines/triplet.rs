@@ -2,6 +2,7 @@ use std::path::{Path, PathBuf};
 
 use log::debug;
 use serde::de::{Deserialize, Deserializer, Error};
+use serde::{Serialize, Serializer};
 
 #[derive(PartialEq, Eq, Clone, Hash)]
 pub struct OwnerAndRepo {
@@ -136,6 +137,36 @@ impl std::fmt::Debug for Triplet {
     }
 }
 
+impl std::str::FromStr for OwnerAndRepo {
+    type Err = String;
+
+    fn from_str(oar_str: &str) -> Result<Self, Self::Err> {
+        match oar_str.split_once('/') {
+            Some((owner, repository)) if !repository.contains('/') => {
+                Ok(Self::new(owner, repository))
+            }
+            _ => Err(format!(
+                "Expected string of format <user>/<repo>, got '{oar_str}'"
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for Triplet {
+    type Err = String;
+
+    fn from_str(triplet_str: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = triplet_str.split('/').collect();
+
+        match parts.as_slice() {
+            [owner, repository, machine_name] => Ok(Self::new(owner, repository, machine_name)),
+            _ => Err(format!(
+                "Expected string of format <user>/<repo>/<machine type>, got '{triplet_str}'"
+            )),
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for Triplet {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -143,16 +174,15 @@ impl<'de> Deserialize<'de> for Triplet {
     {
         let triplet_str: String = Deserialize::deserialize(deserializer)?;
 
-        let parts: Vec<&str> = triplet_str.split('/').collect();
-        let parts_len = parts.len();
-
-        if parts_len != 3 {
-            return Err(D::Error::invalid_length(
-                parts_len,
-                &"Expected string of format <user>/<repo>/<machine type>",
-            ));
-        }
+        triplet_str.parse().map_err(D::Error::custom)
+    }
+}
 
-        Ok(Self::new(parts[0], parts[1], parts[2]))
+impl Serialize for Triplet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
     }
 }
@@ -1,55 +1,369 @@
+mod admin;
+mod api_health;
 mod auth;
+mod cli;
 mod config;
+mod error;
+mod forge;
+mod heartbeat;
 mod ingres;
 mod jobs;
 mod machines;
+mod metrics;
+mod probe;
+mod smoke_test;
+mod status;
+mod subsystems;
+
+use std::sync::Arc;
+
+use clap::{CommandFactory, Parser};
+use cli::{Cli, Command, Output};
+use subsystems::Subsystem;
 
 async fn forrest() -> anyhow::Result<()> {
-    let config_path = {
-        let mut args: Vec<String> = std::env::args().collect();
+    let cli = Cli::parse();
 
-        match args.len() {
-            1 => "config.yaml".to_owned(),
-            2 => args.remove(1),
-            _ => anyhow::bail!("Usage: {} [CONFIG]", args[0]),
+    match cli.command {
+        None => run(&cli.config).await,
+        Some(Command::Calibrate { config, output }) => calibrate(&config, output).await,
+        Some(Command::Recommend { config, output }) => recommend(&config, output),
+        Some(Command::Simulate {
+            history,
+            config,
+            output,
+        }) => simulate(history.as_deref(), &config, output),
+        Some(Command::DryRun {
+            machine,
+            config,
+            output,
+        }) => dry_run(&machine, &config, output),
+        Some(Command::Status { config, output }) => status(&config, output),
+        Some(Command::SmokeTest {
+            repository,
+            config,
+            output,
+        }) => smoke_test(&repository, &config, output).await,
+        Some(Command::ExportState { snapshot, config }) => export_state(&snapshot, &config),
+        Some(Command::ImportState { snapshot, config }) => import_state(&snapshot, &config),
+        Some(Command::Helper { config }) => helper(&config).await,
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                "forrest",
+                &mut std::io::stdout(),
+            );
+            Ok(())
         }
+        Some(Command::MetadataProxy { socket }) => Ok(machines::metadata_proxy(socket.as_ref())?),
+    }
+}
+
+/// Boot each configured machine type once with a benchmark job and record the results
+async fn calibrate(config_path: &str, output: Output) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    // Calibration takes a while, so results are printed as they come in,
+    // as one JSON object per line in JSON mode.
+    let report = |triplet: &machines::Triplet, record: &serde_json::Value| match output {
+        Output::Table => println!("{triplet}: {}", record["results"]),
+        Output::Json => println!(
+            "{}",
+            serde_json::json!({ "machine": triplet.to_string(), "record": record })
+        ),
+    };
+
+    Ok(machines::calibrate(config, report).await?)
+}
+
+/// Print suggestions to resize machine types based on their past resource usage
+fn recommend(config_path: &str, output: Output) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    let recommendations = machines::recommendations(&config.get());
+
+    if output == Output::Json {
+        println!("{}", serde_json::to_string_pretty(&recommendations)?);
+        return Ok(());
+    }
+
+    if recommendations.is_empty() {
+        println!("All machine types with enough recorded jobs are sized well");
+    }
+
+    for recommendation in recommendations {
+        println!("{recommendation}");
+    }
+
+    Ok(())
+}
+
+/// Replay the recorded jobs against a config and print the expected queue times and utilization
+fn simulate(history_path: Option<&str>, config_path: &str, output: Output) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+    let cfg = config.get();
+
+    let history_path = match history_path {
+        Some(path) => std::path::PathBuf::from(path),
+        None => jobs::history_path(&cfg.host.base_dir),
     };
 
+    let simulation = machines::simulate(&cfg, &history_path)?;
+
+    match output {
+        Output::Table => print!("{simulation}"),
+        Output::Json => println!("{}", serde_json::to_string_pretty(&simulation)?),
+    }
+
+    Ok(())
+}
+
+/// Print how a machine type would be started without starting it
+fn dry_run(triplet: &machines::Triplet, config_path: &str, output: Output) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    let dry_run = machines::dry_run(&config.get(), triplet)?;
+
+    match output {
+        Output::Table => print!("{dry_run}"),
+        Output::Json => println!("{}", serde_json::to_string_pretty(&dry_run)?),
+    }
+
+    Ok(())
+}
+
+/// Print the machines, queue, budgets and errors of the running daemon
+fn status(config_path: &str, output: Output) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    Ok(status::print(&config, output)?)
+}
+
+/// Dispatch the smoke test workflow of a repository and check that Forrest runs it
+async fn smoke_test(
+    oar: &machines::OwnerAndRepo,
+    config_path: &str,
+    output: Output,
+) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    match smoke_test::run(&config, oar, output).await? {
+        true => Ok(()),
+        false => anyhow::bail!("The smoke test of {oar} failed"),
+    }
+}
+
+/// Write the persistent state (accounting, calibration records, ...) to a snapshot file
+fn export_state(snapshot_path: &str, config_path: &str) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    Ok(machines::export_state(config, snapshot_path.as_ref())?)
+}
+
+/// Restore the persistent state from a snapshot file, e.g. on a new host
+fn import_state(snapshot_path: &str, config_path: &str) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    Ok(machines::import_state(config, snapshot_path.as_ref())?)
+}
+
+/// Do the privileged work for an instance running as an unprivileged user
+async fn helper(config_path: &str) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    Ok(machines::serve_helper(config).await?)
+}
+
+async fn run(config_path: &str) -> anyhow::Result<()> {
     // Read the config file.
     // The file will be re-read if it changed on disk at many points in the program,
     // allowing changes to be made while jobs are being executed.
-    let config = config::Config::new(&config_path)?;
+    let config = config::Config::new(config_path)?;
+
+    // Machines can register as runners with forges other than GitHub
+    // (or a fake forge for local development),
+    // in which case none of the GitHub specific parts below are used.
+    let forge_kind = config.get().forge;
+    let github = forge_kind == config::ForgeKind::Github;
+
+    if !github {
+        log::warn!("Using the {forge_kind:?} forge. No jobs will be picked up from GitHub");
+    }
 
     // We use a private key to authenticate as a GitHub application
     // and derive installation tokens from it.
     // Use a central registry of cached installation tokens for efficiency.
-    let auth = auth::Auth::new(&config)?;
+    let auth = match github {
+        true => auth::Auth::new(&config),
+        false => auth::Auth::anonymous(&config),
+    }
+    .inspect_err(|e| {
+        log::error!("Failed to set up GitHub authentication ({})", e.category());
+    })?;
+
+    // Point the App webhook at us, if configured, so that a new instance
+    // does not have to be set up by hand in the GitHub App settings.
+    if github {
+        if let Err(e) = ingres::configure_app_hook(&config.get(), &auth).await {
+            log::error!("Failed to set up the App webhook ({}): {e}", e.category());
+        }
+    }
+
+    // Delays in e.g. the delivery of webhooks are collected here
+    // and exposed via the admin API.
+    let metrics = metrics::Metrics::new();
+
+    // The time of the last job update we processed is persisted,
+    // so that after a restart we only have to poll for what we missed.
+    let checkpoint = ingres::Checkpoint::new(&config.get().host.base_dir);
+
+    // Our machines register as runners with the forge.
+    // The fake forge also serves the jobs to run.
+    let fake_forge = (forge_kind == config::ForgeKind::Fake)
+        .then(|| Arc::new(forge::FakeForge::new(config.clone())));
+
+    // Machine types can register with Jenkins instead of the instance wide forge,
+    // so that a single pool of machines can serve e.g. GitHub and Jenkins.
+    // Builds waiting in the Jenkins queue create demand for these machines.
+    let jenkins_forge = (forge_kind == config::ForgeKind::Jenkins
+        || config.get().jenkins.is_some())
+    .then(|| Arc::new(forge::JenkinsForge::new(config.clone())));
+
+    // Buildbot masters request latent workers via the admin API.
+    let buildbot_forge = (forge_kind == config::ForgeKind::Buildbot
+        || config.get().buildbot.is_some())
+    .then(|| Arc::new(forge::BuildbotForge::new(config.clone())));
+
+    let forge: Arc<dyn forge::Forge> = match forge_kind {
+        config::ForgeKind::Github => Arc::new(forge::GitHubForge::new(auth.clone())),
+        config::ForgeKind::Fake => fake_forge.clone().unwrap(),
+        config::ForgeKind::Bitbucket => Arc::new(forge::BitbucketForge::new(config.clone())),
+        config::ForgeKind::AzureDevops => Arc::new(forge::AzureDevOpsForge::new(config.clone())),
+        config::ForgeKind::Jenkins => jenkins_forge.clone().unwrap(),
+        config::ForgeKind::Buildbot => buildbot_forge.clone().unwrap(),
+    };
+
+    let mut forges = forge::Forges::new();
+    forges.insert(forge_kind, forge);
+
+    if let Some(jenkins_forge) = &jenkins_forge {
+        forges.insert(config::ForgeKind::Jenkins, jenkins_forge.clone());
+    }
+
+    if let Some(buildbot_forge) = &buildbot_forge {
+        forges.insert(config::ForgeKind::Buildbot, buildbot_forge.clone());
+    }
+
+    // The webhook handler, relay, poller and machine manager can be stopped
+    // and started individually via the admin API while we run.
+    let subsystems = subsystems::Subsystems::new();
 
     // The machine manager handles our virtual machines and their relation with GitHub.
     // It makes sure we only spawn as many VMs as the host can fit,
     // that all machines we spawn eventually register as runners on GitHub,
     // stopping machines that are no longer required because
     // persisting disk images, cleaning up stale runners etc. etc.
-    let machine_manager = machines::Manager::new(config.clone(), auth.clone());
+    let machine_manager = machines::Manager::new(
+        config.clone(),
+        auth.clone(),
+        forges,
+        metrics.clone(),
+        subsystems.clone(),
+    );
+
+    // Take over the machines a previous instance left running (if enabled),
+    // before the first runner sweep declares their runners orphaned.
+    machine_manager.adopt_machines();
+
+    // Report problems with the host tooling required by the configured
+    // machines (missing qemu binary, no access to /dev/kvm, …) right away.
+    machine_manager.backend_readiness();
 
     // The job manager keeps track of build jobs and their status and
     // communicates the demand for machines with the machine manager.
     // It gets its updates from from the webhook handler and poller below.
-    let job_manager = jobs::Manager::new(machine_manager.clone());
+    let job_manager = jobs::Manager::new(config.clone(), machine_manager.clone(), metrics.clone());
+
+    // Check that the App is installed on all configured repositories and has
+    // the required permissions, so that problems surface right away instead of
+    // when the first job arrives.
+    let prober = probe::Prober::new(config.clone(), auth.clone());
+
+    // The admin API provides insight into the state of our machines and jobs.
+    // It is available via a unix domain socket and optionally on the network.
+    let admin = admin::AdminHandler::new(
+        config.clone(),
+        machine_manager.clone(),
+        job_manager.clone(),
+        prober.clone(),
+        metrics.clone(),
+        buildbot_forge.clone(),
+        subsystems.clone(),
+    );
+
+    // The heartbeat tells external monitoring and the systemd watchdog
+    // that we are still alive.
+    let heartbeat =
+        heartbeat::Heartbeat::new(config.clone(), machine_manager.clone(), job_manager.clone());
 
     // The main method to learn about new jobs to run is via webhooks.
     // These are POST requests sent by GitHub notifying us about events.
-    let mut webhook =
-        ingres::WebhookHandler::new(config.clone(), auth.clone(), job_manager.clone())?;
+    // Hosts that can not be reached from the outside can disable webhooks
+    // by not configuring a webhook secret and rely on polling alone.
+    let webhook = match !github || config.get().github.poll_only() {
+        false => Some(ingres::WebhookHandler::new(
+            config.clone(),
+            auth.clone(),
+            job_manager.clone(),
+            metrics.clone(),
+            checkpoint.clone(),
+        )?),
+        true if !github => None,
+        true => {
+            let interval = config.get().github.polling_interval();
+
+            log::warn!("No webhook secret configured. Running in polling only mode.");
+            log::warn!(
+                "New jobs will only be picked up every {}s, instead of right away.",
+                interval.as_secs()
+            );
+
+            None
+        }
+    };
+
+    // Hosts that can not be reached from the outside can receive webhooks
+    // via a smee.io compatible relay instead.
+    // The relay client only connects if a relay is configured.
+    let relay = ingres::Relay::new(
+        config.clone(),
+        auth.clone(),
+        job_manager.clone(),
+        metrics.clone(),
+        checkpoint.clone(),
+    );
 
     // Our secondary source of information are periodic polls of the GitHub API.
     // These come in handy at startup or after network outages when we may have
     // missed webhooks.
-    let poller = ingres::Poller::new(config.clone(), auth.clone(), job_manager);
+    let poller = ingres::Poller::new(
+        config.clone(),
+        auth.clone(),
+        job_manager.clone(),
+        checkpoint,
+        metrics.clone(),
+    );
 
     // Make sure we can reach GitHub and our authentication works before
     // signaling readiness to systemd.
-    poller.poll_once().await?;
+    // This also picks up the jobs queued while we were down.
+    if github {
+        poller.catch_up().await?;
+    }
+
+    let jenkins_job_manager = job_manager.clone();
+    let buildbot_job_manager = job_manager.clone();
 
     log::info!("Startup complete. Handling requests");
 
@@ -60,11 +374,91 @@ async fn forrest() -> anyhow::Result<()> {
     }
 
     tokio::select! {
-        res = machine_manager.janitor() => res,
-        res = webhook.run() => res,
-        res = poller.poll() => res,
+        // The periodic maintenance of the machines pauses while the machine
+        // manager is stopped. Running machines are still looked after.
+        res = subsystems.run(Subsystem::MachineManager, || async {
+            // Start the machines for the demand that built up meanwhile.
+            machine_manager.apply_config();
+
+            tokio::select! {
+                res = machine_manager.janitor() => res,
+                res = machine_manager.rolling_restart() => res,
+                res = machine_manager.container_image_updates() => res,
+                res = machine_manager.load_shedding() => res,
+                res = machine_manager.ballooning() => res,
+            }
+        }) => res,
+        res = admin.run() => res,
+        res = heartbeat.run() => res,
+        res = machine_manager.forge_feedback() => res,
+        res = machine_manager.start_watchdog() => res,
+        res = async {
+            match github {
+                true => prober.run().await,
+                false => std::future::pending().await,
+            }
+        } => res,
+        res = async {
+            match &webhook {
+                Some(webhook) => subsystems.run(Subsystem::Webhook, || webhook.run()).await,
+                None => std::future::pending().await,
+            }
+        } => res,
+        res = subsystems.run(Subsystem::Relay, || relay.run()) => res,
+        res = async {
+            match &jenkins_forge {
+                Some(jenkins_forge) => jenkins_forge.run(jenkins_job_manager).await,
+                None => std::future::pending().await,
+            }
+        } => res,
+        res = async {
+            match &buildbot_forge {
+                Some(buildbot_forge) => buildbot_forge.run(buildbot_job_manager).await,
+                None => std::future::pending().await,
+            }
+        } => res,
+        res = async {
+            match (github, &fake_forge) {
+                (true, _) => subsystems.run(Subsystem::Poller, || poller.poll()).await,
+                (false, Some(fake_forge)) => fake_forge.run(job_manager).await,
+                // Jobs are not picked up from other forges (yet).
+                // Machines for them can be kept available using pins.
+                (false, None) => std::future::pending().await,
+            }
+        } => res,
+        res = shutdown_signal() => res,
     }?;
 
+    // Leaving the select above stopped all request handling,
+    // so no new jobs are picked up from here on.
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]) {
+        log::info!("Failed to notify systemd about service shutdown: {e}");
+    }
+
+    log::info!("Shutting down. Draining machines");
+
+    machine_manager.drain().await;
+
+    log::info!("Shutdown complete");
+
+    // Exit right away instead of tearing down the async runtime,
+    // which would kill the machines that are left running for the next
+    // instance to take over.
+    std::process::exit(0)
+}
+
+/// Wait for systemd (SIGTERM) or the user (SIGINT) to ask us to stop
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("Received SIGTERM"),
+        _ = sigint.recv() => log::info!("Received SIGINT"),
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use chrono::TimeDelta;
+use serde::Serialize;
+
+use crate::error::Category;
+
+/// Summary statistics of a series of values, like delays in seconds
+#[derive(Serialize, Clone, Default)]
+pub struct DelayStats {
+    pub count: u64,
+    pub last: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+impl DelayStats {
+    fn record(&mut self, seconds: f64) {
+        self.count += 1;
+        self.last = seconds;
+        self.mean += (seconds - self.mean) / (self.count as f64);
+        self.max = self.max.max(seconds);
+    }
+}
+
+/// A histogram of observed values with fixed bucket boundaries
+///
+/// Each bucket counts the values less than or equal to its upper bound `le`,
+/// values above the highest bound are only counted in `count`.
+#[derive(Serialize, Clone)]
+pub struct Histogram {
+    pub buckets: Vec<Bucket>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Bucket {
+    pub le: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            buckets: bounds.iter().map(|&le| Bucket { le, count: 0 }).collect(),
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn record(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+
+        for bucket in self.buckets.iter_mut().filter(|b| value <= b.le) {
+            bucket.count += 1;
+        }
+    }
+}
+
+// Only the most recent boots, jobs and waits of a machine type count
+// towards its health.
+const HEALTH_WINDOW: usize = 20;
+
+// Waits for a machine up to this many seconds count as healthy ...
+const HEALTHY_WAIT: f64 = 60.0;
+// ... while longer ones lower the score, down to zero at this many seconds.
+const UNHEALTHY_WAIT: f64 = 30.0 * 60.0;
+
+/// The recent outcomes a machine type's health is based on
+#[derive(Default)]
+struct HealthSamples {
+    boots: VecDeque<bool>,
+    jobs: VecDeque<bool>,
+    waits: VecDeque<f64>,
+}
+
+fn push_recent<T>(samples: &mut VecDeque<T>, value: T) {
+    if samples.len() >= HEALTH_WINDOW {
+        samples.pop_front();
+    }
+
+    samples.push_back(value);
+}
+
+fn success_rate(samples: &VecDeque<bool>) -> Option<f64> {
+    match samples.is_empty() {
+        true => None,
+        false => Some(samples.iter().filter(|ok| **ok).count() as f64 / samples.len() as f64),
+    }
+}
+
+/// The health of a machine type, based on its recent boots, jobs and waits
+#[derive(Serialize, Clone)]
+pub struct Health {
+    /// The combined score, from 0 (broken) to 100 (healthy)
+    pub score: f64,
+    /// The share of recent machines that came up as runners
+    pub boot_success: Option<f64>,
+    /// The share of recent jobs whose machine did not fail while running them
+    pub job_success: Option<f64>,
+    /// The mean time (in seconds) recent jobs waited for a machine
+    pub mean_wait: Option<f64>,
+}
+
+impl HealthSamples {
+    fn health(&self) -> Health {
+        let boot_success = success_rate(&self.boots);
+        let job_success = success_rate(&self.jobs);
+
+        let mean_wait = match self.waits.is_empty() {
+            true => None,
+            false => Some(self.waits.iter().sum::<f64>() / self.waits.len() as f64),
+        };
+
+        let wait_factor = mean_wait.map(|wait| {
+            let excess = (wait - HEALTHY_WAIT).max(0.0);
+            1.0 - (excess / (UNHEALTHY_WAIT - HEALTHY_WAIT)).min(1.0)
+        });
+
+        // Parts without samples yet do not lower the score.
+        let score = 100.0
+            * boot_success.unwrap_or(1.0)
+            * job_success.unwrap_or(1.0)
+            * wait_factor.unwrap_or(1.0);
+
+        Health {
+            score,
+            boot_success,
+            job_success,
+            mean_wait,
+        }
+    }
+}
+
+/// The number of webhook events of a type that were handled or ignored
+#[derive(Serialize, Clone, Default)]
+pub struct EventCounts {
+    pub handled: u64,
+    pub ignored: u64,
+}
+
+/// Named delay metrics collected throughout the program
+///
+/// These are exposed via the admin API to e.g. tell delays on the GitHub
+/// side apart from delays in our own processing.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    delays: Arc<Mutex<BTreeMap<&'static str, DelayStats>>>,
+    events: Arc<Mutex<BTreeMap<String, EventCounts>>>,
+    histograms: Arc<Mutex<BTreeMap<&'static str, Histogram>>>,
+    errors: Arc<Mutex<BTreeMap<&'static str, BTreeMap<Category, u64>>>>,
+    resources: Arc<Mutex<BTreeMap<String, BTreeMap<&'static str, DelayStats>>>>,
+    aliases: Arc<Mutex<BTreeMap<String, u64>>>,
+    health: Arc<Mutex<BTreeMap<String, HealthSamples>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an occurrence of the delay `name`
+    ///
+    /// Negative delays, e.g. due to clock skew between us and GitHub,
+    /// are recorded as zero.
+    pub fn record_delay(&self, name: &'static str, delay: TimeDelta) {
+        let seconds = (delay.num_milliseconds() as f64 / 1000.0).max(0.0);
+
+        self.delays
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .record(seconds);
+    }
+
+    /// Get a snapshot of all delay metrics recorded so far
+    pub fn delays(&self) -> BTreeMap<&'static str, DelayStats> {
+        self.delays.lock().unwrap().clone()
+    }
+
+    /// Count a webhook event of type `event_type`
+    pub fn count_event(&self, event_type: &str, handled: bool) {
+        let mut events = self.events.lock().unwrap();
+
+        let counts = match events.get_mut(event_type) {
+            Some(counts) => counts,
+            None => events.entry(event_type.to_owned()).or_default(),
+        };
+
+        match handled {
+            true => counts.handled += 1,
+            false => counts.ignored += 1,
+        }
+    }
+
+    /// Get a snapshot of the webhook event counts per event type
+    pub fn events(&self) -> BTreeMap<String, EventCounts> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Record `value` in the histogram `name`
+    ///
+    /// The histogram is created with the given bucket `bounds` the first
+    /// time it is used.
+    pub fn record_histogram(&self, name: &'static str, bounds: &[f64], value: f64) {
+        self.histograms
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_insert_with(|| Histogram::new(bounds))
+            .record(value);
+    }
+
+    /// Get a snapshot of all histograms recorded so far
+    pub fn histograms(&self) -> BTreeMap<&'static str, Histogram> {
+        self.histograms.lock().unwrap().clone()
+    }
+
+    /// Count an error of `category` that occurred in `operation`
+    pub fn count_error(&self, operation: &'static str, category: Category) {
+        *self
+            .errors
+            .lock()
+            .unwrap()
+            .entry(operation)
+            .or_default()
+            .entry(category)
+            .or_default() += 1;
+    }
+
+    /// Get a snapshot of the error counts per operation and category
+    pub fn errors(&self) -> BTreeMap<&'static str, BTreeMap<Category, u64>> {
+        self.errors.lock().unwrap().clone()
+    }
+
+    /// Record the `value` of the resource `name` used by a machine of type `machine`
+    pub fn record_stat(&self, machine: &str, name: &'static str, value: f64) {
+        let mut resources = self.resources.lock().unwrap();
+
+        let stats = match resources.get_mut(machine) {
+            Some(stats) => stats,
+            None => resources.entry(machine.to_owned()).or_default(),
+        };
+
+        stats.entry(name).or_default().record(value);
+    }
+
+    /// Get a snapshot of the resource usage statistics per machine type
+    pub fn resources(&self) -> BTreeMap<String, BTreeMap<&'static str, DelayStats>> {
+        self.resources.lock().unwrap().clone()
+    }
+
+    /// Count a job that used the deprecated machine type alias `alias`
+    pub fn count_alias(&self, alias: &str) {
+        let mut aliases = self.aliases.lock().unwrap();
+
+        match aliases.get_mut(alias) {
+            Some(count) => *count += 1,
+            None => {
+                aliases.insert(alias.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Get a snapshot of the number of jobs per machine type alias
+    pub fn aliases(&self) -> BTreeMap<String, u64> {
+        self.aliases.lock().unwrap().clone()
+    }
+
+    fn record_health(&self, machine: &str, record: impl FnOnce(&mut HealthSamples)) {
+        let mut health = self.health.lock().unwrap();
+
+        match health.get_mut(machine) {
+            Some(samples) => record(samples),
+            None => record(health.entry(machine.to_owned()).or_default()),
+        }
+    }
+
+    /// Record whether a machine of type `machine` came up as a runner
+    pub fn record_boot(&self, machine: &str, success: bool) {
+        self.record_health(machine, |samples| push_recent(&mut samples.boots, success));
+    }
+
+    /// Record whether a machine of type `machine` kept running until its job was done
+    pub fn record_job(&self, machine: &str, success: bool) {
+        self.record_health(machine, |samples| push_recent(&mut samples.jobs, success));
+    }
+
+    /// Record how long a job waited for a machine of type `machine`
+    pub fn record_wait(&self, machine: &str, wait: TimeDelta) {
+        let seconds = (wait.num_milliseconds() as f64 / 1000.0).max(0.0);
+
+        self.record_health(machine, |samples| push_recent(&mut samples.waits, seconds));
+    }
+
+    /// Get the health of each machine type with recorded boots, jobs or waits
+    pub fn health(&self) -> BTreeMap<String, Health> {
+        self.health
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(machine, samples)| (machine.clone(), samples.health()))
+            .collect()
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::machines::OwnerAndRepo;
+
+// Check the config for new repositories in this interval.
+// The config file is re-read if it changed, so this is also roughly the
+// time it takes for a new repository to be probed.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Repositories that failed the probe are probed again after this time,
+// e.g. because the App was installed on it in the meantime.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The result of probing a repository listed in the config
+#[derive(Serialize, Clone)]
+pub struct ProbeResult {
+    pub repository: String,
+    pub ok: bool,
+    pub message: String,
+    pub checked: DateTime<Utc>,
+}
+
+/// Check that we can actually service the repositories listed in the config
+///
+/// Problems like the App not being installed on a repository or lacking
+/// permissions would otherwise only surface once the first job arrives.
+#[derive(Clone)]
+pub struct Prober {
+    auth: Arc<Auth>,
+    config: Config,
+    results: Arc<Mutex<HashMap<OwnerAndRepo, ProbeResult>>>,
+}
+
+fn is_status(err: &octocrab::Error, code: u16) -> bool {
+    match err {
+        octocrab::Error::GitHub { source, .. } => source.status_code.as_u16() == code,
+        _ => false,
+    }
+}
+
+impl Prober {
+    pub fn new(config: Config, auth: Arc<Auth>) -> Self {
+        let results = Arc::new(Mutex::new(HashMap::new()));
+
+        Self {
+            auth,
+            config,
+            results,
+        }
+    }
+
+    /// Get the results of the most recent probe of each configured repository
+    pub fn results(&self) -> Vec<ProbeResult> {
+        let mut results: Vec<_> = self.results.lock().unwrap().values().cloned().collect();
+
+        results.sort_unstable_by(|a, b| a.repository.cmp(&b.repository));
+
+        results
+    }
+
+    /// Check that the App is installed on `oar` and can manage its runners
+    ///
+    /// Returns an actionable description of the problem if not.
+    async fn probe(&self, oar: &OwnerAndRepo) -> Result<(), String> {
+        let installation = self
+            .auth
+            .app()
+            .apps()
+            .get_repository_installation(oar.owner(), oar.repository())
+            .await
+            .map_err(|e| match is_status(&e, 404) {
+                true => format!(
+                    "The GitHub App is not installed on {oar}. Install it for the repository via the App settings"
+                ),
+                false => format!("Failed to get the App installation for {oar}: {e}"),
+            })?;
+
+        // We may as well remember the installation so that we can use it
+        // right away instead of waiting for the next poll.
+        self.auth.update_user(oar.owner(), installation.id);
+
+        let octocrab = self.auth.user(oar.owner()).unwrap();
+
+        octocrab
+            .actions()
+            .list_repo_self_hosted_runners(oar.owner(), oar.repository())
+            .per_page(1u8)
+            .send()
+            .await
+            .map_err(|e| match is_status(&e, 403) || is_status(&e, 404) {
+                true => format!(
+                    "The GitHub App can not list the runners of {oar}. Grant it the \"Administration\" repository permission (read and write)"
+                ),
+                false => format!("Failed to list the runners of {oar}: {e}"),
+            })?;
+
+        Ok(())
+    }
+
+    /// Probe all repositories in the config that were not probed successfully yet
+    async fn probe_new(&self) {
+        let cfg = self.config.get();
+
+        let configured: Vec<OwnerAndRepo> = cfg
+            .repositories
+            .iter()
+            .flat_map(|(owner, repos)| repos.keys().map(move |repo| OwnerAndRepo::new(owner, repo)))
+            .collect();
+
+        // Forget about repositories that were removed from the config.
+        self.results
+            .lock()
+            .unwrap()
+            .retain(|oar, _| configured.contains(oar));
+
+        for oar in configured {
+            let due = match self.results.lock().unwrap().get(&oar) {
+                None => true,
+                Some(result) => {
+                    let age = (Utc::now() - result.checked).to_std().unwrap_or_default();
+                    !result.ok && age > RETRY_INTERVAL
+                }
+            };
+
+            if !due {
+                continue;
+            }
+
+            let (ok, message) = match self.probe(&oar).await {
+                Ok(()) => {
+                    info!("Pre-flight check for {oar} succeeded");
+                    (true, "ok".to_owned())
+                }
+                Err(msg) => {
+                    error!("Pre-flight check failed: {msg}");
+                    (false, msg)
+                }
+            };
+
+            let result = ProbeResult {
+                repository: oar.to_string(),
+                ok,
+                message,
+                checked: Utc::now(),
+            };
+
+            self.results.lock().unwrap().insert(oar, result);
+        }
+    }
+
+    /// Periodically probe repositories that were added to the config
+    pub async fn run(&self) -> std::io::Result<()> {
+        loop {
+            self.probe_new().await;
+
+            tokio::time::sleep(PROBE_INTERVAL).await;
+        }
+    }
+}
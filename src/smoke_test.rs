@@ -0,0 +1,279 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use octocrab::models::RunId;
+use octocrab::Octocrab;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::{self, Auth};
+use crate::cli::Output;
+use crate::config::Config;
+use crate::machines::OwnerAndRepo;
+
+// How often to check on the dispatched workflow run.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// The clocks of GitHub and the host may be a little apart.
+// Runs created up to this long before the dispatch are considered ours.
+const CLOCK_SKEW: TimeDelta = TimeDelta::seconds(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("No smoke_test is configured for {0}")]
+    NotConfigured(String),
+    #[error(transparent)]
+    Auth(#[from] auth::Error),
+    #[error("GitHub API request failed: {0}")]
+    GitHub(#[from] octocrab::Error),
+    #[error("The workflow run did not complete within {}s", .0.as_secs())]
+    Timeout(Duration),
+}
+
+#[derive(Deserialize)]
+struct Run {
+    id: RunId,
+    created_at: DateTime<Utc>,
+    status: String,
+    conclusion: Option<String>,
+    html_url: String,
+}
+
+#[derive(Deserialize)]
+struct Runs {
+    workflow_runs: Vec<Run>,
+}
+
+#[derive(Deserialize)]
+struct Job {
+    name: String,
+    conclusion: Option<String>,
+    runner_name: Option<String>,
+    created_at: DateTime<Utc>,
+    started_at: DateTime<Utc>,
+    completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct Jobs {
+    jobs: Vec<Job>,
+}
+
+/// The outcome of a job of the smoke test workflow run
+#[derive(Serialize)]
+struct JobReport {
+    name: String,
+    conclusion: Option<String>,
+    runner_name: Option<String>,
+    /// Was the job run by a machine of a configured machine type?
+    forrest: bool,
+    /// Seconds from queueing the job to a runner picking it up
+    queued: i64,
+    /// Seconds from a runner picking the job up to its completion
+    ran: i64,
+}
+
+/// The outcome of a smoke test
+#[derive(Serialize)]
+struct Report {
+    repository: String,
+    run_url: String,
+    conclusion: Option<String>,
+    /// Seconds from the dispatch to the completion of the workflow run
+    total: i64,
+    jobs: Vec<JobReport>,
+    passed: bool,
+}
+
+/// Get an API client for the App installation on `oar`
+async fn installation(auth: &Auth, oar: &OwnerAndRepo) -> Result<Arc<Octocrab>, Error> {
+    let installation = auth
+        .app()
+        .apps()
+        .get_repository_installation(oar.owner(), oar.repository())
+        .await?;
+
+    auth.update_user(oar.owner(), installation.id);
+
+    Ok(auth.user(oar.owner()).unwrap())
+}
+
+/// Find the run our dispatch created
+///
+/// The dispatch API does not return the run it created, so the most recent
+/// dispatched run created after the dispatch is assumed to be it.
+async fn find_run(
+    octocrab: &Octocrab,
+    oar: &OwnerAndRepo,
+    workflow: &str,
+    dispatched: DateTime<Utc>,
+) -> Result<Option<Run>, Error> {
+    let route = format!(
+        "/repos/{}/{}/actions/workflows/{workflow}/runs?event=workflow_dispatch&per_page=10",
+        oar.owner(),
+        oar.repository()
+    );
+
+    let runs: Runs = octocrab.get(route, None::<&()>).await?;
+
+    Ok(runs
+        .workflow_runs
+        .into_iter()
+        .filter(|run| run.created_at >= dispatched - CLOCK_SKEW)
+        .min_by_key(|run| run.created_at))
+}
+
+/// Dispatch the configured smoke test workflow of `oar` and wait for it to complete
+///
+/// Forrest itself has to be running (e.g. on this host) to pick up the jobs.
+async fn smoke_test(config: &Config, oar: &OwnerAndRepo) -> Result<Report, Error> {
+    let cfg = config.get();
+
+    let repo = cfg
+        .repositories
+        .get(oar.owner())
+        .and_then(|repos| repos.get(oar.repository()));
+
+    let smoke_test = repo
+        .and_then(|repo| repo.smoke_test.as_ref())
+        .ok_or_else(|| Error::NotConfigured(oar.to_string()))?;
+
+    let machine_names: Vec<&String> = repo
+        .into_iter()
+        .flat_map(|repo| repo.machines.keys())
+        .collect();
+
+    let auth = Auth::new(config)?;
+    let octocrab = installation(&auth, oar).await?;
+
+    let dispatched = Utc::now();
+
+    let route = format!(
+        "/repos/{}/{}/actions/workflows/{}/dispatches",
+        oar.owner(),
+        oar.repository(),
+        smoke_test.workflow
+    );
+
+    // The dispatch is answered with an empty body.
+    let response = octocrab
+        ._post(route, Some(&json!({ "ref": smoke_test.git_ref })))
+        .await?;
+    octocrab::map_github_error(response).await?;
+
+    log::info!(
+        "Dispatched {} on {} of {oar}. Waiting for it to complete",
+        smoke_test.workflow,
+        smoke_test.git_ref
+    );
+
+    let deadline = tokio::time::Instant::now() + smoke_test.timeout;
+
+    let run = loop {
+        if tokio::time::Instant::now() > deadline {
+            return Err(Error::Timeout(smoke_test.timeout));
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let run = find_run(&octocrab, oar, &smoke_test.workflow, dispatched).await?;
+
+        if let Some(run) = run.filter(|run| run.status == "completed") {
+            break run;
+        }
+    };
+
+    let route = format!(
+        "/repos/{}/{}/actions/runs/{}/jobs",
+        oar.owner(),
+        oar.repository(),
+        run.id
+    );
+
+    let jobs: Jobs = octocrab.get(route, None::<&()>).await?;
+
+    let completed = jobs
+        .jobs
+        .iter()
+        .filter_map(|job| job.completed_at)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let jobs: Vec<JobReport> = jobs
+        .jobs
+        .into_iter()
+        .map(|job| {
+            // Our runners are named like "forrest-<machine name>-<random>".
+            let forrest = job.runner_name.as_deref().is_some_and(|runner_name| {
+                machine_names.iter().any(|machine_name| {
+                    runner_name
+                        .strip_prefix("forrest-")
+                        .and_then(|rest| rest.strip_prefix(machine_name.as_str()))
+                        .is_some_and(|rest| rest.starts_with('-'))
+                })
+            });
+
+            let ran = job.completed_at.unwrap_or(job.started_at) - job.started_at;
+
+            JobReport {
+                name: job.name,
+                conclusion: job.conclusion,
+                runner_name: job.runner_name,
+                forrest,
+                queued: (job.started_at - job.created_at).num_seconds(),
+                ran: ran.num_seconds(),
+            }
+        })
+        .collect();
+
+    let passed = run.conclusion.as_deref() == Some("success")
+        && !jobs.is_empty()
+        && jobs.iter().all(|job| job.forrest);
+
+    Ok(Report {
+        repository: oar.to_string(),
+        run_url: run.html_url,
+        conclusion: run.conclusion,
+        total: (completed - dispatched).num_seconds(),
+        jobs,
+        passed,
+    })
+}
+
+/// Run the smoke test of `oar` and print the report
+///
+/// Returns whether the smoke test passed.
+pub async fn run(config: &Config, oar: &OwnerAndRepo, output: Output) -> Result<bool, Error> {
+    let report = smoke_test(config, oar).await?;
+
+    if output == Output::Json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return Ok(report.passed);
+    }
+
+    println!("Run:        {}", report.run_url);
+    println!(
+        "Conclusion: {}",
+        report.conclusion.as_deref().unwrap_or("none")
+    );
+    println!("Total:      {}s", report.total);
+
+    for job in &report.jobs {
+        let runner = job.runner_name.as_deref().unwrap_or("no runner");
+        let served_by = match job.forrest {
+            true => "",
+            false => " (not a Forrest machine!)",
+        };
+
+        println!(
+            "  {}: {} on {runner}{served_by}, queued {}s, ran {}s",
+            job.name,
+            job.conclusion.as_deref().unwrap_or("none"),
+            job.queued,
+            job.ran,
+        );
+    }
+
+    Ok(report.passed)
+}
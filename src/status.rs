@@ -0,0 +1,360 @@
+use std::io::{IsTerminal, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::cli::Output;
+use crate::config::Config;
+
+// The daemon answers admin requests right away, or not at all.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A cell of a table, with the color to print it in
+struct Cell {
+    text: String,
+    color: Option<&'static str>,
+}
+
+impl Cell {
+    fn new(text: impl ToString) -> Self {
+        Self {
+            text: text.to_string(),
+            color: None,
+        }
+    }
+
+    fn colored(text: impl ToString, color: &'static str) -> Self {
+        Self {
+            text: text.to_string(),
+            color: Some(color),
+        }
+    }
+}
+
+/// Send a `GET` request for `path` to the admin socket and parse the JSON response
+fn get(socket: &Path, path: &str) -> std::io::Result<Value> {
+    let mut stream = UnixStream::connect(socket)?;
+
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: forrest\r\n\r\n")?;
+
+    // The admin API always closes the connection after one response.
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed admin response")
+    })?;
+
+    let status_line = head.lines().next().unwrap_or_default();
+
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(std::io::Error::other(format!(
+            "Request for {path} failed: {status_line}"
+        )));
+    }
+
+    Ok(serde_json::from_str(body)?)
+}
+
+fn str_of<'v>(value: &'v Value, key: &str) -> &'v str {
+    value.get(key).and_then(Value::as_str).unwrap_or("-")
+}
+
+fn status_color(status: &str) -> &'static str {
+    match status {
+        "running" => GREEN,
+        "waiting" | "paused" => CYAN,
+        "stopping" | "stopped" => DIM,
+        _ => YELLOW,
+    }
+}
+
+/// Print a table with a bold `title` and `header`, with columns padded to fit
+fn print_table(color: bool, title: &str, header: &[&str], rows: Vec<Vec<Cell>>) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.text.chars().count());
+        }
+    }
+
+    let paint = |text: &str, c: Option<&str>| match (color, c) {
+        (true, Some(c)) => format!("{c}{text}{RESET}"),
+        _ => text.to_owned(),
+    };
+
+    println!("{}", paint(title, Some(BOLD)));
+
+    if rows.is_empty() {
+        println!("  {}", paint("(none)", Some(DIM)));
+        println!();
+        return;
+    }
+
+    let line: Vec<String> = header
+        .iter()
+        .zip(&widths)
+        .map(|(h, w)| paint(&format!("{h:w$}"), Some(DIM)))
+        .collect();
+
+    println!("  {}", line.join("  ").trim_end());
+
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| paint(&format!("{:w$}", cell.text), cell.color))
+            .collect();
+
+        println!("  {}", line.join("  ").trim_end());
+    }
+
+    println!();
+}
+
+fn machines(status: &Value) -> Vec<Vec<Cell>> {
+    let machines = status.get("machines").and_then(Value::as_array);
+
+    let mut rows: Vec<Vec<Cell>> = machines
+        .into_iter()
+        .flatten()
+        .map(|machine| {
+            let state = str_of(machine, "status");
+
+            let notes = match machine.get("draining_reason").and_then(Value::as_str) {
+                Some(reason) => Cell::colored(format!("draining: {reason}"), YELLOW),
+                None => Cell::new(""),
+            };
+
+            vec![
+                Cell::new(str_of(machine, "triplet")),
+                Cell::new(str_of(machine, "runner_name")),
+                Cell::colored(state, status_color(state)),
+                notes,
+            ]
+        })
+        .collect();
+
+    rows.sort_by(|a, b| a[0].text.cmp(&b[0].text));
+
+    rows
+}
+
+fn queue(status: &Value) -> Vec<Vec<Cell>> {
+    let jobs = status.get("jobs").and_then(Value::as_array);
+
+    jobs.into_iter()
+        .flatten()
+        .map(|job| {
+            let mut notes = Vec::new();
+
+            if job.get("budget_exceeded") == Some(&Value::Bool(true)) {
+                notes.push("budget exceeded".to_owned());
+            }
+
+            if let Some(reason) = job.get("quarantine_reason").and_then(Value::as_str) {
+                notes.push(format!("quarantined: {reason}"));
+            }
+
+            if job.get("reserved") == Some(&Value::Bool(true)) {
+                notes.push("reserved".to_owned());
+            }
+
+            if job.get("boosted") == Some(&Value::Bool(true)) {
+                notes.push("boosted".to_owned());
+            }
+
+            let notes = match notes.is_empty() {
+                true => Cell::new(""),
+                false => Cell::colored(notes.join(", "), YELLOW),
+            };
+
+            vec![
+                Cell::new(str_of(job, "triplet")),
+                Cell::new(job.get("job_id").map(Value::to_string).unwrap_or_default()),
+                Cell::new(job.get("run_id").map(Value::to_string).unwrap_or_default()),
+                Cell::new(str_of(job, "status")),
+                notes,
+            ]
+        })
+        .collect()
+}
+
+fn budgets(accounting: &Value) -> Vec<Vec<Cell>> {
+    let budgets = accounting.get("budgets").and_then(Value::as_object);
+
+    let mut rows: Vec<Vec<Cell>> = budgets
+        .into_iter()
+        .flatten()
+        .map(|(owner, budget)| {
+            let used = budget.get("used").and_then(Value::as_f64).unwrap_or(0.0);
+            let monthly = budget.get("monthly").and_then(Value::as_f64).unwrap_or(0.0);
+            let exceeded = budget.get("exceeded") == Some(&Value::Bool(true));
+
+            let used = match exceeded {
+                true => Cell::colored(format!("{used:.2}"), RED),
+                false => Cell::new(format!("{used:.2}")),
+            };
+
+            vec![Cell::new(owner), used, Cell::new(format!("{monthly:.2}"))]
+        })
+        .collect();
+
+    rows.sort_by(|a, b| a[0].text.cmp(&b[0].text));
+
+    rows
+}
+
+fn health(health: &Value) -> Vec<Vec<Cell>> {
+    let machines = health.get("machines").and_then(Value::as_object);
+    let threshold = health.get("threshold").and_then(Value::as_f64);
+
+    let percent = |value: Option<&Value>| match value.and_then(Value::as_f64) {
+        Some(share) => format!("{:.0}%", share * 100.0),
+        None => "-".to_owned(),
+    };
+
+    machines
+        .into_iter()
+        .flatten()
+        .map(|(machine, health)| {
+            let score = health.get("score").and_then(Value::as_f64).unwrap_or(0.0);
+
+            let score = match threshold {
+                Some(threshold) if score < threshold => Cell::colored(format!("{score:.0}"), RED),
+                _ => Cell::colored(format!("{score:.0}"), GREEN),
+            };
+
+            let wait = match health.get("mean_wait").and_then(Value::as_f64) {
+                Some(wait) => format!("{wait:.0}s"),
+                None => "-".to_owned(),
+            };
+
+            vec![
+                Cell::new(machine),
+                score,
+                Cell::new(percent(health.get("boot_success"))),
+                Cell::new(percent(health.get("job_success"))),
+                Cell::new(wait),
+            ]
+        })
+        .collect()
+}
+
+fn errors(metrics: &Value) -> Vec<Vec<Cell>> {
+    let errors = metrics.get("errors").and_then(Value::as_object);
+
+    errors
+        .into_iter()
+        .flatten()
+        .flat_map(|(operation, categories)| {
+            categories
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(move |(category, count)| {
+                    vec![
+                        Cell::new(operation),
+                        Cell::new(category),
+                        Cell::colored(count, RED),
+                    ]
+                })
+        })
+        .collect()
+}
+
+/// Print an overview of the state of the running daemon
+///
+/// The daemon is reached via the admin socket in the `host.base_dir`.
+/// With JSON `output` the unprocessed responses are printed as one object.
+pub fn print(config: &Config, output: Output) -> std::io::Result<()> {
+    let socket = config.get().host.base_dir.join("admin.sock");
+
+    let status = get(&socket, "/status")?;
+    let accounting = get(&socket, "/accounting")?;
+    let metrics = get(&socket, "/metrics")?;
+    let health_report = get(&socket, "/health")?;
+
+    if output == Output::Json {
+        let combined = serde_json::json!({
+            "status": status,
+            "accounting": accounting,
+            "metrics": metrics,
+            "health": health_report,
+        });
+
+        println!("{}", serde_json::to_string_pretty(&combined)?);
+
+        return Ok(());
+    }
+
+    // Do not mess up the output with escape codes when it is e.g. piped
+    // into a file, or if the user asked for it via `NO_COLOR`.
+    let color = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+
+    if let Some(degraded) = status.get("degraded").filter(|d| !d.is_null()) {
+        let warning = format!(
+            "Degraded mode: the GitHub API is failing since {} ({})",
+            str_of(degraded, "since"),
+            str_of(degraded, "last_error"),
+        );
+
+        match color {
+            true => println!("{BOLD}{RED}{warning}{RESET}"),
+            false => println!("{warning}"),
+        }
+
+        println!();
+    }
+
+    print_table(
+        color,
+        "Machines",
+        &["TRIPLET", "RUNNER", "STATUS", "NOTES"],
+        machines(&status),
+    );
+
+    print_table(
+        color,
+        "Queue",
+        &["TRIPLET", "JOB", "RUN", "STATUS", "NOTES"],
+        queue(&status),
+    );
+
+    print_table(
+        color,
+        "Budgets",
+        &["OWNER", "USED", "MONTHLY"],
+        budgets(&accounting),
+    );
+
+    print_table(
+        color,
+        "Health",
+        &["MACHINE", "SCORE", "BOOTS", "JOBS", "MEAN WAIT"],
+        health(&health_report),
+    );
+
+    print_table(
+        color,
+        "Errors since startup",
+        &["OPERATION", "CATEGORY", "COUNT"],
+        errors(&metrics),
+    );
+
+    Ok(())
+}
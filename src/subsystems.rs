@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+/// The parts of Forrest that can be stopped and started while it runs
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    /// Accepting webhooks on `webhook.sock`
+    Webhook,
+    /// Receiving webhooks via `github.webhook_relay`
+    Relay,
+    /// Polling the GitHub API for jobs
+    Poller,
+    /// Starting machines for the demand and the periodic machine maintenance
+    MachineManager,
+}
+
+const ALL: &[Subsystem] = &[
+    Subsystem::Webhook,
+    Subsystem::Relay,
+    Subsystem::Poller,
+    Subsystem::MachineManager,
+];
+
+impl Subsystem {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Webhook => "webhook",
+            Self::Relay => "relay",
+            Self::Poller => "poller",
+            Self::MachineManager => "machine_manager",
+        }
+    }
+}
+
+impl std::fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ALL.iter()
+            .find(|subsystem| subsystem.as_str() == s)
+            .copied()
+            .ok_or_else(|| format!("Unknown subsystem {s}"))
+    }
+}
+
+/// Whether each of the `Subsystem`s should currently run
+///
+/// All subsystems run by default.
+/// A stopped subsystem only stops doing its work, e.g. the poller no longer
+/// polls, it is not torn down and can be started again at any time.
+#[derive(Clone)]
+pub struct Subsystems {
+    running: Arc<BTreeMap<Subsystem, watch::Sender<bool>>>,
+}
+
+impl Subsystems {
+    pub fn new() -> Self {
+        let running = ALL
+            .iter()
+            .map(|subsystem| (*subsystem, watch::Sender::new(true)))
+            .collect();
+
+        Self {
+            running: Arc::new(running),
+        }
+    }
+
+    fn sender(&self, subsystem: Subsystem) -> &watch::Sender<bool> {
+        // All subsystems are added in `new()`.
+        &self.running[&subsystem]
+    }
+
+    pub fn is_running(&self, subsystem: Subsystem) -> bool {
+        *self.sender(subsystem).borrow()
+    }
+
+    /// Start or stop a `subsystem`
+    ///
+    /// Returns whether this changed anything.
+    pub fn set_running(&self, subsystem: Subsystem, running: bool) -> bool {
+        self.sender(subsystem).send_replace(running) != running
+    }
+
+    /// Get whether each subsystem is running
+    pub fn states(&self) -> BTreeMap<Subsystem, bool> {
+        ALL.iter()
+            .map(|subsystem| (*subsystem, self.is_running(*subsystem)))
+            .collect()
+    }
+
+    /// Run the future created by `start` while `subsystem` is running
+    ///
+    /// The future is dropped when the subsystem is stopped and created anew
+    /// once it is started again.
+    /// Completes with the result of the future if it completes on its own.
+    pub async fn run<F, Fut>(&self, subsystem: Subsystem, mut start: F) -> std::io::Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::io::Result<()>>,
+    {
+        let mut running = self.sender(subsystem).subscribe();
+
+        loop {
+            // The sender lives as long as `self`, so this can not fail.
+            let _ = running.wait_for(|running| *running).await;
+
+            tokio::select! {
+                res = start() => return res,
+                _ = running.wait_for(|running| !*running) => {}
+            }
+        }
+    }
+}
+
+impl Default for Subsystems {
+    fn default() -> Self {
+        Self::new()
+    }
+}